@@ -1,29 +1,57 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion, Bencher};
-use measure_time::print_time;
-use pinin_rs::pinin::PinIn;
+use pinin_rs::accelerator::Accelerator;
+use pinin_rs::pinin::{FuzzySettings, NeutralTone, PinIn};
 use pinin_rs::searcher::{Searcher, SearcherLogic, TreeSearcher};
+#[cfg(not(feature = "mimalloc"))]
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
-const SMALL: &str = include_str!("small");
-const LARGE: &str = include_str!("small");
+/// Counts the bytes in use, to report what a loaded dictionary holds.
+#[cfg(not(feature = "mimalloc"))]
+struct Counting;
+
+static USED: AtomicUsize = AtomicUsize::new(0);
 
-fn fibonacci(n: u64) -> u64 {
-    match n {
-        0 => 1,
-        1 => 1,
-        n => fibonacci(n-1) + fibonacci(n-2),
+#[cfg(not(feature = "mimalloc"))]
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        USED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
     }
-}
 
-fn load_file(s: &str) -> Vec<&str> {
-    s.lines().collect()
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        USED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
 }
 
+#[cfg(not(feature = "mimalloc"))]
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const LARGE: &str = include_str!("small");
+
 fn small_build(context: &PinIn, searcher: &mut dyn Searcher<usize>) {
     LARGE.lines().enumerate().for_each(|(i, s)| {
         searcher.insert(context, s, i);
     });
 }
 
+fn fuzzy() -> FuzzySettings {
+    FuzzySettings {
+        zh2z: true,
+        sh2s: true,
+        ch2c: true,
+        ang2an: true,
+        ing2in: true,
+        eng2en: true,
+        u2v: true,
+        any_tone: false,
+        neutral_tone: NeutralTone::Optional,
+    }
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
 
     let mut pinin = PinIn::new();
@@ -32,22 +60,50 @@ fn criterion_benchmark(c: &mut Criterion) {
         pinin.load_default_dict();
         println!("load dict took {}ms", (std::time::Instant::now() - time).as_millis());
     }
+    {
+        let bytes = pinin.to_bytes().unwrap();
+        let time = std::time::Instant::now();
+        black_box(PinIn::from_bytes(&bytes).unwrap());
+        println!("hydrate dict took {}ms", (std::time::Instant::now() - time).as_millis());
+    }
     {
         let time = std::time::Instant::now();
-        let mut searcher = TreeSearcher::new(SearcherLogic::Begin, pinin.accelerator.clone().unwrap());
+        let mut searcher = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
         small_build(&pinin, &mut searcher);
 
         black_box(searcher);
 
         println!("build small dict took {}ms", (std::time::Instant::now() - time).as_millis());
     }
+    if cfg!(not(feature = "mimalloc")) {
+        let used = USED.load(Ordering::Relaxed);
+        let mut pinin = PinIn::new();
+        pinin.fuzzy = fuzzy();
+        pinin.load_default_dict();
+        println!("fuzzy dict holds {}KB", (USED.load(Ordering::Relaxed) - used) / 1024);
+        black_box(pinin);
+    }
+
 
+    c.bench_function("PinIn load dict fuzzy", |b: &mut Bencher| {
+        b.iter(|| {
+            let mut pinin = PinIn::new();
+            pinin.fuzzy = fuzzy();
+            pinin.load_default_dict();
+            black_box(pinin);
+        })
+    });
+
+    c.bench_function("PinIn from bytes", |b: &mut Bencher| {
+        let bytes = pinin.to_bytes().unwrap();
+        b.iter(|| black_box(PinIn::from_bytes(&bytes).unwrap()))
+    });
 
     c.bench_function("TreeSearcher build small", |b: &mut Bencher| {
         let mut pinin = PinIn::new();
         pinin.load_default_dict();
         b.iter(|| {
-            let mut searcher = TreeSearcher::new(SearcherLogic::Begin, pinin.accelerator.clone().unwrap());
+            let mut searcher = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
             small_build(&pinin, &mut searcher);
         })
     });
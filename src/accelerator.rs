@@ -1,13 +1,178 @@
 use crate::compressed::{IndexSet, IndexSetStorage};
-use crate::elements::Pinyin;
-use crate::pinin::PinIn;
-use std::cell::{Cell, RefCell};
-use std::ops::Index;
+use crate::elements::{Character, Pinyin};
+use crate::intern::CharTable;
+use crate::pinin::{is_reserved, PinIn};
+use crate::sync::{lock, read, write};
+use crate::unicode_utils::SegmentedStr;
+use std::ops::{Deref, Index};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, TryLockError};
 use compact_str::CompactString;
 
-pub trait CharProvider: Index<usize, Output = char> {
+pub trait CharProvider {
+    fn char_at(&self, index: usize) -> char;
     fn end(&self, index: usize) -> bool;
+
+    /// The dictionary entry of the char at `index`, for providers that
+    /// resolve it ahead of time. Others are looked up in the context.
+    fn character(&self, _index: usize) -> Option<&Character> {
+        None
+    }
+}
+
+impl<P: CharProvider + ?Sized> CharProvider for Rc<P> {
+    fn char_at(&self, index: usize) -> char {
+        self.as_ref().char_at(index)
+    }
+
+    fn end(&self, index: usize) -> bool {
+        self.as_ref().end(index)
+    }
+
+    fn character(&self, index: usize) -> Option<&Character> {
+        self.as_ref().character(index)
+    }
+}
+
+impl<P: CharProvider + ?Sized> CharProvider for Arc<P> {
+    fn char_at(&self, index: usize) -> char {
+        self.as_ref().char_at(index)
+    }
+
+    fn end(&self, index: usize) -> bool {
+        self.as_ref().end(index)
+    }
+
+    fn character(&self, index: usize) -> Option<&Character> {
+        self.as_ref().character(index)
+    }
+}
+
+/// A provider that can be shared with an [`Accelerator`] and still be
+/// changed by its owner, like the name buffer of a searcher.
+pub type SharedProvider = Arc<RwLock<dyn CharProvider + Send + Sync>>;
+
+/// Random access to a text stored as a sequence of string chunks,
+/// such as the leaves of a rope or the two halves of a gap buffer.
+pub trait CharChunks {
+    fn chunk_count(&self) -> usize;
+    fn chunk(&self, index: usize) -> &str;
+}
+
+impl<S: AsRef<str>> CharChunks for Vec<S> {
+    fn chunk_count(&self) -> usize {
+        self.len()
+    }
+
+    fn chunk(&self, index: usize) -> &str {
+        self[index].as_ref()
+    }
+}
+
+impl<S: AsRef<str>> CharChunks for Box<[S]> {
+    fn chunk_count(&self) -> usize {
+        self.len()
+    }
+
+    fn chunk(&self, index: usize) -> &str {
+        self[index].as_ref()
+    }
+}
+
+impl<C: CharChunks + ?Sized> CharChunks for Rc<C> {
+    fn chunk_count(&self) -> usize {
+        self.as_ref().chunk_count()
+    }
+
+    fn chunk(&self, index: usize) -> &str {
+        self.as_ref().chunk(index)
+    }
+}
+
+impl<C: CharChunks + ?Sized> CharChunks for Arc<C> {
+    fn chunk_count(&self) -> usize {
+        self.as_ref().chunk_count()
+    }
+
+    fn chunk(&self, index: usize) -> &str {
+        self.as_ref().chunk(index)
+    }
+}
+
+/// Adapts any [`CharChunks`] storage to a [`CharProvider`] without
+/// concatenating the chunks into a single string.
+pub struct ChunkedProvider<C> {
+    chunks: C,
+    starts: Vec<usize>,
+    len: usize,
+}
+
+impl<C: CharChunks> ChunkedProvider<C> {
+    pub fn new(chunks: C) -> Self {
+        let mut starts = Vec::with_capacity(chunks.chunk_count());
+        let mut len = 0;
+        for i in 0..chunks.chunk_count() {
+            starts.push(len);
+            len += chunks.chunk(i).chars().count();
+        }
+        ChunkedProvider { chunks, starts, len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn into_inner(self) -> C {
+        self.chunks
+    }
+}
+
+impl<C: CharChunks> From<C> for ChunkedProvider<C> {
+    fn from(chunks: C) -> Self {
+        ChunkedProvider::new(chunks)
+    }
+}
+
+impl<C: CharChunks> CharProvider for ChunkedProvider<C> {
+    fn char_at(&self, index: usize) -> char {
+        // Empty chunks share their start with the next one, so take the last match
+        let chunk = self.starts.partition_point(|start| *start <= index) - 1;
+        self.chunks
+            .chunk(chunk)
+            .chars()
+            .nth(index - self.starts[chunk])
+            .unwrap()
+    }
+
+    fn end(&self, index: usize) -> bool {
+        index >= self.len
+    }
+}
+
+/// A provider from outside the context, with the chars stand-ins are
+/// taken from replaced as [`PinIn`] targets have them.
+pub(crate) struct EscapedProvider<P>(pub(crate) P);
+
+impl<P: CharProvider> CharProvider for EscapedProvider<P> {
+    fn char_at(&self, index: usize) -> char {
+        match self.0.char_at(index) {
+            c if is_reserved(c) => char::REPLACEMENT_CHARACTER,
+            c => c,
+        }
+    }
+
+    fn end(&self, index: usize) -> bool {
+        self.0.end(index)
+    }
+
+    fn character(&self, index: usize) -> Option<&Character> {
+        self.0.character(index)
+    }
 }
 
 #[derive(Default)]
@@ -23,6 +188,14 @@ impl Index<usize> for StringProvider {
     }
 }
 
+impl StringProvider {
+    /// Replaces the content, reusing the existing allocation.
+    pub fn set(&mut self, s: &str) {
+        self.s.clear();
+        self.s.extend(s.chars());
+    }
+}
+
 impl From<&str> for StringProvider {
     fn from(s: &str) -> Self {
         StringProvider {
@@ -32,19 +205,81 @@ impl From<&str> for StringProvider {
 }
 
 impl CharProvider for StringProvider {
+    fn char_at(&self, index: usize) -> char {
+        self.s[index]
+    }
+
     fn end(&self, index: usize) -> bool {
         index >= self.s.len()
     }
 }
 
+/// A target string with its characters resolved against a context, for
+/// strings that are matched over and over with different queries. The
+/// characters are borrowed from the loaded table, not copied.
+pub struct TargetProvider {
+    chars: Vec<char>,
+    table: Arc<CharTable>,
+}
+
+impl TargetProvider {
+    pub fn new(context: &PinIn, s: &str) -> Self {
+        TargetProvider {
+            chars: s.chars().collect(),
+            table: context.chars.clone(),
+        }
+    }
+}
+
+impl CharProvider for TargetProvider {
+    fn char_at(&self, index: usize) -> char {
+        self.chars[index]
+    }
+
+    fn end(&self, index: usize) -> bool {
+        index >= self.chars.len()
+    }
+
+    /// Chars outside the table, such as those of a lazily loaded
+    /// dictionary, are looked up in the context.
+    fn character(&self, index: usize) -> Option<&Character> {
+        self.table.get(self.chars.get(index)?)?.as_ref()
+    }
+}
+
+/// Caches pinyin matches of the current query.
+///
+/// An accelerator serves one search at a time. Searchers take it with
+/// [`Accelerator::claim`], so that a searcher shared between threads
+/// searches in parallel instead of mixing up queries.
 pub struct Accelerator {
-    cache: Rc<RefCell<Vec<IndexSetStorage>>>,
+    cache: Mutex<Vec<IndexSetStorage>>,
 
-    search_chars: RefCell<Vec<char>>,
-    pub search_string: RefCell<CompactString>,
-    pub provider: RefCell<Option<Rc<RefCell<dyn CharProvider>>>>,
+    search_chars: RwLock<Vec<char>>,
+    /// Byte offsets of the graphemes of `search_string`.
+    search_graphemes: RwLock<Vec<usize>>,
+    pub search_string: RwLock<CompactString>,
+    pub provider: RwLock<Option<SharedProvider>>,
 
-    partial: Cell<bool>,
+    partial: AtomicBool,
+    busy: Mutex<()>,
+}
+
+/// An accelerator claimed for one search, see [`Accelerator::claim`].
+pub enum Claim<'a> {
+    Shared(&'a Accelerator, MutexGuard<'a, ()>),
+    Local(Box<Accelerator>),
+}
+
+impl Deref for Claim<'_> {
+    type Target = Accelerator;
+
+    fn deref(&self) -> &Accelerator {
+        match self {
+            Claim::Shared(a, _) => a,
+            Claim::Local(a) => a,
+        }
+    }
 }
 
 impl Default for Accelerator {
@@ -56,40 +291,110 @@ impl Default for Accelerator {
 impl Accelerator {
     pub fn new() -> Self {
         Accelerator {
-            cache: Rc::new(RefCell::new(Default::default())),
-            search_chars: RefCell::new(Default::default()),
-            search_string: RefCell::new("".into()),
-            provider: RefCell::new(None),
-            partial: Cell::new(false),
+            cache: Mutex::new(Default::default()),
+            search_chars: RwLock::new(Default::default()),
+            search_graphemes: RwLock::new(Default::default()),
+            search_string: RwLock::new("".into()),
+            provider: RwLock::new(None),
+            partial: AtomicBool::new(false),
+            busy: Mutex::new(()),
+        }
+    }
+
+    pub fn with_provider(provider: SharedProvider) -> Self {
+        let ret = Self::new();
+        ret.set_provider(provider);
+        ret
+    }
+
+    pub fn set_provider(&self, provider: SharedProvider) {
+        *write(&self.provider) = Some(provider);
+    }
+
+    /// Waits until no other search uses this accelerator, and keeps others
+    /// out until the guard is dropped.
+    pub fn lock(&self) -> MutexGuard<'_, ()> {
+        lock(&self.busy)
+    }
+
+    /// This accelerator, if no other search is using it. Otherwise a new
+    /// one over the same provider, which starts with an empty cache.
+    pub fn claim(&self) -> Claim<'_> {
+        match self.busy.try_lock() {
+            Ok(guard) => Claim::Shared(self, guard),
+            Err(TryLockError::Poisoned(e)) => Claim::Shared(self, e.into_inner()),
+            Err(TryLockError::WouldBlock) => {
+                let local = Accelerator::new();
+                *write(&local.provider) = read(&self.provider).clone();
+                Claim::Local(Box::new(local))
+            }
         }
     }
 
+    /// The number of chars of the current query.
+    pub fn search_len(&self) -> usize {
+        read(&self.search_chars).len()
+    }
+
+    /// The char of the current query at `offset`, `None` at or past its
+    /// end.
+    pub fn search_char(&self, offset: usize) -> Option<char> {
+        read(&self.search_chars).get(offset).copied()
+    }
+
+    fn provider(&self) -> Option<SharedProvider> {
+        read(&self.provider).clone()
+    }
+
     pub fn matches(&self, context: &PinIn, offset: usize, start: usize) -> bool {
-        if self.partial.get() {
-            self.partial.set(false);
+        if self.partial.swap(false, Ordering::Relaxed) {
             self.reset();
         }
         self.check(context, offset, start)
     }
 
     pub fn begins(&self, context: &PinIn, offset: usize, start: usize) -> bool {
-        if !self.partial.get() {
-            self.partial.set(true);
+        if !self.partial.swap(true, Ordering::Relaxed) {
             self.reset();
         }
         self.check(context, offset, start)
     }
 
     pub fn contains(&self, context: &PinIn, offset: usize, start: usize) -> bool {
-        if !self.partial.get() {
-            self.partial.set(true);
+        if !self.partial.swap(true, Ordering::Relaxed) {
+            self.reset();
+        }
+        if offset >= self.search_len() {
+            return true;
+        }
+        if let Some(provider) = self.provider() {
+            let provider = read(&provider);
+            let mut i = start;
+            while !provider.end(i) {
+                if self.check_in(&*provider, context, offset, i) {
+                    return true;
+                }
+
+                i += 1;
+            }
+        }
+        false
+    }
+
+    /// Whether the query, from `offset` on, matches the end of the target
+    /// at or after `start`.
+    pub fn ends(&self, context: &PinIn, offset: usize, start: usize) -> bool {
+        if self.partial.swap(false, Ordering::Relaxed) {
             self.reset();
         }
-        if let Some(provider) = self.provider.borrow().as_ref() {
-            let provider = provider.borrow();
+        if offset >= self.search_len() {
+            return true;
+        }
+        if let Some(provider) = self.provider() {
+            let provider = read(&provider);
             let mut i = start;
             while !provider.end(i) {
-                if self.check(context, offset, i) {
+                if self.check_in(&*provider, context, offset, i) {
                     return true;
                 }
 
@@ -99,16 +404,79 @@ impl Accelerator {
         false
     }
 
+    /// The fewest chars from `start` the query, from `offset` on, spans
+    /// with [`begins`](Accelerator::begins) semantics, or `None` when it
+    /// does not match there. Queries typed as fuller pinyin span fewer.
+    pub fn span(&self, context: &PinIn, offset: usize, start: usize) -> Option<usize> {
+        if !self.partial.swap(true, Ordering::Relaxed) {
+            self.reset();
+        }
+        let provider = self.provider()?;
+        let provider = read(&provider);
+        self.span_in(&*provider, context, offset, start)
+    }
+
+    /// How many query chars each char from `start` consumes along the
+    /// match found by [`span`](Accelerator::span).
+    pub fn path(&self, context: &PinIn, offset: usize, start: usize) -> Option<Vec<usize>> {
+        let mut len = self.span(context, offset, start)?;
+        let provider = self.provider()?;
+        let provider = read(&provider);
+        let (mut offset, mut start) = (offset, start);
+        let mut ret = Vec::with_capacity(len);
+        while len > 0 {
+            let mut step = None;
+            self.at(&*provider, context, offset, start).for_each(|i| {
+                let rest = self.span_in(&*provider, context, offset + i as usize, start + 1);
+                if step.is_none() && rest == Some(len - 1) {
+                    step = Some(i as usize);
+                }
+            });
+            let step = step?;
+            ret.push(step);
+            offset += step;
+            start += 1;
+            len -= 1;
+        }
+        Some(ret)
+    }
+
+    /// What the char at `start` may consume of the query from `offset`.
+    fn at(&self, provider: &dyn CharProvider, context: &PinIn, offset: usize, start: usize) -> IndexSet {
+        match provider.character(start) {
+            Some(c) => self.get_character(c, offset),
+            None => self.get(context, provider.char_at(start), offset),
+        }
+    }
+
+    fn span_in(&self, provider: &dyn CharProvider, context: &PinIn, offset: usize, start: usize) -> Option<usize> {
+        if offset >= self.search_len() {
+            return Some(0);
+        }
+        if provider.end(start) {
+            return None;
+        }
+
+        let s = self.at(provider, context, offset, start);
+        let mut ret: Option<usize> = None;
+        s.for_each(|i| {
+            if let Some(x) = self.span_in(provider, context, offset + i as usize, start + 1) {
+                ret = Some(ret.map_or(x + 1, |y| y.min(x + 1)));
+            }
+        });
+        ret
+    }
+
     pub fn common(&self, s1: usize, s2: usize, max: usize) -> usize {
-        if let Some(provider) = self.provider.borrow().as_ref() {
-            let provider = provider.borrow();
+        if let Some(provider) = self.provider() {
+            let provider = read(&provider);
             let mut i = 0;
             loop {
                 if i >= max {
                     return max;
                 }
-                let a = provider[s1 + i];
-                let b = provider[s2 + i];
+                let a = provider.char_at(s1 + i);
+                let b = provider.char_at(s2 + i);
                 if a != b || a == '\0' {
                     return i;
                 }
@@ -121,65 +489,92 @@ impl Accelerator {
     }
 
     pub fn search(&self, s: &str) {
-        if self.search_string.borrow().as_str() != s {
-            *self.search_string.borrow_mut() = s.into();
-            *self.search_chars.borrow_mut() = s.chars().collect();
+        if read(&self.search_string).as_str() != s {
+            *write(&self.search_string) = s.into();
+            *write(&self.search_chars) = s.chars().collect();
+            *write(&self.search_graphemes) = SegmentedStr::from(s).offsets();
             self.reset();
         }
     }
 
     pub fn reset(&self) {
-        self.cache.borrow_mut().clear();
+        lock(&self.cache).clear();
+    }
+
+    /// Sets whether the query may stop within a syllable, as `begins`
+    /// lets it and `matches` does not, for callers that only use `get`.
+    pub(crate) fn set_partial(&self, partial: bool) {
+        if self.partial.swap(partial, Ordering::Relaxed) != partial {
+            self.reset();
+        }
     }
 
     pub fn get(&self, context: &PinIn, ch: char, offset: usize) -> IndexSet {
-        let c = context.get_character(ch);
-        let mut ret = if self.search_chars.borrow()[offset] == ch {
+        self.get_character(&context.target_character(ch), offset)
+    }
+
+    pub fn get_character(&self, c: &Character, offset: usize) -> IndexSet {
+        let mut ret = if self.search_char(offset) == Some(c.ch) {
             IndexSet::one()
         } else {
             IndexSet::none()
         };
         c.pinyin
             .iter()
-            .for_each(|x| ret.merge(self.get_pinyin(x, offset)));
+            .for_each(|x| ret.union(self.get_pinyin(x, offset)));
         ret
     }
 
+    /// Runs `f` on the query as segmented when it was set.
+    pub(crate) fn with_query<R>(&self, f: impl FnOnce(&SegmentedStr) -> R) -> R {
+        let (search, offsets) = (read(&self.search_string), read(&self.search_graphemes));
+        let s = SegmentedStr::from_offsets(search.as_str(), &offsets);
+        f(&s)
+    }
+
+    /// What `p` may consume of the query from `offset`; nothing at or past
+    /// its end.
     pub fn get_pinyin(&self, p: &Pinyin, offset: usize) -> IndexSet {
-        let mut cache = self.cache.borrow_mut();
-        cache.resize_with(offset + 1, IndexSetStorage::new);
-        let data = &mut cache[offset];
-        let ret = data.get(p.id);
+        if offset >= self.search_len() {
+            return IndexSet::none();
+        }
+        let ret = lock(&self.cache).get(offset).map(|x| x.get(p.id)).unwrap_or(IndexSet::null());
         if ret != IndexSet::null() {
             return ret;
         }
 
-        let set = p.match_string(self.search_string.borrow().as_str(), offset, self.partial.get());
-        data.set(set, p.id);
+        let set = self.with_query(|s| p.match_segmented(s, offset, self.partial.load(Ordering::Relaxed)));
+        let mut cache = lock(&self.cache);
+        if cache.len() <= offset {
+            cache.resize_with(offset + 1, IndexSetStorage::new);
+        }
+        cache[offset].set(set, p.id);
         set
     }
 
     pub fn check(&self, context: &PinIn, offset: usize, start: usize) -> bool {
-        if let Some(provider) = self.provider.borrow().as_ref() {
-            let provider = provider.borrow();
-            if offset == self.search_string.borrow().chars().count() {
-                return self.partial.get() || provider.end(start);
-            }
-
-            if provider.end(start) {
-                return false;
-            }
+        match self.provider() {
+            Some(provider) => self.check_in(&*read(&provider), context, offset, start),
+            None => false,
+        }
+    }
 
-            let s = self.get(context, provider[start], offset);
+    fn check_in(&self, provider: &dyn CharProvider, context: &PinIn, offset: usize, start: usize) -> bool {
+        if offset >= self.search_len() {
+            return self.partial.load(Ordering::Relaxed) || provider.end(start);
+        }
 
-            return if provider.end(start + 1) {
-                let i = self.search_string.borrow().chars().count() - offset;
-                s.get(i)
-            } else {
-                s.traverse(|i| self.check(context, offset + i as usize, start + 1))
-            }
+        if provider.end(start) {
+            return false;
         }
 
-        false
+        let s = self.at(provider, context, offset, start);
+
+        if provider.end(start + 1) {
+            let i = self.search_len() - offset;
+            s.get(i)
+        } else {
+            s.traverse(|i| self.check_in(provider, context, offset + i as usize, start + 1))
+        }
     }
 }
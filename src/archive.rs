@@ -0,0 +1,248 @@
+//! A zero-copy archive of a [`FrozenSearcher`], searched where it lies,
+//! e.g. in a memory-mapped file, so that a big index opens without being
+//! read or deserialized first.
+//!
+//! ```ignore
+//! let bytes = tree.freeze().to_archive()?;
+//! std::fs::write("items.idx", &bytes)?;
+//!
+//! let mapped = MappedSearcher::<u32>::open("items.idx")?;
+//! let hits = mapped.search(&context, "hj");
+//! ```
+
+use crate::frozen::{collect, FrozenSearcher, Layout};
+use crate::pinin::PinIn;
+use crate::searcher::{SearcherLogic, SpillSet};
+use memmap2::Mmap;
+use rkyv::ser::serializers::AllocSerializer;
+use rkyv::validation::validators::DefaultValidator;
+use rkyv::{AlignedVec, Archive, Archived, CheckBytes, Serialize};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::marker::PhantomData;
+use std::ops::{Deref, Range};
+use std::path::Path;
+
+const LOGICS: [SearcherLogic; 4] = [SearcherLogic::Begin, SearcherLogic::Contain, SearcherLogic::Equal, SearcherLogic::EndsWith];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArchiveError {
+    /// The searcher holds more chars or names than fit in 32 bits.
+    TooLarge,
+    /// The objects could not be serialized.
+    Serialize,
+    /// The bytes are not an archive of a searcher with these objects.
+    Invalid,
+}
+
+impl Display for ArchiveError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArchiveError::TooLarge => f.write_str("searcher too large to archive"),
+            ArchiveError::Serialize => f.write_str("failed to serialize the objects"),
+            ArchiveError::Invalid => f.write_str("invalid searcher archive"),
+        }
+    }
+}
+
+impl Error for ArchiveError {}
+
+/// The archived layout of a [`FrozenSearcher`], read in place as
+/// [`ArchivedFrozenIndex`]. Positions are stored as `u32` so that
+/// archives read the same on every platform.
+#[derive(Archive, Serialize)]
+#[archive(check_bytes)]
+pub struct FrozenIndex<T> {
+    logic: u8,
+    /// Five per node: its edges, its ids and the end of its own ids.
+    nodes: Vec<u32>,
+    /// Three per edge: its label and its child.
+    edges: Vec<u32>,
+    ids: Vec<u32>,
+    chars: Vec<u32>,
+    offsets: Vec<u32>,
+    /// Names shown otherwise than they are matched, by index.
+    original_indices: Vec<u32>,
+    originals: Vec<String>,
+    objects: Vec<T>,
+}
+
+impl<T> FrozenSearcher<T> {
+    /// Writes the searcher in the format read by [`archived`] and
+    /// [`MappedSearcher`].
+    pub fn to_archive(&self) -> Result<AlignedVec, ArchiveError>
+    where
+        T: Clone + Serialize<AllocSerializer<1024>>,
+    {
+        fn small<I: IntoIterator<Item = usize>>(x: I) -> Result<Vec<u32>, ArchiveError> {
+            x.into_iter().map(|x| u32::try_from(x).map_err(|_| ArchiveError::TooLarge)).collect()
+        }
+
+        let originals: Vec<(usize, String)> = self.compressor.originals().map(|(i, x)| (i, x.to_string())).collect();
+        let index = FrozenIndex {
+            logic: LOGICS.iter().position(|x| *x == self.logic()).unwrap_or(0) as u8,
+            nodes: small(self.nodes.iter().flat_map(|x| [x.edges.start, x.edges.end, x.ids.start, x.ids.end, x.own]))?,
+            edges: small(self.edges.iter().flat_map(|x| [x.label.start, x.label.end, x.child]))?,
+            ids: small(self.ids.iter().copied())?,
+            chars: self.compressor.flat().iter().map(|x| *x as u32).collect(),
+            offsets: small(self.compressor.offsets.iter().copied())?,
+            original_indices: small(originals.iter().map(|(i, _)| *i))?,
+            originals: originals.into_iter().map(|(_, x)| x).collect(),
+            objects: self.objects.clone(),
+        };
+        rkyv::to_bytes::<_, 1024>(&index).map_err(|_| ArchiveError::Serialize)
+    }
+}
+
+impl<T: Archive> Layout for ArchivedFrozenIndex<T> {
+    fn logic(&self) -> SearcherLogic {
+        LOGICS[self.logic as usize]
+    }
+
+    fn node(&self, index: usize) -> (Range<usize>, Range<usize>, usize) {
+        let x = &self.nodes[index * 5..index * 5 + 5];
+        (x[0] as usize..x[1] as usize, x[2] as usize..x[3] as usize, x[4] as usize)
+    }
+
+    fn edge(&self, index: usize) -> (Range<usize>, usize) {
+        let x = &self.edges[index * 3..index * 3 + 3];
+        (x[0] as usize..x[1] as usize, x[2] as usize)
+    }
+
+    fn id(&self, index: usize) -> usize {
+        self.ids[index] as usize
+    }
+
+    fn char_at(&self, pos: usize) -> char {
+        char::from_u32(self.chars[pos]).unwrap_or('\0')
+    }
+}
+
+impl<T: Archive> ArchivedFrozenIndex<T> {
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Archived<T>> {
+        self.objects.get(index)
+    }
+
+    /// The name the `index`-th object was inserted with.
+    pub fn name(&self, index: usize) -> Option<String> {
+        let start = *self.offsets.get(index)? as usize;
+        if let Ok(i) = self.original_indices.binary_search(&(index as u32)) {
+            return Some(self.originals[i].as_str().to_string());
+        }
+        Some(self.chars[start..].iter().map(|x| char::from_u32(*x).unwrap_or('\0')).take_while(|c| *c != '\0').collect())
+    }
+
+    pub fn logic(&self) -> SearcherLogic {
+        Layout::logic(self)
+    }
+
+    /// Matches in insertion order.
+    pub fn search(&self, context: &PinIn, s: &str) -> Vec<&Archived<T>> {
+        self.search_indices(context, s).into_iter().map(|i| &self.objects[i]).collect()
+    }
+
+    /// Insertion indices of the matching objects, ascending.
+    pub fn search_indices(&self, context: &PinIn, s: &str) -> Vec<usize> {
+        let mut ret = SpillSet::default();
+        collect(self, context, s, &mut ret);
+        ret.into_sorted_vec()
+    }
+
+    pub fn count(&self, context: &PinIn, s: &str) -> usize {
+        self.search_indices(context, s).len()
+    }
+
+    /// Whether every position points into the archive and the tree has
+    /// no cycles, which the byte-level check cannot tell.
+    fn is_consistent(&self) -> bool {
+        let (nodes, edges) = (self.nodes.len() / 5, self.edges.len() / 3);
+        let within = |x: &Range<usize>, len: usize| x.start <= x.end && x.end <= len;
+        self.nodes.len().is_multiple_of(5)
+            && self.edges.len().is_multiple_of(3)
+            && nodes > 0
+            && (self.logic as usize) < LOGICS.len()
+            && matches!(self.chars.last(), None | Some(0))
+            && self.offsets.len() == self.objects.len()
+            && self.offsets.iter().all(|x| (*x as usize) < self.chars.len())
+            && self.ids.iter().all(|x| (*x as usize) < self.objects.len())
+            && self.original_indices.len() == self.originals.len()
+            && (0..nodes).all(|i| {
+                let (e, ids, own) = self.node(i);
+                within(&e, edges) && within(&ids, self.ids.len()) && (ids.start..=ids.end).contains(&own)
+            })
+            // Children come after their parents
+            && (0..nodes).all(|i| {
+                self.node(i).0.all(|e| {
+                    let (label, child) = self.edge(e);
+                    within(&label, self.chars.len()) && child > i && child < nodes
+                })
+            })
+    }
+}
+
+/// The index archived in `bytes`, after checking that it is well-formed.
+/// The bytes must be aligned like those of an [`AlignedVec`] or a
+/// memory map.
+pub fn archived<T>(bytes: &[u8]) -> Result<&ArchivedFrozenIndex<T>, ArchiveError>
+where
+    T: Archive,
+    ArchivedFrozenIndex<T>: for<'a> CheckBytes<DefaultValidator<'a>>,
+{
+    let ret = rkyv::check_archived_root::<FrozenIndex<T>>(bytes).map_err(|_| ArchiveError::Invalid)?;
+    if !ret.is_consistent() {
+        return Err(ArchiveError::Invalid);
+    }
+    Ok(ret)
+}
+
+/// An archive written by [`FrozenSearcher::to_archive`], searched
+/// straight from a memory-mapped file.
+pub struct MappedSearcher<T> {
+    map: Mmap,
+    phantom: PhantomData<fn() -> T>,
+}
+
+impl<T: Archive> MappedSearcher<T> {
+    /// Maps the archive at `path` and checks it, which reads it once.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self>
+    where
+        ArchivedFrozenIndex<T>: for<'a> CheckBytes<DefaultValidator<'a>>,
+    {
+        // SAFETY: Changes to the file while mapped are as undefined as for
+        // any other memory map; the content itself is checked below
+        let map = unsafe { Mmap::map(&File::open(path)?)? };
+        archived::<T>(&map).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(MappedSearcher { map, phantom: PhantomData })
+    }
+
+    /// Maps the archive at `path` without reading it.
+    ///
+    /// # Safety
+    ///
+    /// The file must hold an archive written by
+    /// [`FrozenSearcher::to_archive`] for the same `T`, e.g. one this
+    /// program wrote itself, and must not change while mapped.
+    pub unsafe fn open_unchecked<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let map = Mmap::map(&File::open(path)?)?;
+        Ok(MappedSearcher { map, phantom: PhantomData })
+    }
+}
+
+impl<T: Archive> Deref for MappedSearcher<T> {
+    type Target = ArchivedFrozenIndex<T>;
+
+    fn deref(&self) -> &ArchivedFrozenIndex<T> {
+        // SAFETY: Checked in `open`, or promised to `open_unchecked`
+        unsafe { rkyv::archived_root::<FrozenIndex<T>>(&self.map) }
+    }
+}
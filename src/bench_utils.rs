@@ -0,0 +1,114 @@
+//! Timing of index builds and queries over an app's own names, for
+//! regression tests that should not need a benchmark framework.
+//!
+//! ```
+//! use pinin_rs::bench_utils::bench_searcher;
+//! use pinin_rs::pinin::PinIn;
+//! use pinin_rs::searcher::{SearcherLogic, SimpleSearcher};
+//!
+//! let mut pinin = PinIn::new();
+//! pinin.load_default_dict();
+//! let names = ["合金炉", "洗矿场", "合成台"];
+//! let report = bench_searcher(&pinin, SimpleSearcher::new(SearcherLogic::Contain), names, &["hj", "he"], 10);
+//! assert_eq!(report.entries, 3);
+//! assert_eq!(report.hits, 3);
+//! println!("{}", report);
+//! ```
+
+use crate::pinin::PinIn;
+use crate::searcher::Searcher;
+use std::fmt::{Display, Formatter};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// Distribution of the time single queries took.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Latency {
+    pub min: Duration,
+    pub mean: Duration,
+    pub median: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+impl Latency {
+    /// Summarizes `samples`, all zero when there are none.
+    pub fn from_samples(mut samples: Vec<Duration>) -> Self {
+        if samples.is_empty() {
+            return Latency::default();
+        }
+        samples.sort_unstable();
+        let at = |q: usize| samples[(samples.len() - 1) * q / 100];
+        Latency {
+            min: samples[0],
+            mean: samples.iter().sum::<Duration>() / samples.len() as u32,
+            median: at(50),
+            p95: at(95),
+            max: samples[samples.len() - 1],
+        }
+    }
+}
+
+/// What [`bench_searcher`] measured.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BenchReport {
+    /// Names inserted.
+    pub entries: usize,
+    /// Inserting all names and building the index.
+    pub build: Duration,
+    /// Queries timed, i.e. queries times rounds.
+    pub queries: usize,
+    /// Results of one round of all queries, which should not change
+    /// between runs of the same data.
+    pub hits: usize,
+    pub latency: Latency,
+}
+
+impl Display for BenchReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let l = &self.latency;
+        write!(
+            f,
+            "{} entries built in {:?}, {} queries with {} hits: min {:?}, mean {:?}, median {:?}, p95 {:?}, max {:?}",
+            self.entries, self.build, self.queries, self.hits, l.min, l.mean, l.median, l.p95, l.max
+        )
+    }
+}
+
+/// Inserts `names` into `searcher`, then runs every query `rounds` times.
+/// The object of each name is its position in `names`.
+pub fn bench_searcher<S, I>(context: &PinIn, mut searcher: S, names: I, queries: &[&str], rounds: usize) -> BenchReport
+where
+    S: Searcher<usize>,
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    let time = Instant::now();
+    let mut entries = 0;
+    for (i, name) in names.into_iter().enumerate() {
+        searcher.insert(context, name.as_ref(), i);
+        entries += 1;
+    }
+    searcher.reset(context);
+    let build = time.elapsed();
+
+    let hits = queries.iter().map(|q| searcher.count(context, q)).sum();
+    let mut samples = Vec::with_capacity(queries.len() * rounds);
+    for _ in 0..rounds {
+        for q in queries {
+            let time = Instant::now();
+            black_box(searcher.search(context, black_box(q)));
+            samples.push(time.elapsed());
+        }
+    }
+
+    BenchReport {
+        entries,
+        build,
+        queries: samples.len(),
+        hits,
+        latency: Latency::from_samples(samples),
+    }
+}
@@ -0,0 +1,110 @@
+//! Time limits and cancellation for searches, so that interactive apps
+//! can give up on slow queries instead of blocking.
+
+use crate::searcher::Collection;
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How many checks pass between two looks at the clock.
+const CLOCK_INTERVAL: u32 = 64;
+
+/// A flag shared with another thread, which sets it to stop a search.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// When a search has to stop: a deadline, a [`CancelToken`], both or
+/// neither.
+#[derive(Debug, Clone, Default)]
+pub struct Budget {
+    deadline: Option<Instant>,
+    token: Option<CancelToken>,
+    checks: Cell<u32>,
+    expired: Cell<bool>,
+}
+
+impl Budget {
+    /// A budget that never runs out.
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    pub fn until(deadline: Instant) -> Self {
+        Budget {
+            deadline: Some(deadline),
+            ..Default::default()
+        }
+    }
+
+    pub fn within(duration: Duration) -> Self {
+        Self::until(Instant::now() + duration)
+    }
+
+    pub fn with_token(mut self, token: CancelToken) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Whether the search should stop. The clock is only read every few
+    /// calls, so this is cheap enough for inner loops.
+    pub fn is_expired(&self) -> bool {
+        if self.expired.get() {
+            return true;
+        }
+        let cancelled = self.token.as_ref().map(CancelToken::is_cancelled).unwrap_or(false);
+        let checks = self.checks.get();
+        self.checks.set(checks.wrapping_add(1));
+        let late = checks.is_multiple_of(CLOCK_INTERVAL) && self.deadline.map(|x| Instant::now() >= x).unwrap_or(false);
+        self.expired.set(cancelled || late);
+        self.expired.get()
+    }
+}
+
+/// Returned by searches that ran out of their [`Budget`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("search cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+/// Stops tree traversal once `budget` runs out.
+pub(crate) struct Budgeted<'b, C> {
+    pub(crate) inner: C,
+    pub(crate) budget: &'b Budget,
+}
+
+impl<C: Collection<usize>> Collection<usize> for Budgeted<'_, C> {
+    fn insert(&mut self, data: usize) {
+        self.inner.insert(data);
+    }
+
+    fn is_full(&self) -> bool {
+        self.budget.is_expired() || self.inner.is_full()
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.inner.reserve(additional);
+    }
+}
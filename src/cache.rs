@@ -1 +1,56 @@
+//! A least recently used cache.
 
+use rustc_hash::FxHashMap;
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::hash::Hash;
+
+/// Keeps up to a given number of entries, dropping the one used longest
+/// ago to make room.
+pub(crate) struct Lru<K, V> {
+    entries: FxHashMap<K, (V, u64)>,
+    /// Keys by the tick they were last used at.
+    order: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+impl<K, V> Default for Lru<K, V> {
+    fn default() -> Self {
+        Lru { entries: Default::default(), order: BTreeMap::new(), tick: 0 }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Lru<K, V> {
+    pub fn get<Q: Hash + Eq + ?Sized>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        let (value, tick) = self.entries.get_mut(key)?;
+        let key = self.order.remove(tick).expect("every entry is ordered");
+        self.tick += 1;
+        *tick = self.tick;
+        self.order.insert(self.tick, key);
+        Some(value.clone())
+    }
+
+    /// Adds `value`, dropping the least recently used entries beyond
+    /// `capacity`.
+    pub fn insert(&mut self, key: K, value: V, capacity: usize) {
+        self.tick += 1;
+        if let Some((_, tick)) = self.entries.insert(key.clone(), (value, self.tick)) {
+            self.order.remove(&tick);
+        }
+        self.order.insert(self.tick, key);
+        while self.entries.len() > capacity {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&key);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
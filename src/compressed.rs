@@ -1,10 +1,15 @@
+use std::borrow::Cow;
 use std::fmt::{Display, Formatter};
 use std::ops::Index;
 use smallvec::SmallVec;
 
 use crate::accelerator::CharProvider;
+use crate::unicode_utils::canonicalize;
+use rustc_hash::{FxHashMap, FxHasher};
+use std::collections::hash_map::Entry;
+use std::hash::{Hash, Hasher};
 
-#[derive(Default, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Default, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug)]
 pub struct IndexSet {
     value: i32,
 }
@@ -53,14 +58,21 @@ impl IndexSet {
         IndexSet { value }
     }
 
+    /// The bit of `index`, none for indices past the capacity.
+    #[inline]
+    fn bit(index: usize) -> i32 {
+        u32::try_from(index).ok().and_then(|i| 0x1i32.checked_shl(i)).unwrap_or(0)
+    }
+
+    /// Indices past the capacity of 32 are ignored.
     #[inline]
     pub fn set(&mut self, index: usize) {
-        self.value |= 0x1 << index;
+        self.value |= Self::bit(index);
     }
 
     #[inline]
     pub fn get(&self, index: usize) -> bool {
-        self.value & (0x1 << index) != 0
+        self.value & Self::bit(index) != 0
     }
 
     #[inline]
@@ -99,9 +111,23 @@ impl IndexSet {
 
     #[inline]
     pub fn offset(&mut self, i: i32) {
-        self.value <<= i;
+        self.value = u32::try_from(i).ok().and_then(|i| self.value.checked_shl(i)).unwrap_or(0);
+    }
+
+    /// Adds every index of `s`.
+    #[inline]
+    pub fn union(&mut self, s: Self) {
+        self.value |= s.value;
+    }
+
+    /// Replaces the content with the indices of `s`.
+    #[inline]
+    pub fn replace(&mut self, s: Self) {
+        self.value = s.value;
     }
 
+    /// Merge as in the original PinIn: `replace` when this set only holds
+    /// index 0, `union` otherwise. Note that this drops index 0.
     #[inline]
     pub fn merge(&mut self, s: Self) {
         if self.value == 0x1 {
@@ -157,10 +183,45 @@ impl IndexSetStorage {
     }
 }
 
+/// A run of entries whose first `shared` chars are those from `from`,
+/// followed by the chars from `tail` on.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Front {
+    start: usize,
+    shared: usize,
+    from: usize,
+    tail: usize,
+}
+
+/// Shorter prefixes take more room as a [`Front`] than as chars.
+const MIN_SHARED: usize = size_of::<Front>() / size_of::<char>();
+
+/// The names of a searcher, as one run of chars with each entry ended by
+/// `'\0'`. Positions into it are logical: with front coding, the chars
+/// of an entry may be stored apart or shared with an earlier one, and
+/// are read back through [`CharProvider`] or indexing.
 #[derive(Default, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Compressor {
+    /// The stored chars, which are the logical ones unless front coding
+    /// shared some prefix.
     pub chars: Vec<char>,
     pub offsets: Vec<usize>,
+    /// Entries whose pushed string differs from its canonical form.
+    originals: FxHashMap<usize, String>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    interning: bool,
+    /// Offsets of stored entries by the hash of their chars, built on the
+    /// first push with `interning` set.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    interned: Option<FxHashMap<u64, usize>>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    front_coding: bool,
+    /// Where stored chars stop following logical positions, by logical
+    /// start. Empty as long as no prefix was shared.
+    #[cfg_attr(feature = "serde", serde(default))]
+    fronts: Vec<Front>,
 }
 
 impl Index<usize> for Compressor {
@@ -168,23 +229,244 @@ impl Index<usize> for Compressor {
 
     #[inline]
     fn index(&self, index: usize) -> &Self::Output {
-        &self.chars[index]
+        &self.chars[self.locate(index)]
     }
 }
 
 impl CharProvider for Compressor {
+    #[inline]
+    fn char_at(&self, index: usize) -> char {
+        self[index]
+    }
+
     #[inline]
     fn end(&self, index: usize) -> bool {
-        self.chars.get(index) == Some(&'\0')
+        index < self.len() && self[index] == '\0'
     }
 }
 
 impl Compressor {
+    /// The canonical form of the `index`-th entry, as it is matched.
+    pub fn entry(&self, index: usize) -> Option<String> {
+        let start = *self.offsets.get(index)?;
+        Some(self.chars_at(start).iter().collect())
+    }
+
+    /// How many logical chars there are, terminators included.
+    pub fn len(&self) -> usize {
+        match self.fronts.last() {
+            Some(x) => x.start + x.shared + self.chars.len() - x.tail,
+            None => self.chars.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chars.is_empty()
+    }
+
+    /// Where the char at logical position `pos` is stored.
     #[inline]
-    pub fn push(&mut self, s: &str) -> usize {
-        self.offsets.push(self.chars.len());
-        s.chars().for_each(|c| self.chars.push(c));
+    fn locate(&self, mut pos: usize) -> usize {
+        if self.fronts.is_empty() {
+            return pos;
+        }
+        loop {
+            let front = &self.fronts[self.fronts.partition_point(|x| x.start <= pos) - 1];
+            let at = pos - front.start;
+            if at >= front.shared {
+                return front.tail + at - front.shared;
+            }
+            pos = front.from + at;
+        }
+    }
+
+    /// The chars of the entry from logical position `start` on, without
+    /// its terminator. Borrowed unless they were shared.
+    pub fn chars_at(&self, start: usize) -> Cow<'_, [char]> {
+        let pos = self.locate(start);
+        let len = self.chars[pos..].iter().position(|c| *c == '\0').unwrap_or(0);
+        let front = self.fronts.partition_point(|x| x.start <= start + len);
+        if front == self.fronts.partition_point(|x| x.start <= start)
+            && self.fronts.get(front.wrapping_sub(1)).is_none_or(|x| start - x.start >= x.shared)
+        {
+            return Cow::Borrowed(&self.chars[pos..pos + len]);
+        }
+        Cow::Owned((start..).map(|x| self[x]).take_while(|c| *c != '\0').collect())
+    }
+
+    /// Every logical char, as searchers see them.
+    pub fn flat(&self) -> Cow<'_, [char]> {
+        match self.fronts.is_empty() {
+            true => Cow::Borrowed(&self.chars),
+            false => Cow::Owned((0..self.len()).map(|x| self[x]).collect()),
+        }
+    }
+
+    /// The string pushed as the `index`-th entry, for display.
+    pub fn original(&self, index: usize) -> Option<String> {
+        match self.originals.get(&index) {
+            Some(s) => Some(s.clone()),
+            None => self.entry(index),
+        }
+    }
+
+    /// Entries shown otherwise than they are matched, with their index.
+    pub(crate) fn originals(&self) -> impl Iterator<Item = (usize, &str)> {
+        let mut ret: Vec<(usize, &str)> = self.originals.iter().map(|(i, x)| (*i, x.as_str())).collect();
+        ret.sort_unstable();
+        ret.into_iter()
+    }
+
+    /// Whether entries equal to one already stored point to its chars
+    /// instead of taking a copy, which saves memory on corpora with many
+    /// repeated names, e.g. the same file name in many folders. Only
+    /// whole entries are shared, see [`Compressor::set_front_coding`] for
+    /// common prefixes such as parent folders. Off by default, as the
+    /// lookup makes pushing slower.
+    pub fn set_interning(&mut self, interning: bool) {
+        self.interning = interning;
+        if !interning {
+            self.interned = None;
+        }
+    }
+
+    pub fn interning(&self) -> bool {
+        self.interning
+    }
+
+    /// Whether an entry starting like the one pushed before it stores
+    /// only the chars after their common prefix, which saves memory on
+    /// path-like corpora pushed in order, e.g. the files of a folder.
+    /// Prefixes too short to pay for their bookkeeping are stored as is.
+    /// Reading a char then takes a lookup, so this is off by default.
+    pub fn set_front_coding(&mut self, front_coding: bool) {
+        self.front_coding = front_coding;
+    }
+
+    pub fn front_coding(&self) -> bool {
+        self.front_coding
+    }
+
+    /// Takes the entries out, leaving no entries but the same settings.
+    pub(crate) fn take(&mut self) -> Compressor {
+        let empty = Compressor { interning: self.interning, front_coding: self.front_coding, ..Default::default() };
+        std::mem::replace(self, empty)
+    }
+
+    /// Stores `s` from logical position `end`, sharing its prefix with
+    /// the last entry if front coding is on.
+    fn store(&mut self, s: &str, end: usize) {
+        let last = self.offsets.last().copied();
+        let shared = match (self.front_coding, last) {
+            (true, Some(last)) => self.chars_at(last).iter().zip(s.chars()).take_while(|(a, b)| *a == b).count(),
+            _ => 0,
+        };
+        if let (true, Some(mut from)) = (shared >= MIN_SHARED, last) {
+            // Point to where the prefix is stored, not to another entry
+            // sharing it, so reading it back takes few steps
+            while let Ok(x) = self.fronts.binary_search_by_key(&from, |x| x.start) {
+                match self.fronts[x] {
+                    x if x.shared >= shared => from = x.from,
+                    _ => break,
+                }
+            }
+            if self.fronts.is_empty() {
+                self.fronts.push(Front { start: 0, shared: 0, from: 0, tail: 0 });
+            }
+            self.fronts.push(Front { start: end, shared, from, tail: self.chars.len() });
+        }
+        let skip = if shared >= MIN_SHARED { shared } else { 0 };
+        s.chars().skip(skip).for_each(|c| self.chars.push(c));
         self.chars.push('\0');
-        self.offsets.last().copied().unwrap_or(0)
+    }
+
+    /// The offset of a stored entry equal to `s`, remembering `offset`
+    /// for it if there is none.
+    fn intern(&mut self, s: &str, offset: usize) -> Option<usize> {
+        let hash = |x: &mut dyn Iterator<Item = char>| {
+            let mut hasher = FxHasher::default();
+            x.for_each(|c| c.hash(&mut hasher));
+            hasher.finish()
+        };
+        let mut interned = self.interned.take().unwrap_or_else(|| {
+            let mut ret = FxHashMap::default();
+            self.offsets.iter().for_each(|x| {
+                ret.entry(hash(&mut self.chars_at(*x).iter().copied())).or_insert(*x);
+            });
+            ret
+        });
+        let ret = match interned.entry(hash(&mut s.chars())) {
+            Entry::Occupied(x) if self.chars_at(*x.get()).iter().copied().eq(s.chars()) => Some(*x.get()),
+            Entry::Occupied(_) => None,
+            Entry::Vacant(x) => {
+                x.insert(offset);
+                None
+            }
+        };
+        self.interned = Some(interned);
+        ret
+    }
+
+    /// Appends the canonical form of `s`, see [`canonicalize`].
+    #[inline]
+    pub fn push(&mut self, s: &str) -> usize {
+        self.push_as(s, s)
+    }
+
+    /// Like `push`, with `original` as the string to display.
+    pub fn push_as(&mut self, s: &str, original: &str) -> usize {
+        let canonical = canonicalize(s);
+        if canonical != original {
+            self.originals.insert(self.offsets.len(), original.to_string());
+        }
+        let end = self.len();
+        if let Some(x) = self.interning.then(|| self.intern(&canonical, end)).flatten() {
+            self.offsets.push(x);
+            return x;
+        }
+        self.store(&canonical, end);
+        self.offsets.push(end);
+        end
+    }
+
+    /// Appends the entries of `other` after the existing ones, returning
+    /// how far its positions moved.
+    pub(crate) fn append(&mut self, other: &Compressor) -> usize {
+        let (len, chars, entries) = (self.len(), self.chars.len(), self.offsets.len());
+        if !self.fronts.is_empty() || !other.fronts.is_empty() {
+            if self.fronts.is_empty() && len > 0 {
+                self.fronts.push(Front { start: 0, shared: 0, from: 0, tail: 0 });
+            }
+            match other.fronts.is_empty() {
+                true if other.is_empty() => {}
+                true => self.fronts.push(Front { start: len, shared: 0, from: 0, tail: chars }),
+                false => self.fronts.extend(other.fronts.iter().map(|x| Front {
+                    start: x.start + len,
+                    shared: x.shared,
+                    from: x.from + len,
+                    tail: x.tail + chars,
+                })),
+            }
+        }
+        self.chars.extend_from_slice(&other.chars);
+        self.offsets.extend(other.offsets.iter().map(|x| x + len));
+        self.originals.extend(other.originals.iter().map(|(i, x)| (i + entries, x.clone())));
+        // Rebuilt on the next push
+        self.interned = None;
+        len
+    }
+
+    /// Points the `index`-th entry to a newly appended `s`, shown as
+    /// `original`. The old chars stay in place, as positions into them
+    /// may still be held elsewhere. Returns the new offset.
+    pub fn replace_as(&mut self, index: usize, s: &str, original: &str) -> usize {
+        let pos = self.push_as(s, original);
+        self.offsets.pop();
+        match self.originals.remove(&self.offsets.len()) {
+            Some(x) => self.originals.insert(index, x),
+            None => self.originals.remove(&index),
+        };
+        self.offsets[index] = pos;
+        pos
     }
 }
@@ -0,0 +1,223 @@
+//! One configuration for tools built on the crate, read from the
+//! environment and an optional file instead of each tool parsing its own
+//! flags.
+//!
+//! | Variable        | Meaning                                             |
+//! |-----------------|-----------------------------------------------------|
+//! | `PININ_KEYBOARD`| `quanpin`, `daqian`, `xiaohe` or `ziranma`          |
+//! | `PININ_FUZZY`   | comma separated flags, e.g. `zh2z,sh2s,any_tone`    |
+//! | `PININ_DICT`    | dictionary file used instead of the embedded one    |
+//! | `PININ_CONFIG`  | file with the same settings, overridden by the rest |
+//!
+//! The file holds `key = value` lines with the keys `keyboard`, `fuzzy`
+//! and `dict`:
+//!
+//! ```text
+//! # comments and blank lines are ignored
+//! keyboard = xiaohe
+//! fuzzy = zh2z, sh2s, ch2c
+//! ```
+//!
+//! With the `serde` feature, [`Config`] can also be embedded in a tool's
+//! own configuration.
+
+use crate::dict_loader::DictError;
+use crate::keyboard::{Keyboard, KEYBOARD_DAQIAN, KEYBOARD_QUANPIN, KEYBOARD_XIAOHE, KEYBOARD_ZIRANMA};
+use crate::pinin::{FuzzySettings, NeutralTone, PinIn};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{env, fs, io};
+
+pub const ENV_KEYBOARD: &str = "PININ_KEYBOARD";
+pub const ENV_FUZZY: &str = "PININ_FUZZY";
+pub const ENV_DICT: &str = "PININ_DICT";
+pub const ENV_CONFIG: &str = "PININ_CONFIG";
+
+/// Names accepted in `fuzzy`, after the fields of [`FuzzySettings`].
+/// `neutral_explicit` and `neutral_toneless` pick a [`NeutralTone`].
+pub const FUZZY_FLAGS: [&str; 10] = [
+    "zh2z",
+    "sh2s",
+    "ch2c",
+    "ang2an",
+    "ing2in",
+    "eng2en",
+    "u2v",
+    "any_tone",
+    "neutral_explicit",
+    "neutral_toneless",
+];
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default))]
+pub struct Config {
+    /// Keyboard name, quanpin when unset.
+    pub keyboard: Option<String>,
+    pub fuzzy: Vec<String>,
+    /// Dictionary file, the embedded dictionary when unset. Required
+    /// without the `embedded-dict` feature.
+    pub dict: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    /// A config file line without a `key = value` shape, numbered from 1.
+    Syntax(usize),
+    UnknownKey(usize, String),
+    UnknownKeyboard(String),
+    UnknownFuzzy(String),
+    Dict(DictError),
+    /// No `dict` set, and no embedded dictionary to fall back on.
+    NoDict,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "{}", e),
+            ConfigError::Syntax(line) => write!(f, "line {}: expected `key = value`", line),
+            ConfigError::UnknownKey(line, key) => write!(f, "line {}: unknown key `{}`", line, key),
+            ConfigError::UnknownKeyboard(name) => write!(f, "unknown keyboard `{}`", name),
+            ConfigError::UnknownFuzzy(name) => write!(f, "unknown fuzzy flag `{}`", name),
+            ConfigError::Dict(e) => write!(f, "{}", e),
+            ConfigError::NoDict => write!(f, "no dictionary set and none embedded"),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Io(e) => Some(e),
+            ConfigError::Dict(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(value: io::Error) -> Self {
+        ConfigError::Io(value)
+    }
+}
+
+impl From<DictError> for ConfigError {
+    fn from(value: DictError) -> Self {
+        ConfigError::Dict(value)
+    }
+}
+
+/// The keyboard called `name`, ignoring case.
+pub fn keyboard_by_name(name: &str) -> Option<&'static Keyboard> {
+    match name.to_ascii_lowercase().as_str() {
+        "quanpin" => Some(&KEYBOARD_QUANPIN),
+        "daqian" => Some(&KEYBOARD_DAQIAN),
+        "xiaohe" => Some(&KEYBOARD_XIAOHE),
+        "ziranma" => Some(&KEYBOARD_ZIRANMA),
+        _ => None,
+    }
+}
+
+fn split_list(s: &str) -> Vec<String> {
+    s.split(',').map(str::trim).filter(|x| !x.is_empty()).map(str::to_string).collect()
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    /// Reads the file named by `PININ_CONFIG`, if any, then applies the
+    /// other variables over it.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let mut ret = match env::var_os(ENV_CONFIG) {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if let Ok(keyboard) = env::var(ENV_KEYBOARD) {
+            ret.keyboard = Some(keyboard);
+        }
+        if let Ok(fuzzy) = env::var(ENV_FUZZY) {
+            ret.fuzzy = split_list(&fuzzy);
+        }
+        if let Some(dict) = env::var_os(ENV_DICT) {
+            ret.dict = Some(dict.into());
+        }
+        Ok(ret)
+    }
+
+    pub fn keyboard(&self) -> Result<&'static Keyboard, ConfigError> {
+        match &self.keyboard {
+            Some(name) => keyboard_by_name(name).ok_or_else(|| ConfigError::UnknownKeyboard(name.clone())),
+            None => Ok(&KEYBOARD_QUANPIN),
+        }
+    }
+
+    pub fn fuzzy(&self) -> Result<FuzzySettings, ConfigError> {
+        let mut ret = FuzzySettings::default();
+        for flag in &self.fuzzy {
+            match flag.to_ascii_lowercase().as_str() {
+                "zh2z" => ret.zh2z = true,
+                "sh2s" => ret.sh2s = true,
+                "ch2c" => ret.ch2c = true,
+                "ang2an" => ret.ang2an = true,
+                "ing2in" => ret.ing2in = true,
+                "eng2en" => ret.eng2en = true,
+                "u2v" => ret.u2v = true,
+                "any_tone" => ret.any_tone = true,
+                "neutral_explicit" => ret.neutral_tone = NeutralTone::Explicit,
+                "neutral_toneless" => ret.neutral_tone = NeutralTone::Toneless,
+                _ => return Err(ConfigError::UnknownFuzzy(flag.clone())),
+            }
+        }
+        Ok(ret)
+    }
+
+    /// A context with the configured keyboard and fuzzy settings and the
+    /// dictionary loaded.
+    pub fn build(&self) -> Result<PinIn, ConfigError> {
+        let mut p = PinIn::new();
+        p.keyboard = self.keyboard()?;
+        p.fuzzy = self.fuzzy()?;
+        match &self.dict {
+            Some(path) => p.load_dict_from_path(path)?,
+            #[cfg(feature = "embedded-dict")]
+            None => p.load_default_dict(),
+            #[cfg(not(feature = "embedded-dict"))]
+            None => return Err(ConfigError::NoDict),
+        }
+        Ok(p)
+    }
+}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ret = Config::default();
+        for (i, line) in s.lines().enumerate().map(|(i, x)| (i + 1, x.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or(ConfigError::Syntax(i))?;
+            let value = value.trim();
+            match key.trim() {
+                "keyboard" => ret.keyboard = Some(value.to_string()),
+                "fuzzy" => ret.fuzzy = split_list(value),
+                "dict" => ret.dict = Some(value.into()),
+                key => return Err(ConfigError::UnknownKey(i, key.to_string())),
+            }
+        }
+        Ok(ret)
+    }
+}
+
+impl PinIn {
+    /// A context configured as described in [`crate::config`].
+    pub fn from_env() -> Result<PinIn, ConfigError> {
+        Config::from_env()?.build()
+    }
+}
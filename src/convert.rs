@@ -0,0 +1,110 @@
+//! Traditional and simplified forms of names for display, so that a UI
+//! can show each user the script they read even when the indexed data is
+//! in the other one.
+//!
+//! The tables come from the caller, typically the character and phrase
+//! dictionaries of OpenCC: one entry per line, the key, a tab, then its
+//! conversions separated by spaces.
+//!
+//! Conversion is by longest match, so phrases take precedence over the
+//! characters they contain.
+
+use rustc_hash::FxHashMap;
+use std::borrow::Cow;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Variant {
+    Simplified,
+    Traditional,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ConversionTable {
+    to_simplified: FxHashMap<String, String>,
+    to_traditional: FxHashMap<String, String>,
+    /// Chars of the longest key in either direction.
+    longest: usize,
+}
+
+impl ConversionTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a pair for both directions. Where several forms convert to
+    /// the same one, the reverse direction keeps the first pair added.
+    pub fn insert(&mut self, traditional: &str, simplified: &str) {
+        self.insert_into(Variant::Simplified, traditional, simplified);
+        self.insert_into(Variant::Traditional, simplified, traditional);
+    }
+
+    fn insert_into(&mut self, to: Variant, from: &str, into: &str) {
+        let map = match to {
+            Variant::Simplified => &mut self.to_simplified,
+            Variant::Traditional => &mut self.to_traditional,
+        };
+        if !from.is_empty() && !map.contains_key(from) {
+            self.longest = self.longest.max(from.chars().count());
+            map.insert(from.to_string(), into.to_string());
+        }
+    }
+
+    /// Reads an OpenCC dictionary whose keys are in `keys`, e.g.
+    /// `Traditional` for `TSCharacters.txt`. Only the first conversion of
+    /// each key is used, and lines that are not `key<TAB>value` are
+    /// skipped.
+    pub fn load_opencc(&mut self, s: &str, keys: Variant) {
+        for line in s.lines() {
+            let Some((key, values)) = line.split_once('\t') else {
+                continue;
+            };
+            let Some(value) = values.split_whitespace().next() else {
+                continue;
+            };
+            match keys {
+                Variant::Traditional => self.insert(key.trim(), value),
+                Variant::Simplified => self.insert(value, key.trim()),
+            }
+        }
+    }
+
+    /// `s` in `to`, borrowed if nothing changed.
+    pub fn convert<'a>(&self, s: &'a str, to: Variant) -> Cow<'a, str> {
+        let map = match to {
+            Variant::Simplified => &self.to_simplified,
+            Variant::Traditional => &self.to_traditional,
+        };
+        if map.is_empty() {
+            return Cow::Borrowed(s);
+        }
+
+        let bounds: Vec<usize> = s.char_indices().map(|(i, _)| i).chain(std::iter::once(s.len())).collect();
+        let mut ret = String::with_capacity(s.len());
+        let mut changed = false;
+        let mut i = 0;
+        while i + 1 < bounds.len() {
+            let longest = self.longest.min(bounds.len() - 1 - i);
+            let hit = (1..=longest)
+                .rev()
+                .find_map(|len| map.get(&s[bounds[i]..bounds[i + len]]).map(|x| (len, x)));
+            match hit {
+                Some((len, x)) => {
+                    changed |= x != &s[bounds[i]..bounds[i + len]];
+                    ret.push_str(x);
+                    i += len;
+                }
+                None => {
+                    ret.push_str(&s[bounds[i]..bounds[i + 1]]);
+                    i += 1;
+                }
+            }
+        }
+        if changed {
+            Cow::Owned(ret)
+        } else {
+            Cow::Borrowed(s)
+        }
+    }
+}
@@ -1,19 +1,502 @@
-use std::collections::HashMap;
-use unicode_segmentation::UnicodeSegmentation;
-
-pub trait DictLoader<'a> {
-    fn load_dict(&self) -> HashMap<char, Vec<&'a str>>;
-}
-
-impl<'a> DictLoader<'a> for &'a str {
-    fn load_dict(&self) -> HashMap<char, Vec<&'a str>> {
-        self.lines()
-            .map(|line: &str| {
-                let ch = line.chars().next().unwrap();
-                let index = line.grapheme_indices(true).nth(3).unwrap().0;
-                let records = line[index..].split(", ").collect::<Vec<&str>>();
-                (ch, records)
-            })
+use crate::unicode_utils::is_han;
+use crate::user_dict::is_valid_reading;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::{self, BufRead};
+use std::str::FromStr;
+
+pub trait DictLoader {
+    fn load_dict(&self) -> Result<HashMap<char, Vec<String>>, DictError>;
+}
+
+/// Why a dictionary could not be loaded.
+#[derive(Debug)]
+pub enum DictError {
+    Io(io::Error),
+    /// A malformed line of a text dictionary. The line and column are
+    /// numbered from 1, the column in chars.
+    Syntax { line: usize, column: usize, expected: &'static str },
+    /// A compiled dictionary that does not read back.
+    Format(DictFormatError),
+}
+
+impl Display for DictError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DictError::Io(e) => write!(f, "{}", e),
+            DictError::Syntax { line, column, expected } => {
+                write!(f, "line {}, column {}: expected {}", line, column, expected)
+            }
+            DictError::Format(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for DictError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DictError::Io(e) => Some(e),
+            DictError::Format(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DictError {
+    fn from(value: io::Error) -> Self {
+        DictError::Io(value)
+    }
+}
+
+impl From<DictFormatError> for DictError {
+    fn from(value: DictFormatError) -> Self {
+        DictError::Format(value)
+    }
+}
+
+/// Adds the `i`-th line of a text dictionary to `dict`.
+fn parse_line(i: usize, line: &str, dict: &mut HashMap<char, Vec<String>>) -> Result<(), DictError> {
+    let mut chars = line.chars();
+    let Some(ch) = chars.next() else {
+        return Ok(());
+    };
+    let syntax = |column, expected| DictError::Syntax { line: i, column, expected };
+    let readings = chars.as_str().strip_prefix(": ").ok_or(syntax(2, "`: ` after the char"))?;
+    let mut column = 4;
+    let mut records = Vec::new();
+    for reading in readings.split(", ") {
+        if reading.is_empty() || reading.contains(|c: char| c.is_whitespace() || c == ',') {
+            return Err(syntax(column, "a reading"));
+        }
+        records.push(reading.to_string());
+        column += reading.chars().count() + 2;
+    }
+    dict.insert(ch, records);
+    Ok(())
+}
+
+/// Lines of `char: reading, reading`; blank lines are skipped.
+impl DictLoader for &str {
+    fn load_dict(&self) -> Result<HashMap<char, Vec<String>>, DictError> {
+        let mut ret = HashMap::new();
+        for (i, line) in self.lines().enumerate() {
+            parse_line(i + 1, line, &mut ret)?;
+        }
+        Ok(ret)
+    }
+}
+
+/// The same lines as for `&str`, read as they are parsed, e.g. from a
+/// file shipped next to the binary. The reader is left where loading
+/// stopped, so it is only worth loading from once.
+///
+/// ```no_run
+/// # use pinin_rs::pinin::PinIn;
+/// # use std::cell::RefCell;
+/// # use std::io::BufReader;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let file = BufReader::new(std::fs::File::open("dict.txt")?);
+/// let mut pinin = PinIn::new();
+/// pinin.load_dict(Box::new(RefCell::new(file)))?;
+/// # Ok(())
+/// # }
+/// ```
+impl<R: BufRead> DictLoader for RefCell<R> {
+    fn load_dict(&self) -> Result<HashMap<char, Vec<String>>, DictError> {
+        let mut ret = HashMap::new();
+        let mut reader = self.borrow_mut();
+        for (i, line) in reader.by_ref().lines().enumerate() {
+            parse_line(i + 1, &line?, &mut ret)?;
+        }
+        Ok(ret)
+    }
+}
+
+/// Readings taken from [CC-CEDICT](https://cc-cedict.org), whose lines
+/// hold the traditional and simplified forms of a word, its pinyin and
+/// glosses:
+///
+/// ```text
+/// # comments are ignored
+/// 中國 中国 [Zhong1 guo2] /China/
+/// ```
+///
+/// Each Han char gets the readings it has in the words written with it,
+/// in the order they come. Syllables that are not lowercase pinyin, such
+/// as the `r5` of erhua or the letters of `卡拉OK`, are skipped, and words
+/// whose syllables and chars do not pair up give no readings at all.
+/// Neutral tones, `5` in the source, are read as `0`, and `u:` as `v`.
+#[derive(Debug, Default, Clone)]
+pub struct CedictLoader {
+    chars: HashMap<char, Vec<String>>,
+    phrases: Vec<(String, Vec<String>)>,
+}
+
+/// A CC-CEDICT syllable as a reading, `None` for ones that are not one.
+fn cedict_reading(syllable: &str) -> Option<String> {
+    let mut ret = syllable.to_ascii_lowercase().replace("u:", "v");
+    if ret.ends_with('5') {
+        ret.pop();
+        ret.push('0');
+    }
+    let vowel = ret.contains(['a', 'e', 'i', 'o', 'u', 'v']);
+    (vowel && is_valid_reading(&ret)).then_some(ret)
+}
+
+impl CedictLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self, DictError> {
+        let mut ret = CedictLoader::new();
+        for (i, line) in reader.lines().enumerate() {
+            ret.add_line(i + 1, &line?)?;
+        }
+        Ok(ret)
+    }
+
+    /// Adds the entry on the `i`-th line of a CC-CEDICT file.
+    fn add_line(&mut self, i: usize, line: &str) -> Result<(), DictError> {
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+        let syntax = |rest: &str, expected| DictError::Syntax {
+            line: i,
+            column: line[..line.len() - rest.len()].chars().count() + 1,
+            expected,
+        };
+        let (traditional, rest) = line.split_once(' ').ok_or(syntax("", "the simplified form"))?;
+        let (simplified, rest) = rest.split_once(' ').ok_or(syntax(rest, "the pinyin"))?;
+        let pinyin = rest.strip_prefix('[').ok_or(syntax(rest, "`[`"))?;
+        let (pinyin, rest) = pinyin.split_once(']').ok_or(syntax("", "`]`"))?;
+        if !rest.trim_end().starts_with(" /") || !rest.trim_end().ends_with('/') {
+            return Err(syntax(rest, "glosses between `/`"));
+        }
+
+        let syllables: Vec<&str> = pinyin.split_whitespace().collect();
+        let readings: Vec<Option<String>> = syllables.iter().map(|x| cedict_reading(x)).collect();
+        for word in [traditional, simplified] {
+            if word.chars().count() != syllables.len() {
+                continue;
+            }
+            for (c, reading) in word.chars().zip(&readings) {
+                let Some(reading) = reading.as_ref().filter(|_| is_han(c)) else {
+                    continue;
+                };
+                let known = self.chars.entry(c).or_default();
+                if !known.contains(reading) {
+                    known.push(reading.clone());
+                }
+            }
+        }
+
+        if syllables.len() > 1 && readings.iter().all(Option::is_some) {
+            let readings: Vec<String> = readings.into_iter().flatten().collect();
+            for word in [simplified, traditional] {
+                let known = self.phrases.iter().any(|(x, r)| x == word && *r == readings);
+                if word.chars().count() == readings.len() && !known {
+                    self.phrases.push((word.to_string(), readings.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Words of more than one char with the reading of each, simplified
+    /// forms first, e.g. for [`FrequencyTable::insert`](crate::frequency::FrequencyTable::insert).
+    pub fn phrases(&self) -> impl Iterator<Item = (&str, &[String])> {
+        self.phrases.iter().map(|(word, readings)| (word.as_str(), readings.as_slice()))
+    }
+}
+
+impl FromStr for CedictLoader {
+    type Err = DictError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ret = CedictLoader::new();
+        for (i, line) in s.lines().enumerate() {
+            ret.add_line(i + 1, line)?;
+        }
+        Ok(ret)
+    }
+}
+
+impl DictLoader for CedictLoader {
+    fn load_dict(&self) -> Result<HashMap<char, Vec<String>>, DictError> {
+        Ok(self.chars.clone())
+    }
+}
+
+/// How the readings of one character differ between two dictionaries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CharDiff {
+    pub ch: char,
+    /// Readings only in the new dictionary, in its order.
+    pub added: Vec<String>,
+    /// Readings only in the old dictionary, in its order.
+    pub removed: Vec<String>,
+    /// Whether the readings in both come in another order. The first
+    /// reading is the one formatting picks, so this is worth a look too.
+    pub reordered: bool,
+}
+
+/// Per character changes from `old` to `new`, sorted by character.
+/// Characters with the same readings in the same order are left out.
+pub fn diff(old: &dyn DictLoader, new: &dyn DictLoader) -> Result<Vec<CharDiff>, DictError> {
+    let (old, new) = (old.load_dict()?, new.load_dict()?);
+    let chars: BTreeSet<char> = old.keys().chain(new.keys()).copied().collect();
+
+    let ret = chars
+        .into_iter()
+        .filter_map(|ch| {
+            let a = old.get(&ch).map(Vec::as_slice).unwrap_or_default();
+            let b = new.get(&ch).map(Vec::as_slice).unwrap_or_default();
+            if a == b {
+                return None;
+            }
+            let added: Vec<String> = b.iter().filter(|x| !a.contains(x)).cloned().collect();
+            let removed: Vec<String> = a.iter().filter(|x| !b.contains(x)).cloned().collect();
+            let kept_a = a.iter().filter(|x| b.contains(x));
+            let kept_b = b.iter().filter(|x| a.contains(x));
+            let reordered = !kept_a.eq(kept_b);
+            Some(CharDiff { ch, added, removed, reordered })
+        })
+        .collect();
+    Ok(ret)
+}
+
+const MAGIC: &[u8; 8] = b"PINDICT\0";
+const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DictFormatError {
+    /// The bytes do not start like a compiled dictionary.
+    Magic,
+    /// A dictionary compiled by another version of the format.
+    Version(u32),
+    /// The bytes end early or hold out of range values.
+    Corrupt,
+}
+
+impl Display for DictFormatError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DictFormatError::Magic => f.write_str("not a compiled dictionary"),
+            DictFormatError::Version(x) => write!(f, "unsupported dictionary version {}", x),
+            DictFormatError::Corrupt => f.write_str("corrupt compiled dictionary"),
+        }
+    }
+}
+
+impl Error for DictFormatError {}
+
+/// Turns dictionaries into the binary form read by [`CompiledDict`],
+/// which loads without parsing any text, and by
+/// [`PinIn::load_dict_lazy`](crate::pinin::PinIn::load_dict_lazy), which
+/// only reads the chars in use.
+///
+/// The form holds each distinct reading once, followed by the chars in
+/// order, each with the numbers of its readings.
+#[derive(Debug, Default, Clone)]
+pub struct DictCompiler {
+    chars: BTreeMap<char, Vec<String>>,
+}
+
+impl DictCompiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the entries of `loader`. Chars already added get the readings
+    /// of `loader` instead, as when loading both into a context.
+    pub fn add(&mut self, loader: &dyn DictLoader) -> Result<&mut Self, DictError> {
+        self.chars.extend(loader.load_dict()?);
+        Ok(self)
+    }
+
+    pub fn compile(&self) -> Vec<u8> {
+        let readings: Vec<&str> = self.chars.values().flatten().map(String::as_str).collect::<BTreeSet<_>>().into_iter().collect();
+        let wide = readings.len() > u16::MAX as usize + 1;
+
+        let mut ret = MAGIC.to_vec();
+        ret.extend_from_slice(&VERSION.to_le_bytes());
+        ret.extend_from_slice(&(readings.len() as u32).to_le_bytes());
+        for reading in &readings {
+            ret.extend_from_slice(&(reading.len() as u32).to_le_bytes());
+            ret.extend_from_slice(reading.as_bytes());
+        }
+        ret.extend_from_slice(&(self.chars.len() as u32).to_le_bytes());
+        for (c, ss) in &self.chars {
+            ret.extend_from_slice(&(*c as u32).to_le_bytes());
+            ret.extend_from_slice(&(ss.len() as u32).to_le_bytes());
+            for s in ss {
+                let id = readings.binary_search(&s.as_str()).unwrap_or_default();
+                if wide {
+                    ret.extend_from_slice(&(id as u32).to_le_bytes());
+                } else {
+                    ret.extend_from_slice(&(id as u16).to_le_bytes());
+                }
+            }
+        }
+        ret
+    }
+}
+
+/// A dictionary compiled by [`DictCompiler`]. The bytes are checked once
+/// when it is created, and readings are borrowed from them.
+#[derive(Debug, Clone)]
+pub struct CompiledDict<'a> {
+    readings: Vec<&'a str>,
+    /// The chars section, after the readings.
+    chars: &'a [u8],
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DictFormatError> {
+        if self.0.len() < n {
+            return Err(DictFormatError::Corrupt);
+        }
+        let (ret, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(ret)
+    }
+
+    fn u32(&mut self) -> Result<u32, DictFormatError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn id(&mut self, wide: bool) -> Result<usize, DictFormatError> {
+        if wide {
+            self.u32().map(|x| x as usize)
+        } else {
+            let bytes = self.take(2)?;
+            Ok(u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+        }
+    }
+}
+
+impl<'a> CompiledDict<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<Self, DictFormatError> {
+        let mut r = Reader(bytes);
+        if r.take(MAGIC.len()).map_err(|_| DictFormatError::Magic)? != MAGIC {
+            return Err(DictFormatError::Magic);
+        }
+        match r.u32()? {
+            VERSION => {}
+            x => return Err(DictFormatError::Version(x)),
+        }
+
+        let count = r.u32()? as usize;
+        let mut readings = Vec::with_capacity(count.min(bytes.len()));
+        for _ in 0..count {
+            let len = r.u32()? as usize;
+            readings.push(std::str::from_utf8(r.take(len)?).map_err(|_| DictFormatError::Corrupt)?);
+        }
+
+        let ret = CompiledDict { readings, chars: r.0 };
+        ret.decode(|_, _| {})?;
+        Ok(ret)
+    }
+
+    fn wide(&self) -> bool {
+        self.readings.len() > u16::MAX as usize + 1
+    }
+
+    /// Calls `f` with each char and its readings.
+    fn decode<F: FnMut(char, Vec<&'a str>)>(&self, mut f: F) -> Result<(), DictFormatError> {
+        let mut r = Reader(self.chars);
+        for _ in 0..r.u32()? {
+            let c = char::from_u32(r.u32()?).ok_or(DictFormatError::Corrupt)?;
+            let n = r.u32()? as usize;
+            let mut ss = Vec::with_capacity(n.min(r.0.len()));
+            for _ in 0..n {
+                ss.push(*self.readings.get(r.id(self.wide())?).ok_or(DictFormatError::Corrupt)?);
+            }
+            f(c, ss);
+        }
+        if !r.0.is_empty() {
+            return Err(DictFormatError::Corrupt);
+        }
+        Ok(())
+    }
+}
+
+/// An owned compiled dictionary whose chars are decoded one at a time,
+/// see [`PinIn::load_dict_lazy`](crate::pinin::PinIn::load_dict_lazy).
+/// Only the readings and where each char's entry starts are read up
+/// front.
+#[derive(Debug)]
+pub(crate) struct LazyDict {
+    bytes: Vec<u8>,
+    readings: Vec<String>,
+    /// Each char with the position of its entry in `bytes`, by char.
+    index: Vec<(char, usize)>,
+    wide: bool,
+}
+
+impl LazyDict {
+    pub(crate) fn new(bytes: Vec<u8>) -> Result<Self, DictFormatError> {
+        let dict = CompiledDict::new(&bytes)?;
+        let readings = dict.readings.iter().map(|x| x.to_string()).collect();
+        let wide = dict.wide();
+        let base = bytes.len() - dict.chars.len();
+        let mut index = Vec::new();
+        let mut r = Reader(dict.chars);
+        for _ in 0..r.u32()? {
+            let c = char::from_u32(r.u32()?).ok_or(DictFormatError::Corrupt)?;
+            index.push((c, base + dict.chars.len() - r.0.len()));
+            let n = r.u32()? as usize;
+            r.take(n * if wide { 4 } else { 2 })?;
+        }
+        // Later entries win, as when loading
+        index.reverse();
+        index.sort_by_key(|(c, _)| *c);
+        index.dedup_by_key(|(c, _)| *c);
+        Ok(LazyDict { bytes, readings, index, wide })
+    }
+
+    /// Every distinct reading.
+    pub(crate) fn readings(&self) -> &[String] {
+        &self.readings
+    }
+
+    /// Chars with an entry, ascending.
+    pub(crate) fn chars(&self) -> impl Iterator<Item = char> + '_ {
+        self.index.iter().map(|(c, _)| *c)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Where `c` is among [`chars`](LazyDict::chars).
+    pub(crate) fn position(&self, c: char) -> Option<usize> {
+        self.index.binary_search_by_key(&c, |(x, _)| *x).ok()
+    }
+
+    /// The readings of the `i`-th char, decoded from the checked bytes.
+    pub(crate) fn get(&self, i: usize) -> Vec<&str> {
+        let mut r = Reader(&self.bytes[self.index[i].1..]);
+        let n = r.u32().unwrap_or(0);
+        (0..n)
+            .filter_map(|_| r.id(self.wide).ok())
+            .filter_map(|x| self.readings.get(x).map(String::as_str))
             .collect()
     }
 }
+
+impl DictLoader for CompiledDict<'_> {
+    fn load_dict(&self) -> Result<HashMap<char, Vec<String>>, DictError> {
+        let mut ret = HashMap::new();
+        self.decode(|c, ss| {
+            ret.insert(c, ss.into_iter().map(str::to_string).collect());
+        })?;
+        Ok(ret)
+    }
+}
@@ -1,16 +1,15 @@
 use std::borrow::Cow;
 use std::cmp::min;
-use std::collections::HashSet;
 use std::fmt::{Debug, Formatter};
 use std::hash::Hash;
-use std::rc::Rc;
+use std::sync::Arc;
 use compact_str::CompactString;
 use rustc_hash::FxHashSet;
 use smallvec::SmallVec;
 
 use crate::compressed::IndexSet;
 use crate::keyboard::Keyboard;
-use crate::pinin::FuzzySettings;
+use crate::pinin::{FuzzySettings, NeutralTone};
 use crate::unicode_utils::SegmentedStr;
 
 const VOWEL_CHARS: [char; 6] = ['a', 'e', 'i', 'o', 'u', 'v'];
@@ -18,7 +17,7 @@ const VOWEL_CHARS: [char; 6] = ['a', 'e', 'i', 'o', 'u', 'v'];
 #[derive(Hash, PartialEq, Clone, Eq, PartialOrd, Ord)]
 pub enum Phoneme {
     Single(CompactString),
-    Multiple(Vec<CompactString>),
+    Multiple(Box<[CompactString]>),
 }
 
 impl Debug for Phoneme {
@@ -41,30 +40,22 @@ impl Phoneme {
 
         if let Some(c) = s.chars().next() {
             match c {
-                'c' => {
-                    if settings.ch2c {
-                        ret.insert(Cow::Borrowed("c"));
-                        ret.insert(Cow::Borrowed("ch"));
-                    }
+                'c' if settings.ch2c => {
+                    ret.insert(Cow::Borrowed("c"));
+                    ret.insert(Cow::Borrowed("ch"));
                 }
-                's' => {
-                    if settings.sh2s {
-                        ret.insert(Cow::Borrowed("s"));
-                        ret.insert(Cow::Borrowed("sh"));
-                    }
+                's' if settings.sh2s => {
+                    ret.insert(Cow::Borrowed("s"));
+                    ret.insert(Cow::Borrowed("sh"));
                 }
-                'z' => {
-                    if settings.zh2z {
-                        ret.insert(Cow::Borrowed("z"));
-                        ret.insert(Cow::Borrowed("zh"));
-                    }
+                'z' if settings.zh2z => {
+                    ret.insert(Cow::Borrowed("z"));
+                    ret.insert(Cow::Borrowed("zh"));
                 }
-                'v' => {
-                    if settings.u2v {
-                        let mut str = String::from("u");
-                        str.push_str(&s[1..s.len()]);
-                        ret.insert(Cow::Owned(str));
-                    }
+                'v' if settings.u2v => {
+                    let mut str = String::from("u");
+                    str.push_str(&s[1..s.len()]);
+                    ret.insert(Cow::Owned(str));
                 }
                 _ => {}
             }
@@ -87,9 +78,23 @@ impl Phoneme {
         }
 
         if ret.len() == 1 {
-            Phoneme::Single(keyboard.keys(s).into())
+            return Phoneme::Single(keyboard.keys(s).into());
+        }
+
+        // Different fuzzy variants may map to the same keys on some keyboards
+        let mut strings: SmallVec<[CompactString; 2]> = SmallVec::new();
+        ret.into_iter().for_each(|x| {
+            let key: CompactString = keyboard.keys_cow(x).into();
+            if !strings.contains(&key) {
+                strings.push(key);
+            }
+        });
+        strings.sort_unstable();
+
+        if strings.len() == 1 {
+            Phoneme::Single(strings.pop().unwrap())
         } else {
-            Phoneme::Multiple(ret.into_iter().map(|x| keyboard.keys_cow(x).into()).collect())
+            Phoneme::Multiple(strings.into_vec().into_boxed_slice())
         }
     }
 /*
@@ -149,8 +154,21 @@ impl Phoneme {
         }
     }
 */
+    /// A tone phoneme accepting the key of any tone, or none at all.
+    pub fn any_tone(keyboard: &Keyboard) -> Self {
+        let mut strings: SmallVec<[CompactString; 2]> = SmallVec::new();
+        ["0", "1", "2", "3", "4"].into_iter().for_each(|tone| {
+            let key: CompactString = keyboard.keys(tone).into();
+            if !strings.contains(&key) {
+                strings.push(key);
+            }
+        });
+        strings.sort_unstable();
+        Phoneme::Multiple(strings.into_vec().into_boxed_slice())
+    }
+
     pub fn strcmp(a: &SegmentedStr, b: &SegmentedStr, a_start: usize) -> usize {
-        let len = min(a.graphemes.len() - a_start, b.graphemes.len());
+        let len = min(a.graphemes.len().saturating_sub(a_start), b.graphemes.len());
         for i in 0..len {
             if a.graphemes[i + a_start].1 != b.graphemes[i].1 {
                 return i;
@@ -165,15 +183,25 @@ impl Phoneme {
         idx: IndexSet,
         start: usize,
         partial: bool,
+    ) -> IndexSet {
+        self.match_segmented_idx(&source.into(), idx, start, partial)
+    }
+
+    pub fn match_segmented_idx(
+        &self,
+        source: &SegmentedStr,
+        idx: IndexSet,
+        start: usize,
+        partial: bool,
     ) -> IndexSet {
         if self.is_empty() {
             return idx;
         }
         let mut ret = IndexSet::default();
         idx.for_each(|i| {
-            let mut set = self.match_string(source, start + i as usize, partial);
+            let mut set = self.match_segmented(source, start + i as usize, partial);
             set.offset(i);
-            ret.merge(set);
+            ret.union(set);
         });
         ret
     }
@@ -193,9 +221,12 @@ impl Phoneme {
     }
 
     pub fn match_string(&self, source: &str, start: usize, partial: bool) -> IndexSet {
+        self.match_segmented(&source.into(), start, partial)
+    }
+
+    pub fn match_segmented(&self, source: &SegmentedStr, start: usize, partial: bool) -> IndexSet {
         let mut ret = IndexSet::default();
 
-        let source: SegmentedStr = source.into();
         match &self {
             Phoneme::Single(s) => {
                 if s.trim().is_empty() {
@@ -203,7 +234,7 @@ impl Phoneme {
                 }
 
                 let s = s.as_str().into();
-                let size = Self::strcmp(&source, &s, start);
+                let size = Self::strcmp(source, &s, start);
                 if (partial && start + size == source.graphemes.len()) || size == s.graphemes.len() {
                     ret.set(size);
                 }
@@ -211,7 +242,7 @@ impl Phoneme {
             Phoneme::Multiple(strings) => {
                 for s in strings.iter() {
                     let s = s.as_str().into();
-                    let size = Self::strcmp(&source, &s, start);
+                    let size = Self::strcmp(source, &s, start);
                     if (partial && start + size == source.graphemes.len()) || size == s.graphemes.len() {
                         ret.set(size);
                     }
@@ -223,63 +254,130 @@ impl Phoneme {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Character {
     pub ch: char,
-    pub pinyin: SmallVec<[Pinyin; 4]>,
+    pub pinyin: SmallVec<[Arc<Pinyin>; 4]>,
 }
 
 impl Character {
-    pub fn new(ch: char, pinyin: SmallVec<[Pinyin; 4]>) -> Self {
+    pub fn new(ch: char, pinyin: SmallVec<[Arc<Pinyin>; 4]>) -> Self {
         Character { ch, pinyin }
     }
 
     pub fn match_str(&self, s: &str, start: usize, partial: bool) -> IndexSet {
-        let mut ret = if s.chars().nth(start) == Some(self.ch) {
+        self.match_segmented(&s.into(), start, partial)
+    }
+
+    /// Like `match_str`, over a query segmented once by the caller.
+    pub fn match_segmented(&self, s: &SegmentedStr, start: usize, partial: bool) -> IndexSet {
+        let literal = s
+            .graphemes
+            .get(start)
+            .map(|(_, g)| g.len() == self.ch.len_utf8() && g.starts_with(self.ch))
+            .unwrap_or(false);
+        let mut ret = if literal {
             IndexSet::one()
         } else {
             IndexSet::none()
         };
         self.pinyin
             .iter()
-            .for_each(|p| ret.merge(p.match_string(s, start, partial)));
+            .for_each(|p| ret.union(p.match_segmented(s, start, partial)));
         ret
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Pinyin {
     pub raw: CompactString,
     pub id: usize,
     pub duo: bool,
     pub sequence: bool,
+    /// Whether the tone phoneme has to match before the next syllable, see
+    /// [`NeutralTone::Explicit`].
+    pub tone_required: bool,
     pub phonemes: SmallVec<[Phoneme; 4]>,
+    /// The same reading typed on other keyboards, each matched on its
+    /// own, see [`Pinyin::with_keyboards`].
+    pub alternates: Vec<Pinyin>,
 }
 
 impl Pinyin {
     pub fn new(s: &str, settings: &FuzzySettings, keyboard: &Keyboard, id: usize) -> Pinyin {
         let split = keyboard.split(s);
-        let phonemes: SmallVec<[Phoneme; 4]> = split
+        let mut phonemes: SmallVec<[Phoneme; 4]> = split
             .into_iter()
             .map(|x| Phoneme::new(&x, settings, keyboard))
             .collect();
 
+        let toned = s.ends_with(|c: char| c.is_ascii_digit());
+        let mut tone_required = false;
+        if settings.any_tone && toned {
+            if let Some(tone) = phonemes.last_mut() {
+                *tone = Phoneme::any_tone(keyboard);
+            }
+        } else if toned && s.ends_with('0') {
+            match settings.neutral_tone {
+                NeutralTone::Explicit => tone_required = phonemes.last().map(|x| !x.is_empty()).unwrap_or(false),
+                NeutralTone::Optional => {}
+                NeutralTone::Toneless => {
+                    phonemes.pop();
+                }
+            }
+        }
+
         Pinyin {
             id,
             phonemes,
             raw: s.into(),
             duo: keyboard.duo,
             sequence: keyboard.sequence,
+            tone_required,
+            alternates: Vec::new(),
+        }
+    }
+
+    /// Like `new`, also accepting the keys of `extra` keyboards, so that
+    /// each syllable of a query may be typed on any of them. Keyboards
+    /// typing the reading like `keyboard` add nothing.
+    pub fn with_keyboards(s: &str, settings: &FuzzySettings, keyboard: &Keyboard, extra: &[&Keyboard], id: usize) -> Pinyin {
+        let mut ret = Pinyin::new(s, settings, keyboard, id);
+        for keyboard in extra {
+            let p = Pinyin::new(s, settings, keyboard, id);
+            let same = |x: &Pinyin| x.phonemes == p.phonemes && x.duo == p.duo && x.sequence == p.sequence;
+            if !same(&ret) && !ret.alternates.iter().any(same) {
+                ret.alternates.push(p);
+            }
         }
+        ret
     }
 
     pub fn match_string(&self, s: &str, start: usize, partial: bool) -> IndexSet {
+        self.match_segmented(&s.into(), start, partial)
+    }
+
+    pub fn match_segmented(&self, s: &SegmentedStr, start: usize, partial: bool) -> IndexSet {
+        let mut ret = self.match_keyboard(s, start, partial);
+        self.alternates.iter().for_each(|x| ret.union(x.match_keyboard(s, start, partial)));
+        ret
+    }
+
+    /// Matches with the phonemes of this keyboard only.
+    fn match_keyboard(&self, s: &SegmentedStr, start: usize, partial: bool) -> IndexSet {
+        if start >= s.graphemes.len() {
+            return IndexSet::none();
+        }
+
         if self.duo {
             let mut ret = IndexSet::zero();
-            ret = self.phonemes[0].match_string_idx(s, ret, start, partial);
-            ret = self.phonemes[1].match_string_idx(s, ret, start, partial);
-            if self.phonemes.len() == 3 {
-                ret.merge(self.phonemes[2].match_string_idx(s, ret, start, partial));
+            for phoneme in self.phonemes.iter().take(2) {
+                ret = phoneme.match_segmented_idx(s, ret, start, partial);
+            }
+            if let Some(tone) = self.phonemes.get(2) {
+                let toned = tone.match_segmented_idx(s, ret, start, partial);
+                ret = if self.tone_required { self.at_end(s, start, ret) } else { ret };
+                ret.union(toned);
             }
             ret
         } else {
@@ -289,16 +387,23 @@ impl Pinyin {
             let mut active = IndexSet::zero();
             let mut ret = IndexSet::none();
 
-            self.phonemes.iter().for_each(|phoneme| {
-                active = phoneme.match_string_idx(s, active, start, partial);
+            // The phoneme before the tone
+            let last = self.phonemes.len().saturating_sub(2);
+            self.phonemes.iter().enumerate().for_each(|(i, phoneme)| {
+                active = phoneme.match_segmented_idx(s, active, start, partial);
                 if active == IndexSet::none() {
                     return;
                 }
-                ret.merge(active);
+                if self.tone_required && i == last {
+                    ret.union(self.at_end(s, start, active));
+                } else {
+                    ret.union(active);
+                }
             });
 
+            let first = s.graphemes[start].1.chars().next();
             if self.sequence
-                && self.phonemes[0].match_sequence(s.chars().nth(start).unwrap())
+                && self.phonemes.first().zip(first).map(|(p, c)| p.match_sequence(c)).unwrap_or(false)
             {
                 ret.set(1);
             }
@@ -306,6 +411,65 @@ impl Pinyin {
         }
     }
 
+    /// The matches in `idx` that reach the end of the query.
+    fn at_end(&self, s: &SegmentedStr, start: usize, idx: IndexSet) -> IndexSet {
+        let mut ret = IndexSet::none();
+        if let Some(end) = s.graphemes.len().checked_sub(start) {
+            if idx.get(end) {
+                ret.set(end);
+            }
+        }
+        ret
+    }
+
+    /// The syllable without its tone, e.g. "zhong" for "zhong1".
+    pub fn syllable(&self) -> &str {
+        self.raw.trim_end_matches(|c: char| c.is_ascii_digit())
+    }
+
+    /// How many keys type the syllable in full on this keyboard, leaving
+    /// out the tone and taking the shortest of fuzzy variants. Fewer keys
+    /// abbreviate it, like "x" or "xi" for "xian1".
+    pub fn typed_len(&self) -> usize {
+        let tone = |x: &str| x.chars().all(|c| c.is_ascii_digit() || c == ' ');
+        let len = |x: &CompactString| if tone(x) { 0 } else { x.chars().count() };
+        self.phonemes
+            .iter()
+            .map(|x| match x {
+                Phoneme::Single(x) => len(x),
+                Phoneme::Multiple(xs) => xs.iter().map(len).min().unwrap_or(0),
+            })
+            .sum()
+    }
+
+    /// The tone number, 0 for neutral tone.
+    pub fn tone(&self) -> u8 {
+        self.raw
+            .chars()
+            .last()
+            .and_then(|c| c.to_digit(10))
+            .map(|x| x as u8)
+            .unwrap_or(0)
+    }
+
+    /// The initial consonant, empty for syllables like "an".
+    pub fn initial(&self) -> &str {
+        let syllable = self.syllable();
+        let first = syllable.chars().next().map(char::len_utf8).unwrap_or(0);
+        if !Self::has_initial(syllable) {
+            ""
+        } else if syllable.len() > first + 1 && syllable[first..].starts_with('h') {
+            &syllable[..first + 1]
+        } else {
+            &syllable[..first]
+        }
+    }
+
+    /// The final, i.e. the syllable without its initial.
+    pub fn final_(&self) -> &str {
+        &self.syllable()[self.initial().len()..]
+    }
+
     pub fn has_initial(s: &str) -> bool {
         VOWEL_CHARS
             .iter()
@@ -0,0 +1,123 @@
+//! Pinyin search over the file names below a directory, as used by
+//! launchers and file managers.
+
+use crate::accelerator::Accelerator;
+use crate::pinin::PinIn;
+use crate::searcher::{Searcher, SearcherLogic, TreeSearcher};
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// What a [`FileIndex::refresh`] changed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Refresh {
+    pub added: usize,
+    pub removed: usize,
+}
+
+/// File and directory names below `root`, matched by name only.
+///
+/// Symbolic links are indexed but not followed, and directories that
+/// cannot be read are skipped.
+pub struct FileIndex {
+    root: PathBuf,
+    logic: SearcherLogic,
+    searcher: TreeSearcher<usize>,
+    paths: Vec<Option<PathBuf>>,
+    known: FxHashMap<PathBuf, usize>,
+}
+
+impl FileIndex {
+    /// An empty index; call [`FileIndex::refresh`] to fill it.
+    pub fn new<P: Into<PathBuf>>(root: P, logic: SearcherLogic) -> Self {
+        FileIndex {
+            root: root.into(),
+            logic,
+            searcher: TreeSearcher::new(logic, Arc::new(Accelerator::new())),
+            paths: Vec::new(),
+            known: Default::default(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Walks the tree again, indexing new paths and dropping vanished
+    /// ones. Only fails when the root itself cannot be read.
+    pub fn refresh(&mut self, context: &PinIn) -> io::Result<Refresh> {
+        let mut found = FxHashSet::default();
+        let mut stack = vec![self.root.clone()];
+        let mut root = true;
+        while let Some(dir) = stack.pop() {
+            let entries = match std::fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if root => return Err(e),
+                Err(_) => continue,
+            };
+            root = false;
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                    stack.push(path.clone());
+                }
+                found.insert(path);
+            }
+        }
+
+        let mut ret = Refresh::default();
+        let vanished: Vec<PathBuf> = self.known.keys().filter(|p| !found.contains(*p)).cloned().collect();
+        for path in vanished {
+            if let Some(i) = self.known.remove(&path) {
+                self.paths[i] = None;
+                ret.removed += 1;
+            }
+        }
+        for path in found {
+            if !self.known.contains_key(&path) {
+                self.insert(context, path);
+                ret.added += 1;
+            }
+        }
+
+        if self.known.len() * 2 < self.paths.len() {
+            self.rebuild(context);
+        }
+        Ok(ret)
+    }
+
+    fn insert(&mut self, context: &PinIn, path: PathBuf) {
+        let name = path.file_name().map(|x| x.to_string_lossy()).unwrap_or_default();
+        self.searcher.insert(context, &name, self.paths.len());
+        self.known.insert(path.clone(), self.paths.len());
+        self.paths.push(Some(path));
+    }
+
+    fn rebuild(&mut self, context: &PinIn) {
+        let paths = std::mem::take(&mut self.paths);
+        self.searcher = TreeSearcher::new(self.logic, Arc::new(Accelerator::new()));
+        self.known.clear();
+        paths.into_iter().flatten().for_each(|path| self.insert(context, path));
+    }
+
+    /// Paths whose file name matches `query`, sorted.
+    pub fn search(&self, context: &PinIn, query: &str) -> Vec<&Path> {
+        let mut ret: Vec<&Path> = self
+            .searcher
+            .search(context, query)
+            .into_iter()
+            .filter_map(|i| self.paths[*i].as_deref())
+            .collect();
+        ret.sort_unstable();
+        ret
+    }
+
+    pub fn len(&self) -> usize {
+        self.known.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.known.is_empty()
+    }
+}
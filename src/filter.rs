@@ -0,0 +1,78 @@
+use crate::pinin::PinIn;
+use crate::searcher::{Searcher, TreeSearcher};
+
+/// A filtered list for immediate-mode GUIs, meant to be fed the contents
+/// of a text box every frame.
+///
+/// Setting the same query again does nothing. When a query extends the
+/// previous one, only the entries visible so far are tested again, as
+/// unless the logic [is full](crate::searcher::SearcherLogic::is_full)
+/// a longer query never matches more.
+pub struct Filter<T: 'static> {
+    searcher: TreeSearcher<T>,
+    query: String,
+    visible: Vec<usize>,
+    /// The context version `visible` was computed against, `None` when
+    /// entries changed since.
+    version: Option<u64>,
+}
+
+impl<T: 'static> Filter<T> {
+    pub fn new(searcher: TreeSearcher<T>) -> Self {
+        Filter {
+            searcher,
+            query: String::new(),
+            visible: Vec::new(),
+            version: None,
+        }
+    }
+
+    pub fn insert(&mut self, context: &PinIn, name: &str, id: T) {
+        self.searcher.insert(context, name, id);
+        self.version = None;
+    }
+
+    /// Updates the visible entries for `query`, returning whether it
+    /// changed since the last call.
+    pub fn set_query(&mut self, context: &PinIn, query: &str) -> bool {
+        let current = self.version == Some(context.version());
+        if current && query == self.query {
+            return false;
+        }
+
+        let refine = current
+            && !self.query.is_empty()
+            && query.starts_with(self.query.as_str())
+            && !self.searcher.logic().is_full();
+        if refine {
+            self.searcher.retain_matching(context, query, &mut self.visible);
+        } else {
+            self.visible = self.searcher.search_indices(context, query);
+        }
+        self.query.clear();
+        self.query.push_str(query);
+        self.version = Some(context.version());
+        true
+    }
+
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    /// Insertion indices of the visible entries, ascending.
+    pub fn visible_ids(&self) -> &[usize] {
+        &self.visible
+    }
+
+    pub fn visible(&self) -> impl Iterator<Item = &T> {
+        self.visible.iter().filter_map(|i| self.searcher.get(*i))
+    }
+
+    pub fn searcher(&self) -> &TreeSearcher<T> {
+        &self.searcher
+    }
+
+    pub fn into_inner(self) -> TreeSearcher<T> {
+        self.searcher
+    }
+}
@@ -1,4 +1,7 @@
 use crate::elements::Pinyin;
+use crate::keyboard::KEYBOARD_QUANPIN;
+use crate::pinin::FuzzySettings;
+use compact_str::CompactString;
 use crate::unicode_utils::UnicodeUtils;
 use lazy_static::lazy_static;
 use std::borrow::Cow;
@@ -6,10 +9,10 @@ use std::collections::HashMap;
 use unicode_segmentation::UnicodeSegmentation;
 
 const OFFSET: &[&str] = &[
-    "ui", "iu", "uan", "uang", "ian", "iang", "ua", "ie", "uo", "iong", "iao", "ve", "ia",
+    "ui", "iu", "uan", "uang", "ian", "iang", "ua", "ie", "uo", "iong", "iao", "ve", "ia", "uai",
 ];
 
-pub type PinyinFormat<'a> = Box<dyn Fn(&'a Pinyin) -> Cow<'a, str>>;
+pub type PinyinFormat = Box<dyn Fn(&Pinyin) -> Cow<str> + Send + Sync>;
 
 lazy_static! {
     static ref NONE: HashMap<char, char> = HashMap::from([
@@ -166,6 +169,12 @@ pub fn number_format<'a>(p: &'a Pinyin) -> Cow<'a, str> {
 }
 
 pub fn phonetic_format<'a>(p: &'a Pinyin) -> Cow<'a, str> {
+    phonetic(p)
+        .map(Cow::Owned)
+        .unwrap_or_else(|| Cow::Borrowed(p.raw.as_str()))
+}
+
+fn phonetic(p: &Pinyin) -> Option<String> {
     let mut ret = String::new();
 
     let mut s = p.raw.to_string();
@@ -177,35 +186,41 @@ pub fn phonetic_format<'a>(p: &'a Pinyin) -> Cow<'a, str> {
         }
     }
     let len = s.graphemes(true).count();
-    let split = if Pinyin::has_initial(&s) {
-        ["", s.remove_last_grapheme(), s.last_grapheme()]
+    let split = if !Pinyin::has_initial(&s) {
+        ["", s.substring_checked(0, len.checked_sub(1)?)?, s.substring_checked(len - 1, 1)?]
     } else {
-        let i = if len > 2 && s.starts_with('h') {
+        let i = if len > 2 && s.substring_checked(1, 1) == Some("h") {
             2
         } else {
             1
         };
         [
-            s.substring(0, i),
-            s.substring(i, len - i - 1),
-            s.substring(len - 1, 1),
+            s.substring_checked(0, i)?,
+            s.substring_checked(i, len.checked_sub(i + 1)?)?,
+            s.substring_checked(len - 1, 1)?,
         ]
     };
 
     let weak = split[2] == "0";
     if weak {
-        ret.push_str(SYMBOLS[split[2]]);
+        ret.push_str(SYMBOLS.get(split[2])?);
     }
-    ret.push_str(SYMBOLS[split[0]]);
-    ret.push_str(SYMBOLS[split[1]]);
+    ret.push_str(SYMBOLS.get(split[0])?);
+    ret.push_str(SYMBOLS.get(split[1])?);
     if !weak {
-        ret.push_str(SYMBOLS[split[2]]);
+        ret.push_str(SYMBOLS.get(split[2])?);
     }
 
-    Cow::Owned(ret)
+    Some(ret)
 }
 
 pub fn unicode_format<'a>(p: &'a Pinyin) -> Cow<'a, str> {
+    unicode(p)
+        .map(Cow::Owned)
+        .unwrap_or_else(|| Cow::Borrowed(p.raw.as_str()))
+}
+
+fn unicode(p: &Pinyin) -> Option<String> {
     let s = p.raw.as_str();
     let len = s.graphemes(true).count();
     let mut ret = String::new();
@@ -216,8 +231,8 @@ pub fn unicode_format<'a>(p: &'a Pinyin) -> Cow<'a, str> {
         } else {
             1
         };
-        ret.push_str(s.substring(0, i));
-        s.substring(i, len - i - 1)
+        ret.push_str(s.substring_checked(0, i)?);
+        s.substring_checked(i, len.checked_sub(i + 1)?)?
     } else {
         s.remove_last_grapheme()
     };
@@ -231,6 +246,7 @@ pub fn unicode_format<'a>(p: &'a Pinyin) -> Cow<'a, str> {
         .last()
         .and_then(|c| c.to_digit(10))
         .map(|x| x as usize)
+        .filter(|x| *x < TONES.len())
         .unwrap_or(0)];
     if let Some(c) = finale.chars().nth(offset) {
         if let Some(tone) = group.get(&c) {
@@ -239,8 +255,120 @@ pub fn unicode_format<'a>(p: &'a Pinyin) -> Cow<'a, str> {
     }
     let finale_len = finale.graphemes(true).count();
     if finale_len > offset + 1 {
-        ret.push_str(finale.substring(offset + 1, finale_len));
+        ret.push_str(finale.substring_checked(offset + 1, finale_len - offset - 1)?);
+    }
+
+    Some(ret)
+}
+
+/// A toned syllable in each of the built-in formats.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rendering {
+    pub syllable: String,
+    /// 1 to 4, 0 for neutral tone.
+    pub tone: u8,
+    pub number: String,
+    pub raw: String,
+    pub unicode: String,
+    pub phonetic: String,
+}
+
+impl Rendering {
+    pub fn new(syllable: &str, tone: u8) -> Self {
+        let p = Pinyin::new(&format!("{}{}", syllable, tone), &FuzzySettings::default(), &KEYBOARD_QUANPIN, 0);
+        Rendering {
+            syllable: syllable.to_string(),
+            tone,
+            number: number_format(&p).into_owned(),
+            raw: raw_format(&p).into_owned(),
+            unicode: unicode_format(&p).into_owned(),
+            phonetic: phonetic_format(&p).into_owned(),
+        }
+    }
+}
+
+/// Every toneless syllable in the embedded dictionary.
+#[cfg(feature = "embedded-dict")]
+pub fn syllables() -> std::collections::BTreeSet<String> {
+    use crate::dict_loader::DictLoader;
+
+    include_str!("dict.txt")
+        .load_dict()
+        .expect("embedded dictionary is malformed")
+        .into_values()
+        .flatten()
+        .map(|s| s.trim_end_matches(|c: char| c.is_ascii_digit()).to_string())
+        .collect()
+}
+
+/// The canonical rendering of every syllable from [`syllables`] in every
+/// tone, ordered by syllable, then tone from 0 to 4. Meant for checking
+/// display code against this crate's formatting.
+#[cfg(feature = "embedded-dict")]
+pub fn renderings() -> Vec<Rendering> {
+    syllables()
+        .iter()
+        .flat_map(|s| (0..=4).map(move |tone| Rendering::new(s, tone)))
+        .collect()
+}
+
+fn with_tone(p: &Pinyin, tone: u8) -> Pinyin {
+    let mut ret = p.clone();
+    let mut raw: CompactString = p.syllable().into();
+    raw.push(char::from(b'0' + tone));
+    ret.raw = raw;
+    ret
+}
+
+/// Applies Mandarin tone sandhi to a word given as characters with their
+/// citation readings, returning display copies of the readings:
+///
+/// - in a run of third tones, all but the last become second tone
+/// - 不 becomes second tone before a fourth tone
+/// - 一 becomes second tone before a fourth tone, fourth tone before the
+///   other tones and neutral between reduplicated verbs (看一看)
+///
+/// The returned readings are meant for formatting only; the context
+/// keeps citation tones for matching.
+pub fn sandhi(word: &[(char, &Pinyin)]) -> Vec<Pinyin> {
+    let tones: Vec<u8> = word.iter().map(|(_, p)| p.tone()).collect();
+    let mut ret: Vec<u8> = tones.clone();
+
+    for i in 0..word.len() {
+        let next = tones.get(i + 1).copied();
+        match word[i].0 {
+            '不' if tones[i] == 4 && next == Some(4) => ret[i] = 2,
+            '一' if tones[i] == 1 => {
+                let reduplicated = i > 0 && i + 1 < word.len() && word[i - 1].0 == word[i + 1].0;
+                ret[i] = match next {
+                    _ if reduplicated => 0,
+                    Some(4) => 2,
+                    Some(1..=3) => 4,
+                    _ => 1,
+                };
+            }
+            _ => {}
+        }
     }
 
-    Cow::Owned(ret)
+    for i in (0..word.len().saturating_sub(1)).rev() {
+        if tones[i] == 3 && tones[i + 1] == 3 && ret[i] == 3 {
+            ret[i] = 2;
+        }
+    }
+
+    word.iter()
+        .zip(ret)
+        .map(|((_, p), tone)| with_tone(p, tone))
+        .collect()
+}
+
+/// Formats a word syllable by syllable, optionally applying [`sandhi`].
+pub fn format_word(word: &[(char, &Pinyin)], format: fn(&Pinyin) -> Cow<str>, sandhi_display: bool) -> Vec<String> {
+    if sandhi_display {
+        sandhi(word).iter().map(|p| format(p).into_owned()).collect()
+    } else {
+        word.iter().map(|(_, p)| format(p).into_owned()).collect()
+    }
 }
@@ -0,0 +1,155 @@
+//! Word frequencies, used to rank search results and to pick readings of
+//! characters with more than one.
+//!
+//! The text format has one word per line, with its count and optionally
+//! the reading of every character:
+//!
+//! ```text
+//! # comments and blank lines are ignored
+//! 银行 2500 yin2 hang2
+//! 数据 3000
+//! ```
+//!
+//! With the `frequency` feature, [`FrequencyTable::embedded`] gives the
+//! counts of about 18 000 common words from the dictionary of jieba,
+//! under the MIT license, see the head of `src/frequency.txt`.
+
+use crate::user_dict::is_valid_reading;
+use rustc_hash::FxHashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FrequencyTable {
+    words: FxHashMap<String, (u64, Vec<String>)>,
+    total: u64,
+    /// Length of the longest word, in chars.
+    longest: usize,
+}
+
+/// A line that is not `word count [readings]`, numbered from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseFrequencyError(pub usize);
+
+impl Display for ParseFrequencyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: expected `word count [readings]`", self.0)
+    }
+}
+
+impl Error for ParseFrequencyError {}
+
+impl FrequencyTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The word counts shipped with the crate. They carry no readings, so
+    /// they rank and segment, but reading choice needs a table that has
+    /// them.
+    #[cfg(feature = "frequency")]
+    pub fn embedded() -> Self {
+        include_str!("frequency.txt").parse().expect("embedded frequency table")
+    }
+
+    /// Sets the count of `word` and the readings of its chars, which are
+    /// either empty or one valid reading per char. Returns false and
+    /// leaves the table as is otherwise.
+    pub fn insert(&mut self, word: &str, count: u64, readings: &[&str]) -> bool {
+        let len = word.chars().count();
+        if len == 0 || !(readings.is_empty() || readings.len() == len) || !readings.iter().all(|x| is_valid_reading(x)) {
+            return false;
+        }
+        let readings = readings.iter().map(|x| x.to_string()).collect();
+        if let Some((old, _)) = self.words.insert(word.to_string(), (count, readings)) {
+            self.total -= old;
+        }
+        self.total += count;
+        self.longest = self.longest.max(len);
+        true
+    }
+
+    /// The count of `word`, 0 if it is not listed.
+    pub fn count(&self, word: &str) -> u64 {
+        self.words.get(word).map(|(x, _)| *x).unwrap_or(0)
+    }
+
+    /// The readings of the chars of `word`, if the table has them.
+    pub fn readings(&self, word: &str) -> Option<&[String]> {
+        self.words
+            .get(word)
+            .map(|(_, x)| x.as_slice())
+            .filter(|x| !x.is_empty())
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// Splits `chars` into the likeliest sequence of words, as ranges of
+    /// char positions. Chars that start no listed word stand alone.
+    pub fn segment(&self, chars: &[char]) -> Vec<(usize, usize)> {
+        // Unigram model: maximize the sum of log probabilities. Unlisted
+        // chars count as if seen less than once.
+        let total = (self.total as f64).max(1.0);
+        let unknown = (0.5 / total).ln();
+        let mut best: Vec<(f64, usize)> = vec![(f64::NEG_INFINITY, 0); chars.len() + 1];
+        best[0] = (0.0, 0);
+        let mut word = String::new();
+        for start in 0..chars.len() {
+            let base = best[start].0;
+            word.clear();
+            for end in start + 1..=chars.len().min(start + self.longest.max(1)) {
+                word.push(chars[end - 1]);
+                let score = match self.count(&word) {
+                    0 if end == start + 1 => unknown,
+                    0 => continue,
+                    count => (count as f64 / total).ln(),
+                };
+                if base + score > best[end].0 {
+                    best[end] = (base + score, start);
+                }
+            }
+        }
+
+        let mut ret = Vec::new();
+        let mut end = chars.len();
+        while end > 0 {
+            let start = best[end].1;
+            ret.push((start, end));
+            end = start;
+        }
+        ret.reverse();
+        ret
+    }
+}
+
+impl FromStr for FrequencyTable {
+    type Err = ParseFrequencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ret = FrequencyTable::new();
+        for (i, line) in s.lines().enumerate().map(|(i, x)| (i + 1, x.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let word = parts.next().ok_or(ParseFrequencyError(i))?;
+            let count = parts
+                .next()
+                .and_then(|x| x.parse().ok())
+                .ok_or(ParseFrequencyError(i))?;
+            let readings: Vec<&str> = parts.collect();
+            if !ret.insert(word, count, &readings) {
+                return Err(ParseFrequencyError(i));
+            }
+        }
+        Ok(ret)
+    }
+}
@@ -0,0 +1,258 @@
+use crate::accelerator::Accelerator;
+use crate::compressed::Compressor;
+use crate::pinin::PinIn;
+use crate::searcher::{Collection, Dedup, Objects, SearcherLogic, SpillSet};
+use std::ops::Range;
+
+pub(crate) struct Node {
+    /// This node's slice of `edges`.
+    pub(crate) edges: Range<usize>,
+    /// This node's slice of `ids`: the names ending here up to `own`,
+    /// then the ones below.
+    pub(crate) ids: Range<usize>,
+    pub(crate) own: usize,
+}
+
+pub(crate) struct Edge {
+    /// The chars leading to `child`, in the names buffer.
+    pub(crate) label: Range<usize>,
+    pub(crate) child: usize,
+}
+
+/// What a traversal needs of a frozen tree, so that the same code walks
+/// a [`FrozenSearcher`] and its archived form.
+pub(crate) trait Layout {
+    fn logic(&self) -> SearcherLogic;
+
+    /// The edges, the ids and the end of the own ids of a node.
+    fn node(&self, index: usize) -> (Range<usize>, Range<usize>, usize);
+
+    /// The label and the child of an edge.
+    fn edge(&self, index: usize) -> (Range<usize>, usize);
+
+    fn id(&self, index: usize) -> usize;
+
+    fn char_at(&self, pos: usize) -> char;
+}
+
+/// Feeds the indices of the names matching `s` to `ret`.
+pub(crate) fn collect<L: Layout + ?Sized>(tree: &L, context: &PinIn, s: &str, ret: &mut dyn Collection<usize>) {
+    let a = Accelerator::new();
+    a.set_partial(!tree.logic().is_full());
+    a.search(&context.query_str(s));
+    visit(tree, context, &a, 0, 0, ret);
+}
+
+fn visit<L: Layout + ?Sized>(tree: &L, context: &PinIn, a: &Accelerator, node: usize, offset: usize, ret: &mut dyn Collection<usize>) {
+    if ret.is_full() {
+        return;
+    }
+    let (edges, ids, own) = tree.node(node);
+    if offset >= a.search_len() {
+        let end = if tree.logic().is_full() { own } else { ids.end };
+        (ids.start..end).for_each(|x| ret.insert(tree.id(x)));
+    } else {
+        for edge in edges {
+            let (label, child) = tree.edge(edge);
+            walk(tree, context, a, label.clone(), child, label.start, offset, ret);
+        }
+    }
+}
+
+/// Follows the edge with `label` from its char at `pos`.
+#[allow(clippy::too_many_arguments)]
+fn walk<L: Layout + ?Sized>(
+    tree: &L,
+    context: &PinIn,
+    a: &Accelerator,
+    label: Range<usize>,
+    child: usize,
+    pos: usize,
+    offset: usize,
+    ret: &mut dyn Collection<usize>,
+) {
+    if pos == label.end {
+        visit(tree, context, a, child, offset, ret);
+    } else if offset >= a.search_len() {
+        // The query ended within the edge, so only names below it
+        // can begin with it
+        if !tree.logic().is_full() {
+            tree.node(child).1.for_each(|x| ret.insert(tree.id(x)));
+        }
+    } else {
+        a.get(context, tree.char_at(pos), offset)
+            .for_each(|i| walk(tree, context, a, label.clone(), child, pos + 1, offset + i as usize, ret));
+    }
+}
+
+#[derive(Default)]
+struct Builder {
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+    ids: Vec<usize>,
+}
+
+impl Builder {
+    /// Adds the node for `entries`, which are sorted and share their
+    /// first `depth` chars, and returns its index.
+    fn node(&mut self, chars: &[char], entries: &[(Range<usize>, usize)], depth: usize) -> usize {
+        let index = self.nodes.len();
+        self.nodes.push(Node { edges: 0..0, ids: 0..0, own: 0 });
+
+        let start = self.ids.len();
+        let ended = entries.iter().take_while(|(x, _)| x.len() == depth).count();
+        self.ids.extend(entries[..ended].iter().map(|(_, id)| *id));
+        let own = self.ids.len();
+
+        let rest = &entries[ended..];
+        let mut groups = Vec::new();
+        let mut i = 0;
+        while i < rest.len() {
+            let c = chars[rest[i].0.start + depth];
+            let len = rest[i..].partition_point(|(x, _)| chars[x.start + depth] == c);
+            groups.push(i..i + len);
+            i += len;
+        }
+
+        let edges = self.edges.len()..self.edges.len() + groups.len();
+        self.edges.extend(groups.iter().map(|_| Edge { label: 0..0, child: 0 }));
+        for (edge, group) in edges.clone().zip(groups) {
+            let group = &rest[group];
+            let (first, last) = (&group[0].0, &group[group.len() - 1].0);
+            let mut end = depth + 1;
+            while end < first.len() && end < last.len() && chars[first.start + end] == chars[last.start + end] {
+                end += 1;
+            }
+            let child = self.node(chars, group, end);
+            self.edges[edge] = Edge { label: first.start + depth..first.start + end, child };
+        }
+
+        self.nodes[index] = Node { edges, ids: start..self.ids.len(), own };
+        index
+    }
+}
+
+/// A [`TreeSearcher`](crate::searcher::TreeSearcher) made read-only by
+/// [`freeze`](crate::searcher::TreeSearcher::freeze).
+///
+/// The tree is laid out in a few flat arrays with no locks, and every
+/// query gets an accelerator of its own, so any number of threads can
+/// search at once without waiting on each other.
+pub struct FrozenSearcher<T> {
+    pub(crate) objects: Vec<T>,
+    pub(crate) compressor: Compressor,
+    logic: SearcherLogic,
+    pub(crate) nodes: Vec<Node>,
+    pub(crate) edges: Vec<Edge>,
+    pub(crate) ids: Vec<usize>,
+    /// Where each name may begin to match, kept only when a tokenizer
+    /// gave some name more than its first char.
+    tokens: Vec<Vec<usize>>,
+}
+
+impl<T> Layout for FrozenSearcher<T> {
+    fn logic(&self) -> SearcherLogic {
+        self.logic
+    }
+
+    fn node(&self, index: usize) -> (Range<usize>, Range<usize>, usize) {
+        let node = &self.nodes[index];
+        (node.edges.clone(), node.ids.clone(), node.own)
+    }
+
+    fn edge(&self, index: usize) -> (Range<usize>, usize) {
+        let edge = &self.edges[index];
+        (edge.label.clone(), edge.child)
+    }
+
+    fn id(&self, index: usize) -> usize {
+        self.ids[index]
+    }
+
+    fn char_at(&self, pos: usize) -> char {
+        self.compressor[pos]
+    }
+}
+
+impl<T> FrozenSearcher<T> {
+    /// Indexes the name of each object from the offsets in `starts`.
+    pub(crate) fn build(logic: SearcherLogic, objects: Vec<T>, compressor: Compressor, starts: Vec<Vec<usize>>) -> Self {
+        let chars = compressor.flat().into_owned();
+        let mut entries = Vec::new();
+        for (i, starts) in starts.iter().enumerate() {
+            let start = compressor.offsets[i];
+            let len = chars[start..].iter().position(|c| *c == '\0').unwrap_or(0);
+            entries.extend(starts.iter().map(|x| (start + x..start + len, i)));
+        }
+        let tokenized = logic == SearcherLogic::Begin && starts.iter().any(|x| x.as_slice() != [0]);
+        entries.sort_unstable_by(|(a, i), (b, j)| chars[a.clone()].cmp(&chars[b.clone()]).then(i.cmp(j)));
+
+        let mut builder = Builder::default();
+        builder.node(&chars, &entries, 0);
+        FrozenSearcher {
+            objects,
+            compressor,
+            logic,
+            nodes: builder.nodes,
+            edges: builder.edges,
+            ids: builder.ids,
+            tokens: if tokenized { starts } else { Vec::new() },
+        }
+    }
+
+    /// The chars of the `index`-th name, as matched, in the names buffer.
+    pub(crate) fn chars(&self, index: usize) -> Range<usize> {
+        let start = self.compressor.offsets[index];
+        start..start + self.compressor.chars_at(start).len()
+    }
+
+    /// Where the `index`-th name may begin to match, relative to it.
+    pub(crate) fn starts(&self, index: usize) -> Vec<usize> {
+        match self.logic {
+            SearcherLogic::Contain | SearcherLogic::EndsWith => (0..self.chars(index).len()).collect(),
+            _ => self.tokens.get(index).cloned().unwrap_or_else(|| vec![0]),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.objects.get(index)
+    }
+
+    /// The name the `index`-th object was inserted with.
+    pub fn name(&self, index: usize) -> Option<String> {
+        self.compressor.original(index)
+    }
+
+    pub fn logic(&self) -> SearcherLogic {
+        self.logic
+    }
+
+    /// Matches in insertion order.
+    pub fn search(&self, context: &PinIn, s: &str) -> Vec<&T> {
+        self.search_indices(context, s).into_iter().map(|i| &self.objects[i]).collect()
+    }
+
+    /// Insertion indices of the matching objects, ascending.
+    pub fn search_indices(&self, context: &PinIn, s: &str) -> Vec<usize> {
+        let mut ret = SpillSet::default();
+        collect(self, context, s, &mut ret);
+        ret.into_sorted_vec()
+    }
+
+    /// Feeds each match to `ret` once, in traversal order.
+    pub fn search_into<'s>(&'s self, context: &PinIn, s: &str, ret: &mut dyn Collection<&'s T>) {
+        collect(self, context, s, &mut Dedup::new(Objects { objects: &self.objects, inner: ret }));
+    }
+
+    pub fn count(&self, context: &PinIn, s: &str) -> usize {
+        self.search_indices(context, s).len()
+    }
+}
@@ -0,0 +1,103 @@
+//! Entry points for fuzzers such as `cargo fuzz`. Each one turns
+//! arbitrary bytes into inputs for the public API; none of them, nor the
+//! API itself, may panic on any input.
+//!
+//! ```ignore
+//! fuzz_target!(|data: &[u8]| {
+//!     CONTEXT.with(|context| pinin_rs::fuzz::match_bytes(context, data));
+//! });
+//! ```
+
+use crate::accelerator::Accelerator;
+use crate::elements::{Character, Pinyin};
+use crate::format::{phonetic_format, unicode_format};
+use crate::keyboard::{KEYBOARD_DAQIAN, KEYBOARD_QUANPIN, KEYBOARD_XIAOHE, KEYBOARD_ZIRANMA};
+use crate::pinin::{FuzzySettings, NeutralTone, PinIn};
+use crate::query::Query;
+use crate::searcher::{Searcher, SearcherLogic, SimpleSearcher, TreeSearcher};
+use crate::user_dict::UserDict;
+use std::sync::Arc;
+
+/// The text before and after the first NUL byte, decoded lossily.
+pub fn split_input(data: &[u8]) -> (String, String) {
+    let (a, b) = match data.iter().position(|x| *x == 0) {
+        Some(i) => (&data[..i], &data[i + 1..]),
+        None => (data, &[][..]),
+    };
+    (String::from_utf8_lossy(a).into_owned(), String::from_utf8_lossy(b).into_owned())
+}
+
+/// Matches a target against a query, directly and through both searchers.
+pub fn match_bytes(context: &PinIn, data: &[u8]) {
+    let (target, query) = split_input(data);
+    context.contains(&target, &query);
+    context.begins(&target, &query);
+    context.matches(&target, &query);
+    context.ends(&target, &query);
+    for start in 0..3 {
+        context.check(&target, start, &query, start, true);
+        context.check(&target, 0, &query, start, false);
+    }
+
+    for logic in [SearcherLogic::Begin, SearcherLogic::Contain, SearcherLogic::Equal, SearcherLogic::EndsWith] {
+        let mut tree = TreeSearcher::new(logic, Arc::new(Accelerator::new()));
+        tree.insert(context, &target, 0);
+        tree.insert(context, &query, 1);
+        tree.search(context, &query);
+        tree.count(context, &target);
+
+        let mut simple = SimpleSearcher::new(logic);
+        simple.insert(context, &target, 0);
+        simple.search(context, &query);
+    }
+
+    if let Ok(query) = query.parse::<Query>() {
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        tree.insert(context, &target, ());
+        query.evaluate(context, &tree);
+    }
+}
+
+/// Builds a reading from the first input on every keyboard, with and
+/// without fuzzy settings, and matches and formats it.
+pub fn pinyin_bytes(data: &[u8]) {
+    let (raw, query) = split_input(data);
+    let fuzzy = [
+        FuzzySettings::default(),
+        FuzzySettings {
+            zh2z: true,
+            sh2s: true,
+            ch2c: true,
+            ang2an: true,
+            ing2in: true,
+            eng2en: true,
+            u2v: true,
+            any_tone: true,
+            neutral_tone: NeutralTone::Explicit,
+        },
+    ];
+    for keyboard in [&*KEYBOARD_QUANPIN, &*KEYBOARD_DAQIAN, &*KEYBOARD_XIAOHE, &*KEYBOARD_ZIRANMA] {
+        for fuzzy in &fuzzy {
+            let pinyin = Pinyin::new(&raw, fuzzy, keyboard, 0);
+            for start in 0..=query.chars().count() {
+                pinyin.match_string(&query, start, true);
+                pinyin.match_string(&query, start, false);
+            }
+            unicode_format(&pinyin);
+            phonetic_format(&pinyin);
+            pinyin.initial();
+            pinyin.final_();
+            Character::new('x', [Arc::new(pinyin)].into_iter().collect()).match_str(&query, 0, true);
+        }
+    }
+}
+
+/// Loads the input as a dictionary, and as a user dictionary.
+pub fn dict_bytes(data: &[u8]) {
+    let text = String::from_utf8_lossy(data);
+    let dict = text.parse::<UserDict>().unwrap_or_default();
+    let mut context = PinIn::new();
+    let _ = context.load_dict(Box::new(text.as_ref()));
+    context.load_user_dict(&dict);
+    context.contains(&text, &text);
+}
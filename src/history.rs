@@ -0,0 +1,103 @@
+use crate::pinin::PinIn;
+use crate::searcher::Searcher;
+use compact_str::CompactString;
+use rustc_hash::FxHashMap;
+
+#[derive(Debug, Copy, Clone)]
+struct HistoryEntry {
+    hits: usize,
+    last_used: u64,
+}
+
+/// Records queries that produced results and offers completions for
+/// partially typed ones, ranked by frequency and then recency.
+#[derive(Debug)]
+pub struct QueryHistory {
+    entries: FxHashMap<CompactString, HistoryEntry>,
+    clock: u64,
+    capacity: usize,
+}
+
+impl Default for QueryHistory {
+    fn default() -> Self {
+        Self::new(256)
+    }
+}
+
+impl QueryHistory {
+    pub fn new(capacity: usize) -> Self {
+        QueryHistory {
+            entries: Default::default(),
+            clock: 0,
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn remove(&mut self, query: &str) -> bool {
+        self.entries.remove(query).is_some()
+    }
+
+    pub fn record(&mut self, query: &str) {
+        if query.is_empty() || self.capacity == 0 {
+            return;
+        }
+
+        self.clock += 1;
+        let clock = self.clock;
+        self.entries
+            .entry(query.into())
+            .and_modify(|e| {
+                e.hits += 1;
+                e.last_used = clock;
+            })
+            .or_insert(HistoryEntry { hits: 1, last_used: clock });
+
+        if self.entries.len() > self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    /// Runs `query` against `searcher` and records it when anything was found.
+    pub fn search<'s, T: 'static>(
+        &mut self,
+        searcher: &'s dyn Searcher<T>,
+        context: &PinIn,
+        query: &str,
+    ) -> Vec<&'s T> {
+        let ret = searcher.search(context, query);
+        if !ret.is_empty() {
+            self.record(query);
+        }
+        ret
+    }
+
+    /// Past queries starting with `prefix`, most used first.
+    pub fn complete(&self, prefix: &str, limit: usize) -> Vec<&str> {
+        let mut ret: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(k, _)| k.starts_with(prefix) && k.as_str() != prefix)
+            .collect();
+        ret.sort_by(|(_, a), (_, b)| b.hits.cmp(&a.hits).then(b.last_used.cmp(&a.last_used)));
+        ret.into_iter().take(limit).map(|(k, _)| k.as_str()).collect()
+    }
+}
@@ -0,0 +1,282 @@
+//! Filtering a [`FrozenSearcher`] as a query is typed, one key at a time,
+//! for launcher style UIs that search on every keystroke.
+//!
+//! The contract: once a query has been searched, extending it by one key
+//! costs time proportional to the names the previous query left, not to
+//! the corpus. Taking a key back restores the earlier results without
+//! any matching at all. Only a query that neither extends nor shortens a
+//! recent one searches the index again.
+//!
+//! This holds because each name left keeps where its match may go on:
+//! the positions in the name reached by consuming a prefix of the query.
+//! Consuming a part of the query that ends before its last key does not
+//! depend on the keys after it, so these positions stay valid while the
+//! query grows, and a new key only has to be tried from the few of them
+//! close enough to the end of the query, as no char consumes more than
+//! [`MAX_STEP`] keys. The same positions give where the match lies in the
+//! name, see [`Incremental::spans`].
+//!
+//! For [`Equal`](SearcherLogic::Equal) and
+//! [`EndsWith`](SearcherLogic::EndsWith), names are kept while the query
+//! is a prefix of one of their readings, and shown once it is a whole one.
+
+use crate::accelerator::Accelerator;
+use crate::frozen::{collect, FrozenSearcher, Layout};
+use crate::pinin::PinIn;
+use crate::searcher::{SearcherLogic, SpillSet};
+use rustc_hash::FxHashSet;
+use std::ops::Range;
+
+/// The most query keys one char of a name can consume.
+pub const MAX_STEP: usize = 31;
+
+/// A name's match got as far as `pos`, having consumed the query up to
+/// `offset` from `start` on. Positions are in the names buffer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct State {
+    start: usize,
+    pos: usize,
+    offset: usize,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    id: usize,
+    /// States with `offset` short of the query's length, from which the
+    /// next key may still be consumed.
+    states: Vec<State>,
+}
+
+/// The names left by one query.
+#[derive(Debug)]
+struct Frame {
+    query: String,
+    candidates: Vec<Candidate>,
+    visible: Vec<usize>,
+    spans: Vec<Range<usize>>,
+}
+
+/// The results of a [`FrozenSearcher`] for a query typed key by key.
+///
+/// Like [`Filter`](crate::filter::Filter), it is meant to be fed the
+/// contents of a text box whenever it changes.
+pub struct Incremental<'s, T> {
+    index: &'s FrozenSearcher<T>,
+    /// One frame per query of the current run of extensions, the last
+    /// one current. Empty for the empty query.
+    frames: Vec<Frame>,
+    /// Results of the empty query.
+    all: Vec<usize>,
+    /// The context version the frames were computed against.
+    version: Option<u64>,
+}
+
+/// A frozen tree searched with partial matches only, so that names are
+/// found while the query is still a prefix of what they need.
+struct Relaxed<'a, L: ?Sized>(&'a L);
+
+impl<L: Layout + ?Sized> Layout for Relaxed<'_, L> {
+    fn logic(&self) -> SearcherLogic {
+        match self.0.logic() {
+            SearcherLogic::Equal => SearcherLogic::Begin,
+            SearcherLogic::EndsWith => SearcherLogic::Contain,
+            x => x,
+        }
+    }
+
+    fn node(&self, index: usize) -> (Range<usize>, Range<usize>, usize) {
+        self.0.node(index)
+    }
+
+    fn edge(&self, index: usize) -> (Range<usize>, usize) {
+        self.0.edge(index)
+    }
+
+    fn id(&self, index: usize) -> usize {
+        self.0.id(index)
+    }
+
+    fn char_at(&self, pos: usize) -> char {
+        self.0.char_at(pos)
+    }
+}
+
+impl<'s, T> Incremental<'s, T> {
+    pub fn new(index: &'s FrozenSearcher<T>) -> Self {
+        Incremental {
+            index,
+            frames: Vec::new(),
+            all: Vec::new(),
+            version: None,
+        }
+    }
+
+    /// Updates the results for `query`, returning whether it changed
+    /// since the last call.
+    pub fn set_query(&mut self, context: &PinIn, query: &str) -> bool {
+        let query = context.query_str(query);
+        if self.version != Some(context.version()) {
+            self.frames.clear();
+            self.all = self.index.search_indices(context, "");
+            self.version = Some(context.version());
+        } else if self.query() == query {
+            return false;
+        }
+
+        while self.frames.last().is_some_and(|x| !query.starts_with(x.query.as_str())) {
+            self.frames.pop();
+        }
+        if query.is_empty() || self.frames.last().is_some_and(|x| x.query == query) {
+            return true;
+        }
+        let frame = match self.frames.last() {
+            Some(last) => self.extend(context, last, &query),
+            None => self.search(context, &query),
+        };
+        self.frames.push(frame);
+        true
+    }
+
+    /// Forgets the earlier queries, keeping only the current results.
+    pub fn clear_history(&mut self) {
+        let len = self.frames.len();
+        self.frames.drain(..len.saturating_sub(1));
+    }
+
+    pub fn query(&self) -> &str {
+        self.frames.last().map_or("", |x| x.query.as_str())
+    }
+
+    /// Insertion indices of the matching names, ascending.
+    pub fn visible_ids(&self) -> &[usize] {
+        match self.frames.last() {
+            Some(frame) => &frame.visible,
+            None => &self.all,
+        }
+    }
+
+    pub fn visible(&self) -> impl Iterator<Item = &'s T> + '_ {
+        self.visible_ids().iter().filter_map(|i| self.index.get(*i))
+    }
+
+    /// Where the query matched each of [`visible_ids`](Self::visible_ids),
+    /// in chars of the name as matched. The earliest match is given when
+    /// there are several.
+    pub fn spans(&self) -> &[Range<usize>] {
+        self.frames.last().map_or(&[], |x| x.spans.as_slice())
+    }
+
+    /// How many names the next key will be tried on, the cost of typing
+    /// it. None for the empty query, as the first key searches the index.
+    pub fn candidates(&self) -> usize {
+        self.frames.last().map_or(0, |x| x.candidates.len())
+    }
+
+    pub fn searcher(&self) -> &'s FrozenSearcher<T> {
+        self.index
+    }
+
+    /// A frame for `query` from the names the index gives for it.
+    fn search(&self, context: &PinIn, query: &str) -> Frame {
+        let mut ids = SpillSet::default();
+        collect(&Relaxed(self.index), context, query, &mut ids);
+        let candidates = ids
+            .into_sorted_vec()
+            .into_iter()
+            .map(|id| {
+                let base = self.index.chars(id).start;
+                let states = self
+                    .index
+                    .starts(id)
+                    .into_iter()
+                    .map(|x| State { start: base + x, pos: base + x, offset: 0 })
+                    .collect();
+                Candidate { id, states }
+            })
+            .collect();
+        self.advance(context, candidates, 0, query)
+    }
+
+    /// A frame for `query` from `last`, whose query it extends.
+    fn extend(&self, context: &PinIn, last: &Frame, query: &str) -> Frame {
+        let candidates = last
+            .candidates
+            .iter()
+            .map(|x| Candidate { id: x.id, states: x.states.clone() })
+            .collect();
+        self.advance(context, candidates, last.query.chars().count(), query)
+    }
+
+    /// Consumes the rest of `query`, from `from` on, in each of
+    /// `candidates`, keeping the ones that get to its end.
+    fn advance(&self, context: &PinIn, candidates: Vec<Candidate>, from: usize, query: &str) -> Frame {
+        let a = Accelerator::new();
+        a.set_partial(true);
+        a.search(query);
+        let len = a.search_len();
+        // Tells whole readings from prefixes of them
+        let full = self.index.logic().is_full().then(|| {
+            let a = Accelerator::new();
+            a.set_partial(false);
+            a.search(query);
+            a
+        });
+
+        let mut frame = Frame {
+            query: query.to_string(),
+            candidates: Vec::with_capacity(candidates.len()),
+            visible: Vec::new(),
+            spans: Vec::new(),
+        };
+        let mut seen = FxHashSet::default();
+        for mut candidate in candidates {
+            let chars = self.index.chars(candidate.id);
+            seen.clear();
+            seen.extend(candidate.states.iter().map(|x| (x.pos, x.offset)));
+
+            let mut ended: Option<State> = None;
+            let mut i = 0;
+            while i < candidate.states.len() {
+                let state = candidate.states[i];
+                i += 1;
+                if state.pos == chars.end {
+                    continue;
+                }
+                a.get(context, self.index.char_at(state.pos), state.offset).for_each(|step| {
+                    let offset = state.offset + step as usize;
+                    // Shorter steps were taken by the earlier queries
+                    if step == 0 || offset < from || !seen.insert((state.pos + 1, offset)) {
+                        return;
+                    }
+                    let next = State { start: state.start, pos: state.pos + 1, offset };
+                    match ended {
+                        _ if offset < len => candidate.states.push(next),
+                        Some(x) if (x.start, x.pos) <= (next.start, next.pos) => {}
+                        _ => ended = Some(next),
+                    }
+                });
+            }
+            let Some(ended) = ended else {
+                continue;
+            };
+            candidate.states.retain(|x| x.offset + MAX_STEP >= len);
+
+            let span = match &full {
+                None => Some(ended.start..ended.pos),
+                Some(full) => candidate
+                    .states
+                    .iter()
+                    .filter(|x| x.pos + 1 == chars.end)
+                    .filter(|x| full.get(context, self.index.char_at(x.pos), x.offset).get(len - x.offset))
+                    .map(|x| x.start..chars.end)
+                    .min_by_key(|x| x.start),
+            };
+            if let Some(span) = span {
+                frame.visible.push(candidate.id);
+                frame.spans.push(span.start - chars.start..span.end - chars.start);
+            }
+            frame.candidates.push(candidate);
+        }
+        frame
+    }
+}
@@ -0,0 +1,81 @@
+//! Checks on names before they are indexed, for pipelines that take names
+//! from elsewhere and want to log what [`Searcher::insert`] would quietly
+//! change or what would misbehave once indexed, see
+//! [`Searcher::try_insert`].
+//!
+//! [`Searcher::insert`]: crate::searcher::Searcher::insert
+//! [`Searcher::try_insert`]: crate::searcher::Searcher::try_insert
+
+use crate::pinin::is_reserved;
+use crate::unicode_utils::canonicalize;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+/// A change made to a name to index it, see [`canonicalize`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sanitization {
+    /// Control chars other than whitespace were dropped, NULs included;
+    /// how many.
+    ControlChars(usize),
+    /// Whitespace was trimmed, or runs of it replaced by a single space.
+    Whitespace,
+}
+
+/// A name accepted by [`check_name`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inserted {
+    /// The name as indexed. The original stays the one displayed.
+    pub indexed: String,
+    /// What was changed to get there, empty for names indexed as they are.
+    pub sanitized: Vec<Sanitization>,
+}
+
+impl Inserted {
+    /// Whether the name is indexed as it is.
+    pub fn is_clean(&self) -> bool {
+        self.sanitized.is_empty()
+    }
+}
+
+/// A name refused by [`check_name`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertError {
+    /// Nothing is left once sanitized, so only empty queries would find
+    /// the name.
+    Empty,
+    /// A char of the Supplementary Private Use Areas, where contexts take
+    /// the stand-ins of readings and words from; it is indexed as U+FFFD.
+    Reserved(char),
+}
+
+impl Display for InsertError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InsertError::Empty => write!(f, "name is empty once sanitized"),
+            InsertError::Reserved(c) => write!(f, "name contains reserved char U+{:04X}", *c as u32),
+        }
+    }
+}
+
+impl Error for InsertError {}
+
+/// Whether `name` can be indexed, and how it would be.
+pub fn check_name(name: &str) -> Result<Inserted, InsertError> {
+    if let Some(c) = name.chars().find(|c| is_reserved(*c)) {
+        return Err(InsertError::Reserved(c));
+    }
+    let mut sanitized = Vec::new();
+    let dropped = name.chars().filter(|c| c.is_control() && !c.is_whitespace()).count();
+    if dropped > 0 {
+        sanitized.push(Sanitization::ControlChars(dropped));
+    }
+    let kept: String = name.chars().filter(|c| !c.is_control() || c.is_whitespace()).collect();
+    let indexed = canonicalize(&kept).into_owned();
+    if indexed != kept {
+        sanitized.push(Sanitization::Whitespace);
+    }
+    if indexed.is_empty() {
+        return Err(InsertError::Empty);
+    }
+    Ok(Inserted { indexed, sanitized })
+}
@@ -0,0 +1,70 @@
+//! Sharing loaded tables between contexts, e.g. one context per user on
+//! a server, each with its own fuzzy settings.
+
+use crate::elements::{Character, Pinyin};
+use crate::sync::lock;
+use compact_str::CompactString;
+use rustc_hash::FxHashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+pub(crate) type CharTable = FxHashMap<char, Option<Character>>;
+pub(crate) type PinyinTable = FxHashMap<CompactString, Arc<Pinyin>>;
+pub(crate) type RomanizedTable = FxHashMap<CompactString, char>;
+
+/// Tables of loaded readings, shared by the contexts given the pool with
+/// [`PinIn::set_intern_pool`](crate::pinin::PinIn::set_intern_pool).
+///
+/// Contexts with the same readings, keyboard and fuzzy settings hold one
+/// copy of the character and reading tables between them; contexts that
+/// only load the same readings still share the romanized stand-ins. A
+/// context copies a shared table before changing it, so loading into one
+/// context never affects another. The pool only keeps weak references
+/// and frees nothing itself.
+#[derive(Clone, Default)]
+pub struct InternPool(Arc<Mutex<Entries>>);
+
+#[derive(Default)]
+struct Entries {
+    chars: Vec<Weak<CharTable>>,
+    pinyins: Vec<Weak<PinyinTable>>,
+    romanized: Vec<Weak<RomanizedTable>>,
+}
+
+impl InternPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many distinct tables are alive, of all kinds.
+    pub fn len(&self) -> usize {
+        let entries = lock(&self.0);
+        [
+            entries.chars.iter().filter(|x| x.strong_count() > 0).count(),
+            entries.pinyins.iter().filter(|x| x.strong_count() > 0).count(),
+            entries.romanized.iter().filter(|x| x.strong_count() > 0).count(),
+        ]
+        .into_iter()
+        .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Replaces the tables with equal ones already in the pool, adding
+    /// those that are not.
+    pub(crate) fn intern(&self, chars: &mut Arc<CharTable>, pinyins: &mut Arc<PinyinTable>, romanized: &mut Arc<RomanizedTable>) {
+        let mut entries = lock(&self.0);
+        Self::intern_one(&mut entries.chars, chars);
+        Self::intern_one(&mut entries.pinyins, pinyins);
+        Self::intern_one(&mut entries.romanized, romanized);
+    }
+
+    fn intern_one<T: PartialEq>(entries: &mut Vec<Weak<T>>, table: &mut Arc<T>) {
+        entries.retain(|x| x.strong_count() > 0);
+        match entries.iter().filter_map(Weak::upgrade).find(|x| Arc::ptr_eq(x, table) || **x == **table) {
+            Some(x) => *table = x,
+            None => entries.push(Arc::downgrade(table)),
+        }
+    }
+}
@@ -72,7 +72,9 @@ lazy_static! {
         ("vn", "mp"),
         ("w", "j"),
         ("x", "v"),
-        ("y", "u")
+        ("y", "u"),
+        ("z", "y"),
+        ("zh", "5")
     ]);
     static ref XIAOHE_KEYS: HashMap<&'static str, &'static str> = HashMap::from([
         ("ai", "d"),
@@ -104,7 +106,8 @@ lazy_static! {
         ("uo", "o"),
         ("ve", "t"),
         ("ue", "t"),
-        ("vn", "y")
+        ("vn", "y"),
+        ("zh", "v")
     ]);
     static ref ZIRANMA_KEYS: HashMap<&'static str, &'static str> = HashMap::from([
         ("ai", "l"),
@@ -175,6 +178,7 @@ lazy_static! {
         local: None,
         keys: None,
         cutter: standard_cutter,
+        zero: ZeroInitial::Keep,
         duo: false,
         sequence: true,
     };
@@ -182,38 +186,113 @@ lazy_static! {
         local: Some(&PHONETIC_LOCAL_KEYS),
         keys: Some(&DAQIAN_KEYS),
         cutter: standard_cutter,
+        zero: ZeroInitial::Keep,
         duo: false,
         sequence: false,
     };
     pub static ref KEYBOARD_XIAOHE: Keyboard = Keyboard {
         local: None,
         keys: Some(&XIAOHE_KEYS),
-        cutter: zero_cutter,
+        cutter: standard_cutter,
+        zero: ZeroInitial::FirstLetter,
         duo: true,
         sequence: false,
     };
     pub static ref KEYBOARD_ZIRANMA: Keyboard = Keyboard {
         local: None,
         keys: Some(&ZIRANMA_KEYS),
-        cutter: zero_cutter,
+        cutter: standard_cutter,
+        zero: ZeroInitial::FirstLetter,
         duo: true,
         sequence: false,
     };
 }
 
+/// How a double-pinyin layout types syllables that have no initial.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ZeroInitial {
+    /// Keep the final as a single phoneme.
+    Keep,
+    /// Type the first letter of the final, then the rest of it: "an" as
+    /// "a" + "n", "ang" as "a" + the key of "ang" and "a" as "aa".
+    /// Used by Xiaohe and Ziranma.
+    FirstLetter,
+    /// Type a fixed key, then the key of the final: "ang" as "o" + the key
+    /// of "ang". Used by Microsoft and Sogou style layouts.
+    Fixed(&'static str),
+}
+
 pub struct Keyboard {
     local: Option<&'static HashMap<&'static str, &'static str>>,
     keys: Option<&'static HashMap<&'static str, &'static str>>,
     cutter: fn(&str) -> SmallVec<[&str; 4]>,
+    zero: ZeroInitial,
     pub duo: bool,
     pub sequence: bool,
 }
 
 impl Keyboard {
+    /// A layout mapping phonemes with `keys` after rewriting syllables
+    /// through `local`, e.g. "yuan" to "van".
+    pub fn new(
+        local: Option<&'static HashMap<&'static str, &'static str>>,
+        keys: Option<&'static HashMap<&'static str, &'static str>>,
+        zero: ZeroInitial,
+        duo: bool,
+        sequence: bool,
+    ) -> Self {
+        Keyboard {
+            local,
+            keys,
+            cutter: standard_cutter,
+            zero,
+            duo,
+            sequence,
+        }
+    }
+
+    pub(crate) fn has_keys(&self) -> bool {
+        self.keys.is_some()
+    }
+
+    pub fn zero_initial(&self) -> ZeroInitial {
+        self.zero
+    }
+
     pub fn keys<'a>(&self, s: &'a str) -> &'a str {
         self.keys.and_then(|keys| keys.get(s)).unwrap_or(&s)
     }
 
+    /// Each phoneme and the keys it is typed with, sorted by phoneme.
+    /// Phonemes missing here are typed as they are spelled, so full
+    /// pinyin has none.
+    pub fn iter_mappings(&self) -> impl Iterator<Item = (&'static str, &'static str)> {
+        sorted(self.keys)
+    }
+
+    /// Syllables spelled otherwise before they are cut into phonemes,
+    /// e.g. "yuan" as "van", sorted.
+    pub fn iter_local(&self) -> impl Iterator<Item = (&'static str, &'static str)> {
+        sorted(self.local)
+    }
+
+    /// The keys typed for each phoneme of `syllable`, e.g. `["v", "s"]`
+    /// for "zhong" on Xiaohe. A tone digit at the end, "zhong1", adds the
+    /// key of the tone where the layout has one.
+    pub fn map_syllable(&self, syllable: &str) -> Vec<String> {
+        let toned = syllable.ends_with(|c: char| c.is_ascii_digit());
+        let s = if toned { Cow::Borrowed(syllable) } else { Cow::Owned(format!("{}0", syllable)) };
+        let mut phonemes = self.split(&s);
+        if !toned && phonemes.last().is_some_and(|x| x.as_ref() == "0") {
+            phonemes.pop();
+        }
+        phonemes
+            .into_iter()
+            .map(|x| self.keys_cow(x).into_owned())
+            .filter(|x| !x.is_empty())
+            .collect()
+    }
+
     pub fn keys_cow<'a>(&self, s: Cow<'a, str>) -> Cow<'static, str> {
         self.keys
             .and_then(|keys| keys.get(s.as_ref()))
@@ -221,32 +300,56 @@ impl Keyboard {
             .unwrap_or_else(|| Cow::Owned(s.into_owned()))
     }
 
-    pub fn split<'a, 'b>(&'a self, s: &'b str) -> SmallVec<[Cow<'b, str>; 4]> {
+    pub fn split<'b>(&self, s: &'b str) -> SmallVec<[Cow<'b, str>; 4]> {
         if let Some(local) = self.local {
-            let s = s;
-
             let cut = s.remove_last_grapheme();
             if let Some(alt) = local.get(cut) {
                 let mut sx = alt.to_string();
                 sx.push_str(s.last_grapheme());
-                return (self.cutter)(&sx)
+                return self
+                    .cut(&sx)
                     .into_iter()
                     .map(|x| Cow::Owned(x.to_string()))
                     .collect();
             }
-            (self.cutter)(s)
-                .into_iter()
-                .map(Cow::Borrowed)
-                .collect()
-        } else {
-            (self.cutter)(s)
-                .into_iter()
-                .map(Cow::Borrowed)
-                .collect()
         }
+        self.cut(s).into_iter().map(Cow::Borrowed).collect()
+    }
+
+    fn cut<'b>(&self, s: &'b str) -> SmallVec<[&'b str; 4]> {
+        let mut ss = (self.cutter)(s);
+        // Only syllables without an initial are cut into final and tone
+        if ss.len() != 2 {
+            return ss;
+        }
+
+        let finale = ss[0];
+        match self.zero {
+            ZeroInitial::Keep => {}
+            ZeroInitial::FirstLetter => {
+                let second = finale.char_indices().nth(1).map(|(i, _)| i).unwrap_or(finale.len());
+                ss[0] = &finale[..second];
+                ss[1] = if finale.chars().count() == 2 {
+                    &finale[second..]
+                } else {
+                    finale
+                };
+            }
+            ZeroInitial::Fixed(key) => {
+                ss.insert(0, key);
+            }
+        }
+
+        ss
     }
 }
 
+fn sorted(table: Option<&'static HashMap<&'static str, &'static str>>) -> std::vec::IntoIter<(&'static str, &'static str)> {
+    let mut ret: Vec<_> = table.into_iter().flatten().map(|(k, v)| (*k, *v)).collect();
+    ret.sort_unstable();
+    ret.into_iter()
+}
+
 fn standard_cutter<'a>(s: &'a str) -> SmallVec<[&'a str; 4]> {
     let mut cursor = 0usize;
     let mut ret = SmallVec::new();
@@ -257,30 +360,14 @@ fn standard_cutter<'a>(s: &'a str) -> SmallVec<[&'a str; 4]> {
         } else {
             1
         };
-        ret.push(s.substring(0, cursor));
+        ret.extend(s.substring_checked(0, cursor));
     }
 
-    if graphemes.len() != cursor + 1 {
-        ret.push(s.substring(cursor, graphemes.len() - cursor - 1));
+    if graphemes.len() > cursor + 1 {
+        ret.extend(s.substring_checked(cursor, graphemes.len() - cursor - 1));
     }
 
-    ret.push(s.last_grapheme());
+    ret.extend(graphemes.last().map(|(_, g)| *g));
 
     ret
 }
-
-fn zero_cutter(s: &str) -> SmallVec<[&str; 4]> {
-    let mut ss = standard_cutter(s);
-    if ss.len() != 2 {
-        return ss;
-    }
-    let finale = ss[0];
-    ss[0] = &finale[0..1];
-    ss[1] = if finale.len() == 2 {
-        &finale[1..2]
-    } else {
-        finale
-    };
-
-    ss
-}
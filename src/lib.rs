@@ -1,36 +1,87 @@
 #![allow(dead_code)]
 
-use mimalloc::MiMalloc;
-
+// Choosing the allocator is up to the application; this is only for
+// binaries that want it without depending on mimalloc themselves.
+#[cfg(feature = "mimalloc")]
 #[global_allocator]
-static GLOBAL: MiMalloc = MiMalloc;
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 pub mod accelerator;
+#[cfg(feature = "rkyv")]
+pub mod archive;
+#[cfg(feature = "bench_utils")]
+pub mod bench_utils;
+pub mod budget;
+#[doc(hidden)]
 pub mod cache;
+#[doc(hidden)]
 pub mod compressed;
+pub mod config;
+#[cfg(feature = "convert")]
+pub mod convert;
 pub mod dict_loader;
 pub mod elements;
+#[cfg(feature = "files")]
+pub mod files;
+pub mod filter;
 pub mod format;
+pub mod frequency;
+pub mod frozen;
+pub mod fuzz;
+pub mod history;
+pub mod incremental;
+pub mod ingest;
+pub mod intern;
 pub mod keyboard;
+#[cfg(feature = "lite")]
+pub mod lite;
+pub mod map;
+pub mod micro;
+pub mod multi;
+pub mod persist;
+pub mod phrase;
 pub mod pinin;
+pub mod policy;
+pub mod prelude;
+pub mod query;
+pub mod romanize;
 pub mod searcher;
+pub mod session;
+pub mod snapshot;
+pub mod sort;
+pub mod suggest;
+mod sync;
+pub mod trace;
 pub mod unicode_utils;
+pub mod user_dict;
+#[cfg(feature = "verify")]
+pub mod verify;
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashSet;
-    use std::rc::Rc;
-    use crate::format::{number_format, phonetic_format, raw_format, unicode_format};
-    use crate::keyboard::{KEYBOARD_DAQIAN, KEYBOARD_XIAOHE, KEYBOARD_ZIRANMA};
+    use std::borrow::Cow;
+    use std::collections::BinaryHeap;
+    use std::sync::Arc;
+    use crate::elements::Pinyin;
+    use crate::format::{format_word, number_format, phonetic_format, raw_format, unicode_format};
+    use crate::keyboard::{Keyboard, ZeroInitial, KEYBOARD_DAQIAN, KEYBOARD_XIAOHE, KEYBOARD_ZIRANMA};
     use crate::pinin::PinIn;
     use pretty_assertions::assert_str_eq;
-    use crate::accelerator::Accelerator;
-    use crate::searcher::{Searcher, SearcherLogic, SimpleSearcher, TreeSearcher};
+    use crate::accelerator::{Accelerator, ChunkedProvider};
+    use crate::history::QueryHistory;
+    use crate::compressed::IndexSet;
+    use crate::unicode_utils::{canonicalize, is_han, script_runs, Script, SegmentedStr, UnicodeUtils};
+    use crate::snapshot::SnapshotSearcher;
+    use crate::sort::sort_natural_by_key;
+    use crate::query::{ParseQueryError, Query};
+    use crate::searcher::{Collection, Dedup, DedupBy, Searcher, SearcherLogic, SearcherMut, SimpleSearcher, TreeSearcher};
+    use crate::user_dict::{UserDict, UserDictError};
+    use crate::map::PinyinMap;
 
     #[test]
     fn quanpin() {
         let mut pinin = PinIn::new();
-        pinin.load_dict(Box::new(include_str!("dict.txt")));
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
         pinin.accelerate = true;
         assert!(pinin.contains("测试文本", "ceshiwenben"));
         assert!(pinin.contains("测试文本", "ceshiwenbe"));
@@ -55,7 +106,7 @@ mod tests {
     fn xiaohe() {
         let mut pinin = PinIn::new();
         pinin.keyboard = &KEYBOARD_XIAOHE;
-        pinin.load_dict(Box::new(include_str!("dict.txt")));
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
 
         assert!(pinin.contains("测试文本", "ceuiwfbf"));
         assert!(pinin.contains("测试文本", "ceuiwf2"));
@@ -63,13 +114,14 @@ mod tests {
         assert!(pinin.contains("合金炉", "hej"));
         assert!(pinin.contains("洗矿场", "xikl4"));
         assert!(pinin.contains("月球", "ytqq"));
+        assert!(pinin.contains("中国", "vsgo"));
     }
 
     #[test]
     fn ziranma() {
         let mut pinin = PinIn::new();
         pinin.keyboard = &KEYBOARD_ZIRANMA;
-        pinin.load_dict(Box::new(include_str!("dict.txt")));
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
 
         assert!(pinin.contains("测试文本", "ceuiwfbf"));
         assert!(pinin.contains("测试文本", "ceuiwf2"));
@@ -84,15 +136,13 @@ mod tests {
     fn daqian() {
         let mut pinin = PinIn::new();
         pinin.keyboard = &KEYBOARD_DAQIAN;
-        pinin.load_dict(Box::new(include_str!("dict.txt")));
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
 
         assert!(pinin.contains("测试文本", "hk4g4jp61p3"));
         assert!(pinin.contains("测试文本", "hkgjp1"));
         assert!(pinin.contains("錫", "vu6"));
-        // FIXME
-        // Bug still presents in PininSharp
-        //assert!(pinin.contains("鑽石", "yj0"));
-        //assert!(pinin.contains("物質", "j456"));
+        assert!(pinin.contains("鑽石", "yj0"));
+        assert!(pinin.contains("物質", "j456"));
         assert!(pinin.contains("腳手架", "rul3g.3ru84"));
         assert!(pinin.contains("鵝", "k6"));
         assert!(pinin.contains("葉", "u,4"));
@@ -100,26 +150,2750 @@ mod tests {
     }
 
     #[test]
-    pub fn format() {
+    fn chunked_provider() {
         let mut pinin = PinIn::new();
-        pinin.load_dict(Box::new(include_str!("dict.txt")));
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
 
-        let ch = pinin.chars[&'圆'].as_ref().unwrap();
-        let py = &ch.pinyin[0];
+        let chunks = vec!["", "测试", "", "文", "本"];
+        assert!(pinin.contains_provider(ChunkedProvider::new(chunks.clone()), "shiwenben"));
+        assert!(pinin.begins_provider(ChunkedProvider::new(chunks.clone()), "ceshiw"));
+        assert!(!pinin.begins_provider(ChunkedProvider::new(chunks), "shiwenben"));
+        assert!(!pinin.contains_provider(ChunkedProvider::new(Vec::<String>::new()), "a"));
+    }
 
-        assert_str_eq!(number_format(py), "yuan2");
-        assert_str_eq!(raw_format(py), "yuan");
-        assert_str_eq!(unicode_format(py), "yuán");
-        assert_str_eq!(phonetic_format(py), "ㄩㄢˊ");
+    #[test]
+    fn history() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut searcher = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        searcher.insert(&pinin, "合金炉", 1);
+        searcher.insert(&pinin, "洗矿场", 2);
+
+        let mut history = QueryHistory::new(2);
+        history.search(&searcher, &pinin, "hejin");
+        history.search(&searcher, &pinin, "hejl");
+        history.search(&searcher, &pinin, "hejl");
+        history.search(&searcher, &pinin, "hzz");
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.complete("he", 5), vec!["hejl", "hejin"]);
+
+        history.record("xkc");
+        assert_eq!(history.complete("he", 5), vec!["hejl"]);
+    }
+
+    #[test]
+    fn logic_parse() {
+        for logic in [SearcherLogic::Begin, SearcherLogic::Contain, SearcherLogic::Equal, SearcherLogic::EndsWith] {
+            assert_eq!(logic.to_string().parse::<SearcherLogic>(), Ok(logic));
+        }
+        assert_eq!("ends_with".parse::<SearcherLogic>(), Ok(SearcherLogic::EndsWith));
+        assert_eq!(" Contains".parse::<SearcherLogic>(), Ok(SearcherLogic::Contain));
+        assert!("prefix".parse::<SearcherLogic>().is_err());
+
+        #[cfg(feature = "serde")]
+        {
+            assert_str_eq!(serde_json::to_string(&SearcherLogic::Begin).unwrap(), "\"begin\"");
+            assert_eq!(serde_json::from_str::<SearcherLogic>("\"equal\"").unwrap(), SearcherLogic::Equal);
+        }
+    }
+
+    #[test]
+    fn lossy() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+
+        let mut target = "测试".as_bytes().to_vec();
+        target.push(0xff);
+        target.extend_from_slice("文本".as_bytes());
+        assert!(pinin.contains_lossy(&target, b"wenben"));
+        assert!(pinin.begins_lossy(&target, b"ceshi"));
+        assert!(!pinin.contains_lossy(&target, b"ceshiwenben"));
+        assert!(!pinin.contains_lossy(&[0xc3], b"a"));
+
+        let mut target: Vec<u16> = "测试".encode_utf16().collect();
+        target.push(0xd800);
+        assert!(pinin.contains_utf16_lossy(&target, &"shi".encode_utf16().collect::<Vec<_>>()));
+        assert!(!pinin.matches_utf16_lossy(&[0xdc00], &[0xd800, 0x61]));
+    }
+
+    #[cfg(feature = "lite")]
+    #[test]
+    fn lite() {
+        assert!(crate::lite::contains("测试文本", "shiwb"));
+        assert!(crate::lite::begins("测试文本", "ceshi"));
+        assert!(!crate::lite::begins("测试文本", "shi"));
+        assert!(crate::lite::with_context(|p| p.contains("合金炉", "hjl")));
+        let other = std::thread::spawn(|| crate::lite::context() as *const PinIn as usize).join().unwrap();
+        assert_eq!(other, crate::lite::context() as *const PinIn as usize);
+    }
+
+    #[test]
+    fn query() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut searcher = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        searcher.insert(&pinin, "合金炉", 1);
+        searcher.insert(&pinin, "洗矿场", 2);
+        searcher.insert(&pinin, "流体洗矿场", 3);
+        searcher.insert(&pinin, "流体", 4);
+
+        let q: Query = "hjl | xkc -liuti".parse().unwrap();
+        assert_eq!(q.evaluate(&pinin, &searcher), vec![&1, &2]);
+        let q: Query = "-(xkc | hjl)".parse().unwrap();
+        assert_eq!(q.evaluate(&pinin, &searcher), vec![&4]);
+        let q: Query = "liu & xkc".parse().unwrap();
+        assert_eq!(q.evaluate(&pinin, &searcher), vec![&3]);
+
+        assert_eq!("".parse::<Query>(), Err(ParseQueryError::Empty));
+        assert_eq!("(a | b".parse::<Query>(), Err(ParseQueryError::UnclosedParen(0)));
+        assert_eq!("a )".parse::<Query>(), Err(ParseQueryError::UnexpectedChar(2, ')')));
+    }
+
+    #[test]
+    fn natural_sort() {
+        let mut names = vec!["轰20", "轰6K", "轰100", "轰", "轰007", "轰7", "轰6"];
+        sort_natural_by_key(&mut names, |s| *s);
+        assert_eq!(names, vec!["轰", "轰6", "轰6K", "轰7", "轰007", "轰20", "轰100"]);
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut searcher = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        searcher.insert(&pinin, "轰20", 1);
+        searcher.insert(&pinin, "轰6K", 2);
+        searcher.insert(&pinin, "轰100", 3);
+        assert_eq!(searcher.search_sorted(&pinin, "hong"), vec![&2, &1, &3]);
+        assert_eq!(searcher.name(1).as_deref(), Some("轰6K"));
+    }
+
+    #[test]
+    fn scripts() {
+        assert!(is_han('测'));
+        assert!(is_han('〇'));
+        assert!(is_han('𠀀'));
+        assert!(!is_han('a'));
+        assert!(!is_han('ㄅ'));
+
+        let runs: Vec<_> = script_runs("ICBM必杀技 2号").collect();
+        assert_eq!(runs, vec![
+            (Script::Latin, "ICBM"),
+            (Script::Han, "必杀技"),
+            (Script::Whitespace, " "),
+            (Script::Digit, "2"),
+            (Script::Han, "号"),
+        ]);
+        assert_eq!(script_runs("").count(), 0);
+    }
+
+    #[test]
+    fn session() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+
+        let session = pinin.session();
+        assert!(session.contains("测试文本", "shiwb"));
+        assert!(session.contains("合金炉", "jinlu"));
+        assert!(!session.contains("合金炉", "shiwb"));
+        assert!(session.begins("测试文本", "ceshi"));
+        assert!(!session.begins("测试文本", "shi"));
+        assert!(session.matches("测试文本", "ceshiwenben"));
+        assert!(!session.matches("测试文本", "ceshi"));
+        assert!(session.matches("hong2", "hong2"));
+        assert!(session.ends("测试文本", "wenben"));
+        assert!(!session.ends("测试文本", "ceshi"));
+        assert_eq!(session.ends("合金炉", "jinl"), pinin.ends("合金炉", "jinl"));
+    }
+
+    #[test]
+    fn owned_keys() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut searcher = TreeSearcher::with_keys(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        searcher.insert_key(&pinin, String::from("合金炉"), 1);
+        searcher.insert_key(&pinin, "洗矿场", 2);
+        searcher.insert(&pinin, "流体", 3);
+
+        assert_eq!(searcher.search(&pinin, "hjl"), vec![&1]);
+        assert_eq!(searcher.key(2), Some("流体"));
+        assert_eq!(searcher.key(3), None);
+        let entries: Vec<_> = searcher.entries().collect();
+        assert_eq!(entries, vec![("合金炉", &1), ("洗矿场", &2), ("流体", &3)]);
+
+        let plain: TreeSearcher<i32> = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        assert_eq!(plain.entries().count(), 0);
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut searcher = SnapshotSearcher::new(SearcherLogic::Begin);
+        searcher.insert(&pinin, "合金炉", 1);
+        assert!(searcher.search(&pinin, "hjl").is_empty());
+
+        searcher.publish(&pinin);
+        assert_eq!(searcher.search(&pinin, "hjl"), vec![&1]);
+
+        let old = searcher.snapshot();
+        searcher.insert(&pinin, "合成", 2);
+        searcher.publish(&pinin);
+        assert_eq!(old.search(&pinin, "he").len(), 1);
+        assert_eq!(searcher.search(&pinin, "he").len(), 2);
+        drop(old);
+
+        searcher.insert(&pinin, "洗矿场", 3);
+        assert!(searcher.has_pending());
+        searcher.publish(&pinin);
+        assert_eq!(searcher.search(&pinin, "he").len(), 2);
+        assert_eq!(searcher.search(&pinin, "xkc"), vec![&3]);
+        searcher.insert(&pinin, "合金", 4);
+        searcher.publish(&pinin);
+        assert_eq!(searcher.search(&pinin, "hj").len(), 2);
+        // Keys and tokenizer carry over, also when a reader holds the front
+        let mut tree = TreeSearcher::with_keys(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        tree.set_tokenizer(crate::unicode_utils::word_starts);
+        let mut searcher = SnapshotSearcher::with_searcher(tree);
+        searcher.insert(&pinin, "ItemStack", 1);
+        searcher.publish(&pinin);
+        let old = searcher.snapshot();
+        searcher.insert(&pinin, "合金Block", 2);
+        searcher.publish(&pinin);
+        searcher.insert(&pinin, "流体", 3);
+        searcher.publish(&pinin);
+        drop(old);
+        let front = searcher.snapshot();
+        assert_eq!((0..3).map(|i| front.key(i)).collect::<Vec<_>>(), [Some("ItemStack"), Some("合金Block"), Some("流体")]);
+        assert_eq!(searcher.search(&pinin, "Stack"), vec![&1]);
+        assert_eq!(searcher.search(&pinin, "Block"), vec![&2]);
+    }
+
+    #[test]
+    fn merge() {
+        use crate::sync::lock;
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut a = TreeSearcher::with_keys(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        let mut b = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        a.insert(&pinin, "合金炉", 1);
+        b.insert(&pinin, "洗矿场", 2);
+        b.insert(&pinin, "流体", 3);
+
+        a.merge(&pinin, b);
+        assert_eq!(a.len(), 3);
+        assert_eq!(a.search(&pinin, "kc"), vec![&2]);
+        assert_eq!(a.search(&pinin, "jl"), vec![&1]);
+        assert_eq!(a.key(2), Some("流体"));
+
+        // Grafting gives the same results as inserting, through every kind
+        // of node and with renamed entries on both sides
+        let chars: Vec<char> = "合金炉洗矿场流体测试文本中国重庆火锅要通知西安先在现行走银天大小上下左右东南北".chars().collect();
+        let name = |i: usize| -> String { [i % 37, i / 37 % 13, i / 481 % 29].iter().map(|x| chars[x % chars.len()]).collect() };
+        for logic in [SearcherLogic::Begin, SearcherLogic::Contain, SearcherLogic::Equal] {
+            for (left, right) in [(0, 600), (600, 0), (1500, 1500), (40, 3000)] {
+                let mut all = TreeSearcher::with_keys(logic, Arc::new(Accelerator::new()));
+                let mut a = TreeSearcher::with_keys(logic, Arc::new(Accelerator::new()));
+                let mut b = TreeSearcher::new(logic, Arc::new(Accelerator::new()));
+                for i in 0..left + right {
+                    let searcher = if i < left { &mut a } else { &mut b };
+                    searcher.insert(&pinin, &name(i), i);
+                    all.insert(&pinin, &name(i), i);
+                }
+                for (searcher, index, i) in [(&mut a, 3, 3), (&mut b, 5, left + 5)] {
+                    if searcher.len() > index {
+                        searcher.rename(&pinin, index, "改名");
+                        all.rename(&pinin, i, "改名");
+                    }
+                }
+                // Held elsewhere, as by a snapshot, which must not keep them
+                // registered once the merge drops them from the tree
+                let held = lock(&a.naccs).clone();
+                a.merge(&pinin, b);
+                let mut reachable = Vec::new();
+                a.root.clone().accs(&mut reachable);
+                let registered = lock(&a.naccs);
+                assert!(registered.len() == reachable.len() && registered.iter().all(|x| reachable.iter().any(|y| Arc::ptr_eq(x, y))));
+                drop((registered, held));
+                assert_eq!(a.len(), all.len());
+                for query in ["h", "he", "hejin", "jl", "cs", "gaim", "中", "xian", "lu", "zhongguoxi"] {
+                    assert_eq!(a.search_indices(&pinin, query), all.search_indices(&pinin, query), "{:?} {} {} {}", logic, left, right, query);
+                }
+                assert!((0..a.len()).all(|i| a.key(i) == all.key(i) && a.name(i) == all.name(i)));
+            }
+        }
+    }
+
+    #[test]
+    fn daqian_any_tone() {
+        let mut pinin = PinIn::new();
+        pinin.keyboard = &KEYBOARD_DAQIAN;
+        pinin.fuzzy.any_tone = true;
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+
+        assert!(pinin.contains("测试文本", "hk4g4jp61p3"));
+        assert!(pinin.contains("测试文本", "hk3g6jp1p"));
+        assert!(pinin.contains("测试文本", "hkgjp1p"));
+        assert!(pinin.contains("共同", "ej/wj/"));
+        assert!(pinin.contains("共同", "ej/3wj/4"));
+        assert!(!pinin.contains("测试文本", "hkk"));
+
+        let mut pinin = PinIn::new();
+        pinin.fuzzy.any_tone = true;
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        assert!(pinin.contains("测试文本", "ce2shi1wb"));
+    }
+
+    #[test]
+    fn neutral_tone() {
+        use crate::pinin::NeutralTone;
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        for query in ["deren", "de0ren", "de0", "de", "dr"] {
+            assert!(pinin.contains("的人", query), "{}", query);
+        }
+
+        pinin.fuzzy.neutral_tone = NeutralTone::Explicit;
+        pinin.commit();
+        for query in ["de0ren", "de0", "de", "dr", "d"] {
+            assert!(pinin.contains("的人", query), "{}", query);
+        }
+        assert!(!pinin.contains("的人", "deren"));
+        assert!(pinin.contains("中国", "zhongguo"));
+
+        pinin.fuzzy.neutral_tone = NeutralTone::Toneless;
+        pinin.commit();
+        for query in ["deren", "de", "dr"] {
+            assert!(pinin.contains("的人", query), "{}", query);
+        }
+        assert!(!pinin.contains("的人", "de0ren"));
+        assert!(!pinin.contains("的", "de0"));
+
+        let mut pinin = PinIn::new();
+        pinin.keyboard = &KEYBOARD_XIAOHE;
+        pinin.fuzzy.neutral_tone = NeutralTone::Explicit;
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        assert!(pinin.contains("的人", "de0rf"));
+        assert!(!pinin.contains("的人", "derf"));
+    }
+
+    #[test]
+    fn zero_initial() {
+        let keys = Box::leak(Box::new(std::collections::HashMap::from([("ang", "h"), ("an", "j"), ("ou", "b")])));
+        let keyboard = Box::leak(Box::new(Keyboard::new(None, Some(keys), ZeroInitial::Fixed("o"), true, false)));
+        let mut pinin = PinIn::new();
+        pinin.keyboard = keyboard;
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+
+        assert!(pinin.contains("昂扬", "oh"));
+        assert!(pinin.contains("安全", "oj"));
+        assert!(pinin.contains("欧", "ob"));
+        assert!(!pinin.contains("安全", "aj"));
+
+        assert_eq!(KEYBOARD_XIAOHE.zero_initial(), ZeroInitial::FirstLetter);
+        let mut pinin = PinIn::new();
+        pinin.keyboard = &KEYBOARD_XIAOHE;
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        assert!(pinin.contains("昂扬", "ah"));
+        assert!(pinin.contains("安全", "an"));
+    }
+
+    #[test]
+    fn keyboard_mappings() {
+        use crate::keyboard::KEYBOARD_QUANPIN;
+
+        assert_eq!(KEYBOARD_XIAOHE.map_syllable("zhong"), ["v", "s"]);
+        assert_eq!(KEYBOARD_XIAOHE.map_syllable("zhong1"), ["v", "s", "1"]);
+        assert_eq!(KEYBOARD_XIAOHE.map_syllable("an"), ["a", "n"]);
+        assert_eq!(KEYBOARD_XIAOHE.map_syllable("a"), ["a", "a"]);
+        assert_eq!(KEYBOARD_ZIRANMA.map_syllable("shuang"), ["u", "d"]);
+        assert_eq!(KEYBOARD_DAQIAN.map_syllable("zhong1"), ["5", "j/", " "]);
+        assert_eq!(KEYBOARD_QUANPIN.map_syllable("zhong"), ["zh", "ong"]);
+
+        let mappings: Vec<_> = KEYBOARD_XIAOHE.iter_mappings().collect();
+        assert!(mappings.windows(2).all(|x| x[0] <= x[1]));
+        assert!(mappings.contains(&("zh", "v")));
+        assert_eq!(KEYBOARD_QUANPIN.iter_mappings().count(), 0);
+        assert!(KEYBOARD_DAQIAN.iter_local().any(|x| x == ("yi", "i")));
+        assert_eq!(KEYBOARD_XIAOHE.iter_local().count(), 0);
+
+        let keys = Box::leak(Box::new(std::collections::HashMap::from([("ang", "h")])));
+        let keyboard = Keyboard::new(None, Some(keys), ZeroInitial::Fixed("o"), true, false);
+        assert_eq!(keyboard.map_syllable("ang2"), ["o", "h", "2"]);
+    }
+
+    #[test]
+    fn extra_keyboards() {
+        let mut pinin = PinIn::new();
+        pinin.extra_keyboards = vec![&*KEYBOARD_XIAOHE];
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        assert!(pinin.contains("中国", "zhongguo"));
+        assert!(pinin.contains("中国", "vsgo"));
+        assert!(pinin.contains("中国", "zhonggo"));
+        assert!(!pinin.contains("中国", "vsgv"));
+
+        let mut searcher = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        searcher.insert(&pinin, "中国", 1);
+        searcher.insert(&pinin, "合金炉", 2);
+        assert_eq!(searcher.search(&pinin, "vsgo"), vec![&1]);
+        assert_eq!(searcher.search(&pinin, "hejb"), vec![&2]);
+
+        let hydrated = PinIn::from_bytes(&pinin.to_bytes().unwrap()).unwrap();
+        assert!(std::ptr::eq(hydrated.extra_keyboards[0], &*KEYBOARD_XIAOHE));
+        assert!(hydrated.contains("中国", "vsguo"));
+
+        pinin.extra_keyboards.clear();
+        pinin.commit();
+        assert!(!pinin.contains("中国", "vsgo"));
+        assert!(searcher.search(&pinin, "vsgo").is_empty());
+    }
+
+    #[test]
+    fn segmented_match() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+
+        let ch = pinin.get_character('试');
+        let query = "ce\u{301}试";
+        let segmented: SegmentedStr = query.into();
+        assert_eq!(ch.match_segmented(&segmented, 2, true), IndexSet::one());
+        assert_eq!(ch.match_str(query, 2, true), IndexSet::one());
+        assert!(pinin.contains("测试", "试"));
+    }
+
+    #[test]
+    fn match_out_of_range() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+
+        let py = &pinin.get_character('测').pinyin[0];
+        assert!(py.sequence);
+        assert_eq!(py.match_string("", 0, true), IndexSet::none());
+        assert_eq!(py.match_string("c", 1, true), IndexSet::none());
+        assert_eq!(py.match_string("c", 5, false), IndexSet::none());
+        assert_eq!(py.phonemes[0].match_string("c", 3, true), IndexSet::none());
+        assert_eq!(pinin.get_character('测').match_str("c", 2, true), IndexSet::none());
+        assert!(pinin.begins("测", "c"));
+        assert!(pinin.begins("测", ""));
+    }
+
+    #[test]
+    fn sandhi() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let reading = |c: char, raw: &str| {
+            let ch = pinin.get_character(c).into_owned();
+            (c, ch.pinyin.iter().find(|p| p.raw == raw).unwrap().clone())
+        };
+        let render = |word: &[(char, Arc<Pinyin>)]| {
+            let word: Vec<_> = word.iter().map(|(c, p)| (*c, p.as_ref())).collect();
+            format_word(&word, number_format, true).join(" ")
+        };
+
+        assert_str_eq!(render(&[reading('你', "ni3"), reading('好', "hao3")]), "ni2 hao3");
+        assert_str_eq!(render(&[reading('展', "zhan3"), reading('览', "lan3"), reading('馆', "guan3")]), "zhan2 lan2 guan3");
+        assert_str_eq!(render(&[reading('不', "bu4"), reading('要', "yao4")]), "bu2 yao4");
+        assert_str_eq!(render(&[reading('不', "bu4"), reading('好', "hao3")]), "bu4 hao3");
+        assert_str_eq!(render(&[reading('一', "yi1"), reading('定', "ding4")]), "yi2 ding4");
+        assert_str_eq!(render(&[reading('一', "yi1"), reading('天', "tian1")]), "yi4 tian1");
+        assert_str_eq!(render(&[reading('看', "kan4"), reading('一', "yi1"), reading('看', "kan4")]), "kan4 yi0 kan4");
+        assert_str_eq!(render(&[reading('第', "di4"), reading('一', "yi1")]), "di4 yi1");
+
+        let (c, py) = reading('你', "ni3");
+        assert_eq!(format_word(&[(c, &py)], unicode_format, false), vec!["nǐ"]);
+    }
+
+    #[test]
+    fn pinyin_parts() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let parts = |c: char| {
+            let py = pinin.get_character(c).pinyin[0].clone();
+            (py.syllable().to_string(), py.tone(), py.initial().to_string(), py.final_().to_string())
+        };
+
+        assert_eq!(parts('中'), ("zhong".into(), 1, "zh".into(), "ong".into()));
+        assert_eq!(parts('安'), ("an".into(), 1, "".into(), "an".into()));
+        assert_eq!(parts('圆'), ("yuan".into(), 2, "y".into(), "uan".into()));
+        assert_eq!(parts('㟷'), ("da".into(), 0, "d".into(), "a".into()));
+    }
+
+    #[test]
+    fn target_cache() {
+        let mut dict = UserDict::new();
+        dict.add('测', "ke4");
+        let mut cached = PinIn::new();
+        cached.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        cached.target_cache_capacity = 2;
+        let mut plain = PinIn::new();
+        plain.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        plain.target_cache_capacity = 0;
+
+        let targets = ["测试文本", "合金炉", "轰20", "", "洗矿场"];
+        for query in ["ceshi", "hejin", "hong2", "", "kc", "ce4a"] {
+            for target in targets {
+                assert_eq!(cached.contains(target, query), plain.contains(target, query), "{} {}", target, query);
+                assert_eq!(cached.begins(target, query), plain.begins(target, query), "{} {}", target, query);
+                assert_eq!(cached.matches(target, query), plain.matches(target, query), "{} {}", target, query);
+            }
+        }
+
+        assert!(cached.contains("测试", "ceshi"));
+        cached.load_user_dict(&dict);
+        assert!(cached.contains("测试", "keshi"));
+        assert_eq!(PinIn::new().target_cache_capacity, 0);
+
+        let mut lru = crate::cache::Lru::default();
+        lru.insert("a", 1, 2);
+        lru.insert("b", 2, 2);
+        assert_eq!(lru.get("a"), Some(1));
+        lru.insert("c", 3, 2);
+        assert_eq!((lru.get("a"), lru.get("b"), lru.get("c")), (Some(1), None, Some(3)));
+    }
+
+    #[test]
+    fn acceleration_is_cache_only() {
+        let mut accelerated = PinIn::new();
+        accelerated.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        assert!(accelerated.accelerate);
+        let mut plain = PinIn::new();
+        plain.accelerate = false;
+        plain.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+
+        let targets = ["测试文本", "  ", "", "hong2", "轰20", "昂扬"];
+        let queries = ["", " ", "ceshi", "shiwb", "ceshiwenben", "hong2", "ayang", "anyang", "ce4a"];
+        for target in targets {
+            for query in queries {
+                // Twice, so that the plain context also takes its repeated-query path
+                for _ in 0..2 {
+                    assert_eq!(accelerated.contains(target, query), plain.contains(target, query), "{} {}", target, query);
+                    assert_eq!(accelerated.begins(target, query), plain.begins(target, query), "{} {}", target, query);
+                    assert_eq!(accelerated.matches(target, query), plain.matches(target, query), "{} {}", target, query);
+                }
+            }
+        }
+
+        assert!(plain.matches("测试文本", "ceshiwenben"));
+        assert!(plain.matches("测试文本", "cswb"));
+        assert!(!plain.matches("测试文本", "ceshi"));
+        assert!(plain.contains("", ""));
+        assert!(!plain.contains("", "a"));
+    }
+
+    #[test]
+    fn search_many() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        let mut simple = SimpleSearcher::new(SearcherLogic::Begin);
+        let searchers: [&mut dyn Searcher<usize>; 2] = [&mut tree, &mut simple];
+        for searcher in searchers {
+            searcher.insert(&pinin, "合金炉", 1);
+            searcher.insert(&pinin, "洗矿场", 2);
+            searcher.insert(&pinin, "合成", 3);
+
+            let ret = searcher.search_many(&pinin, &["xkc", "hj", "", "xkc", "zz"]);
+            assert_eq!(ret.len(), 5);
+            assert_eq!(ret[0], vec![&2]);
+            assert_eq!(ret[1], vec![&1]);
+            assert_eq!(ret[2].len(), 3);
+            assert_eq!(ret[3], vec![&2]);
+            assert!(ret[4].is_empty());
+        }
+
+        #[cfg(feature = "rayon")]
+        {
+            let queries = ["xkc", "hj", "", "xkc", "zz", "he"];
+            assert_eq!(tree.search_many_parallel(&pinin, &queries), tree.search_many(&pinin, &queries));
+        }
+    }
+
+    #[test]
+    fn suggest() {
+        use crate::suggest::suggest;
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut searcher = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        searcher.insert(&pinin, "合金炉", 1);
+        searcher.insert(&pinin, "合金块", 2);
+        searcher.insert(&pinin, "洗矿场", 3);
+
+        // A neighbouring key, a swap and a stray key
+        for typo in ["hejinly", "hejniku", "xikuangcxhang"] {
+            assert!(searcher.search(&pinin, typo).is_empty());
+        }
+        let ret = suggest(&searcher, &pinin, "hejinly", 3);
+        assert_eq!(ret[0].query, "hejinlu");
+        assert_eq!(ret[0].count, 1);
+        assert!(suggest(&searcher, &pinin, "hejniku", 5).iter().any(|x| x.query == "hejinku" && x.count == 1));
+        assert!(suggest(&searcher, &pinin, "xikuangcxhang", 5).iter().any(|x| x.query == "xikuangchang"));
+        assert!(suggest(&searcher, &pinin, "zzzzzz", 5).is_empty());
+        assert!(suggest(&searcher, &pinin, "", 5).is_empty());
+    }
+
+    #[test]
+    fn phoneme_memory() {
+        use crate::elements::Phoneme;
+        use crate::keyboard::KEYBOARD_QUANPIN;
+        use crate::pinin::FuzzySettings;
+
+        // Phonemes with more than one key, and their keys
+        let keyboards: [(&'static Keyboard, _); 3] =
+            [(&KEYBOARD_QUANPIN, (843, 1686)), (&KEYBOARD_DAQIAN, (883, 1781)), (&KEYBOARD_XIAOHE, (830, 1660))];
+        for (keyboard, expected) in keyboards {
+            let mut pinin = PinIn::new();
+            pinin.keyboard = keyboard;
+            pinin.fuzzy = FuzzySettings {
+                zh2z: true,
+                sh2s: true,
+                ch2c: true,
+                ang2an: true,
+                ing2in: true,
+                eng2en: true,
+                u2v: true,
+                ..Default::default()
+            };
+            pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+            // Variants are kept to the exact length, in a phoneme no wider
+            // than a single key
+            assert_eq!(size_of::<Phoneme>(), size_of::<compact_str::CompactString>());
+            let (mut multiple, mut variants) = (0, 0);
+            for phoneme in pinin.pinyins.values().flat_map(|p| p.phonemes.iter()) {
+                match phoneme {
+                    Phoneme::Single(x) => assert!(!x.is_heap_allocated()),
+                    Phoneme::Multiple(xs) => {
+                        assert!(xs.iter().all(|x| !x.is_heap_allocated()));
+                        assert!(xs.windows(2).all(|x| x[0] < x[1]), "{:?}", xs);
+                        multiple += 1;
+                        variants += xs.len();
+                    }
+                }
+            }
+            assert_eq!((multiple, variants), expected);
+        }
+    }
+
+    #[test]
+    fn user_dict() {
+        let mut dict: UserDict = "# fixes\n\n行: hang2\n".parse().unwrap();
+        assert!(dict.add('行', "xing2"));
+        assert!(!dict.add('行', "xing2"));
+        assert!(!dict.add('行', "Xing"));
+        assert!(dict.add('囧', "jiong3"));
+        assert!(dict.remove('囧', "jiong3"));
+        assert!(dict.get('囧').is_none());
+        assert!(matches!("行 hang2".parse::<UserDict>(), Err(UserDictError::Syntax(1))));
+        assert!(matches!("\n行: hang".parse::<UserDict>(), Err(UserDictError::InvalidReading(2, _))));
+
+        let path = std::env::temp_dir().join(format!("pinin-user-dict-{}.txt", std::process::id()));
+        dict.save(&path).unwrap();
+        let loaded = UserDict::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, dict);
+        assert_eq!(loaded.get('行').unwrap(), ["hang2", "xing2"]);
+
+        let mut dict = UserDict::new();
+        dict.add('测', "ke4");
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        assert!(pinin.contains("测试", "ceshi"));
+        pinin.load_user_dict(&dict);
+        assert!(pinin.contains("测试", "keshi"));
+        assert!(!pinin.contains("测试", "ceshi"));
+
+        let mut tree = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        tree.insert(&pinin, "测试", 0);
+        tree.insert(&pinin, "\u{2FFF}", 1);
+        assert_eq!(tree.search(&pinin, "keshi"), vec![&0]);
+        let mut more = UserDict::new();
+        more.add('测', "ze4");
+        more.add('\u{2FFF}', "a1");
+        pinin.load_user_dict(&more);
+        assert_eq!(tree.search(&pinin, "a"), vec![&1]);
+        pinin.unload_user_dict();
+        assert!(tree.search(&pinin, "keshi").is_empty() && tree.search(&pinin, "a").is_empty());
+        assert_eq!(tree.search(&pinin, "ceshi"), vec![&0]);
+        assert!(pinin.contains("测试", "ceshi"));
+    }
+
+    #[test]
+    fn dict_overlay() {
+        use crate::pinin::Overlay;
+
+        let raws = |pinin: &PinIn, c: char| -> Vec<String> {
+            pinin.get_character(c).pinyin.iter().map(|p| p.raw.to_string()).collect()
+        };
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new("行: xing2, hang2\n测: ce4")).unwrap();
+        pinin.load_dict_overlay(Box::new("行: heng2, hang2\n㐀: qiu1"), Overlay::Append).unwrap();
+        assert_eq!(raws(&pinin, '行'), ["xing2", "hang2", "heng2"]);
+        assert_eq!(raws(&pinin, '㐀'), ["qiu1"]);
+        assert_eq!(raws(&pinin, '测'), ["ce4"]);
+
+        pinin.load_dict_overlay(Box::new("行: hang2\n测: ke4"), Overlay::Prepend).unwrap();
+        assert_eq!(raws(&pinin, '行'), ["hang2", "xing2", "heng2"]);
+        assert_eq!(raws(&pinin, '测'), ["ke4", "ce4"]);
+        assert!(pinin.contains("测", "ce") && pinin.contains("测", "ke"));
+
+        let version = pinin.version();
+        pinin.load_dict_overlay(Box::new("行: xing2"), Overlay::Replace).unwrap();
+        assert_eq!(raws(&pinin, '行'), ["xing2"]);
+        assert!(pinin.version() > version);
+
+        let dict: UserDict = "测: ze4".parse().unwrap();
+        pinin.load_dict_overlay(Box::new(&dict), Overlay::Append).unwrap();
+        assert_eq!(raws(&pinin, '测'), ["ke4", "ce4", "ze4"]);
+        assert!(pinin.load_dict_overlay(Box::new("行 xing2"), Overlay::Append).is_err());
+        assert_eq!(raws(&pinin, '行'), ["xing2"]);
+    }
+
+    #[test]
+    fn reload_dict() {
+        let mut pinin = PinIn::new();
+        pinin.romanized_targets = true;
+        pinin.load_dict(Box::new("测: ze4\n试: shi4")).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        for (i, name) in ["测试", "ze4shi4"].into_iter().enumerate() {
+            tree.insert(&pinin, name, i);
+        }
+        assert_eq!(tree.search(&pinin, "zeshi"), vec![&0, &1]);
+        let session = pinin.session();
+        assert!(session.contains("测试", "zeshi"));
+        drop(session);
+
+        assert!(pinin.reload_dict(Box::new("测 ce4")).is_err());
+        assert!(pinin.contains("测试", "zeshi"));
+        let version = pinin.version();
+        pinin.reload_dict(Box::new("测: ce4\n试: shi4")).unwrap();
+        assert!(pinin.version() > version);
+        assert_eq!(tree.search(&pinin, "zeshi"), vec![&1]);
+        assert_eq!(tree.search(&pinin, "ceshi"), vec![&0]);
+        assert!(pinin.session().contains("测试", "ceshi"));
+        assert!(pinin.contains("ce4shi4", "ceshi"));
+
+        pinin.unload_dict();
+        assert!(!pinin.contains("测试", "ceshi"));
+        assert!(pinin.contains("测试", "测试"));
+        assert_eq!(tree.search(&pinin, "ceshi"), Vec::<&usize>::new());
+        assert_eq!(tree.search(&pinin, "zeshi"), vec![&1]);
+    }
+
+    #[test]
+    fn owned_readings() {
+        struct App {
+            pinin: PinIn,
+        }
+
+        fn load(source: String) -> App {
+            let mut pinin = PinIn::new();
+            pinin.load_dict(Box::new(source.as_str())).unwrap();
+            pinin.load_user_dict(&"行: hang2".parse().unwrap());
+            App { pinin }
+        }
+
+        let app = load(String::from("测: ce4\n试: shi4\n"));
+        assert!(app.pinin.contains("测试", "ceshi"));
+        assert!(app.pinin.contains("行", "hang"));
+        assert!(app.pinin.contains("测试", "ce4shi4"));
+    }
+
+    #[test]
+    fn canonical_entries() {
+        assert_eq!(canonicalize("a b"), Cow::Borrowed("a b"));
+        assert_eq!(canonicalize(" a \t\n b\u{7}c\r\n"), "a bc");
+        assert_eq!(canonicalize("\n"), "");
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        let mut simple = SimpleSearcher::new(SearcherLogic::Contain);
+        for (i, name) in ["合金  炉\n", "洗矿\t场"].into_iter().enumerate() {
+            tree.insert(&pinin, name, i);
+            simple.insert(&pinin, name, i);
+        }
+
+        for query in ["hejin lu", "hejin   lu", "jin l", "kuang chang", "kuangchang"] {
+            assert_eq!(tree.search(&pinin, query), simple.search(&pinin, query), "{}", query);
+        }
+        assert_eq!(tree.search(&pinin, "jin  l"), vec![&0]);
+        assert_eq!(tree.name(0).unwrap(), "合金  炉\n");
+        assert_eq!(tree.compressor.read().unwrap().entry(0).unwrap(), "合金 炉");
+    }
+
+    #[test]
+    fn pinyin_map() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut map = PinyinMap::new(SearcherLogic::Contain);
+        assert_eq!(map.insert(&pinin, "合金炉", 1), None);
+        assert_eq!(map.insert(&pinin, "洗矿场", 2), None);
+        assert_eq!(map.insert(&pinin, "合成", 3), None);
+        assert_eq!(map.insert(&pinin, "合金炉", 4), Some(1));
+        assert_eq!(map.len(), 3);
+
+        assert_eq!(map.get_fuzzy(&pinin, "he"), vec![&4, &3]);
+        assert_eq!(map.get("合成"), Some(&3));
+        assert_eq!(map.remove(&pinin, "合金炉"), Some(4));
+        assert_eq!(map.remove(&pinin, "合金炉"), None);
+        assert_eq!(map.get_fuzzy(&pinin, "he"), vec![&3]);
+
+        // Rebuilds once most slots are holes
+        assert_eq!(map.remove(&pinin, "合成"), Some(3));
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.get_fuzzy(&pinin, "kc"), vec![&2]);
+        assert!(map.get_fuzzy(&pinin, "he").is_empty());
+        assert_eq!(map.iter().count(), 1);
+    }
+
+    #[cfg(feature = "files")]
+    #[test]
+    fn file_index() {
+        use crate::files::{FileIndex, Refresh};
+        use std::fs;
+
+        let root = std::env::temp_dir().join(format!("pinin-files-{}", std::process::id()));
+        fs::create_dir_all(root.join("资料")).unwrap();
+        fs::write(root.join("资料").join("合金炉.txt"), "").unwrap();
+        fs::write(root.join("洗矿场.md"), "").unwrap();
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut index = FileIndex::new(&root, SearcherLogic::Begin);
+        assert_eq!(index.refresh(&pinin).unwrap(), Refresh { added: 3, removed: 0 });
+        assert_eq!(index.search(&pinin, "hjl"), vec![root.join("资料").join("合金炉.txt")]);
+        assert_eq!(index.search(&pinin, "zl"), vec![root.join("资料")]);
+
+        fs::remove_file(root.join("洗矿场.md")).unwrap();
+        fs::write(root.join("流体.md"), "").unwrap();
+        assert_eq!(index.refresh(&pinin).unwrap(), Refresh { added: 1, removed: 1 });
+        assert!(index.search(&pinin, "xkc").is_empty());
+        assert_eq!(index.search(&pinin, "liuti"), vec![root.join("流体.md")]);
+        assert_eq!(index.len(), 3);
+
+        fs::remove_dir_all(&root).unwrap();
+        assert!(index.refresh(&pinin).is_err());
+    }
+
+    #[test]
+    fn match_alternatives() {
+        use crate::pinin::AlignStep;
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+
+        let step = |target: std::ops::Range<usize>, query: std::ops::Range<usize>, reading: Option<&str>| AlignStep {
+            target,
+            query,
+            reading: reading.map(Into::into),
+        };
+        let ret = pinin.match_alternatives("合金炉", "hj");
+        assert_eq!(ret.len(), 1);
+        assert_eq!(ret[0].steps, vec![step(0..3, 0..1, Some("he2")), step(3..6, 1..2, Some("jin1"))]);
+
+        let ret = pinin.match_alternatives("中国", "zhongguo");
+        let readings: Vec<_> = ret.iter().map(|x| x.steps[0].reading.as_deref().unwrap()).collect();
+        assert_eq!(readings, vec!["zhong1", "zhong4"]);
+        assert!(ret.iter().all(|x| x.steps[0].query == (0..5) && x.steps[1].query == (5..8)));
+
+        assert_eq!(pinin.match_alternatives("合金炉", "金").len(), 1);
+        assert_eq!(pinin.match_alternatives("合金炉", "金")[0].steps, vec![step(3..6, 0..3, None)]);
+        assert_eq!(pinin.match_alternatives("行行", "hang").len(), 4);
+        assert!(pinin.match_alternatives("合金炉", "xkc").is_empty());
+        assert_eq!(pinin.match_alternatives("合金炉", "").len(), 1);
+        for (s1, s2) in [("测试文本", "ceshiwb"), ("洗矿场", "kuangc"), ("合金炉", "hejinlu")] {
+            assert!(!pinin.match_alternatives(s1, s2).is_empty());
+            assert!(pinin.contains(s1, s2));
+        }
+    }
+
+    #[test]
+    fn compiled_dict() {
+        use crate::dict_loader::{CompiledDict, DictCompiler, DictFormatError, DictLoader};
+
+        let text = include_str!("dict.txt");
+        let bytes = DictCompiler::new().add(&text).unwrap().add(&"中: zhong1").unwrap().compile();
+        assert!(bytes.len() < text.len());
+        let dict = CompiledDict::new(&bytes).unwrap();
+
+        let mut a = PinIn::new();
+        a.load_dict(Box::new(text)).unwrap();
+        a.load_dict(Box::new("中: zhong1")).unwrap();
+        let mut b = PinIn::new();
+        b.load_dict(Box::new(dict)).unwrap();
+        assert_eq!(a.to_bytes().unwrap(), b.to_bytes().unwrap());
+        assert!(b.contains("测试文本", "ceshiwenben"));
+
+        assert_eq!(CompiledDict::new(b"PINDICT").unwrap_err(), DictFormatError::Magic);
+        assert_eq!(CompiledDict::new(&bytes[..bytes.len() - 1]).unwrap_err(), DictFormatError::Corrupt);
+        let mut newer = bytes.clone();
+        newer[8] = 2;
+        assert_eq!(CompiledDict::new(&newer).unwrap_err(), DictFormatError::Version(2));
+        assert!(CompiledDict::new(&DictCompiler::new().compile()).unwrap().load_dict().unwrap().is_empty());
+    }
+
+    #[test]
+    fn lazy_dict() {
+        use crate::dict_loader::DictCompiler;
+
+        let text = include_str!("dict.txt");
+        let bytes = DictCompiler::new().add(&text).unwrap().compile();
+        let mut eager = PinIn::new();
+        eager.load_dict(Box::new(text)).unwrap();
+        let mut lazy = PinIn::new();
+        lazy.load_dict_lazy(bytes.clone()).unwrap();
+        assert_eq!(lazy.lazy.as_ref().map(|x| x.decoded()), Some(0));
+        for (s1, s2) in [("测试文本", "ceshiwenben"), ("测试文本", "csw"), ("合金炉", "hjl"), ("中国", "zg2")] {
+            assert_eq!(lazy.contains(s1, s2), eager.contains(s1, s2), "{} {}", s1, s2);
+        }
+        assert!(lazy.lazy.as_ref().unwrap().decoded() <= 9);
+        assert_eq!(lazy.get_character('中'), eager.get_character('中'));
+        assert_eq!(lazy.to_pinyin("银行").len(), 2);
+        assert_eq!(lazy.to_bytes().unwrap(), eager.to_bytes().unwrap());
+
+        lazy.load_dict(Box::new("中: zhong4")).unwrap();
+        assert!(!lazy.contains("中", "zhong1"));
+        lazy.fuzzy.zh2z = true;
+        lazy.commit();
+        assert!(lazy.contains("住", "zu"));
+        assert!(lazy.load_dict_lazy(bytes[..10].to_vec()).is_err());
+        assert!(lazy.contains("国", "guo"));
+        lazy.unload_dict();
+        assert!(!lazy.contains("国", "guo"));
+    }
+
+    #[test]
+    fn config() {
+        use crate::config::{Config, ConfigError};
+
+        let config: Config = "# tool defaults\nkeyboard = XiaoHe\nfuzzy = sh2s, any_tone\n".parse().unwrap();
+        assert_eq!(config.fuzzy, ["sh2s", "any_tone"]);
+        #[cfg(feature = "embedded-dict")]
+        {
+            let pinin = config.build().unwrap();
+            assert!(pinin.contains("测试文本", "ceuiwfbf"));
+            assert!(pinin.contains("测试文本", "cesiwfbf"));
+        }
+        #[cfg(not(feature = "embedded-dict"))]
+        assert!(matches!(config.build(), Err(ConfigError::NoDict)));
+
+        let dict = std::env::temp_dir().join(format!("pinin-config-{}.txt", std::process::id()));
+        std::fs::write(&dict, "测: ce4\n试: shi4\n").unwrap();
+        let config = Config { dict: Some(dict.clone()), ..Default::default() };
+        let pinin = config.build().unwrap();
+        std::fs::remove_file(&dict).unwrap();
+        assert!(pinin.contains("测试", "ceshi"));
+        assert!(!pinin.contains("文本", "wenben"));
+
+        assert!(matches!("keyboard xiaohe".parse::<Config>(), Err(ConfigError::Syntax(1))));
+        assert!(matches!("\nlayout = xiaohe".parse::<Config>(), Err(ConfigError::UnknownKey(2, _))));
+        let config = Config { keyboard: Some("dvorak".into()), ..Default::default() };
+        assert!(matches!(config.build(), Err(ConfigError::UnknownKeyboard(_))));
+        let config = Config { fuzzy: vec!["zh2j".into()], ..Default::default() };
+        assert!(matches!(config.build(), Err(ConfigError::UnknownFuzzy(_))));
+    }
+
+    #[test]
+    fn dict_diff() {
+        use crate::dict_loader::{diff, CharDiff};
+
+        let old = "行: xing2, hang2\n中: zhong1\n测: ce4\n";
+        let new = "行: hang2, xing2\n中: zhong1, zhong4\n好: hao3\n";
+        let ret = diff(&old, &new).unwrap();
+        assert_eq!(ret, vec![
+            CharDiff { ch: '中', added: vec!["zhong4".into()], removed: vec![], reordered: false },
+            CharDiff { ch: '好', added: vec!["hao3".into()], removed: vec![], reordered: false },
+            CharDiff { ch: '测', added: vec![], removed: vec!["ce4".into()], reordered: false },
+            CharDiff { ch: '行', added: vec![], removed: vec![], reordered: true },
+        ]);
+        assert!(diff(&include_str!("dict.txt"), &include_str!("dict.txt")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn dict_errors() {
+        use crate::dict_loader::DictError;
+
+        let syntax = |ret: Result<(), DictError>| match ret {
+            Err(DictError::Syntax { line, column, expected }) => Some((line, column, expected)),
+            _ => None,
+        };
+        let mut pinin = PinIn::new();
+        let version = pinin.version();
+        assert_eq!(syntax(pinin.load_dict(Box::new("中: zhong1\n\n测"))), Some((3, 2, "`: ` after the char")));
+        assert_eq!(syntax(pinin.load_dict(Box::new("中:zhong1"))), Some((1, 2, "`: ` after the char")));
+        assert_eq!(syntax(pinin.load_dict(Box::new("中: "))), Some((1, 4, "a reading")));
+        assert_eq!(syntax(pinin.load_dict(Box::new("中: zhong1, "))), Some((1, 12, "a reading")));
+        assert_eq!(syntax(pinin.load_dict(Box::new("中: zhong1,zhong4"))), Some((1, 4, "a reading")));
+        assert_eq!(
+            pinin.load_dict(Box::new("中: zhong1, zhong4\n测: ce4 ")).unwrap_err().to_string(),
+            "line 2, column 4: expected a reading",
+        );
+        // Nothing from a malformed dictionary is loaded
+        assert!(!pinin.contains("中", "zhong"));
+        assert_eq!(pinin.version(), version);
+        assert_ne!(version, PinIn::new().version());
+        pinin.load_dict(Box::new("中: zhong1, zhong4\r\n测: ce4\n")).unwrap();
+        assert!(pinin.contains("中测", "zhongce"));
+    }
+
+    #[test]
+    fn cedict() {
+        use crate::dict_loader::{CedictLoader, DictError, DictLoader};
+
+        let source = "# CC-CEDICT\n\
+            中國 中国 [Zhong1 guo2] /China/\n\
+            中 中 [zhong4] /to hit (the mark)/\n\
+            銀行 银行 [yin2 hang2] /bank/\n\
+            行 行 [xing2] /to walk/\n\
+            女 女 [nu:3] /female/\n\
+            的 的 [de5] /of/\n\
+            哪兒 哪儿 [na3 r5] /where?/\n\
+            卡拉OK 卡拉OK [ka3 la1 O K] /karaoke/\n";
+        let dict: CedictLoader = source.parse().unwrap();
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(dict.clone())).unwrap();
+        assert_eq!(pinin.get_character('中').pinyin.iter().map(|x| x.raw.as_str()).collect::<Vec<_>>(), ["zhong1", "zhong4"]);
+        assert_eq!(pinin.get_character('行').pinyin.iter().map(|x| x.raw.as_str()).collect::<Vec<_>>(), ["hang2", "xing2"]);
+        assert!(pinin.contains("中國银行", "zhongguoyinhang"));
+        assert!(pinin.contains("女的", "nvde"));
+        assert!(pinin.contains("哪儿", "na"));
+        assert!(!pinin.contains("哪儿", "nar"));
+        assert!(pinin.contains("卡拉OK", "kala"));
+
+        let phrases: Vec<_> = dict.phrases().collect();
+        assert_eq!(phrases.len(), 4);
+        assert_eq!(phrases[0], ("中国", &["zhong1".to_string(), "guo2".to_string()][..]));
+        assert_eq!(phrases[1].0, "中國");
+
+        let from_reader = CedictLoader::from_reader(source.as_bytes()).unwrap();
+        assert_eq!(from_reader.load_dict().unwrap(), dict.load_dict().unwrap());
+        let error = "中國 中国 Zhong1 guo2 /China/".parse::<CedictLoader>().unwrap_err();
+        assert!(matches!(error, DictError::Syntax { line: 1, column: 7, .. }));
+        assert!(matches!("中 中 [zhong1]".parse::<CedictLoader>(), Err(DictError::Syntax { line: 1, column: 13, .. })));
+    }
+
+    #[test]
+    fn dict_files() {
+        use crate::dict_loader::DictError;
+        use std::cell::RefCell;
+
+        let reader = RefCell::new("中: zhong1\r\n\n测: ce4\n".as_bytes());
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(reader)).unwrap();
+        assert!(pinin.contains("中测", "zhongce"));
+        let reader = RefCell::new("中: zhong1\n测: ce4,\n".as_bytes());
+        assert!(matches!(pinin.load_dict(Box::new(reader)), Err(DictError::Syntax { line: 2, column: 4, .. })));
+        let reader = RefCell::new(&b"\xff: zhong1\n"[..]);
+        assert!(matches!(pinin.load_dict(Box::new(reader)), Err(DictError::Io(_))));
+
+        let path = std::env::temp_dir().join(format!("pinin-dict-{}.txt", std::process::id()));
+        std::fs::write(&path, include_str!("dict.txt")).unwrap();
+        let mut pinin = PinIn::new();
+        let loaded = pinin.load_dict_from_path(&path);
+        std::fs::remove_file(&path).unwrap();
+        loaded.unwrap();
+        assert!(pinin.contains("测试文本", "ceshiwenben"));
+        assert!(matches!(pinin.load_dict_from_path(&path), Err(DictError::Io(_))));
+    }
+
+    #[test]
+    fn budget() {
+        use crate::budget::{Budget, CancelToken, Cancelled};
+        use std::time::{Duration, Instant};
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        let mut simple = SimpleSearcher::new(SearcherLogic::Contain);
+        for (i, name) in ["合金炉", "洗矿场", "合成"].into_iter().enumerate() {
+            tree.insert(&pinin, name, i);
+            simple.insert(&pinin, name, i);
+        }
+        let searchers: [&dyn Searcher<usize>; 2] = [&tree, &simple];
+
+        for searcher in searchers {
+            let ret = searcher.search_within(&pinin, "he", &Budget::unlimited()).unwrap();
+            assert_eq!(ret.len(), 2);
+            let ret = searcher.search_within(&pinin, "he", &Budget::within(Duration::from_secs(60)));
+            assert_eq!(ret.unwrap().len(), 2);
+
+            let past = Budget::until(Instant::now() - Duration::from_millis(1));
+            assert_eq!(searcher.search_within(&pinin, "he", &past), Err(Cancelled));
+
+            let token = CancelToken::new();
+            let budget = Budget::unlimited().with_token(token.clone());
+            assert!(searcher.search_within(&pinin, "h", &budget).is_ok());
+            token.cancel();
+            assert_eq!(searcher.search_within(&pinin, "h", &budget), Err(Cancelled));
+        }
+    }
+
+    #[test]
+    fn fuzz_entries() {
+        const PIECES: &[&str] = &[
+            "a", "e", "v", "ng", "zh", "h", "1", "4", "0", " ", "\0", "合", "〇", "\u{301}", "😀", "ü", "|", "-", "(",
+            "\n", ":", ", ",
+        ];
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut seed = 0x2545F4914F6CDD1Du64;
+        for _ in 0..200 {
+            let mut data = Vec::new();
+            for _ in 0..seed % 48 {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                data.extend_from_slice(PIECES[(seed % PIECES.len() as u64) as usize].as_bytes());
+            }
+            // Truncated UTF-8 too
+            data.extend_from_slice(&[0xE5, 0x90]);
+            crate::fuzz::match_bytes(&pinin, &data);
+            crate::fuzz::pinyin_bytes(&data);
+        }
+        crate::fuzz::dict_bytes("中: zhong1\n\nx\n行:\n: a1, ".as_bytes());
+
+        assert!(!pinin.check("", 0, "a", 0, true));
+        assert!(!pinin.check("中", 3, "a", 0, true));
+        assert!(!pinin.check("中", 0, "a", 5, true));
+        assert!(pinin.check("中", 1, "zhong", 5, false));
+        assert!(pinin.check("中国", 0, "zhong", 0, true));
+        assert!(!pinin.check("中国", 0, "zhong", 0, false));
+    }
+
+    #[test]
+    fn romanize() {
+        use crate::romanize::{romanize, romanize_reader};
+        use std::io::Read;
+
+        /// Hands out one byte per read.
+        struct Trickle<'b>(&'b [u8]);
+
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                match self.0.split_first() {
+                    Some((b, rest)) if !buf.is_empty() => {
+                        buf[0] = *b;
+                        self.0 = rest;
+                        Ok(1)
+                    }
+                    _ => Ok(0),
+                }
+            }
+        }
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let text = "合金炉 is 3国 😀!";
+        let expected = "hé jīn lú is 3guó 😀!";
+
+        let mut out = Vec::new();
+        romanize(&pinin, text.chars(), unicode_format, &mut out).unwrap();
+        assert_str_eq!(String::from_utf8(out).unwrap(), expected);
+
+        let mut out = Vec::new();
+        romanize_reader(&pinin, Trickle(text.as_bytes()), unicode_format, &mut out).unwrap();
+        assert_str_eq!(String::from_utf8(out).unwrap(), expected);
+
+        let mut out = Vec::new();
+        romanize_reader(&pinin, &b"a\xFF\xE5\x90"[..], number_format, &mut out).unwrap();
+        assert_str_eq!(String::from_utf8(out).unwrap(), "a\u{FFFD}\u{FFFD}\u{FFFD}");
+
+        let long = "中".repeat(20000);
+        let mut out = Vec::new();
+        romanize_reader(&pinin, long.as_bytes(), number_format, &mut out).unwrap();
+        assert_eq!(out.len(), 20000 * 7 - 1);
+    }
+
+    #[test]
+    fn romanized_targets() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        assert!(!pinin.contains("zhong1guo2", "zg"));
+        assert_eq!(pinin.romanized("abc1 x"), "abc1 x");
+        assert_eq!(pinin.romanized("zhong1guo"), "zhong1guo");
+        assert_eq!(pinin.romanized("Zhong1guo2 ren2!").chars().count(), 5);
+
+        pinin.romanized_targets = true;
+        assert!(pinin.contains("zhong1guo2", "zg"));
+        assert!(pinin.begins("zhong1guo2", "zhongguo"));
+        assert!(pinin.matches("Zhong1Guo2", "zhong1guo2"));
+        assert!(pinin.contains("hong2 kong3", "h k"));
+        assert!(pinin.contains("hong2 kong", "kong"));
+        assert!(!pinin.contains("zhong1guo2", "zhong4"));
+
+        let mut tree = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        let mut simple = SimpleSearcher::new(SearcherLogic::Begin);
+        for (i, name) in ["zhong1guo2", "中国", "zhongguo"].into_iter().enumerate() {
+            tree.insert(&pinin, name, i);
+            simple.insert(&pinin, name, i);
+        }
+        assert_eq!(tree.search_sorted(&pinin, "zg"), vec![&0, &1]);
+        assert_eq!(simple.search(&pinin, "zg"), vec![&0, &1]);
+        assert_eq!(tree.name(0).unwrap(), "zhong1guo2");
+    }
+
+    #[test]
+    fn reserved_chars() {
+        use crate::accelerator::ChunkedProvider;
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        pinin.set_phrases(Some("重庆 chong2 qing4".parse().unwrap()));
+        let stand_in = pinin.romanized("a1").chars().next().unwrap();
+        assert_ne!(stand_in, 'a');
+        assert!(pinin.get_character(stand_in).pinyin.is_empty());
+        assert!(pinin.get_character('\u{100000}').pinyin.is_empty());
+
+        for romanized_targets in [false, true] {
+            pinin.romanized_targets = romanized_targets;
+            let raw = format!("{}docs", stand_in);
+            assert!(!pinin.contains(&raw, "a"));
+            assert!(!pinin.contains("\u{100000}", "chong"));
+            assert!(pinin.contains(&raw, "docs"));
+            assert!(!pinin.contains_provider(ChunkedProvider::new(vec![raw.clone()]), "a"));
+
+            let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+            tree.insert(&pinin, &raw, 0);
+            tree.insert(&pinin, "a1 docs", 1);
+            assert_eq!(tree.search(&pinin, "a"), vec![&1]);
+            assert_eq!(tree.name(0).as_deref(), Some(raw.as_str()));
+        }
+        assert!(pinin.contains("zhong1guo2 docs", "zg docs"));
+
+        let hydrated = PinIn::from_bytes(&pinin.to_bytes().unwrap()).unwrap();
+        assert!(hydrated.contains("zhong1guo2", "zg"));
+        assert!(!hydrated.contains(&stand_in.to_string(), "a"));
+    }
+
+    #[test]
+    fn folding() {
+        use crate::unicode_utils::fold;
+
+        assert_eq!(fold("第２集"), "第2集");
+        assert_eq!(fold("ＡＢｃ！"), "ABc");
+        assert_eq!(fold("第Ⅻ章，上　下"), "第XII章上 下");
+        assert!(matches!(fold("〇々中国 1"), Cow::Borrowed(_)));
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        assert!(!pinin.contains("第２集", "di2ji"));
+        assert!(!pinin.contains("集、上", "jishang"));
+
+        pinin.fold = true;
+        assert!(pinin.contains("第２集", "di2ji"));
+        assert!(pinin.contains("集、上", "jishang"));
+        assert!(pinin.matches("《流浪地球》", "liulangdiqiu"));
+        assert!(pinin.contains("第2集", "di２ji"));
+
+        let mut tree = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        for (i, name) in ["第２集", "第二集", "合金炉、洗矿场"].into_iter().enumerate() {
+            tree.insert(&pinin, name, i);
+        }
+        assert_eq!(tree.search_sorted(&pinin, "di2"), vec![&0]);
+        assert_eq!(tree.search_sorted(&pinin, "ｄｉ２"), vec![&0]);
+        assert_eq!(tree.search_sorted(&pinin, "hejinluxi"), vec![&2]);
+        assert_eq!(tree.name(0).unwrap(), "第２集");
+
+        let restored = PinIn::from_bytes(&pinin.to_bytes().unwrap()).unwrap();
+        assert!(restored.fold);
+    }
+
+    #[test]
+    fn case_folding() {
+        use crate::unicode_utils::fold_case;
+
+        assert_eq!(fold_case("ΩМЕГА Ёж"), "ωмега ёж");
+        assert_eq!(fold_case("ΆΈΏΣΑΣ ÀÖØÞ×"), "άέώσασ àöøþ×");
+        assert_eq!(fold_case("ς"), "σ");
+        assert!(matches!(fold_case("ωмега 中国 1"), Cow::Borrowed(_)));
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        assert!(!pinin.contains("Ω型钢", "ω"));
+        assert!(!pinin.contains("АК-47", "ак"));
+
+        pinin.fold_case = true;
+        assert!(pinin.contains("Ω型钢", "ωxing"));
+        assert!(pinin.contains("ω型钢", "Ωxing"));
+        assert!(pinin.contains("АК-47", "ак"));
+        assert!(pinin.matches("Ceshi测试", "CESHICESHI"));
+
+        let mut tree = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        for (i, name) in ["Дом", "ΣΙΓΜΑ", "Σίγμα", "sigma"].into_iter().enumerate() {
+            tree.insert(&pinin, name, i);
+        }
+        assert_eq!(tree.search_sorted(&pinin, "дом"), vec![&0]);
+        assert_eq!(tree.search_sorted(&pinin, "σιγμα"), vec![&1]);
+        assert_eq!(tree.search_sorted(&pinin, "ΣΊ"), vec![&2]);
+        assert_eq!(tree.search_sorted(&pinin, "SIG"), vec![&3]);
+        assert_eq!(tree.name(1).unwrap(), "ΣΙΓΜΑ");
+
+        let restored = PinIn::from_bytes(&pinin.to_bytes().unwrap()).unwrap();
+        assert!(restored.fold_case && !restored.fold);
+    }
+
+    #[test]
+    fn query_trace() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        pinin.fuzzy.zh2z = true;
+        pinin.commit();
+        let trace = pinin.trace_query("  xian zhong1国z");
+        assert_eq!(trace.normalized, "xian zhong1国z");
+        let lines: Vec<String> = trace.to_string().lines().take(5).map(String::from).collect();
+        assert_eq!(lines[0], r#""  xian zhong1国z" -> "xian zhong1国z""#);
+        assert_eq!(lines[1..], ["xian: xian1, xian2, xian3, xian4 (x ian 1)", " : literal", "zhong1: zhong1, zong1 ([z zh] ong 1)", "国: literal"]);
+        let steps: Vec<String> = trace.steps.iter().map(|x| trace.keys[x.start..x.end].concat()).collect();
+        assert_eq!(steps, ["xian", " ", "zhong1", "国", "z"]);
+        assert!(trace.steps[0].readings.contains(&"xian1".to_string()));
+        assert!(trace.steps[1].readings.is_empty() && trace.steps[3].readings.is_empty());
+        assert_eq!(trace.steps[2].readings, ["zhong1", "zong1"]);
+        assert_eq!(trace.steps[2].phonemes[0], ["z", "zh"]);
+        assert!(!trace.steps[2].partial);
+        assert!(!trace.steps[4].readings.is_empty() && !trace.steps[4].partial);
+
+        let trace = pinin.trace_query("zho");
+        assert_eq!(trace.steps.len(), 1);
+        assert!(trace.steps[0].partial);
+        assert!(trace.to_string().contains("partial"));
+
+        pinin.keyboard = &KEYBOARD_XIAOHE;
+        pinin.commit();
+        let trace = pinin.trace_query("vsgo");
+        let steps: Vec<String> = trace.steps.iter().map(|x| trace.keys[x.start..x.end].concat()).collect();
+        assert_eq!(steps, ["vs", "go"]);
+        assert!(trace.steps[0].readings.contains(&"zhong1".to_string()));
+        assert!(pinin.trace_query("").steps.is_empty());
+    }
+
+    #[test]
+    fn ranked() {
+        use crate::sort::literal_hits;
+
+        assert_eq!(literal_hits("合金炉", "合jl"), 1);
+        assert_eq!(literal_hits("Hong2", "hong2"), 5);
+        assert_eq!(literal_hits("轰20", "hong2"), 1);
+        assert_eq!(literal_hits("ab", "ba"), 1);
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        for (i, name) in ["和金", "合金炉", "hejin", "合金"].into_iter().enumerate() {
+            tree.insert(&pinin, name, i);
+        }
+        assert_eq!(tree.search_ranked(&pinin, "合j", usize::MAX), vec![&3, &1]);
+        assert_eq!(tree.search_ranked(&pinin, "hej", usize::MAX), vec![&2, &3, &1, &0]);
+
+        pinin.set_frequencies(Some("和金 10\n合金 5".parse().unwrap()));
+        assert_eq!(tree.search_ranked(&pinin, "hej", usize::MAX), vec![&2, &0, &3, &1]);
+        assert_eq!(tree.search_ranked(&pinin, "hej", 2), vec![&2, &0]);
+        assert_eq!(tree.search_ranked(&pinin, "合金", 1), vec![&3]);
+        assert!(tree.search_ranked(&pinin, "hej", 0).is_empty());
+
+        // Fewer chars spanned first, then full pinyin before abbreviations
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        for (i, name) in ["西安", "先", "在现", "西"].into_iter().enumerate() {
+            tree.insert(&pinin, name, i);
+        }
+        assert_eq!(tree.search_ranked(&pinin, "xian", 3), vec![&1, &2, &0]);
+        assert_eq!(tree.search_ranked(&pinin, "xi", usize::MAX), vec![&3, &0, &1, &2]);
+
+        // Names are compared folded like the query
+        pinin.fold_case = true;
+        let mut tree = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        for (i, name) in ["HEJIN2", "Hejin"].into_iter().enumerate() {
+            tree.insert(&pinin, name, i);
+        }
+        assert_eq!(tree.search_ranked(&pinin, "hejin", usize::MAX), vec![&1, &0]);
+    }
+
+    #[test]
+    fn frequency() {
+        use crate::frequency::{FrequencyTable, ParseFrequencyError};
+
+        let mut table: FrequencyTable = "# comment\n银行 2500 yin2 hang2\n\n行走 600 xing2 zou3\n走 900".parse().unwrap();
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.count("银行"), 2500);
+        assert_eq!(table.count("银"), 0);
+        assert_eq!(table.readings("走"), None);
+        assert!(!table.insert("银行", 1, &["yin2"]));
+        assert!(!table.insert("银", 1, &["yin"]));
+        assert_eq!("a b".parse::<FrequencyTable>(), Err(ParseFrequencyError(1)));
+        assert_eq!("a 1\nab 1 a1".parse::<FrequencyTable>(), Err(ParseFrequencyError(2)));
+
+        let chars: Vec<char> = "去银行走走".chars().collect();
+        assert_eq!(table.segment(&chars), vec![(0, 1), (1, 3), (3, 4), (4, 5)]);
+        assert_eq!(table.segment(&[]), vec![]);
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let readings = |pinin: &PinIn, s: &str| -> Vec<String> {
+            pinin.to_pinyin(s).into_iter().map(|x| x.map(|x| x.raw.to_string()).unwrap_or_default()).collect()
+        };
+        assert_eq!(readings(&pinin, "银行a"), vec!["yin2", "xing2", ""]);
+        table.insert("行", 10, &["hang2"]);
+        pinin.set_frequencies(Some(table));
+        assert_eq!(readings(&pinin, "银行a"), vec!["yin2", "hang2", ""]);
+        assert_eq!(readings(&pinin, "行走"), vec!["xing2", "zou3"]);
+        assert_eq!(readings(&pinin, "行"), vec!["hang2"]);
+
+        #[cfg(feature = "frequency")]
+        {
+            let table = FrequencyTable::embedded();
+            assert_eq!(table.count("银行"), 7684);
+            assert_eq!(table.segment(&"重要的银行".chars().collect::<Vec<_>>()), vec![(0, 2), (2, 3), (3, 5)]);
+
+            pinin.load_default_frequencies();
+            let mut tree = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+            tree.insert(&pinin, "银河", 0);
+            tree.insert(&pinin, "银行", 1);
+            assert_eq!(tree.search_ranked(&pinin, "yinh", 2), vec![&1, &0]);
+        }
+    }
+
+    #[test]
+    fn multi_searcher() {
+        use crate::compressed::Compressor;
+        use crate::multi::MultiSearcher;
+        use crate::sync::read;
+        use std::sync::RwLock;
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let names = ["测试文本", "文本测试", "测试", "文本", "测试 文本"];
+        let mut multi = MultiSearcher::new();
+        names.iter().enumerate().for_each(|(i, x)| multi.insert(&pinin, x, i * 10));
+        assert_eq!(multi.len(), names.len());
+        assert_eq!(read(&multi.names()).offsets.len(), names.len());
+
+        let logics = [SearcherLogic::Begin, SearcherLogic::Contain, SearcherLogic::Equal, SearcherLogic::EndsWith];
+        for logic in logics {
+            let mut tree = TreeSearcher::new(logic, Arc::new(Accelerator::new()));
+            names.iter().enumerate().for_each(|(i, x)| tree.insert(&pinin, x, i * 10));
+            for query in ["ceshi", "wenben", "ceshiwenben", "csw", "ce"] {
+                assert_eq!(multi.search(&pinin, logic, query), tree.search(&pinin, query), "{:?} {}", logic, query);
+            }
+        }
+        assert_eq!(multi.search(&pinin, SearcherLogic::Equal, "ceshi"), vec![&20]);
+        assert_eq!(multi.name(4).as_deref(), Some("测试 文本"));
+
+        let bytes = read(&multi.names()).to_bytes();
+        let pool = Arc::new(RwLock::new(Compressor::from_bytes(&bytes).unwrap()));
+        let mut restored = MultiSearcher::with_names(&pinin, pool, (0..names.len()).map(|i| i * 10).collect());
+        restored.insert(&pinin, "测试文", 50);
+        for logic in logics {
+            let mut expected = multi.search(&pinin, logic, "ceshiwen");
+            expected.push(&50);
+            assert_eq!(restored.search(&pinin, logic, "ceshiwen"), expected, "{:?}", logic);
+        }
+    }
+
+    #[test]
+    fn phrases() {
+        use crate::phrase::{ParsePhraseError, PhraseDict};
+
+        let dict: PhraseDict = "# comment\n重庆 chong2 qing4\n\n重庆市 chong2 qing4 shi4\n长大 zhang3 da4".parse().unwrap();
+        assert_eq!(dict.len(), 3);
+        assert_eq!(dict.readings("长大").map(<[String]>::len), Some(2));
+        assert_eq!("重 chong2".parse::<PhraseDict>(), Err(ParsePhraseError(1)));
+        assert_eq!("重庆 chong2 qing4\n重庆 chong2".parse::<PhraseDict>(), Err(ParsePhraseError(2)));
+        let chars: Vec<char> = "去重庆市长大".chars().collect();
+        assert_eq!(dict.segment(&chars), vec![(1, 4), (4, 6)]);
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        assert!(pinin.contains("重庆", "zhongqing"));
+        let version = pinin.version();
+        pinin.set_phrases(Some(dict));
+        assert!(pinin.version() > version);
+        for (s1, s2, expected) in [
+            ("重庆", "chongqing", true),
+            ("重庆", "zhongqing", false),
+            ("重庆", "cq", true),
+            ("重庆", "zq", false),
+            ("重庆", "重庆", true),
+            ("重庆", "重qing", true),
+            ("重要", "zhongyao", true),
+            ("重", "zhong", true),
+            ("重", "chong", true),
+            ("长大重庆", "zhangdachongqing", true),
+            ("长大重庆", "changda", false),
+        ] {
+            assert_eq!(pinin.contains(s1, s2), expected, "{} {}", s1, s2);
+        }
+
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        for (i, name) in ["重庆火锅", "重要通知", "重庆"].iter().enumerate() {
+            tree.insert(&pinin, name, i);
+        }
+        assert_eq!(tree.search(&pinin, "zhong"), vec![&1]);
+        assert_eq!(tree.search(&pinin, "chongqing"), vec![&0, &2]);
+        assert_eq!(tree.search(&pinin, "重庆"), vec![&0, &2]);
+        assert_eq!(tree.name(0).as_deref(), Some("重庆火锅"));
+
+        pinin.fuzzy.ch2c = true;
+        pinin.commit();
+        assert!(pinin.contains("重庆", "congqing"));
+        pinin.set_phrases(None);
+        assert!(pinin.contains("重庆", "zhongqing"));
+    }
+
+    #[test]
+    fn search_indices() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        let mut simple = SimpleSearcher::new(SearcherLogic::Contain);
+        for name in ["合成", "洗矿场", "合金炉", "流体"] {
+            tree.insert(&pinin, name, ());
+            simple.insert(&pinin, name, ());
+        }
+        assert_eq!(tree.search_indices(&pinin, "he"), vec![0, 2]);
+        assert_eq!(simple.search_indices(&pinin, "he"), vec![0, 2]);
+
+        // The indices outlive a mutable use of the searcher
+        let rows = tree.search_indices(&pinin, "kc");
+        tree.insert(&pinin, "矿石", ());
+        assert_eq!(rows, vec![1]);
+    }
+
+    #[test]
+    fn search_streaming() {
+        use std::ops::ControlFlow;
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        let names = ["洗矿场", "合金炉", "合成", "流体"];
+        for i in 0..200 {
+            tree.insert(&pinin, &format!("{}合{}", names[i % names.len()], i), i);
+        }
+
+        let mut all = Vec::new();
+        let flow = tree.search_streaming(&pinin, "he", |x| {
+            all.push(*x);
+            ControlFlow::Continue(())
+        });
+        assert_eq!(flow, ControlFlow::Continue(()));
+        all.sort_unstable();
+        assert_eq!(all, tree.search_indices(&pinin, "he"));
+
+        let mut first = Vec::new();
+        let flow = tree.search_streaming(&pinin, "he", |x| {
+            first.push(*x);
+            if first.len() == 3 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+        });
+        assert_eq!(flow, ControlFlow::Break(()));
+        assert_eq!(first.len(), 3);
+    }
+
+    #[test]
+    fn remove() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::with_keys(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        let mut simple = SimpleSearcher::new(SearcherLogic::Contain);
+        let mut micro = crate::micro::MicroSearcher::new(SearcherLogic::Contain);
+        let searchers: [&mut dyn SearcherMut<usize>; 3] = [&mut tree, &mut simple, &mut micro];
+        for searcher in searchers {
+            for (i, name) in ["合成", "洗矿场", "合金炉", "流体", "合成"].into_iter().enumerate() {
+                searcher.insert(&pinin, name, i);
+            }
+            assert_eq!(searcher.count(&pinin, "he"), 3);
+            assert_eq!(searcher.remove(&pinin, &2), 1);
+            assert_eq!(searcher.remove(&pinin, &2), 0);
+            assert_eq!(searcher.remove_by_name(&pinin, "合成"), 2);
+            assert!(!searcher.any(&pinin, "he"));
+            assert_eq!(searcher.search(&pinin, "liu"), vec![&3]);
+            searcher.insert(&pinin, "合成", 5);
+            assert_eq!(searcher.search(&pinin, "hc"), vec![&5]);
+        }
+        assert_eq!(tree.entries().collect::<Vec<_>>(), vec![("洗矿场", &1), ("流体", &3), ("合成", &5)]);
+        assert_eq!(tree.search_indices(&pinin, "kc"), vec![0]);
+        assert_eq!(tree.compressor.read().unwrap().chars.len(), 10);
+    }
+
+    #[test]
+    fn update() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::with_keys(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        let mut simple = SimpleSearcher::new(SearcherLogic::Begin);
+        let mut micro = crate::micro::MicroSearcher::new(SearcherLogic::Begin);
+        let searchers: [&mut dyn SearcherMut<usize>; 3] = [&mut tree, &mut simple, &mut micro];
+        for searcher in searchers {
+            for (i, name) in ["合成", "洗矿场", "合金炉", "流体"].into_iter().enumerate() {
+                searcher.insert(&pinin, name, i);
+            }
+            assert_eq!(searcher.update(&pinin, &1, "矿石"), 1);
+            assert_eq!(searcher.update(&pinin, &7, "矿石"), 0);
+            assert!(!searcher.any(&pinin, "xi"));
+            assert_eq!(searcher.search(&pinin, "ks"), vec![&1]);
+            assert_eq!(searcher.update(&pinin, &1, "洗矿场"), 1);
+            assert_eq!(searcher.search(&pinin, "xkc"), vec![&1]);
+            assert!(!searcher.any(&pinin, "ks"));
+        }
+
+        for i in 0..10 {
+            tree.rename(&pinin, 2, &format!("合金炉{}", i));
+        }
+        assert_eq!(tree.search_indices(&pinin, "hj"), vec![2]);
+        assert_eq!(tree.search_indices(&pinin, "he"), vec![0, 2]);
+        assert_eq!(tree.key(2), Some("合金炉9"));
+        assert_eq!(tree.name(1).as_deref(), Some("洗矿场"));
+        assert!(!tree.rename(&pinin, 4, "流体"));
+
+        // Large enough for accelerated nodes
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        let names: Vec<String> = include_str!("dict.txt").lines().take(300).map(|x| x[..x.find(':').unwrap()].to_string() + "场").collect();
+        names.iter().enumerate().for_each(|(i, x)| tree.insert(&pinin, x, i));
+        let before = tree.search_indices(&pinin, "chang");
+        tree.rename(&pinin, before[0], "流体");
+        assert_eq!(tree.search_indices(&pinin, "chang"), before[1..]);
+        assert!(tree.search_indices(&pinin, "liuti").contains(&before[0]));
+    }
+
+    #[test]
+    fn insertion_order() {
+        let mut p = PinIn::new();
+        p.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        for logic in [SearcherLogic::Begin, SearcherLogic::Contain] {
+            let mut tree = TreeSearcher::new(logic, Arc::new(Accelerator::new()));
+            let names = ["中国", "中文", "钟表", "种子", "中间", "终点", "中国人", "中"];
+            for (i, name) in names.iter().enumerate() {
+                tree.insert(&p, name, i);
+            }
+            let expected: Vec<usize> = (0..names.len()).collect();
+            assert_eq!(tree.search(&p, "zh").into_iter().copied().collect::<Vec<_>>(), expected);
+            let budget = crate::budget::Budget::unlimited();
+            assert_eq!(tree.search_within(&p, "zh", &budget).unwrap().into_iter().copied().collect::<Vec<_>>(), expected);
+        }
+    }
+
+    #[test]
+    fn search_iter() {
+        let mut p = PinIn::new();
+        p.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        let names: Vec<&str> = include_str!("dict.txt").lines().take(500).map(|x| &x[..x.find(':').unwrap()]).collect();
+        for (i, name) in names.iter().enumerate() {
+            tree.insert(&p, &format!("{}场", name), i);
+        }
+
+        let mut all: Vec<usize> = tree.search_iter(&p, "chang").copied().collect();
+        all.sort_unstable();
+        assert_eq!(all, tree.search_indices(&p, "chang"));
+        assert_eq!(all.len(), 500);
+        assert_eq!(tree.search_iter(&p, "chang").take(3).count(), 3);
+        assert_eq!(tree.search_iter(&p, "xyz").next(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "bench_utils")]
+    fn bench_utils() {
+        use crate::bench_utils::{bench_searcher, Latency};
+        use std::time::Duration;
+
+        let mut p = PinIn::new();
+        p.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let names: Vec<&str> = include_str!("dict.txt").lines().take(200).map(|x| &x[..x.find(':').unwrap()]).collect();
+        let tree = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        let report = bench_searcher(&p, tree, &names, &["a", "yi", "zzz"], 5);
+        assert_eq!((report.entries, report.queries), (200, 15));
+        assert_eq!(report.hits, bench_searcher(&p, SimpleSearcher::new(SearcherLogic::Begin), &names, &["a", "yi", "zzz"], 1).hits);
+        let l = report.latency;
+        assert!(l.min <= l.median && l.median <= l.p95 && l.p95 <= l.max);
+
+        let ms = Duration::from_millis;
+        let l = Latency::from_samples((1..=100).rev().map(ms).collect());
+        assert_eq!((l.min, l.median, l.p95, l.max), (ms(1), ms(50), ms(95), ms(100)));
+        assert_eq!(Latency::from_samples(Vec::new()), Latency::default());
+    }
+
+    #[test]
+    fn offsets_past_query() {
+        use crate::accelerator::StringProvider;
+        use std::sync::RwLock;
+
+        let mut p = PinIn::new();
+        p.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let a = Accelerator::with_provider(Arc::new(RwLock::new(StringProvider::from("中国"))));
+        a.search("zg");
+        for offset in [2, 3, 100] {
+            assert_eq!(a.search_char(offset), None);
+            assert_eq!(a.get(&p, '中', offset), IndexSet::none());
+            assert!(a.begins(&p, offset, 0));
+            assert!(a.contains(&p, offset, 0));
+            assert!(!a.matches(&p, offset, 0));
+            assert!(a.matches(&p, offset, 2));
+            assert_eq!(a.span(&p, offset, 0), Some(0));
+            assert_eq!(a.span(&p, offset, 1), Some(0));
+        }
+        assert!(a.matches(&p, 1, 1));
+        assert!(a.matches(&p, 0, 0));
+
+        // Equal at exactly the query's length, with names that extend it
+        let names = ["中国", "中国人", "中", "种过", "中国银行", "国"];
+        let mut tree = TreeSearcher::new(SearcherLogic::Equal, Arc::new(Accelerator::new()));
+        let mut simple = SimpleSearcher::new(SearcherLogic::Equal);
+        names.iter().enumerate().for_each(|(i, x)| {
+            tree.insert(&p, x, i);
+            simple.insert(&p, x, i);
+        });
+        for (q, expected) in [("zg", vec![0, 3]), ("zhongguo", vec![0, 3]), ("zgr", vec![1]), ("z", vec![2]), ("zgyh", vec![4]), ("zgrr", vec![])] {
+            assert_eq!(tree.search_indices(&p, q), expected, "{}", q);
+            let mut ret: Vec<usize> = simple.search(&p, q).into_iter().copied().collect();
+            ret.sort_unstable();
+            assert_eq!(ret, expected, "{}", q);
+        }
+    }
+
+    #[test]
+    fn search_into_collection() {
+        use crate::micro::{AutoSearcher, MicroSearcher};
+        use crate::searcher::Collection;
+        use smallvec::SmallVec;
+
+        struct FirstTwo<'a>(Vec<&'a usize>);
+
+        impl<'a> Collection<&'a usize> for FirstTwo<'a> {
+            fn insert(&mut self, data: &'a usize) {
+                self.0.push(data);
+            }
+
+            fn is_full(&self) -> bool {
+                self.0.len() >= 2
+            }
+        }
+
+        let mut p = PinIn::new();
+        p.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        let mut simple = SimpleSearcher::new(SearcherLogic::Contain);
+        let mut micro = MicroSearcher::new(SearcherLogic::Contain);
+        let mut auto: AutoSearcher<usize, 2> = AutoSearcher::new(SearcherLogic::Contain);
+        let names = ["合金炉", "洗矿场", "合成", "流体", "合金"];
+        let searchers: [&mut dyn Searcher<usize>; 4] = [&mut tree, &mut simple, &mut micro, &mut auto];
+        for searcher in searchers {
+            names.iter().enumerate().for_each(|(i, x)| searcher.insert(&p, x, i));
+            let mut buf: SmallVec<[&usize; 8]> = SmallVec::new();
+            for (q, expected) in [("he", vec![0, 2, 4]), ("hj", vec![0, 4]), ("xyz", vec![])] {
+                buf.clear();
+                searcher.search_into(&p, q, &mut buf);
+                let mut ret: Vec<usize> = buf.iter().map(|x| **x).collect();
+                ret.sort_unstable();
+                assert_eq!(ret, expected, "{}", q);
+            }
+            let mut two = FirstTwo(Vec::new());
+            searcher.search_into(&p, "he", &mut two);
+            assert_eq!(two.0.len(), 2);
+        }
+    }
+
+    #[test]
+    fn policy() {
+        use crate::policy::{QueryPolicy, QueryTooShort};
+
+        let mut p = PinIn::new();
+        p.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        let mut simple = SimpleSearcher::new(SearcherLogic::Contain);
+        for (i, name) in ["合金炉", "洗矿场", "合成", "炉子"].into_iter().enumerate() {
+            tree.insert(&p, name, i);
+            simple.insert(&p, name, i);
+        }
+        let ids = |x: Result<Vec<&usize>, QueryTooShort>| x.map(|x| x.into_iter().copied().collect::<Vec<_>>());
+
+        // The default changes nothing
+        assert_eq!(ids(tree.search_checked(&p, "l")), Ok(vec![0, 3]));
+        assert_eq!(ids(simple.search_checked(&p, "")), Ok(vec![0, 1, 2, 3]));
+
+        let policy = QueryPolicy { min_len: 1, begin_below: 3 };
+        tree.set_policy(policy);
+        simple.set_policy(policy);
+        for (q, expected) in [("", Err(QueryTooShort { len: 0, min: 1 })), ("l", Ok(vec![3])), ("lu", Ok(vec![3])), ("luz", Ok(vec![3])), ("kc", Ok(vec![])), ("kuangc", Ok(vec![1])), ("hej", Ok(vec![0]))] {
+            assert_eq!(ids(tree.search_checked(&p, q)), expected, "{}", q);
+            assert_eq!(ids(simple.search_checked(&p, q)), expected, "{}", q);
+        }
+        assert_eq!(ids(tree.search_checked(&p, "jinl")), Ok(vec![0]));
+
+        assert_eq!(QueryPolicy::recommended(SearcherLogic::Contain).resolve(SearcherLogic::Contain, "h"), Ok(SearcherLogic::Begin));
+        assert_eq!(QueryPolicy::recommended(SearcherLogic::Begin), QueryPolicy::default());
+    }
+
+    #[test]
+    fn freeze() {
+        let mut p = PinIn::new();
+        p.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut names: Vec<String> = include_str!("dict.txt").lines().take(300).map(|x| x[..x.find(':').unwrap()].to_string()).collect();
+        names.extend(["合金炉", "合金", "洗矿场", "合成台", "合成", "ICBM必杀技", "中国人", "中国"].map(String::from));
+
+        for logic in [SearcherLogic::Begin, SearcherLogic::Contain, SearcherLogic::Equal, SearcherLogic::EndsWith] {
+            let mut tree = TreeSearcher::new(logic, Arc::new(Accelerator::new()));
+            names.iter().enumerate().for_each(|(i, x)| tree.insert(&p, x, i));
+            tree.rename(&p, 0, "合金炉子");
+            let expected: Vec<_> = ["he", "hj", "hejin", "hejinlu", "jl", "lu", "zg", "zhongguo", "ren", "c", "cheng", "ICBM", "bsj", "a", "yi1", "xyz"]
+                .into_iter()
+                .map(|q| (q, tree.search_indices(&p, q)))
+                .collect();
+            let frozen = tree.freeze();
+            assert_eq!(frozen.len(), names.len());
+            assert_eq!(frozen.name(0).as_deref(), Some("合金炉子"));
+            assert!(frozen.search_indices(&p, "hj").contains(&(names.len() - 7)));
+            for (q, ids) in expected {
+                assert_eq!(frozen.search_indices(&p, q), ids, "{:?} {}", logic, q);
+            }
+
+            let counts: Vec<usize> = std::thread::scope(|scope| {
+                let threads: Vec<_> = (0..4).map(|_| scope.spawn(|| frozen.count(&p, "hej"))).collect();
+                threads.into_iter().map(|x| x.join().unwrap()).collect()
+            });
+            assert!(counts.iter().all(|x| *x == frozen.count(&p, "hej")));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "rkyv")]
+    fn archive() {
+        use crate::archive::{archived, MappedSearcher};
+
+        let mut p = PinIn::new();
+        p.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut names: Vec<String> = include_str!("dict.txt").lines().take(300).map(|x| x[..x.find(':').unwrap()].to_string()).collect();
+        names.extend(["合金炉", "洗矿场", "合成台", "ICBM必杀技", "中国人"].map(String::from));
+
+        for logic in [SearcherLogic::Begin, SearcherLogic::Contain, SearcherLogic::Equal, SearcherLogic::EndsWith] {
+            let mut tree = TreeSearcher::new(logic, Arc::new(Accelerator::new()));
+            names.iter().enumerate().for_each(|(i, x)| tree.insert(&p, x, i as u32));
+            let frozen = tree.freeze();
+            let bytes = frozen.to_archive().unwrap();
+            let index = archived::<u32>(&bytes).unwrap();
+            assert_eq!(index.len(), names.len());
+            assert_eq!(index.logic(), logic);
+            assert_eq!(index.name(names.len() - 2).as_deref(), Some("ICBM必杀技"));
+            assert_eq!(index.get(1), Some(&1));
+            for q in ["he", "hj", "hejinlu", "kc", "zg", "icbm", "bsj", "a", "xyz"] {
+                assert_eq!(index.search_indices(&p, q), frozen.search_indices(&p, q), "{:?} {}", logic, q);
+            }
+
+            let path = std::env::temp_dir().join(format!("pinin-archive-{}-{}.idx", std::process::id(), logic));
+            std::fs::write(&path, &bytes).unwrap();
+            let mapped = MappedSearcher::<u32>::open(&path).unwrap();
+            assert_eq!(mapped.search(&p, "hejinlu"), frozen.search(&p, "hejinlu"));
+            drop(mapped);
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        let bytes = TreeSearcher::<u32>::new(SearcherLogic::Begin, Arc::new(Accelerator::new())).freeze().to_archive().unwrap();
+        assert!(archived::<u32>(&bytes).unwrap().is_empty());
+        // Valid bytes, but no tree
+        assert!(archived::<u32>(&[0; 64]).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "convert")]
+    fn convert() {
+        use crate::convert::{ConversionTable, Variant};
+        use std::borrow::Cow;
+
+        let mut table = ConversionTable::new();
+        table.load_opencc("萬\t万\n礦\t矿\n場\t场 塲\n乾\t干 乾\n乾坤\t乾坤\nbroken line\n", Variant::Traditional);
+        table.load_opencc("干\t幹 乾 干\n", Variant::Simplified);
+        assert_eq!(table.convert("洗礦場", Variant::Simplified), "洗矿场");
+        assert_eq!(table.convert("乾淨", Variant::Simplified), "干淨");
+        assert_eq!(table.convert("乾坤", Variant::Simplified), "乾坤");
+        assert!(matches!(table.convert("乾坤", Variant::Simplified), Cow::Borrowed(_)));
+        assert_eq!(table.convert("洗矿场", Variant::Traditional), "洗礦場");
+        // 乾 was added first for 干
+        assert_eq!(table.convert("干", Variant::Traditional), "乾");
+
+        let mut p = PinIn::new();
+        p.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        tree.insert(&p, "洗礦場", 0);
+        let shown: Vec<String> = tree
+            .search_indices(&p, "kc")
+            .into_iter()
+            .map(|i| table.convert(&tree.name(i).unwrap(), Variant::Simplified).into_owned())
+            .collect();
+        assert_eq!(shown, vec!["洗矿场"]);
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn build_parallel() {
+        let mut p = PinIn::new();
+        p.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut names: Vec<String> = include_str!("dict.txt").lines().take(1500).map(|x| x[..x.find(':').unwrap()].to_string()).collect();
+        names.extend(["合金炉", "洗矿场", "", "合成台", "ICBM必杀技"].map(String::from));
+        names.extend(names.clone().chunks(3).map(|x| x.concat()));
+
+        for logic in [SearcherLogic::Begin, SearcherLogic::Contain, SearcherLogic::Equal, SearcherLogic::EndsWith] {
+            let mut tree = TreeSearcher::with_keys(logic, Arc::new(Accelerator::new()));
+            let mut parallel = TreeSearcher::with_keys(logic, Arc::new(Accelerator::new()));
+            names.iter().enumerate().for_each(|(i, x)| tree.insert(&p, x, i));
+            parallel.insert(&p, &names[0], 0);
+            parallel.build_parallel(&p, names[1..].iter().enumerate().map(|(i, x)| (x.as_str(), i + 1)).collect::<Vec<_>>());
+            assert_eq!(parallel.len(), names.len());
+            assert_eq!(parallel.key(names.len() - 1), Some(names[names.len() - 1].as_str()));
+            // Queries ending on a map node used to miss everything below it
+            assert!(tree.search_indices(&p, "kc").contains(&1501) == matches!(logic, SearcherLogic::Contain | SearcherLogic::EndsWith));
+            for q in ["he", "hj", "hejinlu", "kc", "a", "yi", "ICBM", "bsj", "1", "zzz"] {
+                assert_eq!(parallel.search_indices(&p, q), tree.search_indices(&p, q), "{:?} {}", logic, q);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn tree_serde() {
+        let mut p = PinIn::new();
+        p.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut names: Vec<String> = include_str!("dict.txt").lines().take(1500).map(|x| x[..x.find(':').unwrap()].to_string()).collect();
+        names.extend(["合金炉", "洗矿场", "", "合成台", "ICBM必杀技"].map(String::from));
+
+        for logic in [SearcherLogic::Begin, SearcherLogic::Contain, SearcherLogic::Equal] {
+            let mut tree = TreeSearcher::with_keys(logic, Arc::new(Accelerator::new()));
+            names.iter().enumerate().for_each(|(i, x)| tree.insert(&p, x, i));
+            tree.rename(&p, 0, "合金块");
+
+            let json = serde_json::to_string(&tree).unwrap();
+            let mut loaded: TreeSearcher<usize> = serde_json::from_str(&json).unwrap();
+            assert_eq!(loaded.len(), tree.len());
+            assert_eq!(loaded.key(1501), Some("洗矿场"));
+            for q in ["he", "hj", "hejinlu", "hejinkuai", "kc", "a", "yi", "ICBM", "bsj", "zzz"] {
+                assert_eq!(loaded.search_indices(&p, q), tree.search_indices(&p, q), "{:?} {}", logic, q);
+            }
+            loaded.insert(&p, "测试文本", 9999);
+            assert_eq!(loaded.search(&p, "ceshiwenben"), vec![&9999]);
+        }
+        assert!(serde_json::from_str::<TreeSearcher<usize>>("{}").is_err());
+    }
+
+    #[test]
+    fn spill_set() {
+        use crate::searcher::SpillSet;
+
+        let mut set = SpillSet::new(4);
+        set.reserve(10);
+        (0..1000).for_each(|i| set.insert((i * 7) % 50));
+        assert!(!set.is_truncated());
+        assert_eq!(set.into_sorted_vec(), (0..50).collect::<Vec<_>>());
+
+        for threshold in [4, 100] {
+            let mut set = SpillSet::new(threshold).with_cap(20);
+            (0..1000).for_each(|i| set.insert(i % 50));
+            let ret = set.into_sorted_vec();
+            assert_eq!(ret.len(), 20, "{}", threshold);
+            let mut set = SpillSet::new(threshold).with_cap(50);
+            (0..1000).for_each(|i| set.insert(i % 50));
+            assert!(!set.is_truncated());
+            assert_eq!(set.into_sorted_vec().len(), 50);
+        }
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        for (i, name) in ["合成", "洗矿场", "合金炉", "流体", "合成"].into_iter().enumerate() {
+            tree.insert(&pinin, name, i);
+        }
+        assert_eq!(tree.search_capped(&pinin, "he", 5), (vec![&0, &2, &4], false));
+        let (ret, truncated) = tree.search_capped(&pinin, "he", 2);
+        assert_eq!((ret.len(), truncated), (2, true));
+    }
+
+    #[test]
+    fn context_version() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        let mut simple = SimpleSearcher::new(SearcherLogic::Begin);
+        // Enough distinct first chars for the root to be accelerated
+        let names: Vec<String> = include_str!("dict.txt").lines().take(100).map(|x| x[..x.find(':').unwrap()].to_string() + "场").collect();
+        for name in names.iter().chain([&"中场".to_string()]) {
+            tree.insert(&pinin, name, name.clone());
+            simple.insert(&pinin, name, name.clone());
+        }
+        assert!(tree.search(&pinin, "zongc").is_empty());
+        assert!(simple.search(&pinin, "zongc").is_empty());
+
+        let version = pinin.version();
+        pinin.fuzzy.zh2z = true;
+        pinin.commit();
+        assert!(pinin.version() > version);
+        assert!(pinin.contains("中场", "zongc"));
+        // No reset needed
+        let mut ret = tree.search(&pinin, "zongc");
+        ret.sort();
+        assert!(ret.contains(&&"中场".to_string()));
+        assert_eq!(ret, simple.search(&pinin, "zongc"));
+        let len = ret.len();
+        tree.reset(&pinin);
+        assert_eq!(tree.search(&pinin, "zongc").len(), len);
+    }
+
+    #[test]
+    fn ticket() {
+        use crate::pinin::FuzzySettings;
+        use std::cell::Cell;
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        tree.insert(&pinin, "中场", 0);
+        assert!(tree.search(&pinin, "zongc").is_empty());
+
+        let reloads = Cell::new(0);
+        let mut ticket = pinin.ticket(|| reloads.set(reloads.get() + 1));
+        assert!(!ticket.renew(&pinin));
+        pinin.set_fuzzy(FuzzySettings { zh2z: true, ..Default::default() });
+        assert!(ticket.renew(&pinin));
+        assert!(!ticket.renew(&pinin));
+        assert_eq!(tree.search(&pinin, "zongc"), vec![&0]);
+
+        pinin.set_keyboard(&KEYBOARD_XIAOHE);
+        assert!(ticket.renew(&pinin));
+        assert_eq!(tree.search(&pinin, "vsih"), vec![&0]);
+        assert_eq!(reloads.get(), 2);
+    }
+
+    #[test]
+    fn filter() {
+        use crate::filter::Filter;
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut filter = Filter::new(TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new())));
+        for (i, name) in ["洗矿场", "合金炉", "合成", "流体", "轰20"].into_iter().enumerate() {
+            filter.insert(&pinin, name, i);
+        }
+
+        assert!(filter.set_query(&pinin, "h"));
+        assert_eq!(filter.visible_ids(), &[1, 2, 4]);
+        assert!(!filter.set_query(&pinin, "h"));
+        for (query, expected) in [("he", &[1, 2][..]), ("hej", &[1]), ("h", &[1, 2, 4]), ("hong2", &[4]), ("", &[0, 1, 2, 3, 4])] {
+            assert!(filter.set_query(&pinin, query));
+            assert_eq!(filter.visible_ids(), expected, "{}", query);
+            assert_eq!(filter.searcher().search_indices(&pinin, query), expected, "{}", query);
+        }
+
+        filter.set_query(&pinin, "liu");
+        filter.insert(&pinin, "流量", 5);
+        assert!(filter.set_query(&pinin, "liu"));
+        assert_eq!(filter.visible().collect::<Vec<_>>(), vec![&3, &5]);
+    }
+
+    #[test]
+    fn incremental() {
+        use crate::incremental::Incremental;
+        use std::ops::Range;
+
+        let mut p = PinIn::new();
+        p.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut names: Vec<String> = include_str!("dict.txt").lines().take(300).map(|x| x[..x.find(':').unwrap()].to_string()).collect();
+        names.extend(["合金炉", "合金", "洗矿场", "合成台", "合成", "ICBM必杀技", "中国人", "中国"].map(String::from));
+
+        for logic in [SearcherLogic::Begin, SearcherLogic::Contain, SearcherLogic::Equal, SearcherLogic::EndsWith] {
+            let mut tree = TreeSearcher::new(logic, Arc::new(Accelerator::new()));
+            names.iter().enumerate().for_each(|(i, x)| tree.insert(&p, x, i));
+            let frozen = tree.freeze();
+            let mut typing = Incremental::new(&frozen);
+            for query in ["hejinlu", "zhongguoren", "zgr", "icbm", "bisha", "he2cheng", "jinl", "a1"] {
+                for end in 0..=query.len() {
+                    typing.set_query(&p, &query[..end]);
+                    assert_eq!(typing.visible_ids(), frozen.search_indices(&p, &query[..end]), "{:?} {}", logic, &query[..end]);
+                }
+                // Taking keys back
+                for end in (0..query.len()).rev() {
+                    typing.set_query(&p, &query[..end]);
+                    assert_eq!(typing.visible_ids(), frozen.search_indices(&p, &query[..end]), "{:?} {}", logic, &query[..end]);
+                }
+            }
+        }
+
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        names.iter().enumerate().for_each(|(i, x)| tree.insert(&p, x, i));
+        let frozen = tree.freeze();
+        let mut typing = Incremental::new(&frozen);
+        assert!(typing.set_query(&p, "j"));
+        let first = typing.candidates();
+        assert!(typing.set_query(&p, "jinl"));
+        assert!(!typing.set_query(&p, "jinl"));
+        assert!(typing.candidates() < first);
+        assert_eq!(typing.visible().collect::<Vec<_>>(), vec![&300]);
+        assert_eq!(typing.spans(), [Range { start: 1, end: 3 }]);
+        typing.set_query(&p, "guoren");
+        assert_eq!(typing.visible().collect::<Vec<_>>(), vec![&306]);
+        assert_eq!(typing.spans(), [Range { start: 1, end: 3 }]);
+        typing.set_query(&p, "");
+        assert_eq!(typing.visible_ids().len(), names.len());
+        assert_eq!(typing.candidates(), 0);
+    }
+
+    #[test]
+    fn tokenizer() {
+        use crate::filter::Filter;
+        use crate::unicode_utils::word_starts;
+
+        let chars = |s: &str| s.chars().collect::<Vec<_>>();
+        assert_eq!(word_starts(&chars("ICBM必杀技")), vec![0, 4]);
+        assert_eq!(word_starts(&chars("HTMLParser v2")), vec![0, 4, 11, 12]);
+        assert_eq!(word_starts(&chars("itemStack")), vec![0, 4]);
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        tree.set_tokenizer(word_starts);
+        for (i, name) in ["ICBM必杀技", "ItemStack", "必杀", "杀技"].into_iter().enumerate() {
+            tree.insert(&pinin, name, i);
+        }
+        assert_eq!(tree.search_indices(&pinin, "bsj"), vec![0]);
+        assert_eq!(tree.search_indices(&pinin, "bs"), vec![0, 2]);
+        assert_eq!(tree.search_indices(&pinin, "ICBM"), vec![0]);
+        assert_eq!(tree.search_indices(&pinin, "Stack"), vec![1]);
+        assert_eq!(tree.search_indices(&pinin, "sj"), vec![3]);
+
+        let mut filter = Filter::new(tree);
+        filter.set_query(&pinin, "b");
+        assert_eq!(filter.visible_ids(), &[0, 2]);
+        filter.set_query(&pinin, "bsj");
+        assert_eq!(filter.visible_ids(), &[0]);
+    }
+
+    #[test]
+    fn threads() {
+        fn shareable<T: Send + Sync>() {}
+        shareable::<PinIn>();
+        shareable::<Accelerator>();
+        shareable::<TreeSearcher<String>>();
+        shareable::<SimpleSearcher<String>>();
+        shareable::<crate::micro::MicroSearcher<String>>();
+        shareable::<crate::frozen::FrozenSearcher<String>>();
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        let names = ["洗矿场", "合金炉", "合成", "流体", "轰20"];
+        for i in 0..3000 {
+            tree.insert(&pinin, &format!("{}{}", names[i % names.len()], i), i);
+        }
+
+        let queries = ["hej", "liu2", "hong2", "hcheng12"];
+        let expected: Vec<Vec<usize>> = queries.iter().map(|q| tree.search_indices(&pinin, q)).collect();
+        assert!(expected.iter().all(|x| !x.is_empty()));
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let (pinin, tree, expected) = (&pinin, &tree, &expected);
+                s.spawn(move || {
+                    for i in 0..20 {
+                        let j = (i + t) % queries.len();
+                        assert_eq!(&tree.search_indices(pinin, queries[j]), &expected[j]);
+                        assert!(pinin.contains("合金炉", "hjl"));
+                        assert_eq!(pinin.contains("合金炉", queries[j]), j == 0);
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn micro_searcher() {
+        use crate::micro::{AutoSearcher, MicroSearcher};
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let names = ["洗矿场", "合金炉", "合成", "合金炉", "流体", "轰20"];
+        let mut micro = MicroSearcher::new(SearcherLogic::Contain);
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        let mut auto: AutoSearcher<usize, 3> = AutoSearcher::new(SearcherLogic::Contain);
+        for (i, name) in names.into_iter().enumerate() {
+            micro.insert(&pinin, name, i);
+            tree.insert(&pinin, name, i);
+            auto.insert(&pinin, name, i);
+        }
+        assert!(matches!(auto, AutoSearcher::Tree(_)));
+
+        assert_eq!(micro.search(&pinin, "he"), vec![&1, &2, &3]);
+        for query in ["he", "hjl", "kc", "", "hong2", "zz"] {
+            let a: Vec<_> = micro.search(&pinin, query);
+            let b: Vec<_> = tree.search(&pinin, query);
+            let c: Vec<_> = auto.search(&pinin, query);
+            assert_eq!(a, b, "{}", query);
+            assert_eq!(a, c, "{}", query);
+            assert_eq!(micro.count(&pinin, query), a.len());
+        }
+    }
+
+    #[test]
+    fn collectors() {
+        /// Keeps the two smallest indices.
+        #[derive(Default)]
+        struct TopTwo(BinaryHeap<usize>);
+
+        impl Collection<usize> for TopTwo {
+            fn insert(&mut self, data: usize) {
+                self.0.push(data);
+                if self.0.len() > 2 {
+                    self.0.pop();
+                }
+            }
+        }
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        for (i, name) in ["合合", "洗矿场", "合成", "合金炉", "合"].into_iter().enumerate() {
+            tree.insert(&pinin, name, i);
+        }
+
+        let mut ret = Dedup::new(Vec::new());
+        tree.search_ids_into(&pinin, "he", &mut ret);
+        let mut ret = ret.into_inner();
+        ret.sort();
+        assert_eq!(ret, vec![0, 2, 3, 4]);
+
+        let mut ret = Dedup::new(TopTwo::default());
+        tree.search_ids_into(&pinin, "he", &mut ret);
+        assert_eq!(ret.into_inner().0.into_sorted_vec(), vec![0, 2]);
+
+        // Entries 0 and 4 stand for the same object
+        let mut ret = DedupBy::new(Vec::new(), |i| if i == 4 { 0 } else { i });
+        tree.search_ids_into(&pinin, "he", &mut ret);
+        assert_eq!(ret.into_inner().len(), 3);
+    }
+
+    #[test]
+    fn count_and_any() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        let mut simple = SimpleSearcher::new(SearcherLogic::Contain);
+        for (i, name) in ["合金炉", "洗矿场", "合成", "合合"].into_iter().enumerate() {
+            tree.insert(&pinin, name, i);
+            simple.insert(&pinin, name, i);
+        }
+
+        for query in ["h", "he", "hh", "kc", "", "zz"] {
+            assert_eq!(tree.count(&pinin, query), tree.search(&pinin, query).len());
+            assert_eq!(simple.count(&pinin, query), simple.search(&pinin, query).len());
+            assert_eq!(tree.any(&pinin, query), !tree.search(&pinin, query).is_empty());
+            assert_eq!(simple.any(&pinin, query), !simple.search(&pinin, query).is_empty());
+        }
+        assert_eq!(tree.count(&pinin, "h"), 3);
+    }
+
+    #[cfg(feature = "verify")]
+    #[test]
+    fn verify_keyboards() {
+        use crate::keyboard::KEYBOARD_QUANPIN;
+        use crate::verify::{syllables, verify};
+
+        let inventory = syllables();
+        assert!(inventory.contains("zhuang"));
+        assert!(verify(&KEYBOARD_QUANPIN, &inventory).is_ok());
+        assert!(verify(&KEYBOARD_DAQIAN, &inventory).is_ok());
+        // "lo" and "luo" share keys in both layouts, as in their IMEs
+        for keyboard in [&*KEYBOARD_XIAOHE, &*KEYBOARD_ZIRANMA] {
+            let report = verify(keyboard, &inventory);
+            assert!(report.unreachable.is_empty());
+            assert_eq!(report.collisions, vec![("lo".to_string(), vec!["lo".to_string(), "luo".to_string()])]);
+        }
+    }
+
+    #[cfg(feature = "verify")]
+    #[test]
+    fn keyboard_conformance() {
+        use crate::keyboard::KEYBOARD_QUANPIN;
+        use crate::verify::{conformance, vectors, TestVector, XIAOHE_VECTORS};
+
+        for keyboard in [&*KEYBOARD_QUANPIN, &*KEYBOARD_DAQIAN, &*KEYBOARD_XIAOHE, &*KEYBOARD_ZIRANMA] {
+            assert!(!vectors(keyboard).is_empty());
+            assert!(conformance(keyboard, vectors(keyboard)).is_ok());
+        }
+
+        let custom: &'static Keyboard = Box::leak(Box::new(Keyboard::new(None, None, ZeroInitial::Keep, false, true)));
+        assert!(vectors(custom).is_empty());
+        // The second vector is wrong, "hej" does match
+        let own = [TestVector::hit("合金炉", "hjl"), TestVector::miss("合金炉", "hej")];
+        assert_eq!(conformance(custom, &own).failures, vec![own[1]]);
+        assert!(!conformance(custom, XIAOHE_VECTORS).is_ok());
+    }
+
+    #[test]
+    fn index_set_ops() {
+        let mut a = IndexSet::zero();
+        a.union(IndexSet::one());
+        assert!(a.get(0) && a.get(1));
+
+        let mut b = IndexSet::zero();
+        b.merge(IndexSet::one());
+        assert!(!b.get(0) && b.get(1));
+
+        let mut c = IndexSet::from(0b101);
+        c.replace(IndexSet::one());
+        assert_eq!(c, IndexSet::one());
+
+        let mut d = IndexSet::none();
+        d.union(IndexSet::zero());
+        d.union(IndexSet::from(0b100));
+        assert_eq!(d, IndexSet::from(0b101));
+    }
+
+    #[test]
+    fn stable_ids() {
+        let load = || {
+            let mut pinin = PinIn::new();
+            pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+            pinin.load_dict(Box::new("中: zhong4, zhong5")).unwrap();
+            pinin
+        };
+        let (a, b) = (load(), load());
+        assert_eq!(a.pinyins.len(), b.pinyins.len());
+        for (raw, p) in a.pinyins.iter() {
+            assert_eq!(b.pinyins[raw].id, p.id, "{}", raw);
+        }
+        assert_eq!(a.pinyins["zhong5"].id, a.pinyins.len() - 1);
+        assert_eq!(a.romanized("zhong1guo2"), b.romanized("zhong1guo2"));
+    }
+
+    #[test]
+    fn intern_pool() {
+        use crate::intern::InternPool;
+
+        let pool = InternPool::new();
+        let load = |sh2s: bool| {
+            let mut pinin = PinIn::new();
+            pinin.fuzzy.sh2s = sh2s;
+            pinin.set_intern_pool(pool.clone());
+            pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+            pinin
+        };
+        let (mut a, b, c) = (load(false), load(false), load(true));
+        assert!(Arc::ptr_eq(&a.chars, &b.chars) && Arc::ptr_eq(&a.pinyins, &b.pinyins));
+        assert!(!Arc::ptr_eq(&a.chars, &c.chars));
+        assert!(Arc::ptr_eq(&a.romanized, &c.romanized));
+        assert_eq!(pool.len(), 5);
+        assert!(c.contains("石头", "sitou") && !b.contains("石头", "sitou"));
+
+        a.load_dict(Box::new("中: zhong4, zhong5")).unwrap();
+        assert!(!Arc::ptr_eq(&a.chars, &b.chars));
+        assert!(a.contains("中", "zhong5") && !b.contains("中", "zhong5"));
+
+        // Changing the settings back shares the tables again
+        let mut d = load(true);
+        d.fuzzy.sh2s = false;
+        d.commit();
+        assert!(Arc::ptr_eq(&d.chars, &b.chars));
+        drop((a, b, c, d));
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn search_hits() {
+        use crate::searcher::SearchHit;
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        for (i, name) in ["测试文本", "洗矿场", "a  文本"].into_iter().enumerate() {
+            tree.insert(&pinin, name, i);
+        }
+        assert_eq!(tree.search_hits(&pinin, "wenb"), vec![
+            SearchHit { id: &0, entry_index: 0, matched_len: 2, span: 6..12 },
+            SearchHit { id: &2, entry_index: 2, matched_len: 2, span: 0..9 },
+        ]);
+        assert_eq!(tree.search_hits(&pinin, "kc")[0].span, 3..9);
+
+        let mut tree = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        tree.insert(&pinin, "测试文本", 0);
+        assert_eq!(tree.search_hits(&pinin, "ces")[0].span, 0..6);
+    }
+
+    #[test]
+    fn search_options() {
+        use crate::searcher::SearchOptions;
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        for (i, name) in ["合金炉", "合成台", "合金炉的升级版本"].into_iter().enumerate() {
+            tree.insert(&pinin, name, i);
+        }
+        let short = SearchOptions { max_len: Some(3), ..Default::default() };
+        assert_eq!(tree.search_with(&pinin, "he", &short), vec![&0, &1]);
+        let spanning = SearchOptions { min_span: 2, ..Default::default() };
+        assert!(tree.search_with(&pinin, "h", &spanning).is_empty());
+        assert_eq!(tree.search_with(&pinin, "hj", &spanning), vec![&0, &2]);
+        assert_eq!(tree.search_with(&pinin, "hj", &SearchOptions::default()), tree.search(&pinin, "hj"));
+
+        assert!(tree.rename(&pinin, 0, "合金炉子"));
+        assert_eq!(tree.search_with(&pinin, "hj", &SearchOptions { max_len: Some(4), min_span: 2 }), vec![&0]);
+    }
+
+    #[test]
+    fn try_insert() {
+        use crate::ingest::{InsertError, Sanitization};
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        let clean = tree.try_insert(&pinin, "合金炉", 0).unwrap();
+        assert!(clean.is_clean());
+        let dirty = tree.try_insert(&pinin, " 合成\0台\t 本\u{7}", 1).unwrap();
+        assert_eq!(dirty.indexed, "合成台 本");
+        assert_eq!(dirty.sanitized, vec![Sanitization::ControlChars(2), Sanitization::Whitespace]);
+        assert_eq!(tree.try_insert(&pinin, " \0\n", 2), Err(InsertError::Empty));
+        assert_eq!(tree.try_insert(&pinin, "炉\u{F0000}", 3), Err(InsertError::Reserved('\u{F0000}')));
+        assert_eq!(tree.search(&pinin, "hc"), vec![&1]);
+        assert_eq!(tree.search(&pinin, "h").len(), 2);
+    }
+
+    #[test]
+    fn ends_with() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        assert!(pinin.ends("测试文本", "wenben"));
+        assert!(pinin.ends("测试文本", "wb"));
+        assert!(pinin.ends("测试文本", ""));
+        assert!(!pinin.ends("测试文本", "wenbe"));
+        assert!(!pinin.ends("测试文本", "ceshi"));
+
+        let names = ["报告.txt", "测试文本", "文本文件", "合成表.txt"];
+        let mut tree = TreeSearcher::new(SearcherLogic::EndsWith, Arc::new(Accelerator::new()));
+        let mut simple = SimpleSearcher::new(SearcherLogic::EndsWith);
+        let mut micro = crate::micro::MicroSearcher::new(SearcherLogic::EndsWith);
+        for (i, name) in names.into_iter().enumerate() {
+            tree.insert(&pinin, name, i);
+            simple.insert(&pinin, name, i);
+            micro.insert(&pinin, name, i);
+        }
+        for (query, expected) in [(".txt", vec![0, 3]), ("wenben", vec![1]), ("wb", vec![1]), ("wj", vec![2]), ("wen", vec![])] {
+            let sorted = |mut x: Vec<usize>| { x.sort_unstable(); x };
+            assert_eq!(tree.search_indices(&pinin, query), expected, "{}", query);
+            assert_eq!(simple.search_indices(&pinin, query), expected, "{}", query);
+            assert_eq!(sorted(micro.search(&pinin, query).into_iter().copied().collect()), expected, "{}", query);
+        }
+        assert_eq!(tree.search_hits(&pinin, "txt")[0].span, 7..10);
+
+        // Large enough for accelerated nodes
+        let mut tree = TreeSearcher::new(SearcherLogic::EndsWith, Arc::new(Accelerator::new()));
+        let names: Vec<String> = include_str!("dict.txt").lines().take(300).map(|x| "文".to_string() + &x[..x.find(':').unwrap()]).collect();
+        names.iter().enumerate().for_each(|(i, x)| tree.insert(&pinin, x, i));
+        for (i, name) in names.iter().enumerate().step_by(37) {
+            let last: String = name.chars().last().into_iter().collect();
+            assert!(tree.search_indices(&pinin, &last).contains(&i), "{}", name);
+            assert!(!tree.search_indices(&pinin, "文").contains(&i), "{}", name);
+        }
+
+        // Dense nodes reached with the query used up hold longer names too
+        let mut tree = TreeSearcher::new(SearcherLogic::Equal, Arc::new(Accelerator::new()));
+        names.iter().enumerate().for_each(|(i, x)| tree.insert(&pinin, x, i));
+        assert!(tree.search_indices(&pinin, "文").is_empty());
+    }
+
+    #[test]
+    fn persist() {
+        use crate::persist::PersistError;
+
+        let mut pinin = PinIn::new();
+        pinin.keyboard = &KEYBOARD_XIAOHE;
+        pinin.fuzzy.sh2s = true;
+        pinin.fuzzy.neutral_tone = crate::pinin::NeutralTone::Toneless;
+        pinin.romanized_targets = true;
+        pinin.extra_keyboards = vec![&KEYBOARD_DAQIAN];
+        let time = std::time::Instant::now();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let parse = time.elapsed();
+        pinin.load_dict(Box::new("㐀: qiu1")).unwrap();
+        let bytes = pinin.to_bytes().unwrap();
+        let time = std::time::Instant::now();
+        let hydrated = PinIn::from_bytes(&bytes).unwrap();
+        let hydrate = time.elapsed();
+        // Several times faster in practice, well under 50 ms in release
+        assert!(hydrate < parse, "{:?} {:?}", hydrate, parse);
+
+        assert!(std::ptr::eq(hydrated.keyboard, &*KEYBOARD_XIAOHE));
+        assert!(hydrated.fuzzy.sh2s && !hydrated.fuzzy.zh2z);
+        assert!(hydrated.romanized_targets);
+        assert_eq!(hydrated.pinyins.len(), pinin.pinyins.len());
+        for (raw, p) in pinin.pinyins.iter() {
+            assert_eq!(hydrated.pinyins[raw], *p, "{}", raw);
+        }
+        assert!(pinin.pinyins.values().any(|x| !x.alternates.is_empty()));
+        // Chars share their readings instead of holding copies
+        let ce = hydrated.get_character('测');
+        assert!(Arc::ptr_eq(&ce.pinyin[0], &hydrated.pinyins[&ce.pinyin[0].raw]));
+        for (s1, s2) in [("测试文本", "cesi"), ("测试文本", "ceui"), ("㐀", "qq"), ("的", "de"), ("zhong1guo2", "vsgo")] {
+            assert_eq!(hydrated.contains(s1, s2), pinin.contains(s1, s2), "{} {}", s1, s2);
+        }
+        assert!(hydrated.contains("㐀", "qq"));
+        assert_eq!(hydrated.to_bytes().unwrap(), bytes);
+
+        for len in 0..bytes.len().min(200) {
+            assert!(PinIn::from_bytes(&bytes[..len]).is_err());
+        }
+        assert_eq!(PinIn::from_bytes(b"hello world").err(), Some(PersistError::Magic));
+        let mut newer = bytes.clone();
+        newer[6] = 9;
+        assert_eq!(PinIn::from_bytes(&newer).err(), Some(PersistError::Version(9)));
+
+        pinin.keyboard = Box::leak(Box::new(Keyboard::new(None, None, ZeroInitial::Keep, false, true)));
+        assert_eq!(pinin.to_bytes().err(), Some(PersistError::CustomKeyboard));
+    }
+
+    #[test]
+    fn interned_names() {
+        use crate::compressed::Compressor;
+        use crate::sync::read;
+
+        let mut names = Compressor::default();
+        names.set_interning(true);
+        let (a, b, c) = (names.push("mod.rs"), names.push("lib.rs"), names.push("mod.rs"));
+        assert_eq!((a, b, c), (0, 7, 0));
+        assert_eq!(names.chars.len(), 14);
+        assert_eq!(names.entry(2).as_deref(), Some("mod.rs"));
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let mut tree = TreeSearcher::new(SearcherLogic::Contain, Arc::new(Accelerator::new()));
+        tree.set_interning(true);
+        for (i, name) in ["测试", "文本", "测试", "测试文本"].into_iter().enumerate() {
+            tree.insert(&pinin, name, i);
+        }
+        assert_eq!(read(&tree.compressor).chars.len(), 11);
+        assert_eq!(tree.search(&pinin, "ceshi"), vec![&0, &2, &3]);
+        assert!(tree.rename(&pinin, 2, "文本"));
+        assert_eq!(tree.search(&pinin, "wenben"), vec![&1, &2, &3]);
+        assert_eq!(tree.name(2).as_deref(), Some("文本"));
+
+        tree.retain(&pinin, &mut |_, x| *x != 0);
+        assert!(read(&tree.compressor).interning());
+        assert_eq!(tree.search(&pinin, "wb"), vec![&1, &2, &3]);
+    }
+
+    #[test]
+    fn front_coded_names() {
+        use crate::accelerator::CharProvider;
+        use crate::compressed::Compressor;
+        use crate::sync::read;
+
+        let paths = ["资源/模型/方块/合金炉.json", "资源/模型/方块/洗矿场.json", "资源/模型/物品/流体.json", "资源/模型/物品/流体管道.json", "测试"];
+        let (mut names, mut plain) = (Compressor::default(), Compressor::default());
+        names.set_front_coding(true);
+        for path in paths {
+            assert_eq!(names.push(path), plain.push(path));
+        }
+        assert!(names.chars.len() < plain.chars.len());
+        assert_eq!(names.flat(), plain.flat());
+        assert_eq!(names.len(), plain.len());
+        assert!((0..plain.len() + 1).all(|i| names.end(i) == plain.end(i)));
+        assert_eq!(names.entry(3).as_deref(), Some(paths[3]));
+        names.append(&plain);
+        assert_eq!(names.entry(8).as_deref(), Some(paths[3]));
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        for logic in [SearcherLogic::Begin, SearcherLogic::Contain, SearcherLogic::Equal] {
+            let new = |front_coding| {
+                let mut tree = TreeSearcher::new(logic, Arc::new(Accelerator::new()));
+                tree.set_front_coding(front_coding);
+                paths.iter().enumerate().for_each(|(i, x)| tree.insert(&pinin, x, i));
+                tree
+            };
+            let (mut tree, mut all) = (new(true), new(false));
+            tree.merge(&pinin, new(true));
+            all.merge(&pinin, new(false));
+            assert!(tree.rename(&pinin, 6, "资源/模型/方块/流体.json"));
+            assert!(all.rename(&pinin, 6, "资源/模型/方块/流体.json"));
+            assert!(read(&tree.compressor).chars.len() < read(&all.compressor).chars.len());
+            // Enough names under one prefix to split the dense nodes
+            for i in 0..600 {
+                let path = format!("资源/模型/方块/{}{}.json", ["合金", "矿石", "流体"][i % 3], i);
+                tree.insert(&pinin, &path, paths.len() * 2 + i);
+                all.insert(&pinin, &path, paths.len() * 2 + i);
+            }
+            for query in ["ziyuan", "mxfk", "liuti", "ltgd", "ceshi", "json", "hejin1", "kuangshi12"] {
+                assert_eq!(tree.search(&pinin, query), all.search(&pinin, query), "{query}");
+            }
+            let (tree, all) = (tree.freeze(), all.freeze());
+            assert_eq!(tree.search(&pinin, "liuti"), all.search(&pinin, "liuti"));
+        }
+    }
+
+    #[test]
+    fn shared_names() {
+        use crate::compressed::Compressor;
+        use crate::persist::PersistError;
+        use crate::sync::read;
+        use std::sync::RwLock;
+
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        let names = ["测试文本", "文本测试", "  拼音  输入法 ", "pinyin", "测试"];
+        let mut begin = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
+        names.iter().enumerate().for_each(|(i, x)| begin.insert(&pinin, x, i));
+        begin.rename(&pinin, 4, "拼写");
+
+        let bytes = read(&begin.share_names()).to_bytes();
+        let pool = Arc::new(RwLock::new(Compressor::from_bytes(&bytes).unwrap()));
+        assert_eq!(read(&pool).to_bytes(), bytes);
+        assert_eq!(read(&pool).original(2).as_deref(), Some("  拼音  输入法 "));
+        let objects: Vec<usize> = (0..names.len()).collect();
+        let stored = TreeSearcher::with_names(&pinin, SearcherLogic::Begin, Arc::new(Accelerator::new()), pool.clone(), objects.clone());
+        let contain = TreeSearcher::with_names(&pinin, SearcherLogic::Contain, Arc::new(Accelerator::new()), pool.clone(), objects.clone());
+        for query in ["ceshi", "wenben", "pinyin", "shurufa", "pinxie", "ce"] {
+            assert_eq!(stored.search(&pinin, query), begin.search(&pinin, query), "{}", query);
+        }
+        assert_eq!(contain.search(&pinin, "wenben"), vec![&0, &1]);
+        assert_eq!(contain.search(&pinin, "shurufa"), vec![&2]);
+
+        // Changing one searcher leaves the others' names alone
+        let mut shared = TreeSearcher::with_names(&pinin, SearcherLogic::Begin, Arc::new(Accelerator::new()), pool.clone(), objects);
+        shared.insert(&pinin, "文本", 5);
+        shared.rename(&pinin, 0, "输入");
+        assert_eq!(shared.search(&pinin, "wenben"), vec![&1, &5]);
+        assert_eq!(read(&pool).offsets.len(), names.len());
+        assert_eq!(contain.search(&pinin, "ceshi"), vec![&0, &1]);
+        assert_eq!(contain.freeze().search(&pinin, "ceshi"), vec![&0, &1]);
+        assert_eq!(read(&pool).offsets.len(), names.len());
+
+        assert_eq!(Compressor::from_bytes(&pinin.to_bytes().unwrap()).err(), Some(PersistError::Magic));
+        for len in 0..bytes.len() {
+            assert!(Compressor::from_bytes(&bytes[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn minimal() {
+        let mut pinin = PinIn::minimal();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        assert!(pinin.contains("测试文本", "ceshi"));
+        assert!(pinin.contains("测试文本", "ceshi"));
+        assert!(pinin.begins("测试文本", "ce"));
+        assert!(!pinin.matches("测试文本", "ceshi"));
+    }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn match_ranges() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+        assert_eq!(pinin.match_ranges("测试文本", "ceshi"), Some(vec![0..6]));
+        assert_eq!(pinin.match_ranges("测试文本", "shiwb"), Some(vec![3..12]));
+        assert_eq!(pinin.match_ranges("测试文本", "wenben"), Some(vec![6..12]));
+        assert_eq!(pinin.match_ranges("ab测试", "b测"), Some(vec![1..5]));
+        assert_eq!(pinin.match_ranges("测试文本", "wc"), None);
+        assert_eq!(pinin.match_ranges("测试", ""), Some(vec![]));
+        assert_eq!(pinin.match_ranges("", ""), Some(vec![]));
+
+        // The fewest chars at the leftmost position
+        assert_eq!(pinin.match_ranges("西安先", "xian"), Some(vec![0..6]));
+        assert_eq!(pinin.match_ranges("先西安", "xian"), Some(vec![0..3]));
+
+        // Ranges are those of the target as given, whatever it is matched as
+        pinin.fold = true;
+        pinin.fold_case = true;
+        pinin.romanized_targets = true;
+        for (s1, s2, expected) in [
+            ("第２集", "di2ji", vec![0..9]),
+            ("Ωmega", "ω", vec![0..2]),
+            ("ΩMEGA", "ωmega", vec![0..6]),
+            ("x zhong1guo2", "guo", vec![8..12]),
+            ("\u{F0001}a", "a", vec![4..5]),
+        ] {
+            assert!(pinin.contains(s1, s2), "{} {}", s1, s2);
+            assert_eq!(pinin.match_ranges(s1, s2), Some(expected), "{} {}", s1, s2);
+            assert!(!pinin.match_alternatives(s1, s2).is_empty(), "{} {}", s1, s2);
+        }
+        let steps = &pinin.match_alternatives("第２集", "DI２")[0].steps;
+        assert_eq!(steps.iter().map(|x| (x.target.clone(), x.query.clone())).collect::<Vec<_>>(), vec![(0..3, 0..2), (3..6, 2..5)]);
+    }
+
+    #[cfg(feature = "embedded-dict")]
+    #[test]
+    fn renderings() {
+        use crate::format::{renderings, Rendering};
+        use std::collections::HashMap;
+
+        let table = renderings();
+        assert_eq!(table.len(), crate::format::syllables().len() * 5);
+        assert!(table.contains(&Rendering {
+            syllable: "lv".into(),
+            tone: 4,
+            number: "lv4".into(),
+            raw: "lv".into(),
+            unicode: "lǜ".into(),
+            phonetic: "ㄌㄩˋ".into(),
+        }));
+
+        // Every format tells all toned syllables apart, so display text
+        // maps back to the reading it came from
+        let formats: [fn(&Rendering) -> &str; 3] = [|x| &x.number, |x| &x.unicode, |x| &x.phonetic];
+        for format in formats {
+            let mut back: HashMap<&str, Vec<&str>> = HashMap::new();
+            table.iter().for_each(|x| back.entry(format(x)).or_default().push(&x.number));
+            let collisions: Vec<_> = back.values().filter(|x| x.len() > 1).collect();
+            assert!(collisions.is_empty(), "{:?}", collisions);
+        }
+    }
+
+    #[test]
+    pub fn format() {
+        let mut pinin = PinIn::new();
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
+
+        let ch = pinin.chars[&'圆'].as_ref().unwrap();
+        let py = &ch.pinyin[0];
+
+        assert_str_eq!(number_format(py), "yuan2");
+        assert_str_eq!(raw_format(py), "yuan");
+        assert_str_eq!(unicode_format(py), "yuán");
+        assert_str_eq!(phonetic_format(py), "ㄩㄢˊ");
+
+        let py = &pinin.chars[&'中'].as_ref().unwrap().pinyin[0];
+        assert_str_eq!(unicode_format(py), "zhōng");
+        assert_str_eq!(phonetic_format(py), "ㄓㄨㄥ");
+        let py = &pinin.chars[&'快'].as_ref().unwrap().pinyin[0];
+        assert_str_eq!(unicode_format(py), "kuài");
+        assert_str_eq!(phonetic_format(py), "ㄎㄨㄞˋ");
+    }
+
+    #[test]
+    fn substring() {
+        let s = "a\u{301}bc";
+        assert_eq!(s.substring_checked(0, 2), Some("a\u{301}b"));
+        assert_eq!(s.substring_checked(1, 2), Some("bc"));
+        assert_eq!(s.substring_checked(3, 0), Some(""));
+        assert_eq!(s.substring_checked(2, 2), None);
+        assert_eq!(s.substring_checked(4, 0), None);
+        assert_eq!(s.substring_saturating(1, 10), "bc");
+        assert_eq!(s.substring_saturating(7, 1), "");
+
+        let owned = s.to_string();
+        let seg: SegmentedStr = owned.as_str().into();
+        assert_eq!(seg.substring_checked(1, 2), Some("bc"));
+        assert_eq!(seg.substring_checked(2, 2), None);
+        assert_eq!(seg.substring_saturating(0, 9), s);
+
+        let seg = SegmentedStr::from("ce\u{301}测试文本");
+        assert_eq!(seg.len(), 6);
+        assert_eq!(seg.graphemes().nth(1), Some("e\u{301}"));
+        assert_eq!(seg.byte_range(2, 2), 4..10);
+        let sub = seg.slice(1, 3);
+        assert_eq!(sub, SegmentedStr::from("e\u{301}测试"));
+        assert_eq!(sub.substring(1, 9), "测试");
+        assert_eq!(sub.slice(2, 9).as_str(), "试");
+        assert!(seg.slice(9, 1).is_empty());
+        assert_eq!(SegmentedStr::from_offsets(seg.as_str(), &seg.offsets()), seg);
+        assert!(SegmentedStr::from_offsets("", &[]).is_empty());
     }
 
     #[test]
     pub fn full() {
         let mut pinin = PinIn::new();
-        pinin.load_dict(Box::new(include_str!("dict.txt")));
+        pinin.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
 
         let mut ss: Vec<Box<dyn Searcher<i32>>> = vec![
-            Box::new(TreeSearcher::new(SearcherLogic::Equal, Rc::new(Accelerator::new()))),
+            Box::new(TreeSearcher::new(SearcherLogic::Equal, Arc::new(Accelerator::new()))),
             Box::new(SimpleSearcher::new(SearcherLogic::Equal))
         ];
 
@@ -173,11 +2947,11 @@ mod tests {
         let mut context = PinIn::new();
         {
             let time = std::time::Instant::now();
-            context.load_dict(Box::new(include_str!("dict.txt")));
+            context.load_dict(Box::new(include_str!("dict.txt"))).unwrap();
             println!("load dict took {:?}", std::time::Instant::now() - time);
         }
 
-        let mut searcher = TreeSearcher::new(SearcherLogic::Begin, context.accelerator.clone().unwrap());
+        let mut searcher = TreeSearcher::new(SearcherLogic::Begin, Arc::new(Accelerator::new()));
 
         let lines: Vec<_> = SMALL.lines().collect();
 
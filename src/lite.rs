@@ -0,0 +1,36 @@
+//! One-line matching against a lazily initialized default context.
+//!
+//! The context uses quanpin with the embedded dictionary and no fuzzy
+//! settings. It is created once per process on first use and shared by
+//! all threads.
+
+use crate::pinin::PinIn;
+use std::sync::OnceLock;
+
+static CONTEXT: OnceLock<PinIn> = OnceLock::new();
+
+/// The default context, see [`crate::lite`].
+pub fn context() -> &'static PinIn {
+    CONTEXT.get_or_init(|| {
+        let mut pinin = PinIn::new();
+        pinin.load_default_dict();
+        pinin
+    })
+}
+
+pub fn contains(s1: &str, s2: &str) -> bool {
+    context().contains(s1, s2)
+}
+
+pub fn begins(s1: &str, s2: &str) -> bool {
+    context().begins(s1, s2)
+}
+
+pub fn matches(s1: &str, s2: &str) -> bool {
+    context().matches(s1, s2)
+}
+
+/// Runs `f` with the default context, e.g. to feed it into a searcher.
+pub fn with_context<R>(f: impl FnOnce(&PinIn) -> R) -> R {
+    f(context())
+}
@@ -0,0 +1,96 @@
+use crate::accelerator::Accelerator;
+use crate::pinin::PinIn;
+use crate::searcher::{Searcher, SearcherLogic, TreeSearcher};
+use rustc_hash::FxHashMap;
+use std::sync::Arc;
+
+/// A map from names to values that can be filtered by pinyin.
+///
+/// Keys are unique as in a `HashMap`. Removal leaves a hole in the index
+/// that is skipped by queries; the index is rebuilt once holes make up
+/// half of it.
+pub struct PinyinMap<K: AsRef<str>, V> {
+    logic: SearcherLogic,
+    searcher: TreeSearcher<usize>,
+    slots: Vec<Option<(K, V)>>,
+    keys: FxHashMap<String, usize>,
+}
+
+impl<K: AsRef<str>, V> PinyinMap<K, V> {
+    pub fn new(logic: SearcherLogic) -> Self {
+        PinyinMap {
+            logic,
+            searcher: TreeSearcher::new(logic, Arc::new(Accelerator::new())),
+            slots: Vec::new(),
+            keys: Default::default(),
+        }
+    }
+
+    /// Inserts `value` under `key`, returning the value it replaces.
+    pub fn insert(&mut self, context: &PinIn, key: K, value: V) -> Option<V> {
+        if let Some(slot) = self.keys.get(key.as_ref()) {
+            let (_, old) = self.slots[*slot].replace((key, value))?;
+            return Some(old);
+        }
+
+        let slot = self.slots.len();
+        self.searcher.insert(context, key.as_ref(), slot);
+        self.keys.insert(key.as_ref().to_string(), slot);
+        self.slots.push(Some((key, value)));
+        None
+    }
+
+    pub fn remove(&mut self, context: &PinIn, key: &str) -> Option<V> {
+        let slot = self.keys.remove(key)?;
+        let (_, ret) = self.slots[slot].take()?;
+        if self.keys.len() * 2 < self.slots.len() {
+            self.rebuild(context);
+        }
+        Some(ret)
+    }
+
+    fn rebuild(&mut self, context: &PinIn) {
+        let slots = std::mem::take(&mut self.slots);
+        self.searcher = TreeSearcher::new(self.logic, Arc::new(Accelerator::new()));
+        self.keys.clear();
+        for (key, value) in slots.into_iter().flatten() {
+            self.insert(context, key, value);
+        }
+    }
+
+    /// The value stored under exactly `key`.
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.slots[*self.keys.get(key)?].as_ref().map(|(_, v)| v)
+    }
+
+    /// Values whose key matches `query`, in insertion order.
+    pub fn get_fuzzy(&self, context: &PinIn, query: &str) -> Vec<&V> {
+        self.search(context, query).into_iter().map(|(_, v)| v).collect()
+    }
+
+    /// Entries whose key matches `query`, in insertion order.
+    pub fn search(&self, context: &PinIn, query: &str) -> Vec<(&K, &V)> {
+        // Slots are inserted in order, so the searcher's order is theirs
+        self.searcher
+            .search(context, query)
+            .into_iter()
+            .filter_map(|i| self.slots[*i].as_ref().map(|(k, v)| (k, v)))
+            .collect()
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.keys.contains_key(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.slots.iter().flatten().map(|(k, v)| (k, v))
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
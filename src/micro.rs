@@ -0,0 +1,225 @@
+use crate::accelerator::Accelerator;
+use crate::compressed::Compressor;
+use crate::pinin::PinIn;
+use crate::searcher::{Collection, Objects, Searcher, SearcherLogic, SearcherMut, TreeSearcher};
+use crate::sync::{lock, read, write};
+use std::borrow::Cow;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// A searcher for small indexes such as menus and settings pages.
+///
+/// Names are kept in one flat buffer, sorted, and every query scans them
+/// all with an accelerator. Building one costs next to nothing and equal
+/// names are only matched once. `search` gives insertion order, as a
+/// [`TreeSearcher`] does, while `search_into` feeds matches in name order.
+pub struct MicroSearcher<T> {
+    objects: Vec<T>,
+    /// Object indices, ordered by name.
+    order: Vec<usize>,
+    accelerator: Accelerator,
+    compressor: Arc<RwLock<Compressor>>,
+    logic: SearcherLogic,
+    version: Mutex<Option<u64>>,
+}
+
+impl<T> MicroSearcher<T> {
+    pub fn new(logic: SearcherLogic) -> Self {
+        let compressor = Arc::new(RwLock::new(Compressor::default()));
+        let accelerator = Accelerator::with_provider(compressor.clone());
+        MicroSearcher {
+            objects: Vec::new(),
+            order: Vec::new(),
+            accelerator,
+            compressor,
+            logic,
+            version: Mutex::new(None),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    fn name(compressor: &Compressor, index: usize) -> Cow<'_, [char]> {
+        compressor.chars_at(compressor.offsets[index])
+    }
+
+    /// Takes the objects out with the names they were inserted with.
+    pub fn into_entries(self) -> impl Iterator<Item = (String, T)> {
+        let compressor = read(&self.compressor);
+        let names: Vec<String> = (0..self.objects.len())
+            .map(|i| compressor.original(i).unwrap_or_default())
+            .collect();
+        names.into_iter().zip(self.objects)
+    }
+
+    fn matching(&self, context: &PinIn, s: &str) -> Vec<usize> {
+        let mut ret = Vec::new();
+        self.matching_into(context, s, &mut ret);
+        ret
+    }
+
+    fn matching_into(&self, context: &PinIn, s: &str, ret: &mut dyn Collection<usize>) {
+        {
+            let mut version = lock(&self.version);
+            if *version != Some(context.version()) {
+                self.accelerator.reset();
+                *version = Some(context.version());
+            }
+        }
+        let a = self.accelerator.claim();
+        a.search(&context.query_str(s));
+
+        let compressor = read(&self.compressor);
+        let mut last: Option<(usize, bool)> = None;
+        for i in self.order.iter().copied() {
+            if ret.is_full() {
+                break;
+            }
+            let hit = match last {
+                Some((j, hit)) if Self::name(&compressor, i) == Self::name(&compressor, j) => hit,
+                _ => self.logic.test_accelerator(&a, context, 0, compressor.offsets[i]),
+            };
+            if hit {
+                ret.insert(i);
+            }
+            last = Some((i, hit));
+        }
+    }
+}
+
+impl<T: 'static> Searcher<T> for MicroSearcher<T> {
+    fn insert(&mut self, context: &PinIn, name: &str, id: T) {
+        let index = self.objects.len();
+        let mut compressor = write(&self.compressor);
+        compressor.push_as(&context.target_str(name), name);
+        let name = Self::name(&compressor, index);
+        let pos = self.order.partition_point(|i| Self::name(&compressor, *i) <= name);
+        self.order.insert(pos, index);
+        self.objects.push(id);
+    }
+
+    /// Matches in insertion order.
+    fn search(&self, context: &PinIn, s: &str) -> Vec<&T> {
+        let mut ret = self.matching(context, s);
+        ret.sort_unstable();
+        ret.into_iter().map(|i| &self.objects[i]).collect()
+    }
+
+    fn search_into<'s>(&'s self, context: &PinIn, s: &str, ret: &mut dyn Collection<&'s T>) {
+        self.matching_into(context, s, &mut Objects { objects: &self.objects, inner: ret });
+    }
+
+    fn reset(&mut self, _context: &PinIn) {
+        self.accelerator.reset();
+    }
+
+    fn count(&self, context: &PinIn, s: &str) -> usize {
+        self.matching(context, s).len()
+    }
+}
+
+impl<T: 'static> SearcherMut<T> for MicroSearcher<T> {
+    fn update(&mut self, context: &PinIn, id: &T, name: &str) -> usize where T: PartialEq {
+        let indices: Vec<usize> = (0..self.objects.len()).filter(|i| self.objects[*i] == *id).collect();
+        let mut compressor = write(&self.compressor);
+        for i in indices.iter().copied() {
+            self.order.retain(|x| *x != i);
+            compressor.replace_as(i, &context.target_str(name), name);
+            let name = Self::name(&compressor, i);
+            let pos = self.order.partition_point(|x| Self::name(&compressor, *x) <= name);
+            self.order.insert(pos, i);
+        }
+        indices.len()
+    }
+
+    fn retain(&mut self, context: &PinIn, f: &mut dyn FnMut(&str, &T) -> bool) {
+        let compressor = write(&self.compressor).take();
+        let objects = std::mem::take(&mut self.objects);
+        self.order.clear();
+        self.accelerator.reset();
+        for (i, id) in objects.into_iter().enumerate() {
+            let name = compressor.original(i).unwrap_or_default();
+            if f(&name, &id) {
+                self.insert(context, &name, id);
+            }
+        }
+    }
+}
+
+/// Starts out as a [`MicroSearcher`] and moves to a [`TreeSearcher`] once
+/// it holds more than `N` entries.
+pub enum AutoSearcher<T: 'static, const N: usize = 256> {
+    Micro(MicroSearcher<T>),
+    Tree(TreeSearcher<T>),
+}
+
+impl<T: 'static, const N: usize> AutoSearcher<T, N> {
+    pub fn new(logic: SearcherLogic) -> Self {
+        AutoSearcher::Micro(MicroSearcher::new(logic))
+    }
+
+    fn inner(&self) -> &dyn Searcher<T> {
+        match self {
+            AutoSearcher::Micro(x) => x,
+            AutoSearcher::Tree(x) => x,
+        }
+    }
+
+    fn inner_mut(&mut self) -> &mut dyn SearcherMut<T> {
+        match self {
+            AutoSearcher::Micro(x) => x,
+            AutoSearcher::Tree(x) => x,
+        }
+    }
+}
+
+impl<T: 'static, const N: usize> Searcher<T> for AutoSearcher<T, N> {
+    fn insert(&mut self, context: &PinIn, name: &str, id: T) {
+        let logic = match self {
+            AutoSearcher::Micro(micro) if micro.len() >= N => Some(micro.logic),
+            _ => None,
+        };
+        if let Some(logic) = logic {
+            let tree = AutoSearcher::Tree(TreeSearcher::new(logic, Arc::new(Accelerator::new())));
+            if let AutoSearcher::Micro(micro) = std::mem::replace(self, tree) {
+                micro.into_entries().for_each(|(name, id)| self.insert(context, &name, id));
+            }
+        }
+        self.inner_mut().insert(context, name, id);
+    }
+
+    fn search(&self, context: &PinIn, s: &str) -> Vec<&T> {
+        self.inner().search(context, s)
+    }
+
+    fn search_into<'s>(&'s self, context: &PinIn, s: &str, ret: &mut dyn Collection<&'s T>) {
+        self.inner().search_into(context, s, ret);
+    }
+
+    fn reset(&mut self, context: &PinIn) {
+        self.inner_mut().reset(context);
+    }
+
+    fn count(&self, context: &PinIn, s: &str) -> usize {
+        self.inner().count(context, s)
+    }
+
+    fn any(&self, context: &PinIn, s: &str) -> bool {
+        self.inner().any(context, s)
+    }
+}
+
+impl<T: 'static, const N: usize> SearcherMut<T> for AutoSearcher<T, N> {
+    fn update(&mut self, context: &PinIn, id: &T, name: &str) -> usize where T: PartialEq {
+        self.inner_mut().update(context, id, name)
+    }
+
+    fn retain(&mut self, context: &PinIn, f: &mut dyn FnMut(&str, &T) -> bool) {
+        self.inner_mut().retain(context, f);
+    }
+}
@@ -0,0 +1,120 @@
+use crate::accelerator::Accelerator;
+use crate::compressed::Compressor;
+use crate::pinin::PinIn;
+use crate::searcher::{Searcher, SearcherLogic, TreeSearcher};
+use crate::sync::{read, write};
+use std::sync::{Arc, RwLock};
+
+/// Begin, Contain and Equal searches over one set of names and objects.
+///
+/// Each logic has a tree of its own, but the names are stored once, in a
+/// [`Compressor`] the trees share, and so are the objects; the trees only
+/// hold object indices. [`SearcherLogic::EndsWith`] queries go through
+/// the Contain tree, testing the end of each name found.
+pub struct MultiSearcher<T: 'static> {
+    names: Arc<RwLock<Compressor>>,
+    objects: Vec<T>,
+    begin: TreeSearcher<usize>,
+    contain: TreeSearcher<usize>,
+    equal: TreeSearcher<usize>,
+}
+
+impl<T: 'static> Default for MultiSearcher<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: 'static> MultiSearcher<T> {
+    pub fn new() -> Self {
+        let names = Arc::new(RwLock::new(Compressor::default()));
+        let accelerator = Arc::new(Accelerator::new());
+        let tree = |logic| TreeSearcher::over_names(logic, accelerator.clone(), names.clone());
+        MultiSearcher {
+            begin: tree(SearcherLogic::Begin),
+            contain: tree(SearcherLogic::Contain),
+            equal: tree(SearcherLogic::Equal),
+            names,
+            objects: Vec::new(),
+        }
+    }
+
+    /// A searcher over names already stored in `names`, the `i`-th entry
+    /// naming the `i`-th of `objects`, see [`TreeSearcher::with_names`].
+    ///
+    /// # Panics
+    ///
+    /// If there are not as many objects as entries.
+    pub fn with_names(context: &PinIn, names: Arc<RwLock<Compressor>>, objects: Vec<T>) -> Self {
+        let accelerator = Arc::new(Accelerator::new());
+        let ids: Vec<usize> = (0..objects.len()).collect();
+        let tree = |logic| TreeSearcher::with_names(context, logic, accelerator.clone(), names.clone(), ids.clone());
+        MultiSearcher {
+            begin: tree(SearcherLogic::Begin),
+            contain: tree(SearcherLogic::Contain),
+            equal: tree(SearcherLogic::Equal),
+            names,
+            objects,
+        }
+    }
+
+    pub fn insert(&mut self, context: &PinIn, name: &str, id: T) {
+        let index = self.objects.len();
+        write(&self.names).push_as(&context.target_str(name), name);
+        self.begin.index_last(context, index);
+        self.contain.index_last(context, index);
+        self.equal.index_last(context, index);
+        self.objects.push(id);
+    }
+
+    /// Matches of `s` with `logic`, in insertion order.
+    pub fn search(&self, context: &PinIn, logic: SearcherLogic, s: &str) -> Vec<&T> {
+        self.search_indices(context, logic, s).into_iter().map(|i| &self.objects[i]).collect()
+    }
+
+    /// Like `search`, giving the insertion indices.
+    pub fn search_indices(&self, context: &PinIn, logic: SearcherLogic, s: &str) -> Vec<usize> {
+        match logic {
+            SearcherLogic::Begin => self.begin.search_indices(context, s),
+            SearcherLogic::Contain => self.contain.search_indices(context, s),
+            SearcherLogic::Equal => self.equal.search_indices(context, s),
+            SearcherLogic::EndsWith => {
+                let mut ret = self.contain.search_indices(context, s);
+                ret.retain(|i| self.name(*i).is_some_and(|x| context.ends(&x, s)));
+                ret
+            }
+        }
+    }
+
+    /// Rebuilds stale indexes if the context changed since the last use.
+    /// Queries do this by themselves, so calling it is optional.
+    pub fn reset(&mut self, context: &PinIn) {
+        self.begin.reset(context);
+        self.contain.reset(context);
+        self.equal.reset(context);
+    }
+
+    /// The names shared by the trees, e.g. to store them with
+    /// [`Compressor::to_bytes`] and build the searcher again with
+    /// [`MultiSearcher::with_names`].
+    pub fn names(&self) -> Arc<RwLock<Compressor>> {
+        self.names.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.objects.get(index)
+    }
+
+    /// The name the `index`-th object was inserted with.
+    pub fn name(&self, index: usize) -> Option<String> {
+        read(&self.names).original(index)
+    }
+}
@@ -0,0 +1,368 @@
+//! A binary image of a loaded [`PinIn`], for cold starts where parsing
+//! the dictionary takes too long, e.g. on every page load in a browser.
+//!
+//! The image holds the characters, the interned readings with their
+//! phonemes already mapped through the keyboard and extra keyboards, and
+//! the settings. The
+//! format function, word frequencies and phrases are not part of it; a
+//! hydrated context formats with [`number_format`](crate::format::number_format)
+//! until told otherwise.
+//!
+//! ```
+//! use pinin_rs::pinin::PinIn;
+//!
+//! let mut pinin = PinIn::new();
+//! pinin.load_default_dict();
+//! let bytes = pinin.to_bytes().unwrap();
+//! let hydrated = PinIn::from_bytes(&bytes).unwrap();
+//! assert!(hydrated.contains("测试文本", "ceshi"));
+//! ```
+//!
+//! The names of a searcher are stored on their own, see
+//! [`Compressor::to_bytes`], so that searchers of several logics over the
+//! same names can share one copy of them.
+
+use crate::compressed::Compressor;
+use crate::elements::{Character, Phoneme, Pinyin};
+use crate::keyboard::{Keyboard, KEYBOARD_DAQIAN, KEYBOARD_QUANPIN, KEYBOARD_XIAOHE, KEYBOARD_ZIRANMA};
+use crate::pinin::{NeutralTone, PinIn};
+use compact_str::CompactString;
+use rustc_hash::FxHashMap;
+use smallvec::SmallVec;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+const MAGIC: &[u8; 6] = b"PININ\0";
+const NAMES_MAGIC: &[u8; 6] = b"PINNM\0";
+const VERSION: u32 = 2;
+/// Marks a char known to have no readings.
+const NONE: u32 = u32::MAX;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PersistError {
+    /// The context uses a keyboard, or an extra keyboard, other than the
+    /// built-in ones.
+    CustomKeyboard,
+    /// The bytes do not start like an image of the expected kind.
+    Magic,
+    /// An image written by another version of the format.
+    Version(u32),
+    /// The image ends early or holds out of range values.
+    Corrupt,
+}
+
+impl Display for PersistError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PersistError::CustomKeyboard => f.write_str("only built-in keyboards can be persisted"),
+            PersistError::Magic => f.write_str("not a context or names image"),
+            PersistError::Version(x) => write!(f, "unsupported image version {}", x),
+            PersistError::Corrupt => f.write_str("corrupt context image"),
+        }
+    }
+}
+
+impl Error for PersistError {}
+
+fn keyboards() -> [&'static Keyboard; 4] {
+    [&KEYBOARD_QUANPIN, &KEYBOARD_DAQIAN, &KEYBOARD_XIAOHE, &KEYBOARD_ZIRANMA]
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u32(&mut self, x: usize) {
+        self.0.extend_from_slice(&(x as u32).to_le_bytes());
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u32(s.len());
+        self.0.extend_from_slice(s.as_bytes());
+    }
+
+    /// Everything of `p` but its raw reading and id, which its alternates
+    /// share.
+    fn pinyin(&mut self, p: &Pinyin) {
+        self.0.push(u8::from(p.tone_required) | u8::from(p.duo) << 1 | u8::from(p.sequence) << 2);
+        self.u32(p.phonemes.len());
+        for phoneme in &p.phonemes {
+            match phoneme {
+                Phoneme::Single(x) => {
+                    self.0.push(0);
+                    self.str(x);
+                }
+                Phoneme::Multiple(xs) => {
+                    self.0.push(1);
+                    self.u32(xs.len());
+                    xs.iter().for_each(|x| self.str(x));
+                }
+            }
+        }
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], PersistError> {
+        if self.0.len() < n {
+            return Err(PersistError::Corrupt);
+        }
+        let (ret, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(ret)
+    }
+
+    fn u8(&mut self) -> Result<u8, PersistError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, PersistError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn len(&mut self) -> Result<usize, PersistError> {
+        self.u32().map(|x| x as usize)
+    }
+
+    fn str(&mut self) -> Result<&'a str, PersistError> {
+        let len = self.len()?;
+        std::str::from_utf8(self.take(len)?).map_err(|_| PersistError::Corrupt)
+    }
+
+    fn char(&mut self) -> Result<char, PersistError> {
+        char::from_u32(self.u32()?).ok_or(PersistError::Corrupt)
+    }
+
+    fn pinyin(&mut self, raw: &str, id: usize) -> Result<Pinyin, PersistError> {
+        let flags = self.u8()?;
+        let mut phonemes = SmallVec::new();
+        for _ in 0..self.len()? {
+            phonemes.push(match self.u8()? {
+                0 => Phoneme::Single(self.str()?.into()),
+                1 => {
+                    let n = self.len()?;
+                    Phoneme::Multiple((0..n).map(|_| self.str().map(CompactString::from)).collect::<Result<_, _>>()?)
+                }
+                _ => return Err(PersistError::Corrupt),
+            });
+        }
+        Ok(Pinyin {
+            raw: raw.into(),
+            id,
+            duo: flags & 2 != 0,
+            sequence: flags & 4 != 0,
+            tone_required: flags & 1 != 0,
+            phonemes,
+            alternates: Vec::new(),
+        })
+    }
+}
+
+impl PinIn {
+    /// Writes the loaded context as an image, see the [module docs](crate::persist).
+    pub fn to_bytes(&self) -> Result<Vec<u8>, PersistError> {
+        let position = |keyboard: &Keyboard| keyboards().iter().position(|x| std::ptr::eq(*x, keyboard));
+        let keyboard = position(self.keyboard).ok_or(PersistError::CustomKeyboard)?;
+        // The extra keyboards as a set, in the bits above the keyboard
+        let extra = self
+            .extra_keyboards
+            .iter()
+            .try_fold(0, |set, x| position(x).map(|i| set | 1 << (4 + i)))
+            .ok_or(PersistError::CustomKeyboard)?;
+
+        let mut w = Writer(MAGIC.to_vec());
+        w.u32(VERSION as usize);
+        w.0.push((keyboard | extra) as u8);
+        let fuzzy = &self.fuzzy;
+        let flags = [
+            fuzzy.zh2z, fuzzy.sh2s, fuzzy.ch2c, fuzzy.ang2an, fuzzy.ing2in, fuzzy.eng2en, fuzzy.u2v, fuzzy.any_tone,
+            self.accelerate, self.romanized_targets, self.fold, self.fold_case,
+        ];
+        w.u32(flags.iter().enumerate().map(|(i, x)| usize::from(*x) << i).sum());
+        w.0.push(match fuzzy.neutral_tone {
+            NeutralTone::Explicit => 0,
+            NeutralTone::Optional => 1,
+            NeutralTone::Toneless => 2,
+        });
+        w.u32(self.target_cache_capacity.min(NONE as usize - 1));
+
+        // Readings in id order, so that chars refer to them by id
+        let mut pinyins: Vec<&Pinyin> = self.pinyins.values().map(Arc::as_ref).collect();
+        pinyins.sort_by_key(|x| x.id);
+        w.u32(pinyins.len());
+        for p in pinyins {
+            w.str(&p.raw);
+            w.pinyin(p);
+            w.u32(p.alternates.len());
+            p.alternates.iter().for_each(|x| w.pinyin(x));
+        }
+
+        let mut chars = self.loaded_chars();
+        chars.sort_unstable_by_key(|(c, _)| *c);
+        w.u32(chars.len());
+        for (c, character) in chars {
+            w.u32(c as usize);
+            match character {
+                Some(x) => {
+                    w.u32(x.pinyin.len());
+                    x.pinyin.iter().for_each(|p| w.u32(p.id));
+                }
+                None => w.u32(NONE as usize),
+            }
+        }
+
+        let mut romanized: Vec<_> = self.romanized.iter().collect();
+        romanized.sort_unstable_by_key(|(_, c)| **c);
+        w.u32(romanized.len());
+        for (raw, c) in romanized {
+            w.str(raw);
+            w.u32(*c as usize);
+        }
+        Ok(w.0)
+    }
+
+    /// Restores a context written by [`PinIn::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<PinIn, PersistError> {
+        let mut r = Reader(bytes);
+        if r.take(MAGIC.len()).map_err(|_| PersistError::Magic)? != MAGIC {
+            return Err(PersistError::Magic);
+        }
+        match r.u32()? {
+            VERSION => {}
+            x => return Err(PersistError::Version(x)),
+        }
+
+        let mut ret = PinIn::new();
+        let keyboard = r.u8()?;
+        ret.keyboard = *keyboards().get(keyboard as usize & 0xF).ok_or(PersistError::Corrupt)?;
+        ret.extra_keyboards = (0..4).filter(|i| keyboard & (1 << (4 + i)) != 0).map(|i| keyboards()[i]).collect();
+        let flags = r.u32()?;
+        let flag = |i: u32| flags & (1 << i) != 0;
+        let fuzzy = &mut ret.fuzzy;
+        (fuzzy.zh2z, fuzzy.sh2s, fuzzy.ch2c, fuzzy.ang2an) = (flag(0), flag(1), flag(2), flag(3));
+        (fuzzy.ing2in, fuzzy.eng2en, fuzzy.u2v, fuzzy.any_tone) = (flag(4), flag(5), flag(6), flag(7));
+        (ret.accelerate, ret.romanized_targets, ret.fold, ret.fold_case) = (flag(8), flag(9), flag(10), flag(11));
+        fuzzy.neutral_tone = match r.u8()? {
+            0 => NeutralTone::Explicit,
+            1 => NeutralTone::Optional,
+            2 => NeutralTone::Toneless,
+            _ => return Err(PersistError::Corrupt),
+        };
+        ret.target_cache_capacity = r.len()?;
+
+        let count = r.len()?;
+        let mut pinyins: Vec<Arc<Pinyin>> = Vec::with_capacity(count.min(bytes.len()));
+        for id in 0..count {
+            let raw = r.str()?;
+            let mut pinyin = r.pinyin(raw, id)?;
+            pinyin.alternates = (0..r.len()?).map(|_| r.pinyin(raw, id)).collect::<Result<_, _>>()?;
+            pinyins.push(Arc::new(pinyin));
+        }
+
+        let count = r.len()?;
+        let chars = Arc::make_mut(&mut ret.chars);
+        chars.reserve(count.min(bytes.len()));
+        for _ in 0..count {
+            let c = r.char()?;
+            let character = match r.u32()? {
+                NONE => None,
+                n => {
+                    let pinyin = (0..n)
+                        .map(|_| pinyins.get(r.len()?).cloned().ok_or(PersistError::Corrupt))
+                        .collect::<Result<_, _>>()?;
+                    Some(Character::new(c, pinyin))
+                }
+            };
+            chars.insert(c, character);
+        }
+
+        for _ in 0..r.len()? {
+            let raw = r.str()?;
+            let c = r.char()?;
+            Arc::make_mut(&mut ret.romanized).insert(raw.into(), c);
+        }
+
+        ret.pinyins = Arc::new(pinyins.into_iter().map(|x| (x.raw.clone(), x)).collect());
+        let stand_ins = ret.romanized.iter().filter_map(|(raw, c)| {
+            let pinyin = ret.pinyins.get(raw)?.clone();
+            Some((*c, Character::new(*c, [pinyin].into_iter().collect())))
+        });
+        ret.romanized_chars = Arc::new(stand_ins.collect());
+        if !r.0.is_empty() {
+            return Err(PersistError::Corrupt);
+        }
+        Ok(ret)
+    }
+}
+
+impl Compressor {
+    /// Writes the entries as an image, leaving out the chars of renamed
+    /// away names.
+    ///
+    /// ```
+    /// use pinin_rs::accelerator::Accelerator;
+    /// use pinin_rs::compressed::Compressor;
+    /// use pinin_rs::pinin::PinIn;
+    /// use pinin_rs::searcher::{Searcher, SearcherLogic, TreeSearcher};
+    /// use std::sync::{Arc, RwLock};
+    ///
+    /// let mut pinin = PinIn::new();
+    /// pinin.load_default_dict();
+    /// let mut names = Compressor::default();
+    /// names.push("测试文本");
+    /// names.push("文本测试");
+    /// let bytes = names.to_bytes();
+    ///
+    /// let names = Arc::new(RwLock::new(Compressor::from_bytes(&bytes).unwrap()));
+    /// let new = |logic| TreeSearcher::with_names(&pinin, logic, Arc::new(Accelerator::new()), names.clone(), vec![0, 1]);
+    /// let (begin, contain) = (new(SearcherLogic::Begin), new(SearcherLogic::Contain));
+    /// assert_eq!(begin.search(&pinin, "wenben"), vec![&1]);
+    /// assert_eq!(contain.search(&pinin, "wenben"), vec![&0, &1]);
+    /// ```
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer(NAMES_MAGIC.to_vec());
+        w.u32(VERSION as usize);
+        let originals: FxHashMap<usize, &str> = self.originals().collect();
+        w.u32(self.offsets.len());
+        for i in 0..self.offsets.len() {
+            w.str(&self.entry(i).unwrap_or_default());
+            match originals.get(&i) {
+                Some(x) => {
+                    w.0.push(1);
+                    w.str(x);
+                }
+                None => w.0.push(0),
+            }
+        }
+        w.0
+    }
+
+    /// Restores entries written by [`Compressor::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Compressor, PersistError> {
+        let mut r = Reader(bytes);
+        if r.take(NAMES_MAGIC.len()).map_err(|_| PersistError::Magic)? != NAMES_MAGIC {
+            return Err(PersistError::Magic);
+        }
+        match r.u32()? {
+            VERSION => {}
+            x => return Err(PersistError::Version(x)),
+        }
+
+        let mut ret = Compressor::default();
+        for _ in 0..r.len()? {
+            let entry = r.str()?;
+            match r.u8()? {
+                0 => ret.push(entry),
+                1 => ret.push_as(entry, r.str()?),
+                _ => return Err(PersistError::Corrupt),
+            };
+        }
+        if !r.0.is_empty() {
+            return Err(PersistError::Corrupt);
+        }
+        Ok(ret)
+    }
+}
@@ -0,0 +1,128 @@
+//! Readings of whole words, for characters read differently depending on
+//! the word they are in. The character dictionary gives 重 both zhong4
+//! and chong2, so on its own 重庆 matches "zhongqing" as well as
+//! "chongqing"; with 重庆 listed here, it only matches the latter, while
+//! 重 outside of listed words keeps both readings.
+//!
+//! The text format has one word per line, with the reading of every
+//! character:
+//!
+//! ```text
+//! # comments and blank lines are ignored
+//! 重庆 chong2 qing4
+//! 银行 yin2 hang2
+//! ```
+//!
+//! Words are found in targets by longest match from the left, see
+//! [`PhraseDict::segment`], and [`PinIn::set_phrases`](crate::pinin::PinIn::set_phrases)
+//! applies them to names inserted from then on.
+
+use crate::user_dict::is_valid_reading;
+use rustc_hash::FxHashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PhraseDict {
+    words: FxHashMap<String, Vec<String>>,
+    /// Length of the longest word, in chars.
+    longest: usize,
+}
+
+/// A line that is not `word readings`, numbered from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsePhraseError(pub usize);
+
+impl Display for ParsePhraseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: expected `word readings`", self.0)
+    }
+}
+
+impl Error for ParsePhraseError {}
+
+impl PhraseDict {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the readings of the chars of `word`, which has at least two
+    /// chars, one valid reading per char. Returns false and leaves the
+    /// dictionary as is otherwise; single chars keep all their readings.
+    pub fn insert(&mut self, word: &str, readings: &[&str]) -> bool {
+        let len = word.chars().count();
+        if len < 2 || readings.len() != len || !readings.iter().all(|x| is_valid_reading(x)) {
+            return false;
+        }
+        let readings = readings.iter().map(|x| x.to_string()).collect();
+        self.words.insert(word.to_string(), readings);
+        self.longest = self.longest.max(len);
+        true
+    }
+
+    /// The readings of the chars of `word`, if it is listed.
+    pub fn readings(&self, word: &str) -> Option<&[String]> {
+        self.words.get(word).map(Vec::as_slice)
+    }
+
+    /// Listed words with their readings, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[String])> {
+        self.words.iter().map(|(word, readings)| (word.as_str(), readings.as_slice()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// The listed words of `chars`, as ranges of char positions, taking
+    /// the longest word starting at each position and going on after it.
+    pub fn segment(&self, chars: &[char]) -> Vec<(usize, usize)> {
+        let mut ret = Vec::new();
+        let mut word = String::new();
+        let mut start = 0;
+        while start < chars.len() {
+            word.clear();
+            let mut found = None;
+            for end in start + 1..=chars.len().min(start + self.longest) {
+                word.push(chars[end - 1]);
+                if self.words.contains_key(&word) {
+                    found = Some(end);
+                }
+            }
+            match found {
+                Some(end) => {
+                    ret.push((start, end));
+                    start = end;
+                }
+                None => start += 1,
+            }
+        }
+        ret
+    }
+}
+
+impl FromStr for PhraseDict {
+    type Err = ParsePhraseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ret = PhraseDict::new();
+        for (i, line) in s.lines().enumerate().map(|(i, x)| (i + 1, x.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let word = parts.next().ok_or(ParsePhraseError(i))?;
+            let readings: Vec<&str> = parts.collect();
+            if !ret.insert(word, &readings) {
+                return Err(ParsePhraseError(i));
+            }
+        }
+        Ok(ret)
+    }
+}
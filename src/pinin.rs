@@ -1,28 +1,138 @@
-use crate::accelerator::{Accelerator, StringProvider};
-use crate::dict_loader::DictLoader;
+use crate::accelerator::{Accelerator, CharProvider, Claim, EscapedProvider, StringProvider, TargetProvider};
+use crate::cache::Lru;
+use crate::dict_loader::{DictError, DictLoader, LazyDict};
 use crate::elements::{Character, Pinyin};
 use crate::format::{number_format, PinyinFormat};
+use crate::frequency::FrequencyTable;
+use crate::intern::{CharTable, InternPool, PinyinTable, RomanizedTable};
 use crate::keyboard::{Keyboard, KEYBOARD_QUANPIN};
+use crate::phrase::PhraseDict;
+use crate::session::MatchSession;
+use crate::sync::lock;
+use crate::unicode_utils::{canonicalize, fold, fold_case, fold_char, lower_char, SegmentedStr};
+use crate::user_dict::UserDict;
+use smallvec::SmallVec;
 use std::borrow::Cow;
 use std::cell::RefCell;
-use std::rc::Rc;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::fs::File;
+use std::io::BufReader;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use compact_str::CompactString;
 use rustc_hash::FxHashMap;
 
 type HashMap<K, V> = FxHashMap<K, V>;
 
-pub struct PinIn<'a> {
-    pub(crate) chars: HashMap<char, Option<Character>>,
+pub struct PinIn {
+    pub(crate) chars: Arc<CharTable>,
 
+    /// Applied to loaded readings by [`PinIn::commit`], or set with
+    /// [`PinIn::set_keyboard`].
     pub keyboard: &'static Keyboard,
+    /// Keyboards accepted besides `keyboard`, for apps that cannot tell
+    /// which one the user types on, e.g. quanpin and xiaohe at once. Each
+    /// syllable of a query may be typed on any of them, which also lets
+    /// through some mixes no user would type. Applied by [`PinIn::commit`].
+    pub extra_keyboards: Vec<&'static Keyboard>,
+    /// Applied to loaded readings by [`PinIn::commit`], or set with
+    /// [`PinIn::set_fuzzy`].
     pub fuzzy: FuzzySettings,
-    pub format: PinyinFormat<'a>,
+    pub format: PinyinFormat,
     pub accelerate: bool,
-    pub accelerator: Option<Rc<Accelerator>>,
+    /// Unused: contexts build their own accelerator on first use, and
+    /// searchers are given theirs.
+    #[deprecated(note = "unused, searchers take their own accelerator")]
+    pub accelerator: Option<Arc<Accelerator>>,
+    /// How many target strings `contains`, `begins` and `matches` keep
+    /// resolved between calls, dropping the least recently used. Off (0)
+    /// by default, as resolving a target again costs about as much as
+    /// finding it in the cache.
+    pub target_cache_capacity: usize,
+    /// Match numbered pinyin in targets, such as "zhong1guo2", like the
+    /// hanzi it stands for. See [`PinIn::romanized`].
+    pub romanized_targets: bool,
+    /// Fold fullwidth digits and letters, roman numerals and punctuation
+    /// in both targets and queries, so that "di2ji" matches "第２集" and
+    /// "jishang" matches "集、上". See [`fold`](crate::unicode_utils::fold).
+    pub fold: bool,
+    /// Match Latin, Greek and Cyrillic letters regardless of case, in
+    /// both targets and queries, so that "ω" matches "Ω" and "ABC" matches
+    /// "abc". See [`fold_case`](crate::unicode_utils::fold_case).
+    pub fold_case: bool,
 
-    pub(crate) pinyins: Rc<RefCell<HashMap<&'a str, Pinyin>>>,
+    pub(crate) pinyins: Arc<PinyinTable>,
 
-    total: AtomicUsize,
+    /// Created on first use, see [`PinIn::minimal`].
+    matcher: OnceLock<Accelerator>,
+    last_query: Mutex<CompactString>,
+    targets: Mutex<Lru<Box<str>, Arc<TargetProvider>>>,
+    /// Stand-in characters for the loaded readings.
+    pub(crate) romanized: Arc<RomanizedTable>,
+    /// The reading of each romanized stand-in. Kept out of `chars`, so
+    /// that only targets rewritten by [`PinIn::romanized`] reach them.
+    pub(crate) romanized_chars: Arc<HashMap<char, Character>>,
+    frequencies: Option<FrequencyTable>,
+    phrases: Option<PhraseDict>,
+    /// Stand-ins for chars of listed words, carrying the char and its
+    /// reading in the word, by the char and reading.
+    phrase_chars: HashMap<(char, CompactString), char>,
+    phrase_stand_ins: Arc<HashMap<char, Character>>,
+    pool: Option<InternPool>,
+    /// Readings of the chars user dictionaries replaced, `None` for chars
+    /// that were not loaded, see [`PinIn::unload_user_dict`].
+    user_base: HashMap<char, Option<Vec<String>>>,
+    pub(crate) lazy: Option<LazyChars>,
+    version: u64,
+}
+
+/// A dictionary loaded with [`PinIn::load_dict_lazy`], with the chars
+/// decoded so far.
+pub(crate) struct LazyChars {
+    dict: Arc<LazyDict>,
+    decoded: Vec<OnceLock<Option<Box<Character>>>>,
+}
+
+impl LazyChars {
+    fn new(dict: Arc<LazyDict>) -> Self {
+        let decoded = (0..dict.len()).map(|_| OnceLock::new()).collect();
+        LazyChars { dict, decoded }
+    }
+
+    fn get(&self, pinyins: &PinyinTable, c: char) -> Option<&Character> {
+        let i = self.dict.position(c)?;
+        let decoded = self.decoded[i].get_or_init(|| {
+            let pinyin: SmallVec<[Arc<Pinyin>; 4]> = self.dict.get(i).into_iter().filter_map(|x| pinyins.get(x).cloned()).collect();
+            (!pinyin.is_empty()).then(|| Box::new(Character::new(c, pinyin)))
+        });
+        decoded.as_deref()
+    }
+
+    /// How many chars were decoded so far.
+    pub(crate) fn decoded(&self) -> usize {
+        self.decoded.iter().filter(|x| x.get().is_some()).count()
+    }
+}
+
+/// The last version given to a context, see [`PinIn::version`].
+static VERSIONS: AtomicU64 = AtomicU64::new(0);
+
+fn next_version() -> u64 {
+    VERSIONS.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+/// Stand-ins for readings are taken from the Supplementary Private Use
+/// Area-A onwards.
+const ROMANIZED_BASE: u32 = 0xF0000;
+/// Stand-ins for chars of listed words, from the Supplementary Private Use
+/// Area-B onwards.
+const PHRASE_BASE: u32 = 0x100000;
+
+/// Whether `c` is in the range stand-ins are taken from. Such chars in
+/// targets are replaced with U+FFFD, so that they never pass for one.
+pub(crate) fn is_reserved(c: char) -> bool {
+    c as u32 >= ROMANIZED_BASE
 }
 
 #[derive(Default, Debug)]
@@ -34,134 +144,926 @@ pub struct FuzzySettings {
     pub ing2in: bool,
     pub eng2en: bool,
     pub u2v: bool,
+    /// Treat the tone as a wildcard: any tone key, or none, is accepted.
+    pub any_tone: bool,
+    /// How readings in neutral tone take their tone key. Ignored when
+    /// `any_tone` is set.
+    pub neutral_tone: NeutralTone,
+}
+
+/// Whether the neutral tone key, "0" on most keyboards, has to be typed.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NeutralTone {
+    /// The key is needed after a whole syllable unless it is the last one
+    /// typed so far, e.g. "de0ren", "de" and "dr" match 的人 but "deren"
+    /// does not. Keyboards without a neutral tone key behave as with
+    /// `Optional`.
+    Explicit,
+    /// The key may be typed or left out, like the key of any other tone.
+    #[default]
+    Optional,
+    /// The neutral tone has no key, e.g. "de" matches 的 but "de0" does
+    /// not.
+    Toneless,
 }
 
-impl Default for PinIn<'_> {
+/// How [`PinIn::load_dict_overlay`] combines the readings of a char with
+/// the ones it already has.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Overlay {
+    /// New readings come after the existing ones, which stay preferred.
+    Append,
+    /// New readings come first, becoming the ones formatting picks.
+    Prepend,
+    /// New readings are the only ones, as with [`PinIn::load_dict`].
+    Replace,
+}
+
+impl Default for PinIn {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl PinIn<'static> {
+impl PinIn {
+    /// Loads the dictionary embedded with the `embedded-dict` feature, on
+    /// by default. Without it, supply one with [`PinIn::load_dict`].
+    #[cfg(feature = "embedded-dict")]
     pub fn load_default_dict(&mut self) {
-        self.load_dict(Box::new(include_str!("dict.txt")));
+        self.load_dict(Box::new(include_str!("dict.txt"))).expect("embedded dictionary is malformed");
+    }
+}
+
+impl PinIn {
+    /// Uses the embedded [`FrequencyTable`].
+    #[cfg(feature = "frequency")]
+    pub fn load_default_frequencies(&mut self) {
+        self.set_frequencies(Some(FrequencyTable::embedded()));
+    }
+
+    /// Word frequencies used by [`PinIn::to_pinyin`] and ranked search.
+    pub fn set_frequencies(&mut self, table: Option<FrequencyTable>) {
+        self.frequencies = table;
+    }
+
+    pub fn frequencies(&self) -> Option<&FrequencyTable> {
+        self.frequencies.as_ref()
+    }
+
+    /// Word readings that restrict the chars of listed words to their
+    /// reading in the word, see [`crate::phrase`]. Names inserted into
+    /// searchers before the call keep matching as they did.
+    pub fn set_phrases(&mut self, dict: Option<PhraseDict>) {
+        self.invalidate();
+        self.phrase_chars.clear();
+        let mut stand_ins = HashMap::default();
+        for (word, readings) in dict.iter().flat_map(PhraseDict::iter) {
+            for (c, reading) in word.chars().zip(readings) {
+                let key = (c, CompactString::from(reading.as_str()));
+                if self.phrase_chars.contains_key(&key) {
+                    continue;
+                }
+                let Some(stand_in) = char::from_u32(PHRASE_BASE + self.phrase_chars.len() as u32) else {
+                    break;
+                };
+                let pinyin = self.get_or_insert_pinyin(reading);
+                stand_ins.insert(stand_in, Character::new(c, [pinyin].into_iter().collect()));
+                self.phrase_chars.insert(key, stand_in);
+            }
+        }
+        self.phrase_stand_ins = Arc::new(stand_ins);
+        self.phrases = dict;
+    }
+
+    pub fn phrases(&self) -> Option<&PhraseDict> {
+        self.phrases.as_ref()
+    }
+
+    /// `s` with the chars of listed words replaced by their stand-ins.
+    fn phrased<'s>(&self, s: &'s str) -> Cow<'s, str> {
+        let Some(dict) = &self.phrases else {
+            return Cow::Borrowed(s);
+        };
+        let mut chars: Vec<char> = s.chars().collect();
+        let words = dict.segment(&chars);
+        if words.is_empty() {
+            return Cow::Borrowed(s);
+        }
+        for (start, end) in words {
+            let word: String = chars[start..end].iter().collect();
+            let Some(readings) = dict.readings(&word) else {
+                continue;
+            };
+            for (c, reading) in chars[start..end].iter_mut().zip(readings) {
+                if let Some(stand_in) = self.phrase_chars.get(&(*c, reading.as_str().into())) {
+                    *c = *stand_in;
+                }
+            }
+        }
+        Cow::Owned(chars.into_iter().collect())
+    }
+
+    /// One reading per char of `s`, `None` for chars without any. With
+    /// word frequencies set, readings of the likeliest words are used,
+    /// e.g. 行 reads hang2 in 银行 and xing2 in 行走; otherwise every char
+    /// gets its first reading.
+    pub fn to_pinyin(&self, s: &str) -> Vec<Option<&Pinyin>> {
+        let chars: Vec<char> = s.chars().collect();
+        let mut ret: Vec<Option<&Pinyin>> = chars
+            .iter()
+            .map(|c| self.loaded(*c).and_then(|x| x.pinyin.first()).map(Arc::as_ref))
+            .collect();
+
+        if let Some(table) = &self.frequencies {
+            for (start, end) in table.segment(&chars) {
+                let word: String = chars[start..end].iter().collect();
+                let Some(readings) = table.readings(&word) else {
+                    continue;
+                };
+                for (i, reading) in readings.iter().enumerate() {
+                    // Only readings the dictionary has for the char
+                    let known = self.loaded(chars[start + i]);
+                    if let Some(p) = known.and_then(|x| x.pinyin.iter().find(|p| p.raw == reading.as_str())) {
+                        ret[start + i] = Some(p.as_ref());
+                    }
+                }
+            }
+        }
+        ret
     }
 }
 
-impl<'a> PinIn<'a> {
-    pub fn new() -> PinIn<'a> {
-        let mut p = PinIn {
+impl PinIn {
+    pub fn new() -> PinIn {
+        Self::with_acceleration(true)
+    }
+
+    /// A context without acceleration or caches, for one-off matching
+    /// such as a short-lived command line tool, where building them costs
+    /// more than they save.
+    pub fn minimal() -> PinIn {
+        Self::with_acceleration(false)
+    }
+
+    #[allow(deprecated)]
+    fn with_acceleration(accelerate: bool) -> PinIn {
+        PinIn {
             chars: Default::default(),
             keyboard: &KEYBOARD_QUANPIN,
+            extra_keyboards: Vec::new(),
             fuzzy: FuzzySettings::default(),
             format: Box::new(number_format),
-            accelerate: false,
+            accelerate,
             accelerator: None,
-            pinyins: Rc::new(RefCell::new(Default::default())),
-            total: AtomicUsize::default(),
-        };
-        p.accelerator = Some(Rc::new(Accelerator::new()));
+            target_cache_capacity: 0,
+            romanized_targets: false,
+            fold: false,
+            fold_case: false,
+            pinyins: Default::default(),
+            matcher: OnceLock::new(),
+            last_query: Default::default(),
+            targets: Default::default(),
+            romanized: Default::default(),
+            romanized_chars: Default::default(),
+            frequencies: None,
+            phrases: None,
+            phrase_chars: Default::default(),
+            phrase_stand_ins: Default::default(),
+            pool: None,
+            user_base: Default::default(),
+            lazy: None,
+            version: next_version(),
+        }
+    }
 
+    /// The loaded reading `x`, shared by every char that has it.
+    pub fn get_or_insert_pinyin(&mut self, x: &str) -> Arc<Pinyin> {
+        if let Some(p) = self.pinyins.get(x) {
+            return p.clone();
+        }
+        let p = Arc::new(Pinyin::with_keyboards(x, &self.fuzzy, self.keyboard, &self.extra_keyboards, self.pinyins.len()));
+        Arc::make_mut(&mut self.pinyins).insert(x.into(), p.clone());
         p
     }
 
-    pub fn get_or_insert_pinyin(&self, x: &'a str) -> Pinyin {
-        self.pinyins
-            .as_ref()
-            .borrow_mut()
-            .entry(x)
-            .or_insert_with(|| Pinyin::new(
-                x,
-                &self.fuzzy,
-                self.keyboard,
-                self.total.fetch_add(1, Ordering::SeqCst),
-            ))
-            .clone()
+    /// Loads readings from `loader`. They are copied, so the source only
+    /// needs to live for the call, e.g. a file read at runtime. Nothing is
+    /// loaded if the source is malformed.
+    pub fn load_dict<'d>(&mut self, loader: Box<dyn DictLoader + 'd>) -> Result<(), DictError> {
+        self.insert_readings(loader.load_dict()?);
+        Ok(())
     }
 
-    pub fn load_dict(&mut self, loader: Box<dyn DictLoader<'a>>) {
-        loader.load_dict().into_iter().for_each(|(c, ss)| {
+    /// Replaces every loaded reading with the ones of `loader`, e.g. to
+    /// pick up a corrected dictionary file while running. Readings layered
+    /// with [`load_user_dict`](PinIn::load_user_dict) or
+    /// [`load_dict_overlay`](PinIn::load_dict_overlay) are dropped too.
+    /// Nothing changes if the source is malformed.
+    ///
+    /// Searchers built over the context refresh their caches on their
+    /// next use, as after any change of readings; only names inserted
+    /// into them stay as they were transformed.
+    pub fn reload_dict<'d>(&mut self, loader: Box<dyn DictLoader + 'd>) -> Result<(), DictError> {
+        let readings = loader.load_dict()?;
+        self.unload_dict();
+        self.insert_readings(readings);
+        Ok(())
+    }
+
+    /// Forgets the readings of every char, so that chars only match
+    /// themselves until a dictionary is loaded again. Stand-ins for
+    /// [`romanized_targets`](PinIn::romanized_targets) and phrases are
+    /// kept.
+    pub fn unload_dict(&mut self) {
+        self.invalidate();
+        self.user_base.clear();
+        self.chars = Default::default();
+        self.lazy = None;
+        self.intern();
+    }
+
+    /// Loads readings from `loader` over the ones already loaded, e.g. a
+    /// custom dictionary over the embedded one. Each char of `loader` has
+    /// its readings combined with its existing ones as `overlay` says,
+    /// without repeating any; other chars are left as they are.
+    pub fn load_dict_overlay<'d>(&mut self, loader: Box<dyn DictLoader + 'd>, overlay: Overlay) -> Result<(), DictError> {
+        let mut readings = loader.load_dict()?;
+        if overlay != Overlay::Replace {
+            for (c, new) in readings.iter_mut() {
+                let Some(old) = self.loaded(*c) else {
+                    continue;
+                };
+                let old = old.pinyin.iter().map(|p| p.raw.to_string());
+                let (first, rest): (Vec<String>, Vec<String>) = match overlay {
+                    Overlay::Append => (old.collect(), std::mem::take(new)),
+                    _ => (std::mem::take(new), old.collect()),
+                };
+                *new = first;
+                for reading in rest {
+                    if !new.contains(&reading) {
+                        new.push(reading);
+                    }
+                }
+            }
+        }
+        self.insert_readings(readings);
+        Ok(())
+    }
+
+    /// Loads a dictionary compiled by [`DictCompiler`](crate::dict_loader::DictCompiler),
+    /// decoding the readings of a char only when it is first looked up,
+    /// for apps that start often and touch few chars. Its chars replace
+    /// the readings they had, as with [`load_dict`](PinIn::load_dict);
+    /// chars of a dictionary loaded lazily before are decoded and kept
+    /// unless this one has them. Nothing is loaded if the bytes are
+    /// malformed.
+    ///
+    /// Anything going over every char, such as [`PinIn::to_bytes`],
+    /// decodes all of them.
+    pub fn load_dict_lazy(&mut self, bytes: Vec<u8>) -> Result<(), DictError> {
+        let dict = LazyDict::new(bytes)?;
+        self.invalidate();
+        self.user_base.clear();
+        self.number_readings(dict.readings().iter().map(String::as_str).collect());
+        if let Some(old) = self.lazy.take() {
+            let kept: Vec<(char, Option<Character>)> = old
+                .dict
+                .chars()
+                .filter(|c| dict.position(*c).is_none() && !self.chars.contains_key(c))
+                .map(|c| (c, old.get(&self.pinyins, c).cloned()))
+                .collect();
+            Arc::make_mut(&mut self.chars).extend(kept);
+        }
+        Arc::make_mut(&mut self.chars).retain(|c, _| dict.position(*c).is_none());
+        self.romanize_readings();
+        self.lazy = Some(LazyChars::new(Arc::new(dict)));
+        self.intern();
+        Ok(())
+    }
+
+    /// Loads a dictionary file in the format of the embedded one, for
+    /// applications that ship it next to the binary.
+    pub fn load_dict_from_path<P: AsRef<Path>>(&mut self, path: P) -> Result<(), DictError> {
+        let file = BufReader::new(File::open(path)?);
+        self.load_dict(Box::new(RefCell::new(file)))
+    }
+
+    /// `keyboard`, then the `extra_keyboards`.
+    pub fn keyboards(&self) -> impl Iterator<Item = &'static Keyboard> + '_ {
+        std::iter::once(self.keyboard).chain(self.extra_keyboards.iter().copied())
+    }
+
+    /// Changed whenever readings change. Searchers compare it with the
+    /// version they were last used with and drop stale caches by
+    /// themselves. Versions are unique within the process, so a searcher
+    /// moved to another context never takes it for the one it knew.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn invalidate(&mut self) {
+        self.version = next_version();
+        lock(&self.targets).clear();
+        if let Some(matcher) = self.matcher.get() {
+            matcher.reset();
+        }
+    }
+
+    /// Applies changes made to `fuzzy`, `keyboard` or `extra_keyboards`
+    /// to the loaded readings.
+    pub fn commit(&mut self) {
+        let (fuzzy, keyboard, extra) = (&self.fuzzy, self.keyboard, &self.extra_keyboards);
+        for (raw, p) in Arc::make_mut(&mut self.pinyins).iter_mut() {
+            *p = Arc::new(Pinyin::with_keyboards(raw, fuzzy, keyboard, extra, p.id));
+        }
+        let chars = Arc::make_mut(&mut self.chars).values_mut().flatten();
+        let stand_ins = Arc::make_mut(&mut self.phrase_stand_ins).values_mut();
+        for c in chars.chain(stand_ins).chain(Arc::make_mut(&mut self.romanized_chars).values_mut()) {
+            for p in c.pinyin.iter_mut() {
+                if let Some(new) = self.pinyins.get(p.raw.as_str()) {
+                    *p = new.clone();
+                }
+            }
+        }
+        if let Some(lazy) = &mut self.lazy {
+            *lazy = LazyChars::new(lazy.dict.clone());
+        }
+        self.intern();
+        self.invalidate();
+    }
+
+    /// Sets `fuzzy` and commits it.
+    pub fn set_fuzzy(&mut self, fuzzy: FuzzySettings) {
+        self.fuzzy = fuzzy;
+        self.commit();
+    }
+
+    /// Sets `keyboard` and commits it.
+    pub fn set_keyboard(&mut self, keyboard: &'static Keyboard) {
+        self.keyboard = keyboard;
+        self.commit();
+    }
+
+    /// A [`Ticket`] running `on_change` once the context changes from now.
+    pub fn ticket<F: FnMut()>(&self, on_change: F) -> Ticket<F> {
+        Ticket { version: self.version, on_change }
+    }
+
+    fn insert_readings(&mut self, readings: std::collections::HashMap<char, Vec<String>>) {
+        self.invalidate();
+        self.user_base.clear();
+        self.number_readings(readings.values().flatten().map(String::as_str).collect());
+        for (c, ss) in readings {
             if ss.is_empty() {
-                self.chars.insert(c, None);
+                Arc::make_mut(&mut self.chars).insert(c, None);
             } else {
-                self.chars.insert(
-                    c,
-                    Some(Character::new(
-                        c,
-                        ss.iter().map(|s| self.get_or_insert_pinyin(s)).collect(),
-                    )),
-                );
+                let pinyin = ss.iter().map(|s| self.get_or_insert_pinyin(s)).collect();
+                Arc::make_mut(&mut self.chars).insert(c, Some(Character::new(c, pinyin)));
             }
+        }
+        self.romanize_readings();
+        self.intern();
+    }
+
+    /// Adds the readings not loaded yet, numbered in sorted order rather
+    /// than in the order they come in, so that the same loads always give
+    /// the same ids and romanized stand-ins.
+    fn number_readings(&mut self, mut raws: Vec<&str>) {
+        raws.sort_unstable();
+        raws.dedup();
+        raws.into_iter().for_each(|x| {
+            self.get_or_insert_pinyin(x);
         });
     }
 
-    pub fn get_character(&self, c: char) -> Cow<Character> {
-        self.chars
-            .get(&c)
-            .and_then(|x| x.as_ref().map(Cow::Borrowed))
+    /// Gives the readings without a romanized stand-in one.
+    fn romanize_readings(&mut self) {
+        let mut readings: Vec<(CompactString, Arc<Pinyin>)> = self
+            .pinyins
+            .iter()
+            .filter(|(raw, _)| !self.romanized.contains_key(*raw))
+            .map(|(raw, p)| (raw.clone(), p.clone()))
+            .collect();
+        readings.sort_by_key(|(_, p)| p.id);
+        for (raw, pinyin) in readings {
+            let Some(c) = char::from_u32(ROMANIZED_BASE + self.romanized.len() as u32) else {
+                break;
+            };
+            Arc::make_mut(&mut self.romanized).insert(raw, c);
+            Arc::make_mut(&mut self.romanized_chars).insert(c, Character::new(c, [pinyin].into_iter().collect()));
+        }
+    }
+
+    /// Shares the loaded tables through `pool`, now and after every later
+    /// load or [`commit`](PinIn::commit).
+    pub fn set_intern_pool(&mut self, pool: InternPool) {
+        self.pool = Some(pool);
+        self.intern();
+    }
+
+    fn intern(&mut self) {
+        if let Some(pool) = &self.pool {
+            pool.intern(&mut self.chars, &mut self.pinyins, &mut self.romanized);
+        }
+    }
+
+    /// Layers user readings over the loaded dictionary. Characters in
+    /// `dict` keep only their user readings, see
+    /// [`load_dict_overlay`](PinIn::load_dict_overlay) to keep the others.
+    /// Searchers pick up the change by themselves, and
+    /// [`unload_user_dict`](PinIn::unload_user_dict) takes it back.
+    pub fn load_user_dict(&mut self, dict: &UserDict) {
+        let readings = dict.readings();
+        let mut base = std::mem::take(&mut self.user_base);
+        for c in readings.keys() {
+            base.entry(*c)
+                .or_insert_with(|| self.loaded(*c).map(|x| x.pinyin.iter().map(|p| p.raw.to_string()).collect()));
+        }
+        self.insert_readings(readings);
+        self.user_base = base;
+    }
+
+    /// Gives the chars of every user dictionary loaded since the last
+    /// dictionary the readings they had before.
+    pub fn unload_user_dict(&mut self) {
+        let base = std::mem::take(&mut self.user_base);
+        let (restored, unloaded): (Vec<_>, Vec<_>) = base.into_iter().partition(|(_, x)| x.is_some());
+        for (c, _) in unloaded {
+            Arc::make_mut(&mut self.chars).remove(&c);
+        }
+        self.insert_readings(restored.into_iter().map(|(c, x)| (c, x.unwrap_or_default())).collect());
+    }
+
+    /// Starts a [`MatchSession`] that keeps its own caches, leaving the
+    /// context itself untouched while matching.
+    pub fn session(&self) -> MatchSession<'_> {
+        MatchSession::new(self)
+    }
+
+    /// The readings of `c`. Stand-ins are not resolved, they only come
+    /// out of targets rewritten by the context itself.
+    pub fn get_character(&self, c: char) -> Cow<'_, Character> {
+        self.loaded(c)
+            .map(Cow::Borrowed)
             .unwrap_or_else(|| Cow::Owned(Character::new(c, Default::default())))
     }
 
+    /// The readings of `c` in a target, stand-ins included.
+    pub(crate) fn target_character(&self, c: char) -> Cow<'_, Character> {
+        let stand_in = if c as u32 >= PHRASE_BASE {
+            self.phrase_stand_ins.get(&c)
+        } else if is_reserved(c) {
+            self.romanized_chars.get(&c)
+        } else {
+            None
+        };
+        match stand_in {
+            Some(x) => Cow::Borrowed(x),
+            None => self.get_character(c),
+        }
+    }
+
+    /// Every char of the loaded dictionaries with its readings, decoding
+    /// those loaded lazily.
+    pub(crate) fn loaded_chars(&self) -> Vec<(char, Option<&Character>)> {
+        let mut ret: Vec<_> = self.chars.iter().map(|(c, x)| (*c, x.as_ref())).collect();
+        if let Some(lazy) = &self.lazy {
+            let rest = lazy.dict.chars().filter(|c| !self.chars.contains_key(c));
+            ret.extend(rest.map(|c| (c, lazy.get(&self.pinyins, c))));
+        }
+        ret
+    }
+
+    /// The readings of `c` in the loaded dictionaries, decoding them if
+    /// they come from one loaded lazily.
+    pub(crate) fn loaded(&self, c: char) -> Option<&Character> {
+        match self.chars.get(&c) {
+            Some(x) => x.as_ref(),
+            None => self.lazy.as_ref().and_then(|x| x.get(&self.pinyins, c)),
+        }
+    }
+
+    /// Whether `s2` from its `start2`-th grapheme matches `s1` from its
+    /// `start1`-th char, all of `s1` or, if `partial`, a prefix of it.
+    /// Starts past the end only match nothing with nothing.
     pub fn check(&self, s1: &str, start1: usize, s2: &str, start2: usize, partial: bool) -> bool {
-        if start2 == s2.chars().count() {
-            return partial || start1 == s1.chars().count();
+        let s1: Vec<char> = s1.chars().collect();
+        self.check_segmented(&s1, start1, &s2.into(), start2, partial)
+    }
+
+    fn check_segmented(&self, s1: &[char], start1: usize, s2: &SegmentedStr, start2: usize, partial: bool) -> bool {
+        if start2 >= s2.graphemes.len() || start1 >= s1.len() {
+            return start2 == s2.graphemes.len() && start1 <= s1.len() && (partial || start1 == s1.len());
         }
 
-        let r = self.get_character(s1.chars().nth(start1).unwrap());
-        let s = r.match_str(s2, start2, partial);
+        let r = self.get_character(s1[start1]);
+        let s = r.match_segmented(s2, start2, partial);
 
-        if start1 == s1.chars().count() - 1 {
-            let i = s2.chars().count() - start2;
+        if start1 == s1.len() - 1 {
+            let i = s2.graphemes.len() - start2;
             return s.get(i);
         }
 
-        s.traverse(|i| self.check(s1, start1 + 1, s2, start2 + i as usize, partial))
+        s.traverse(|i| self.check_segmented(s1, start1 + 1, s2, start2 + i as usize, partial))
     }
 
-    pub fn contains(&self, s1: &str, s2: &str) -> bool {
-        if !self.accelerate {
-            return if s1.trim().is_empty() {
-                s1.contains(s2)
-            } else {
-                s1.chars()
-                    .enumerate()
-                    .any(|(i, _)| self.check(s1, i, s2, 0, true))
+    /// The accelerator to match `s2` with. Matching always runs the same
+    /// algorithm; acceleration only decides whether its cache outlives the
+    /// call. Without `accelerate`, the shared cache is still picked when the
+    /// same query is repeated, as when refiltering a table on every change.
+    /// Threads matching at the same time get their own accelerator.
+    fn with_accelerator<P, F, R>(&self, provider: P, s2: &str, f: F) -> R
+    where
+        P: CharProvider + Send + Sync + 'static,
+        F: FnOnce(&Accelerator) -> R,
+    {
+        let repeated = {
+            let mut last = lock(&self.last_query);
+            let repeated = last.as_str() == s2;
+            if !repeated {
+                *last = s2.into();
+            }
+            repeated
+        };
+
+        let a = if self.accelerate || repeated {
+            self.matcher.get_or_init(Accelerator::new).claim()
+        } else {
+            Claim::Local(Box::default())
+        };
+        a.set_provider(Arc::new(RwLock::new(provider)));
+        a.search(&self.folded(s2));
+        f(&a)
+    }
+
+    /// `s` with every run of numbered pinyin, such as "zhong1guo2" or
+    /// "Hong2", replaced by private-use characters that carry those
+    /// readings, so that the run matches like the hanzi it stands for.
+    /// Runs are only replaced when every syllable has a tone digit and is
+    /// a reading of the loaded dictionary.
+    pub fn romanized<'s>(&self, s: &'s str) -> Cow<'s, str> {
+        if !s.bytes().any(|b| b.is_ascii_digit()) {
+            return Cow::Borrowed(s);
+        }
+
+        let mut ret = String::with_capacity(s.len());
+        let mut changed = false;
+        for run in s.split_inclusive(|c: char| !c.is_ascii_alphanumeric()) {
+            let (run, rest) = run.split_at(run.trim_end_matches(|c: char| !c.is_ascii_alphanumeric()).len());
+            match self.romanize_run(run) {
+                Some(chars) => {
+                    ret.extend(chars);
+                    changed = true;
+                }
+                None => ret.push_str(run),
+            }
+            ret.push_str(rest);
+        }
+
+        if changed {
+            Cow::Owned(ret)
+        } else {
+            Cow::Borrowed(s)
+        }
+    }
+
+    fn romanize_run(&self, run: &str) -> Option<Vec<char>> {
+        if !run.ends_with(|c: char| c.is_ascii_digit()) {
+            return None;
+        }
+        let run = run.to_ascii_lowercase();
+        run.split_inclusive(|c: char| c.is_ascii_digit())
+            .map(|syllable| self.romanized.get(syllable).copied())
+            .collect()
+    }
+
+    /// `s` with the chars stand-ins are taken from replaced.
+    fn escaped<'s>(&self, s: &'s str) -> Cow<'s, str> {
+        if !s.chars().any(is_reserved) {
+            return Cow::Borrowed(s);
+        }
+        Cow::Owned(s.chars().map(|c| if is_reserved(c) { char::REPLACEMENT_CHARACTER } else { c }).collect())
+    }
+
+    pub(crate) fn target_str<'s>(&self, s1: &'s str) -> Cow<'s, str> {
+        let s1 = match self.escaped(s1) {
+            Cow::Borrowed(s1) => self.folded(s1),
+            Cow::Owned(s1) => Cow::Owned(self.folded(&s1).into_owned()),
+        };
+        let s1 = match s1 {
+            Cow::Borrowed(s1) => self.phrased(s1),
+            Cow::Owned(s1) => Cow::Owned(self.phrased(&s1).into_owned()),
+        };
+        match (s1, self.romanized_targets) {
+            (Cow::Borrowed(s1), true) => self.romanized(s1),
+            (Cow::Owned(s1), true) => Cow::Owned(self.romanized(&s1).into_owned()),
+            (s1, false) => s1,
+        }
+    }
+
+    pub(crate) fn folded<'s>(&self, s: &'s str) -> Cow<'s, str> {
+        let s = if self.fold { fold(s) } else { Cow::Borrowed(s) };
+        match s {
+            Cow::Borrowed(s) if self.fold_case => fold_case(s),
+            Cow::Owned(s) if self.fold_case => Cow::Owned(fold_case(&s).into_owned()),
+            s => s,
+        }
+    }
+
+    /// The chars of [`folded`](PinIn::folded) `s`, each with the bytes of
+    /// `s` it comes from.
+    fn folded_chars(&self, s: &str) -> Vec<(char, Range<usize>)> {
+        let mut ret = Vec::with_capacity(s.len());
+        for (i, c) in s.char_indices() {
+            let range = i..i + c.len_utf8();
+            let mut push = |c: char| {
+                let c = if self.fold_case { lower_char(c).unwrap_or(c) } else { c };
+                ret.push((c, range.clone()));
             };
+            match fold_char(c).filter(|_| self.fold) {
+                Some(x) => x.chars().for_each(&mut push),
+                None => push(c),
+            }
+        }
+        ret
+    }
+
+    /// The chars of [`target_str`](PinIn::target_str) `s`, each with the
+    /// bytes of `s` it comes from; a romanized stand-in comes from its
+    /// whole syllable.
+    fn target_chars(&self, s: &str) -> Vec<(char, Range<usize>)> {
+        let mut chars = self.folded_chars(s);
+        chars.iter_mut().filter(|(c, _)| is_reserved(*c)).for_each(|(c, _)| *c = char::REPLACEMENT_CHARACTER);
+        let folded: String = chars.iter().map(|(c, _)| *c).collect();
+        if let Cow::Owned(phrased) = self.phrased(&folded) {
+            chars.iter_mut().zip(phrased.chars()).for_each(|((c, _), x)| *c = x);
+        }
+        if !self.romanized_targets || !folded.bytes().any(|b| b.is_ascii_digit()) {
+            return chars;
+        }
+
+        let s: String = chars.iter().map(|(c, _)| *c).collect();
+        let mut ret = Vec::with_capacity(chars.len());
+        let mut at = 0;
+        for run in s.split_inclusive(|c: char| !c.is_ascii_alphanumeric()) {
+            let (run, rest) = run.split_at(run.trim_end_matches(|c: char| !c.is_ascii_alphanumeric()).len());
+            match self.romanize_run(run) {
+                Some(stand_ins) => {
+                    for (c, syllable) in stand_ins.into_iter().zip(run.split_inclusive(|c: char| c.is_ascii_digit())) {
+                        ret.push((c, chars[at].1.start..chars[at + syllable.len() - 1].1.end));
+                        at += syllable.len();
+                    }
+                }
+                None => {
+                    ret.extend_from_slice(&chars[at..at + run.len()]);
+                    at += run.len();
+                }
+            }
+            let rest = rest.chars().count();
+            ret.extend_from_slice(&chars[at..at + rest]);
+            at += rest;
+        }
+        ret
+    }
+
+    /// `s` as searchers look it up: folded, then canonical.
+    pub(crate) fn query_str<'s>(&self, s: &'s str) -> Cow<'s, str> {
+        match self.folded(s) {
+            Cow::Borrowed(s) => canonicalize(s),
+            Cow::Owned(s) => Cow::Owned(canonicalize(&s).into_owned()),
+        }
+    }
+
+    /// The cached resolution of `s1`, or `None` when caching is off.
+    fn target(&self, s1: &str) -> Option<Arc<TargetProvider>> {
+        if self.target_cache_capacity == 0 {
+            return None;
+        }
+        if let Some(ret) = lock(&self.targets).get(s1) {
+            return Some(ret);
         }
 
-        let a = self.accelerator.as_ref().unwrap().as_ref();
-        *a.provider.borrow_mut() = Some(Rc::new(RefCell::new(StringProvider::from(s1))));
-        a.search(s2);
-        a.contains(self, 0, 0)
+        let ret = Arc::new(TargetProvider::new(self, s1));
+        lock(&self.targets).insert(s1.into(), ret.clone(), self.target_cache_capacity);
+        Some(ret)
+    }
+
+    pub fn contains(&self, s1: &str, s2: &str) -> bool {
+        let s1 = self.target_str(s1);
+        match self.target(&s1) {
+            Some(target) => self.with_accelerator(target, s2, |a| a.contains(self, 0, 0)),
+            None => self.with_accelerator(StringProvider::from(s1.as_ref()), s2, |a| a.contains(self, 0, 0)),
+        }
+    }
+
+    /// `contains` over any [`CharProvider`], e.g. a
+    /// [`ChunkedProvider`](crate::accelerator::ChunkedProvider) wrapping an editor buffer.
+    pub fn contains_provider<P: CharProvider + Send + Sync + 'static>(&self, provider: P, s2: &str) -> bool {
+        self.with_accelerator(EscapedProvider(provider), s2, |a| a.contains(self, 0, 0))
     }
 
     pub fn begins(&self, s1: &str, s2: &str) -> bool {
-        if !self.accelerate {
-            return if s1.trim().is_empty() {
-                s1.starts_with(s2)
-            } else {
-                self.check(s1, 0, s2, 0, true)
-            };
+        let s1 = self.target_str(s1);
+        match self.target(&s1) {
+            Some(target) => self.with_accelerator(target, s2, |a| a.begins(self, 0, 0)),
+            None => self.with_accelerator(StringProvider::from(s1.as_ref()), s2, |a| a.begins(self, 0, 0)),
         }
+    }
 
-        let a = self.accelerator.as_ref().unwrap();
-        *a.provider.borrow_mut() = Some(Rc::new(RefCell::new(StringProvider::from(s1))));
-        a.search(s2);
-        a.begins(self, 0, 0)
+    /// `begins` over any [`CharProvider`].
+    pub fn begins_provider<P: CharProvider + Send + Sync + 'static>(&self, provider: P, s2: &str) -> bool {
+        self.with_accelerator(EscapedProvider(provider), s2, |a| a.begins(self, 0, 0))
     }
 
     pub fn matches(&self, s1: &str, s2: &str) -> bool {
-        if !self.accelerate {
-            return if s1.trim().is_empty() {
-                s1 == s2
-            } else {
-                self.check(s1, 0, s2, 0, true)
-            };
+        let s1 = self.target_str(s1);
+        match self.target(&s1) {
+            Some(target) => self.with_accelerator(target, s2, |a| a.matches(self, 0, 0)),
+            None => self.with_accelerator(StringProvider::from(s1.as_ref()), s2, |a| a.matches(self, 0, 0)),
+        }
+    }
+
+    /// `matches` over any [`CharProvider`].
+    pub fn matches_provider<P: CharProvider + Send + Sync + 'static>(&self, provider: P, s2: &str) -> bool {
+        self.with_accelerator(EscapedProvider(provider), s2, |a| a.matches(self, 0, 0))
+    }
+
+    /// Whether `s2` matches the end of `s1`, e.g. "wenben" for "测试文本".
+    pub fn ends(&self, s1: &str, s2: &str) -> bool {
+        let s1 = self.target_str(s1);
+        match self.target(&s1) {
+            Some(target) => self.with_accelerator(target, s2, |a| a.ends(self, 0, 0)),
+            None => self.with_accelerator(StringProvider::from(s1.as_ref()), s2, |a| a.ends(self, 0, 0)),
+        }
+    }
+
+    /// `ends` over any [`CharProvider`].
+    pub fn ends_provider<P: CharProvider + Send + Sync + 'static>(&self, provider: P, s2: &str) -> bool {
+        self.with_accelerator(EscapedProvider(provider), s2, |a| a.ends(self, 0, 0))
+    }
+
+    /// Byte ranges of `s1` consumed by `s2` where `contains` finds it,
+    /// for highlighting. Takes the leftmost match and, at that position,
+    /// the one spanning the fewest chars. Adjacent chars are merged into
+    /// one range. Chars that folding or
+    /// [`romanized_targets`](PinIn::romanized_targets) rewrite are
+    /// highlighted whole.
+    pub fn match_ranges(&self, s1: &str, s2: &str) -> Option<Vec<Range<usize>>> {
+        let chars = self.target_chars(s1);
+        let target: String = chars.iter().map(|(c, _)| *c).collect();
+        let (start, path) = self.with_accelerator(StringProvider::from(target.as_str()), s2, |a| {
+            (0..chars.len().max(1)).find_map(|start| a.path(self, 0, start).map(|x| (start, x)))
+        })?;
+
+        let mut ret: Vec<Range<usize>> = Vec::new();
+        for (i, consumed) in path.into_iter().enumerate() {
+            if consumed == 0 {
+                continue;
+            }
+            let range = chars[start + i].1.clone();
+            match ret.last_mut() {
+                Some(last) if last.end >= range.start => last.end = last.end.max(range.end),
+                _ => ret.push(range),
+            }
+        }
+        Some(ret)
+    }
+
+    /// Every distinct way `contains` can find `s2` in `s1`: where it
+    /// starts, and which reading of each char consumed which part of the
+    /// query. Byte ranges are those of `s1` and `s2` as given, like in
+    /// [`match_ranges`](PinIn::match_ranges).
+    pub fn match_alternatives(&self, s1: &str, s2: &str) -> Vec<Alignment> {
+        let chars = self.target_chars(s1);
+        let folded = self.folded_chars(s2);
+        let query: String = folded.iter().map(|(c, _)| *c).collect();
+        let query = SegmentedStr::from(query.as_str());
+        if query.graphemes.is_empty() {
+            return vec![Alignment::default()];
+        }
+        let mut ret = Vec::new();
+        for start in 0..chars.len() {
+            self.align(&chars, start, &query, 0, &mut Vec::new(), &mut ret);
+        }
+
+        // Steps hold bytes of the folded query until here
+        let starts: Vec<usize> = query.raw.char_indices().map(|(i, _)| i).collect();
+        let char_at = |byte: usize| starts.partition_point(|x| *x <= byte) - 1;
+        for step in ret.iter_mut().flat_map(|x| x.steps.iter_mut()) {
+            step.query = folded[char_at(step.query.start)].1.start..folded[char_at(step.query.end - 1)].1.end;
+        }
+        ret.dedup();
+        ret
+    }
+
+    fn align(&self, chars: &[(char, Range<usize>)], at: usize, query: &SegmentedStr, offset: usize, steps: &mut Vec<AlignStep>, ret: &mut Vec<Alignment>) {
+        if offset == query.graphemes.len() {
+            ret.push(Alignment { steps: steps.clone() });
+            return;
         }
+        let Some((c, target)) = chars.get(at).cloned() else {
+            return;
+        };
+        let byte = |i: usize| query.graphemes.get(i).map(|(x, _)| *x).unwrap_or(query.raw.len());
+
+        let mut options: Vec<(Option<CompactString>, usize)> = Vec::new();
+        if query.graphemes[offset].1.chars().eq([c]) {
+            options.push((None, 1));
+        }
+        for p in self.target_character(c).pinyin.iter() {
+            p.match_segmented(query, offset, true).for_each(|i| {
+                if i > 0 {
+                    options.push((Some(p.raw.clone()), i as usize));
+                }
+            });
+        }
+        for (reading, len) in options {
+            steps.push(AlignStep { target: target.clone(), query: byte(offset)..byte(offset + len), reading });
+            self.align(chars, at + 1, query, offset + len, steps, ret);
+            steps.pop();
+        }
+    }
+}
+
+/// How a query lines up with a target, see [`PinIn::match_alternatives`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Alignment {
+    /// One step per matched char of the target, in order.
+    pub steps: Vec<AlignStep>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlignStep {
+    /// Bytes of the char in the target.
+    pub target: Range<usize>,
+    /// Bytes of the query it consumed.
+    pub query: Range<usize>,
+    /// The reading that consumed them, `None` when the query has the char
+    /// itself.
+    pub reading: Option<CompactString>,
+}
+
+/// Tracks a context for code that keeps state derived from it outside of
+/// the searchers, which keep up by themselves: each call to
+/// [`Ticket::renew`] runs the callback if readings, fuzzy settings or
+/// keyboards changed since the previous one.
+///
+/// ```
+/// use pinin_rs::pinin::{FuzzySettings, PinIn};
+///
+/// let mut pinin = PinIn::new();
+/// let mut reloads = 0;
+/// let mut ticket = pinin.ticket(|| reloads += 1);
+/// ticket.renew(&pinin);
+/// pinin.set_fuzzy(FuzzySettings { zh2z: true, ..Default::default() });
+/// ticket.renew(&pinin);
+/// ticket.renew(&pinin);
+/// drop(ticket);
+/// assert_eq!(reloads, 1);
+/// ```
+pub struct Ticket<F> {
+    version: u64,
+    on_change: F,
+}
+
+impl<F: FnMut()> Ticket<F> {
+    /// Runs the callback if `context` changed since the last call, or
+    /// since the ticket was made. Returns whether it ran.
+    pub fn renew(&mut self, context: &PinIn) -> bool {
+        if self.version == context.version() {
+            return false;
+        }
+        self.version = context.version();
+        (self.on_change)();
+        true
+    }
+}
+
+/// Entry points for bindings that may hand over malformed text, such as
+/// invalid UTF-8 from C or unpaired surrogates from JNI/N-API strings.
+/// Invalid sequences are replaced with U+FFFD before matching.
+impl PinIn {
+    pub fn contains_lossy(&self, s1: &[u8], s2: &[u8]) -> bool {
+        self.contains(&String::from_utf8_lossy(s1), &String::from_utf8_lossy(s2))
+    }
+
+    pub fn begins_lossy(&self, s1: &[u8], s2: &[u8]) -> bool {
+        self.begins(&String::from_utf8_lossy(s1), &String::from_utf8_lossy(s2))
+    }
+
+    pub fn matches_lossy(&self, s1: &[u8], s2: &[u8]) -> bool {
+        self.matches(&String::from_utf8_lossy(s1), &String::from_utf8_lossy(s2))
+    }
+
+    pub fn contains_utf16_lossy(&self, s1: &[u16], s2: &[u16]) -> bool {
+        self.contains(&String::from_utf16_lossy(s1), &String::from_utf16_lossy(s2))
+    }
+
+    pub fn begins_utf16_lossy(&self, s1: &[u16], s2: &[u16]) -> bool {
+        self.begins(&String::from_utf16_lossy(s1), &String::from_utf16_lossy(s2))
+    }
 
-        let a = self.accelerator.as_ref().unwrap();
-        *a.provider.borrow_mut() = Some(Rc::new(RefCell::new(StringProvider::from(s1))));
-        a.search(s2);
-        a.begins(self, 0, 0)
+    pub fn matches_utf16_lossy(&self, s1: &[u16], s2: &[u16]) -> bool {
+        self.matches(&String::from_utf16_lossy(s1), &String::from_utf16_lossy(s2))
     }
 }
@@ -0,0 +1,61 @@
+//! Guards against queries too short to be useful, which on a
+//! [`Contain`](SearcherLogic::Contain) index match nearly everything.
+
+use crate::searcher::SearcherLogic;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+/// How a searcher treats short queries, see
+/// [`TreeSearcher::search_checked`](crate::searcher::TreeSearcher::search_checked).
+/// Lengths are in chars of the query, and the default changes nothing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct QueryPolicy {
+    /// Shorter queries are refused with [`QueryTooShort`].
+    pub min_len: usize,
+    /// Shorter queries on a [`Contain`](SearcherLogic::Contain) index only
+    /// match from the start of names, as with
+    /// [`Begin`](SearcherLogic::Begin).
+    pub begin_below: usize,
+}
+
+impl QueryPolicy {
+    /// What suits interactive search with `logic`: single letters on a
+    /// `Contain` index only match from the start, other logics are left
+    /// alone.
+    pub fn recommended(logic: SearcherLogic) -> Self {
+        match logic {
+            SearcherLogic::Contain => QueryPolicy { min_len: 1, begin_below: 2 },
+            _ => QueryPolicy::default(),
+        }
+    }
+
+    /// The logic to run `s` with on an index built for `logic`.
+    pub fn resolve(&self, logic: SearcherLogic, s: &str) -> Result<SearcherLogic, QueryTooShort> {
+        let len = s.chars().count();
+        if len < self.min_len {
+            return Err(QueryTooShort { len, min: self.min_len });
+        }
+        Ok(match logic {
+            SearcherLogic::Contain if len < self.begin_below => SearcherLogic::Begin,
+            _ => logic,
+        })
+    }
+}
+
+/// A query refused by a [`QueryPolicy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueryTooShort {
+    /// Chars in the query.
+    pub len: usize,
+    /// Chars the policy asks for.
+    pub min: usize,
+}
+
+impl Display for QueryTooShort {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "query of {} chars is shorter than {}", self.len, self.min)
+    }
+}
+
+impl Error for QueryTooShort {}
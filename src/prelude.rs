@@ -0,0 +1,35 @@
+//! The supported public surface, meant to be glob-imported:
+//!
+//! ```
+//! use pinin_rs::prelude::*;
+//!
+//! let mut pinin = PinIn::new();
+//! pinin.load_default_dict();
+//! assert!(pinin.contains("测试文本", "ceshi"));
+//! ```
+//!
+//! Modules hidden from the docs are implementation details and may change
+//! between releases.
+
+pub use crate::accelerator::{Accelerator, CharProvider, ChunkedProvider, StringProvider};
+pub use crate::elements::{Character, Pinyin};
+pub use crate::filter::Filter;
+#[cfg(feature = "embedded-dict")]
+pub use crate::format::renderings;
+pub use crate::format::{number_format, phonetic_format, raw_format, unicode_format, PinyinFormat, Rendering};
+pub use crate::frequency::FrequencyTable;
+pub use crate::frozen::FrozenSearcher;
+pub use crate::ingest::{InsertError, Inserted, Sanitization};
+pub use crate::keyboard::{
+    Keyboard, ZeroInitial, KEYBOARD_DAQIAN, KEYBOARD_QUANPIN, KEYBOARD_XIAOHE, KEYBOARD_ZIRANMA,
+};
+pub use crate::map::PinyinMap;
+pub use crate::micro::{AutoSearcher, MicroSearcher};
+pub use crate::pinin::{FuzzySettings, NeutralTone, PinIn};
+pub use crate::policy::{QueryPolicy, QueryTooShort};
+pub use crate::searcher::{
+    Collection, Dedup, DedupBy, SearchHit, SearchOptions, Searcher, SearcherLogic, SearcherMut, SimpleSearcher, SpillSet,
+    Tokenizer, TreeSearcher,
+};
+pub use crate::unicode_utils::{SegmentedStr, UnicodeUtils};
+pub use crate::user_dict::UserDict;
@@ -0,0 +1,195 @@
+//! A small filter language over pinyin queries.
+//!
+//! Terms separated by whitespace (or `&`) must all match, `|` separates
+//! alternatives, a leading `-` or `!` negates a term or group and
+//! parentheses group sub-expressions: `hjl | xkc -liuti`.
+
+use crate::pinin::PinIn;
+use crate::searcher::TreeSearcher;
+use compact_str::CompactString;
+use rustc_hash::FxHashSet;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::iter::Peekable;
+use std::str::{CharIndices, FromStr};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Term(CompactString),
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Box<Query>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseQueryError {
+    Empty,
+    UnexpectedChar(usize, char),
+    UnclosedParen(usize),
+}
+
+impl Display for ParseQueryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseQueryError::Empty => f.write_str("empty query"),
+            ParseQueryError::UnexpectedChar(i, c) => write!(f, "unexpected `{}` at {}", c, i),
+            ParseQueryError::UnclosedParen(i) => write!(f, "unclosed `(` at {}", i),
+        }
+    }
+}
+
+impl Error for ParseQueryError {}
+
+struct Parser<'a> {
+    src: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+    }
+
+    fn peek(&mut self) -> Option<(usize, char)> {
+        self.skip_whitespace();
+        self.chars.peek().copied()
+    }
+
+    fn parse_or(&mut self) -> Result<Query, ParseQueryError> {
+        let mut ret = vec![self.parse_and()?];
+        while let Some((_, '|')) = self.peek() {
+            self.chars.next();
+            ret.push(self.parse_and()?);
+        }
+        Ok(if ret.len() == 1 { ret.pop().unwrap() } else { Query::Or(ret) })
+    }
+
+    fn parse_and(&mut self) -> Result<Query, ParseQueryError> {
+        let mut ret = vec![];
+        loop {
+            match self.peek() {
+                None | Some((_, '|')) | Some((_, ')')) => break,
+                Some((_, '&')) => {
+                    self.chars.next();
+                }
+                Some(_) => ret.push(self.parse_unary()?),
+            }
+        }
+        match ret.len() {
+            0 => match self.peek() {
+                Some((i, c)) => Err(ParseQueryError::UnexpectedChar(i, c)),
+                None => Err(ParseQueryError::Empty),
+            },
+            1 => Ok(ret.pop().unwrap()),
+            _ => Ok(Query::And(ret)),
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Query, ParseQueryError> {
+        match self.peek() {
+            Some((_, '-')) | Some((_, '!')) => {
+                self.chars.next();
+                Ok(Query::Not(Box::new(self.parse_unary()?)))
+            }
+            Some((start, '(')) => {
+                self.chars.next();
+                let ret = self.parse_or()?;
+                match self.peek() {
+                    Some((_, ')')) => {
+                        self.chars.next();
+                        Ok(ret)
+                    }
+                    _ => Err(ParseQueryError::UnclosedParen(start)),
+                }
+            }
+            Some((start, c)) => {
+                let mut end = start;
+                while let Some((i, c)) = self
+                    .chars
+                    .next_if(|(_, c)| !c.is_whitespace() && !"|&()".contains(*c))
+                {
+                    end = i + c.len_utf8();
+                }
+                if end == start {
+                    return Err(ParseQueryError::UnexpectedChar(start, c));
+                }
+                Ok(Query::Term(self.src[start..end].into()))
+            }
+            None => Err(ParseQueryError::Empty),
+        }
+    }
+}
+
+impl FromStr for Query {
+    type Err = ParseQueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            src: s,
+            chars: s.char_indices().peekable(),
+        };
+        let ret = parser.parse_or()?;
+        match parser.peek() {
+            Some((i, c)) => Err(ParseQueryError::UnexpectedChar(i, c)),
+            None => Ok(ret),
+        }
+    }
+}
+
+impl Query {
+    /// Matching insertion indices of `searcher`, in ascending order.
+    pub fn evaluate_ids<T>(&self, context: &PinIn, searcher: &TreeSearcher<T>) -> Vec<usize> {
+        let mut ret: Vec<_> = self.eval(context, searcher).into_iter().collect();
+        ret.sort_unstable();
+        ret
+    }
+
+    pub fn evaluate<'s, T>(&self, context: &PinIn, searcher: &'s TreeSearcher<T>) -> Vec<&'s T> {
+        self.evaluate_ids(context, searcher)
+            .into_iter()
+            .filter_map(|i| searcher.get(i))
+            .collect()
+    }
+
+    fn eval<T>(&self, context: &PinIn, searcher: &TreeSearcher<T>) -> FxHashSet<usize> {
+        match self {
+            Query::Term(s) => searcher.search_ids(context, s),
+            Query::Or(queries) => {
+                let mut ret = FxHashSet::default();
+                queries.iter().for_each(|q| ret.extend(q.eval(context, searcher)));
+                ret
+            }
+            Query::Not(query) => {
+                let excluded = query.eval(context, searcher);
+                (0..searcher.len()).filter(|i| !excluded.contains(i)).collect()
+            }
+            Query::And(queries) => {
+                // Negated terms only shrink the candidate set, so they are
+                // subtracted instead of being complemented over the whole index
+                let (negative, positive): (Vec<_>, Vec<_>) =
+                    queries.iter().partition(|q| matches!(q, Query::Not(_)));
+
+                let mut ret: Option<FxHashSet<usize>> = None;
+                for q in positive {
+                    let set = q.eval(context, searcher);
+                    ret = Some(match ret {
+                        Some(ret) => ret.into_iter().filter(|i| set.contains(i)).collect(),
+                        None => set,
+                    });
+                    if ret.as_ref().map(|x| x.is_empty()).unwrap_or(false) {
+                        return FxHashSet::default();
+                    }
+                }
+                let mut ret = ret.unwrap_or_else(|| (0..searcher.len()).collect());
+                for q in negative {
+                    if let Query::Not(q) = q {
+                        q.eval(context, searcher).iter().for_each(|i| {
+                            ret.remove(i);
+                        });
+                    }
+                }
+                ret
+            }
+        }
+    }
+}
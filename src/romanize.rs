@@ -0,0 +1,137 @@
+//! Romanization of text of any size, written out as it is read.
+//!
+//! Characters with a reading are replaced by their first reading and
+//! consecutive readings are separated by a space; everything else is
+//! copied as is. Memory use does not depend on the input size.
+
+use crate::elements::Pinyin;
+use crate::pinin::PinIn;
+use std::borrow::Cow;
+use std::io::{self, ErrorKind, Read, Write};
+
+/// Output is written in pieces of about this many bytes.
+const CHUNK: usize = 8 * 1024;
+
+pub fn romanize<I, W>(context: &PinIn, input: I, format: fn(&Pinyin) -> Cow<str>, out: &mut W) -> io::Result<()>
+where
+    I: IntoIterator<Item = char>,
+    W: Write,
+{
+    romanize_results(context, input.into_iter().map(Ok), format, out)
+}
+
+/// Like [`romanize`], decoding `input` as UTF-8. Invalid sequences are
+/// replaced with U+FFFD.
+pub fn romanize_reader<R, W>(context: &PinIn, input: R, format: fn(&Pinyin) -> Cow<str>, out: &mut W) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    romanize_results(context, Utf8Chars::new(input), format, out)
+}
+
+fn romanize_results<I, W>(context: &PinIn, input: I, format: fn(&Pinyin) -> Cow<str>, out: &mut W) -> io::Result<()>
+where
+    I: Iterator<Item = io::Result<char>>,
+    W: Write,
+{
+    let mut buf = String::with_capacity(CHUNK + 64);
+    let mut syllable = false;
+    for c in input {
+        let c = c?;
+        match context.get_character(c).pinyin.first() {
+            Some(p) => {
+                if syllable {
+                    buf.push(' ');
+                }
+                buf.push_str(&format(p));
+                syllable = true;
+            }
+            None => {
+                buf.push(c);
+                syllable = false;
+            }
+        }
+        if buf.len() >= CHUNK {
+            out.write_all(buf.as_bytes())?;
+            buf.clear();
+        }
+    }
+    out.write_all(buf.as_bytes())?;
+    out.flush()
+}
+
+/// Decodes UTF-8 from a reader a buffer at a time.
+struct Utf8Chars<R> {
+    input: R,
+    buf: Vec<u8>,
+    pos: usize,
+    eof: bool,
+}
+
+impl<R: Read> Utf8Chars<R> {
+    fn new(input: R) -> Self {
+        Utf8Chars {
+            input,
+            buf: Vec::with_capacity(CHUNK),
+            pos: 0,
+            eof: false,
+        }
+    }
+
+    /// Makes sure at least 4 bytes are buffered unless the input ended.
+    fn fill(&mut self) -> io::Result<()> {
+        while !self.eof && self.buf.len() - self.pos < 4 {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+            let len = self.buf.len();
+            self.buf.resize(CHUNK.max(len + 4), 0);
+            match self.input.read(&mut self.buf[len..]) {
+                Ok(0) => {
+                    self.eof = true;
+                    self.buf.truncate(len);
+                }
+                Ok(n) => self.buf.truncate(len + n),
+                Err(e) if e.kind() == ErrorKind::Interrupted => self.buf.truncate(len),
+                Err(e) => {
+                    self.buf.truncate(len);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for Utf8Chars<R> {
+    type Item = io::Result<char>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.fill() {
+            return Some(Err(e));
+        }
+        let rest = &self.buf[self.pos..];
+        if rest.is_empty() {
+            return None;
+        }
+
+        let len = match rest[0] {
+            0x00..=0x7F => 1,
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF7 => 4,
+            _ => 0,
+        };
+        match rest.get(..len).map(std::str::from_utf8) {
+            Some(Ok(s)) if len > 0 => {
+                self.pos += len;
+                s.chars().next().map(Ok)
+            }
+            // Skip one byte at a time until in sync again
+            _ => {
+                self.pos += 1;
+                Some(Ok(char::REPLACEMENT_CHARACTER))
+            }
+        }
+    }
+}
@@ -1,12 +1,22 @@
-use std::cell::{Cell, RefCell};
-use crate::accelerator::{Accelerator, CharProvider};
+use crate::accelerator::{Accelerator, Claim};
+use crate::budget::{Budget, Budgeted, Cancelled};
 use crate::compressed::{Compressor, IndexSet};
+use crate::frozen::FrozenSearcher;
+use crate::ingest::{check_name, InsertError, Inserted};
 use crate::pinin::PinIn;
+use crate::policy::{QueryPolicy, QueryTooShort};
+use crate::sync::{lock, read, write};
+use crate::sort::{literal_hits, natural_cmp};
+use std::cmp::Reverse;
 use std::collections::{BTreeSet, HashSet};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
 use std::hash::Hash;
 use std::marker::PhantomData;
-use std::ptr::NonNull;
-use std::rc::Rc;
+use std::ops::{ControlFlow, Range};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use rustc_hash::{FxHashMap, FxHashSet};
 use smallvec::{Array, SmallVec};
 
@@ -16,10 +26,136 @@ pub trait Searcher<T: 'static> {
     fn insert(&mut self, context: &PinIn, name: &str, id: T);
     fn search(&self, context: &PinIn, s: &str) -> Vec<&T>;
     fn reset(&mut self, context: &PinIn);
+
+    /// Like `insert`, refusing names that would misbehave once indexed and
+    /// reporting what was changed in the others, see [`check_name`].
+    fn try_insert(&mut self, context: &PinIn, name: &str, id: T) -> Result<Inserted, InsertError> {
+        let ret = check_name(name)?;
+        self.insert(context, name, id);
+        Ok(ret)
+    }
+
+    /// Number of objects `search` would return.
+    fn count(&self, context: &PinIn, s: &str) -> usize {
+        self.search(context, s).len()
+    }
+
+    /// Whether `search` would return anything.
+    fn any(&self, context: &PinIn, s: &str) -> bool {
+        !self.search(context, s).is_empty()
+    }
+
+    /// Like `search`, feeding each match to `ret` instead of returning a
+    /// new vector, so hot loops can reuse one buffer across queries. Stops
+    /// early once `ret` is full.
+    fn search_into<'s>(&'s self, context: &PinIn, s: &str, ret: &mut dyn Collection<&'s T>) {
+        for x in self.search(context, s) {
+            if ret.is_full() {
+                break;
+            }
+            ret.insert(x);
+        }
+    }
+
+    /// Runs several queries at once, evaluating duplicate queries once.
+    /// Distinct queries share nothing: the accelerator starts over on any
+    /// change of query, as what a char matches depends on the whole rest
+    /// of it. Results follow the order of `queries`.
+    fn search_many(&self, context: &PinIn, queries: &[&str]) -> Vec<Vec<&T>> {
+        let mut order: Vec<usize> = (0..queries.len()).collect();
+        order.sort_by_key(|i| queries[*i]);
+
+        let mut ret: Vec<Vec<&T>> = vec![Vec::new(); queries.len()];
+        let mut last: Option<usize> = None;
+        for i in order {
+            ret[i] = match last {
+                Some(j) if queries[j] == queries[i] => ret[j].clone(),
+                _ => self.search(context, queries[i]),
+            };
+            last = Some(i);
+        }
+        ret
+    }
+
+    /// Like `search_many`, running the distinct queries on the rayon
+    /// thread pool. Concurrent queries each claim an accelerator of their
+    /// own, so this pays off for batches of slow queries rather than many
+    /// cheap ones.
+    #[cfg(feature = "rayon")]
+    fn search_many_parallel(&self, context: &PinIn, queries: &[&str]) -> Vec<Vec<&T>>
+    where
+        Self: Sized + Sync,
+        T: Sync,
+    {
+        use rayon::prelude::*;
+
+        let mut distinct = queries.to_vec();
+        distinct.sort_unstable();
+        distinct.dedup();
+        let found: Vec<Vec<&T>> = distinct.par_iter().map(|s| self.search(context, s)).collect();
+        queries
+            .iter()
+            .map(|s| found[distinct.binary_search(s).unwrap()].clone())
+            .collect()
+    }
+
+    /// Like `search`, giving up once `budget` runs out.
+    fn search_within(&self, context: &PinIn, s: &str, budget: &Budget) -> Result<Vec<&T>, Cancelled> {
+        if budget.is_expired() {
+            return Err(Cancelled);
+        }
+        Ok(self.search(context, s))
+    }
+}
+
+/// Searchers whose entries can be removed or renamed once inserted.
+pub trait SearcherMut<T: 'static>: Searcher<T> {
+    /// Keeps the entries for which `f`, given the inserted name and the
+    /// object, returns true. Removed entries are dropped together with
+    /// their indexed names, and the remaining ones are renumbered in
+    /// their original order.
+    fn retain(&mut self, context: &PinIn, f: &mut dyn FnMut(&str, &T) -> bool);
+
+    /// Gives every entry whose object equals `id` the name `name`, keeping
+    /// its place. Returns how many entries were renamed.
+    fn update(&mut self, context: &PinIn, id: &T, name: &str) -> usize where T: PartialEq;
+
+    /// Removes every entry whose object equals `id`, returning how many
+    /// there were. See [`SearcherMut::retain`].
+    fn remove(&mut self, context: &PinIn, id: &T) -> usize where T: PartialEq {
+        let mut ret = 0;
+        self.retain(context, &mut |_, x| {
+            let keep = x != id;
+            ret += usize::from(!keep);
+            keep
+        });
+        ret
+    }
+
+    /// Removes every entry inserted under `name`, returning how many there
+    /// were. See [`SearcherMut::retain`].
+    fn remove_by_name(&mut self, context: &PinIn, name: &str) -> usize {
+        let mut ret = 0;
+        self.retain(context, &mut |x, _| {
+            let keep = x != name;
+            ret += usize::from(!keep);
+            keep
+        });
+        ret
+    }
 }
 
 pub trait Collection<T> {
     fn insert(&mut self, data: T);
+
+    /// Tree traversal stops early once this returns true.
+    fn is_full(&self) -> bool {
+        false
+    }
+
+    /// Upper bound of the items a traversal may insert, given before it
+    /// starts. Collections that allocate per item are free to ignore it.
+    fn reserve(&mut self, _additional: usize) {}
 }
 
 impl<T> Collection<T> for HashSet<T> where T: Eq + Hash {
@@ -28,6 +164,12 @@ impl<T> Collection<T> for HashSet<T> where T: Eq + Hash {
     }
 }
 
+impl<T> Collection<T> for FxHashSet<T> where T: Eq + Hash {
+    fn insert(&mut self, data: T) {
+        self.insert(data);
+    }
+}
+
 impl<T> Collection<T> for BTreeSet<T> where T: Eq + Ord {
     fn insert(&mut self, data: T) {
         self.insert(data);
@@ -46,123 +188,1528 @@ impl<A> Collection<A::Item> for SmallVec<A> where A: Array {
     }
 }
 
+/// Remembers whether anything was inserted.
+#[derive(Default)]
+struct AnyHit(bool);
+
+impl Collection<usize> for AnyHit {
+    fn insert(&mut self, _data: usize) {
+        self.0 = true;
+    }
+
+    fn is_full(&self) -> bool {
+        self.0
+    }
+}
+
+/// Counts what it is given.
+#[derive(Default)]
+struct Counter(usize);
+
+impl Collection<usize> for Counter {
+    fn insert(&mut self, _data: usize) {
+        self.0 += 1;
+    }
+}
+
+/// Hands the object of each index to a callback until it breaks.
+struct Streaming<'t, T, F> {
+    objects: &'t [T],
+    on_hit: F,
+    stopped: bool,
+}
+
+impl<'t, T, F: FnMut(&'t T) -> ControlFlow<()>> Collection<usize> for Streaming<'t, T, F> {
+    fn insert(&mut self, data: usize) {
+        if !self.stopped {
+            self.stopped = (self.on_hit)(&self.objects[data]).is_break();
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.stopped
+    }
+}
+
+/// Collects hits until `cap` of them are found.
+struct Batch {
+    found: Vec<usize>,
+    cap: usize,
+}
+
+impl Collection<usize> for Batch {
+    fn insert(&mut self, data: usize) {
+        self.found.push(data);
+    }
+
+    fn is_full(&self) -> bool {
+        self.found.len() >= self.cap
+    }
+}
+
+/// Returned by [`TreeSearcher::search_iter`]. Walks the tree again for
+/// every batch of hits, each batch twice as large as the one before,
+/// skipping the ones already yielded.
+struct SearchIter<'s, T: 'static> {
+    searcher: &'s TreeSearcher<T>,
+    context: &'s PinIn,
+    query: String,
+    hits: Dedup<Batch>,
+    next: usize,
+    done: bool,
+}
+
+impl<'s, T> Iterator for SearchIter<'s, T> {
+    type Item = &'s T;
+
+    fn next(&mut self) -> Option<&'s T> {
+        if self.next == self.hits.inner.found.len() {
+            if self.done {
+                return None;
+            }
+            let batch = &mut self.hits.inner;
+            batch.found.clear();
+            batch.cap = (batch.cap * 2).max(16);
+            self.next = 0;
+            self.searcher.search_ids_into(self.context, &self.query, &mut self.hits);
+            self.done = !self.hits.inner.is_full();
+        }
+        let ret = self.hits.inner.found.get(self.next)?;
+        self.next += 1;
+        Some(&self.searcher.objects[*ret])
+    }
+}
+
+/// Passes on the objects at the inserted indices.
+pub(crate) struct Objects<'s, 'c, T> {
+    pub(crate) objects: &'s [T],
+    pub(crate) inner: &'c mut dyn Collection<&'s T>,
+}
+
+impl<'s, T> Collection<usize> for Objects<'s, '_, T> {
+    fn insert(&mut self, data: usize) {
+        self.inner.insert(&self.objects[data]);
+    }
+
+    fn is_full(&self) -> bool {
+        self.inner.is_full()
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.inner.reserve(additional);
+    }
+}
+
+/// Translates tree ids to object indices, dropping ids of old names.
+struct Slots<'c> {
+    slots: &'c [usize],
+    inner: &'c mut dyn Collection<usize>,
+}
+
+impl Collection<usize> for Slots<'_> {
+    fn insert(&mut self, data: usize) {
+        match self.slots[data] {
+            usize::MAX => {}
+            x => self.inner.insert(x),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.inner.is_full()
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.inner.reserve(additional);
+    }
+}
+
+/// Passes on the indices of names within `options`, see
+/// [`TreeSearcher::search_ids_into_with`].
+struct Limited<'c, T: 'static> {
+    searcher: &'c TreeSearcher<T>,
+    context: &'c PinIn,
+    accelerator: &'c Accelerator,
+    options: SearchOptions,
+    inner: &'c mut dyn Collection<usize>,
+}
+
+impl<T: 'static> Collection<usize> for Limited<'_, T> {
+    fn insert(&mut self, data: usize) {
+        if self.searcher.within(self.context, self.accelerator, &self.options, data) {
+            self.inner.insert(data);
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.inner.is_full()
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.inner.reserve(additional);
+    }
+}
+
+/// Passes each index on to `inner` only the first time it is seen, so
+/// collectors such as scoring heaps need no dedup of their own.
+pub struct Dedup<C> {
+    inner: C,
+    seen: Vec<u64>,
+}
+
+impl<C: Collection<usize>> Dedup<C> {
+    pub fn new(inner: C) -> Self {
+        Dedup { inner, seen: Vec::new() }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: Collection<usize>> Collection<usize> for Dedup<C> {
+    fn insert(&mut self, data: usize) {
+        let (word, bit) = (data / 64, 1 << (data % 64));
+        if word >= self.seen.len() {
+            self.seen.resize(word + 1, 0);
+        }
+        if self.seen[word] & bit == 0 {
+            self.seen[word] |= bit;
+            self.inner.insert(data);
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.inner.is_full()
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.seen.reserve(additional.div_ceil(64));
+    }
+}
+
+/// Distinct indices in a hash set while there are few of them, then in a
+/// vector that is sorted and deduplicated whenever it fills up, which
+/// takes far less memory for queries with huge numbers of hits. With a
+/// cap, collection stops once more distinct indices than that are seen.
+pub struct SpillSet {
+    set: FxHashSet<usize>,
+    spilled: Option<Vec<usize>>,
+    threshold: usize,
+    cap: usize,
+    hint: usize,
+    truncated: bool,
+}
+
+impl Default for SpillSet {
+    fn default() -> Self {
+        Self::new(4096)
+    }
+}
+
+impl SpillSet {
+    /// Switches to the vector past `threshold` distinct indices.
+    pub fn new(threshold: usize) -> Self {
+        SpillSet {
+            set: FxHashSet::default(),
+            spilled: None,
+            threshold,
+            cap: usize::MAX,
+            hint: 0,
+            truncated: false,
+        }
+    }
+
+    /// Keeps at most `cap` indices, see [`SpillSet::is_truncated`].
+    pub fn with_cap(mut self, cap: usize) -> Self {
+        self.cap = cap;
+        self
+    }
+
+    /// Whether indices were dropped because of the cap.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// The collected indices, ascending.
+    pub fn into_sorted_vec(mut self) -> Vec<usize> {
+        match self.spilled.take() {
+            Some(mut ret) => {
+                Self::compact(&mut ret, self.cap, &mut self.truncated);
+                ret
+            }
+            None => {
+                let mut ret: Vec<usize> = self.set.into_iter().collect();
+                ret.sort_unstable();
+                ret
+            }
+        }
+    }
+
+    fn compact(v: &mut Vec<usize>, cap: usize, truncated: &mut bool) {
+        v.sort_unstable();
+        v.dedup();
+        if v.len() > cap {
+            v.truncate(cap);
+            *truncated = true;
+        }
+    }
+}
+
+impl Collection<usize> for SpillSet {
+    fn insert(&mut self, data: usize) {
+        if self.truncated {
+            return;
+        }
+        let Some(spilled) = self.spilled.as_mut() else {
+            if self.set.len() == self.cap && !self.set.contains(&data) {
+                self.truncated = true;
+            } else if self.set.insert(data) && self.set.len() > self.threshold {
+                // Room for every distinct index the traversal may still
+                // find, plus slack so that compaction is not too frequent
+                let capacity = self.hint.min(self.cap).max(self.threshold) + self.threshold;
+                let mut spilled = Vec::with_capacity(capacity);
+                spilled.extend(std::mem::take(&mut self.set));
+                self.spilled = Some(spilled);
+            }
+            return;
+        };
+        if spilled.len() == spilled.capacity() {
+            Self::compact(spilled, self.cap, &mut self.truncated);
+            if spilled.len() * 2 > spilled.capacity() {
+                spilled.reserve(spilled.capacity());
+            }
+        }
+        if !self.truncated {
+            spilled.push(data);
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.truncated
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.hint = additional;
+    }
+}
+
+/// Like [`Dedup`], comparing a key extracted from each index instead,
+/// e.g. the id of an object inserted under several names.
+pub struct DedupBy<C, F, K> {
+    inner: C,
+    key: F,
+    seen: FxHashSet<K>,
+}
+
+impl<C, F, K> DedupBy<C, F, K> where C: Collection<usize>, F: FnMut(usize) -> K, K: Eq + Hash {
+    pub fn new(inner: C, key: F) -> Self {
+        DedupBy { inner, key, seen: Default::default() }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C, F, K> Collection<usize> for DedupBy<C, F, K> where C: Collection<usize>, F: FnMut(usize) -> K, K: Eq + Hash {
+    fn insert(&mut self, data: usize) {
+        if self.seen.insert((self.key)(data)) {
+            self.inner.insert(data);
+        }
+    }
 
+    fn is_full(&self) -> bool {
+        self.inner.is_full()
+    }
+}
 
 pub struct SimpleSearcher<T> {
     objects: Vec<T>,
+    version: Mutex<Option<u64>>,
     accelerator: Accelerator,
-    compressor: Rc<RefCell<Compressor>>,
+    compressor: Arc<RwLock<Compressor>>,
     logic: SearcherLogic,
+    policy: QueryPolicy,
 }
 
 impl<T> Searcher<T> for SimpleSearcher<T> where T: 'static {
     fn insert(&mut self, context: &PinIn, name: &str, id: T) {
-        self.compressor.borrow_mut().push(name);
-        name.chars().for_each(|c| {
-            context.get_character(c);
-        });
-
+        write(&self.compressor).push_as(&context.target_str(name), name);
         self.objects.push(id);
     }
 
     fn search(&self, context: &PinIn, s: &str) -> Vec<&T> {
-        self.accelerator.search(s);
-        let offsets = &self.compressor.borrow().offsets;
+        let a = self.prepare(context, s);
+        let offsets = &read(&self.compressor).offsets;
         offsets
             .iter()
             .enumerate()
-            .filter(|(_i, s)| self.logic.test_accelerator(&self.accelerator, context, 0, **s))
+            .filter(|(_i, s)| self.logic.test_accelerator(&a, context, 0, **s))
             .map(|(i, _)| &self.objects[i])
             .collect()
     }
 
-    fn reset(&mut self, _context: &PinIn) {
+    fn search_into<'s>(&'s self, context: &PinIn, s: &str, ret: &mut dyn Collection<&'s T>) {
+        let a = self.prepare(context, s);
+        let offsets = &read(&self.compressor).offsets;
+        for (i, offset) in offsets.iter().enumerate() {
+            if ret.is_full() {
+                break;
+            }
+            if self.logic.test_accelerator(&a, context, 0, *offset) {
+                ret.insert(&self.objects[i]);
+            }
+        }
+    }
+
+    /// Drops cached matches if the context changed since the last query.
+    /// Queries do this by themselves, so calling it is optional.
+    fn reset(&mut self, context: &PinIn) {
+        self.sync(context);
+    }
+
+    fn count(&self, context: &PinIn, s: &str) -> usize {
+        let a = self.prepare(context, s);
+        let offsets = &read(&self.compressor).offsets;
+        offsets
+            .iter()
+            .filter(|s| self.logic.test_accelerator(&a, context, 0, **s))
+            .count()
+    }
+
+    fn any(&self, context: &PinIn, s: &str) -> bool {
+        let a = self.prepare(context, s);
+        let offsets = &read(&self.compressor).offsets;
+        offsets
+            .iter()
+            .any(|s| self.logic.test_accelerator(&a, context, 0, *s))
+    }
+
+    fn search_within(&self, context: &PinIn, s: &str, budget: &Budget) -> Result<Vec<&T>, Cancelled> {
+        let a = self.prepare(context, s);
+        let offsets = &read(&self.compressor).offsets;
+        let mut ret = Vec::new();
+        for (i, offset) in offsets.iter().enumerate() {
+            if budget.is_expired() {
+                return Err(Cancelled);
+            }
+            if self.logic.test_accelerator(&a, context, 0, *offset) {
+                ret.push(&self.objects[i]);
+            }
+        }
+        Ok(ret)
+    }
+}
+
+impl<T> SearcherMut<T> for SimpleSearcher<T> where T: 'static {
+    fn update(&mut self, context: &PinIn, id: &T, name: &str) -> usize where T: PartialEq {
+        let indices: Vec<usize> = (0..self.objects.len()).filter(|i| self.objects[*i] == *id).collect();
+        indices.iter().for_each(|i| {
+            self.rename(context, *i, name);
+        });
+        indices.len()
+    }
+
+    fn retain(&mut self, context: &PinIn, f: &mut dyn FnMut(&str, &T) -> bool) {
+        let compressor = write(&self.compressor).take();
+        let objects = std::mem::take(&mut self.objects);
         self.accelerator.reset();
+        for (i, id) in objects.into_iter().enumerate() {
+            let name = compressor.original(i).unwrap_or_default();
+            if f(&name, &id) {
+                self.insert(context, &name, id);
+            }
+        }
     }
 }
 
 impl<T> SimpleSearcher<T> {
     pub fn new(logic: SearcherLogic) -> Self {
-        let compressor = Rc::new(RefCell::new(Compressor::default()));
-        let accelerator = Accelerator::new();
-        *accelerator.provider.borrow_mut() = Some(compressor.clone() as Rc<RefCell<dyn CharProvider>>);
+        let compressor = Arc::new(RwLock::new(Compressor::default()));
+        let accelerator = Accelerator::with_provider(compressor.clone());
         SimpleSearcher {
             objects: Vec::new(),
+            version: Mutex::new(None),
             accelerator,
             compressor,
-            logic
+            logic,
+            policy: QueryPolicy::default(),
+        }
+    }
+
+    /// Sets how [`SimpleSearcher::search_checked`] treats short queries.
+    pub fn set_policy(&mut self, policy: QueryPolicy) {
+        self.policy = policy;
+    }
+
+    fn sync(&self, context: &PinIn) {
+        let mut version = lock(&self.version);
+        if *version != Some(context.version()) {
+            self.accelerator.reset();
+            *version = Some(context.version());
+        }
+    }
+
+    /// Gives the `index`-th object the name `name`. Returns false if
+    /// there is no such object.
+    pub fn rename(&mut self, context: &PinIn, index: usize, name: &str) -> bool {
+        if index >= self.objects.len() {
+            return false;
         }
+        write(&self.compressor).replace_as(index, &context.target_str(name), name);
+        true
+    }
+
+    fn prepare(&self, context: &PinIn, s: &str) -> Claim<'_> {
+        self.sync(context);
+        let a = self.accelerator.claim();
+        a.search(&context.query_str(s));
+        a
+    }
+
+    /// Insertion indices of the matching objects, ascending.
+    pub fn search_indices(&self, context: &PinIn, s: &str) -> Vec<usize> {
+        self.indices_as(context, s, self.logic)
+    }
+
+    fn indices_as(&self, context: &PinIn, s: &str, logic: SearcherLogic) -> Vec<usize> {
+        let a = self.prepare(context, s);
+        let offsets = &read(&self.compressor).offsets;
+        (0..offsets.len())
+            .filter(|i| logic.test_accelerator(&a, context, 0, offsets[*i]))
+            .collect()
     }
 
+    /// Like `search`, applying the searcher's [`QueryPolicy`].
+    pub fn search_checked(&self, context: &PinIn, s: &str) -> Result<Vec<&T>, QueryTooShort> {
+        let logic = self.policy.resolve(self.logic, s)?;
+        Ok(self.indices_as(context, s, logic).into_iter().map(|i| &self.objects[i]).collect())
+    }
 }
 
 const BTREE_THRESHOLD: usize = 1024;
 
-pub trait Node<T> where T: 'static {
-    fn get_offset(&self, context: &PinIn, p: &TreeSearcher<T>, ret: &mut dyn Collection<usize>, offset: usize);
+#[doc(hidden)]
+/// Puts the names below `other` into `node` one by one, for nodes that
+/// do not graft otherwise.
+fn put_all<T: 'static>(node: Arc<dyn Node<T>>, context: &PinIn, p: &TreeSearcher<T>, other: &dyn Node<T>) -> Arc<dyn Node<T>> {
+    let mut entries = Vec::new();
+    other.entries(p, 0, &mut entries);
+    entries.into_iter().fold(node, |node, (name, id)| node.put(context, p, name, id))
+}
+
+pub trait Node<T>: Send + Sync where T: 'static {
+    fn get_offset(&self, context: &PinIn, p: &TreeSearcher<T>, a: &Accelerator, ret: &mut dyn Collection<usize>, offset: usize);
 
     fn get(&self, context: &PinIn, p: &TreeSearcher<T>, ret: &mut dyn Collection<usize>);
 
-    fn put(self: Rc<Self>, context: &PinIn, p: &TreeSearcher<T>, name: usize, id: usize) -> Rc<dyn Node<T>>;
+    fn put(self: Arc<Self>, context: &PinIn, p: &TreeSearcher<T>, name: usize, id: usize) -> Arc<dyn Node<T>>;
+
+    /// A copy of this node and those below it for names whose chars
+    /// moved `chars` on and whose ids moved `ids` on, see
+    /// [`TreeSearcher::merge`].
+    fn moved(&self, p: &TreeSearcher<T>, chars: usize, ids: usize) -> Arc<dyn Node<T>>;
+
+    /// Adds the names below `other`, a node as deep as this one.
+    fn graft(self: Arc<Self>, context: &PinIn, p: &TreeSearcher<T>, other: Arc<dyn Node<T>>) -> Arc<dyn Node<T>>;
+
+    /// The names below this node as `put` takes them on a node `depth`
+    /// chars above it.
+    fn entries(&self, p: &TreeSearcher<T>, depth: usize, ret: &mut Vec<(usize, usize)>);
+
+    /// The children and leaves of map nodes, which graft onto each other
+    /// child by child.
+    fn map(&self) -> Option<&NMap<T>> {
+        None
+    }
+
+    /// Adds the accelerated maps at or below this node.
+    fn accs(self: Arc<Self>, ret: &mut Vec<Arc<NAcc<T>>>);
+
+    /// Appends the images of this node and those below it, children
+    /// first, and returns the index of its own.
+    #[cfg(feature = "serde")]
+    fn image(&self, nodes: &mut Vec<NodeImage>) -> usize;
+}
+
+/// A node of a serialized [`TreeSearcher`]. Nodes refer to their
+/// children by index, and children come first.
+#[cfg(feature = "serde")]
+#[doc(hidden)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub enum NodeImage {
+    Dense(Vec<usize>),
+    Slice { start: usize, end: usize, exit: usize },
+    Map { leaves: Vec<usize>, children: Vec<(char, usize)>, acc: bool },
 }
 
+/// Limits on the names a query may match, checked as the tree is walked,
+/// to drop noise such as two keys hitting a 40 char name. The default
+/// limits nothing; see [`TreeSearcher::search_with`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SearchOptions {
+    /// Longest name matched, in chars after the context's target
+    /// transformation.
+    pub max_len: Option<usize>,
+    /// Fewest chars of a name the query has to span, counted as by
+    /// [`TreeSearcher::search_ranked`]: with 2, `h` no longer matches 合金炉
+    /// but `hj` still does.
+    pub min_span: usize,
+}
+
+/// A match found by [`TreeSearcher::search_hits`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchHit<'a, T> {
+    pub id: &'a T,
+    /// Insertion index of the object.
+    pub entry_index: usize,
+    /// How many chars of the name the query matched.
+    pub matched_len: usize,
+    /// Bytes of [`TreeSearcher::name`] that matched, at the leftmost place
+    /// the query matches. The whole name when it was indexed in another
+    /// form, e.g. with [`romanized_targets`](PinIn::romanized_targets).
+    pub span: Range<usize>,
+}
+
+/// Splits a name, after the context's target transformation, into the
+/// char offsets of its tokens, see [`TreeSearcher::set_tokenizer`].
+pub type Tokenizer = Arc<dyn Fn(&[char]) -> Vec<usize> + Send + Sync>;
+
 pub struct TreeSearcher<T> where T: 'static {
-    root: Rc<dyn Node<T>>,
+    pub(crate) root: Arc<dyn Node<T>>,
 
     objects: Vec<T>,
-    naccs: RefCell<Vec<Rc<NAcc<T>>>>,
+    pub(crate) naccs: Mutex<Vec<Arc<NAcc<T>>>>,
+    version: Mutex<Option<u64>>,
+
+    accelerator: Arc<Accelerator>,
+    pub(crate) compressor: Arc<RwLock<Compressor>>,
+    logic: SearcherLogic,
 
-    accelerator: Rc<Accelerator>,
-    pub(crate) compressor: Rc<RefCell<Compressor>>,
-    logic: SearcherLogic
+    keys: Option<Vec<String>>,
+    tokenizer: Option<Tokenizer>,
+
+    /// The object of each tree id, `usize::MAX` for renamed away names.
+    /// Empty while ids and object indices are the same, i.e. until the
+    /// first rename.
+    slots: Vec<usize>,
+    stale: usize,
+    policy: QueryPolicy,
+    /// Whether the names may be used by other searchers too, in which
+    /// case they are copied before the first change.
+    shared: bool,
 }
 
 impl<T> Searcher<T> for TreeSearcher<T> where T: 'static {
     fn insert(&mut self, context: &PinIn, name: &str, id: T) {
-        let pos = self.compressor.borrow_mut().push(name);
-        let end = if self.logic == SearcherLogic::Contain { name.chars().count() } else { 1 };
-        for i in 0..end {
-            self.root = self.root.clone().put(context, self, pos + i, self.objects.len());
+        if let Some(keys) = self.keys.as_mut() {
+            keys.push(name.to_string());
         }
-
-        self.objects.push(id);
+        self.insert_indexed(context, name, id);
     }
 
+    /// Matches in insertion order.
     fn search(&self, context: &PinIn, s: &str) -> Vec<&T> {
-        self.accelerator.search(s);
-        let mut ret: HashSet<usize> = Default::default();
-        self.root.get_offset(context, self, &mut ret, 0);
-        ret.into_iter().map(|i| &self.objects[i]).collect()
+        self.search_indices(context, s).into_iter().map(|i| &self.objects[i]).collect()
+    }
+
+    /// Matches in traversal order, see [`TreeSearcher::search_ids_into`] for
+    /// the indices instead.
+    fn search_into<'s>(&'s self, context: &PinIn, s: &str, ret: &mut dyn Collection<&'s T>) {
+        let mut ret = Dedup::new(Objects { objects: &self.objects, inner: ret });
+        TreeSearcher::search_ids_into(self, context, s, &mut ret);
     }
 
+    /// Rebuilds stale indexes if the context changed since the last use.
+    /// Queries do this by themselves, so calling it is optional.
     fn reset(&mut self, context: &PinIn) {
-        self.naccs.borrow().iter().for_each(|i| i.reload(context));
+        self.sync(context);
+    }
+
+    fn count(&self, context: &PinIn, s: &str) -> usize {
+        let mut ret = Dedup::new(Counter::default());
+        self.search_ids_into(context, s, &mut ret);
+        ret.into_inner().0
+    }
+
+    fn any(&self, context: &PinIn, s: &str) -> bool {
+        let mut ret = AnyHit::default();
+        self.search_ids_into(context, s, &mut ret);
+        ret.0
+    }
+
+    fn search_within(&self, context: &PinIn, s: &str, budget: &Budget) -> Result<Vec<&T>, Cancelled> {
+        let mut ret = Budgeted { inner: SpillSet::default(), budget };
+        self.search_ids_into(context, s, &mut ret);
+        if budget.is_expired() {
+            return Err(Cancelled);
+        }
+        Ok(ret.inner.into_sorted_vec().into_iter().map(|i| &self.objects[i]).collect())
+    }
+}
+
+impl<T> SearcherMut<T> for TreeSearcher<T> where T: 'static {
+    fn update(&mut self, context: &PinIn, id: &T, name: &str) -> usize where T: PartialEq {
+        let indices: Vec<usize> = (0..self.objects.len()).filter(|i| self.objects[*i] == *id).collect();
+        indices.iter().for_each(|i| {
+            self.rename(context, *i, name);
+        });
+        indices.len()
+    }
+
+    /// Rebuilds the tree from the kept entries.
+    fn retain(&mut self, context: &PinIn, f: &mut dyn FnMut(&str, &T) -> bool) {
+        self.unshare();
+        let compressor = write(&self.compressor).take();
+        let objects = std::mem::take(&mut self.objects);
+        let mut keys = self.keys.as_mut().map(std::mem::take).map(Vec::into_iter);
+        self.root = Arc::new(NDense::new());
+        self.slots.clear();
+        self.stale = 0;
+        lock(&self.naccs).clear();
         self.accelerator.reset();
+        for (i, id) in objects.into_iter().enumerate() {
+            let key = keys.as_mut().and_then(Iterator::next);
+            let name = compressor.original(i).unwrap_or_default();
+            if f(&name, &id) {
+                self.insert_indexed(context, &name, id);
+                if let (Some(keys), Some(key)) = (self.keys.as_mut(), key) {
+                    keys.push(key);
+                }
+            }
+        }
+    }
+}
+
+/// A copy with names, tree and accelerator of its own, keeping the keys,
+/// tokenizer and policy, so that changing either leaves the other as it
+/// was.
+impl<T: Clone> Clone for TreeSearcher<T> where T: 'static {
+    fn clone(&self) -> Self {
+        let compressor = Arc::new(RwLock::new(read(&self.compressor).clone()));
+        let mut ret = TreeSearcher {
+            logic: self.logic,
+            root: Arc::new(NDense::new()),
+            objects: self.objects.clone(),
+            naccs: Mutex::new(Vec::new()),
+            version: Mutex::new(*lock(&self.version)),
+            accelerator: Arc::new(Accelerator::with_provider(compressor.clone())),
+            compressor,
+            keys: self.keys.clone(),
+            tokenizer: self.tokenizer.clone(),
+            slots: self.slots.clone(),
+            stale: self.stale,
+            policy: self.policy,
+            shared: false,
+        };
+        ret.root = self.root.moved(&ret, 0, 0);
+        let mut accs = Vec::new();
+        ret.root.clone().accs(&mut accs);
+        *lock(&ret.naccs) = accs;
+        ret
     }
 }
 
 impl<T> TreeSearcher<T> where T: 'static {
-    pub fn new(logic: SearcherLogic, accelerator: Rc<Accelerator>) -> Self {
-        let compressor = Rc::new(RefCell::new(Compressor::default()));
-        let _ = accelerator.provider.borrow_mut().insert(compressor.clone());
+    pub fn new(logic: SearcherLogic, accelerator: Arc<Accelerator>) -> Self {
+        let compressor = Arc::new(RwLock::new(Compressor::default()));
+        accelerator.set_provider(compressor.clone());
         TreeSearcher {
             logic,
-            root: Rc::new(NDense::new()),
+            root: Arc::new(NDense::new()),
             objects: Vec::new(),
-            naccs: RefCell::new(Vec::new()),
+            naccs: Mutex::new(Vec::new()),
+            version: Mutex::new(None),
             accelerator,
             compressor,
+            keys: None,
+            tokenizer: None,
+            slots: Vec::new(),
+            stale: 0,
+            policy: QueryPolicy::default(),
+            shared: false,
+        }
+    }
+
+    /// A searcher over names already stored in `names`, e.g. loaded with
+    /// [`Compressor::from_bytes`] or taken from another searcher with
+    /// [`TreeSearcher::share_names`], the `i`-th entry naming the `i`-th
+    /// of `objects`. Searchers of different logics built this way keep a
+    /// single copy of the names between them; the first one to insert,
+    /// rename or remove names copies them for itself.
+    ///
+    /// # Panics
+    ///
+    /// If there are not as many objects as entries.
+    pub fn with_names(
+        context: &PinIn,
+        logic: SearcherLogic,
+        accelerator: Arc<Accelerator>,
+        names: Arc<RwLock<Compressor>>,
+        objects: Vec<T>,
+    ) -> Self {
+        assert_eq!(read(&names).offsets.len(), objects.len(), "one object per entry");
+        let mut ret = Self::over_names(logic, accelerator, names);
+        ret.objects = objects;
+        ret.sync(context);
+
+        let positions: Vec<(usize, usize)> = {
+            let compressor = read(&ret.compressor);
+            compressor
+                .offsets
+                .iter()
+                .enumerate()
+                .flat_map(|(i, start)| ret.starts(&compressor.chars_at(*start)).into_iter().map(move |x| (start + x, i)))
+                .collect()
+        };
+        for (pos, id) in positions {
+            ret.root = ret.root.clone().put(context, &ret, pos, id);
+        }
+        ret
+    }
+
+    /// An empty searcher over `names`, for owners that push names there
+    /// themselves and add them with [`TreeSearcher::index_last`].
+    pub(crate) fn over_names(logic: SearcherLogic, accelerator: Arc<Accelerator>, names: Arc<RwLock<Compressor>>) -> Self {
+        let mut ret = Self::new(logic, accelerator);
+        ret.accelerator.set_provider(names.clone());
+        ret.compressor = names;
+        ret.shared = true;
+        ret
+    }
+
+    /// The names of the searcher, to build more searchers over with
+    /// [`TreeSearcher::with_names`] or to store with
+    /// [`Compressor::to_bytes`].
+    pub fn share_names(&mut self) -> Arc<RwLock<Compressor>> {
+        self.shared = true;
+        self.compressor.clone()
+    }
+
+    /// Gives the searcher its own copy of shared names, so that changing
+    /// them does not change the names of other searchers. Positions stay
+    /// the same, so the tree is kept.
+    fn unshare(&mut self) {
+        if !self.shared {
+            return;
+        }
+        let names = read(&self.compressor).clone();
+        self.compressor = Arc::new(RwLock::new(names));
+        self.accelerator.set_provider(self.compressor.clone());
+        self.shared = false;
+    }
+
+    /// Sets how [`TreeSearcher::search_checked`] treats short queries.
+    pub fn set_policy(&mut self, policy: QueryPolicy) {
+        self.policy = policy;
+    }
+
+    /// Whether names equal to one already inserted share its chars, see
+    /// [`Compressor::set_interning`].
+    pub fn set_interning(&mut self, interning: bool) {
+        self.unshare();
+        write(&self.compressor).set_interning(interning);
+    }
+
+    /// Whether names store only the chars after the prefix they share
+    /// with the name inserted before them, see
+    /// [`Compressor::set_front_coding`].
+    pub fn set_front_coding(&mut self, front_coding: bool) {
+        self.unshare();
+        write(&self.compressor).set_front_coding(front_coding);
+    }
+
+    /// Like `search`, applying the searcher's [`QueryPolicy`]. Queries
+    /// run with another logic than the tree's test every name instead of
+    /// walking the tree.
+    pub fn search_checked(&self, context: &PinIn, s: &str) -> Result<Vec<&T>, QueryTooShort> {
+        let logic = self.policy.resolve(self.logic, s)?;
+        if logic == self.logic {
+            return Ok(self.search(context, s));
+        }
+        let mut ret = (0..self.objects.len()).collect();
+        self.retain_matching_as(context, s, logic, &mut ret);
+        Ok(ret.into_iter().map(|i| &self.objects[i]).collect())
+    }
+
+    /// Like `new`, but the searcher keeps an owned copy of every inserted
+    /// key, available through [`TreeSearcher::key`].
+    pub fn with_keys(logic: SearcherLogic, accelerator: Arc<Accelerator>) -> Self {
+        let mut ret = Self::new(logic, accelerator);
+        ret.keys = Some(Vec::new());
+        ret
+    }
+
+    /// Indexes every token of names inserted from now on, so that with
+    /// [`SearcherLogic::Begin`] a query may match from the start of any
+    /// of them. [`word_starts`](crate::unicode_utils::word_starts) splits mixed entries such as `ICBM必杀技`
+    /// or `ItemStack`. Other logics ignore the tokenizer.
+    pub fn set_tokenizer<F>(&mut self, tokenizer: F)
+    where
+        F: Fn(&[char]) -> Vec<usize> + Send + Sync + 'static,
+    {
+        self.tokenizer = Some(Arc::new(tokenizer));
+    }
+
+    /// Offsets into `name` the tree indexes it under.
+    fn starts(&self, name: &[char]) -> Vec<usize> {
+        match (self.logic, &self.tokenizer) {
+            (SearcherLogic::Contain | SearcherLogic::EndsWith, _) => (0..name.len()).collect(),
+            (SearcherLogic::Begin, Some(tokenizer)) => {
+                let mut ret = tokenizer(name);
+                ret.retain(|x| *x < name.len());
+                ret.push(0);
+                ret.sort_unstable();
+                ret.dedup();
+                ret
+            }
+            _ => vec![0],
+        }
+    }
+
+    fn sync(&self, context: &PinIn) {
+        let mut version = lock(&self.version);
+        if *version != Some(context.version()) {
+            lock(&self.naccs).iter().for_each(|i| i.reload(context));
+            self.accelerator.reset();
+            *version = Some(context.version());
+        }
+    }
+
+    fn insert_indexed(&mut self, context: &PinIn, name: &str, id: T) {
+        self.sync(context);
+        self.unshare();
+        let pos = write(&self.compressor).push_as(&context.target_str(name), name);
+        self.put_name(context, pos, self.objects.len());
+        self.objects.push(id);
+    }
+
+    /// Adds the last entry of the names, pushed by whoever shares them,
+    /// for a new object.
+    pub(crate) fn index_last(&mut self, context: &PinIn, id: T) {
+        self.sync(context);
+        let pos = read(&self.compressor).offsets.last().copied().unwrap_or(0);
+        self.put_name(context, pos, self.objects.len());
+        self.objects.push(id);
+    }
+
+    /// Adds the name at `pos` to the tree, for the `index`-th object.
+    fn put_name(&mut self, context: &PinIn, pos: usize, index: usize) {
+        let id = if self.slots.is_empty() {
+            index
+        } else {
+            self.slots.push(index);
+            self.slots.len() - 1
+        };
+        let starts = {
+            let compressor = read(&self.compressor);
+            self.starts(&compressor.chars_at(pos))
+        };
+        for i in starts {
+            self.root = self.root.clone().put(context, self, pos + i, id);
+        }
+    }
+
+    /// Adds many entries at once, then rebuilds the whole tree with the
+    /// subtree under each first char built on its own thread. Entries get
+    /// indices in the order of `entries`, after the existing ones.
+    #[cfg(feature = "rayon")]
+    pub fn build_parallel<I, S>(&mut self, context: &PinIn, entries: I)
+    where
+        I: rayon::iter::IntoParallelIterator<Item = (S, T)>,
+        S: AsRef<str> + Send,
+        T: Send + Sync,
+    {
+        use rayon::prelude::*;
+
+        self.sync(context);
+        self.unshare();
+        let entries: Vec<(String, S, T)> = entries
+            .into_par_iter()
+            .map(|(name, id)| (context.target_str(name.as_ref()).into_owned(), name, id))
+            .collect();
+        {
+            let mut compressor = write(&self.compressor);
+            for (target, name, id) in entries {
+                compressor.push_as(&target, name.as_ref());
+                if let Some(keys) = self.keys.as_mut() {
+                    keys.push(name.as_ref().to_string());
+                }
+                self.objects.push(id);
+            }
+        }
+
+        let mut positions: Vec<(usize, usize)> = {
+            let compressor = read(&self.compressor);
+            compressor
+                .offsets
+                .iter()
+                .enumerate()
+                .flat_map(|(i, start)| self.starts(&compressor.chars_at(*start)).into_iter().map(move |x| (start + x, i)))
+                .collect()
+        };
+        self.root = Arc::new(NDense::new());
+        self.slots.clear();
+        self.stale = 0;
+        lock(&self.naccs).clear();
+        self.accelerator.reset();
+        if positions.len() < BTREE_THRESHOLD {
+            for (pos, id) in positions {
+                self.root = self.root.clone().put(context, self, pos, id);
+            }
+            return;
+        }
+
+        // Empty names sort first and end at the root
+        let map = Arc::new(NMap::new());
+        map.init();
+        let groups: Vec<(char, Vec<(usize, usize)>)> = {
+            let names = read(&self.compressor);
+            let chars = &*names;
+            positions.sort_by_key(|(pos, _)| chars[*pos]);
+            let ended = positions.partition_point(|(pos, _)| chars[*pos] == '\0');
+            write(&map.leaves).extend(positions[..ended].iter().map(|(_, id)| *id));
+            positions[ended..]
+                .chunk_by(|(a, _), (b, _)| chars[*a] == chars[*b])
+                .map(|group| (chars[group[0].0], group.to_vec()))
+                .collect()
+        };
+        let this = &*self;
+        let children: Vec<(char, Arc<dyn Node<T>>)> = groups
+            .into_par_iter()
+            .map(|(ch, group)| {
+                let mut node: Arc<dyn Node<T>> = Arc::new(NDense::new());
+                for (pos, id) in group {
+                    node = node.put(context, this, pos + 1, id);
+                }
+                (ch, node)
+            })
+            .collect();
+        children.into_iter().for_each(|(ch, node)| map.put_char(ch, node));
+        self.root = if read(&map.children).as_ref().map(|x| x.len() > 32).unwrap_or_default() {
+            NAcc::new(context, self, map)
+        } else {
+            map
+        };
+    }
+
+    /// Turns the tree into a read-only [`FrozenSearcher`], for indexes
+    /// that are built once and then searched from many threads.
+    pub fn freeze(mut self) -> FrozenSearcher<T> {
+        self.unshare();
+        let compressor = std::mem::take(&mut *write(&self.compressor));
+        let starts = compressor
+            .offsets
+            .iter()
+            .map(|start| self.starts(&compressor.chars_at(*start)))
+            .collect();
+        FrozenSearcher::build(self.logic, self.objects, compressor, starts)
+    }
+
+    /// Gives the `index`-th object the name `name`, keeping its index.
+    /// The old name stays in the tree but no longer matches; the tree is
+    /// rebuilt once there are more old names than objects. Returns false
+    /// if there is no such object.
+    pub fn rename(&mut self, context: &PinIn, index: usize, name: &str) -> bool {
+        if index >= self.objects.len() {
+            return false;
+        }
+        self.sync(context);
+        if self.slots.is_empty() {
+            self.slots = (0..self.objects.len()).collect();
+        }
+        if let Some(old) = self.slots.iter().rposition(|x| *x == index) {
+            self.slots[old] = usize::MAX;
+            self.stale += 1;
+        }
+        if let Some(key) = self.keys.as_mut().and_then(|x| x.get_mut(index)) {
+            name.clone_into(key);
+        }
+        self.unshare();
+        let pos = write(&self.compressor).replace_as(index, &context.target_str(name), name);
+        self.put_name(context, pos, index);
+        if self.stale > self.objects.len() {
+            self.retain(context, &mut |_, _| true);
+        }
+        true
+    }
+
+    /// Inserts any string-like key. When the searcher stores keys, the
+    /// key is moved in without an extra copy where possible.
+    pub fn insert_key<K: AsRef<str> + Into<String>>(&mut self, context: &PinIn, key: K, id: T) {
+        self.insert_indexed(context, key.as_ref(), id);
+        if let Some(keys) = self.keys.as_mut() {
+            keys.push(key.into());
+        }
+    }
+
+    /// The stored key of the `index`-th object, if keys are stored.
+    pub fn key(&self, index: usize) -> Option<&str> {
+        self.keys.as_ref()?.get(index).map(String::as_str)
+    }
+
+    /// Stored keys paired with their objects, in insertion order.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &T)> {
+        self.keys.iter().flatten().map(String::as_str).zip(self.objects.iter())
+    }
+
+    /// Appends every entry of `other`, which must have the same logic and
+    /// have been built with the same context. Objects of `other` get
+    /// indices after the existing ones, in their original order, and keep
+    /// their stored keys. The names and tree of `other` are grafted on
+    /// rather than inserted again: subtrees under chars only one side has
+    /// are moved over whole.
+    pub fn merge(&mut self, context: &PinIn, other: TreeSearcher<T>) {
+        self.sync(context);
+        other.sync(context);
+        self.unshare();
+        let (count, entries) = (self.objects.len(), other.objects.len());
+        let ids = if self.slots.is_empty() { count } else { self.slots.len() };
+        if !self.slots.is_empty() || !other.slots.is_empty() {
+            if self.slots.is_empty() {
+                self.slots = (0..count).collect();
+            }
+            let moved = |x: usize| if x == usize::MAX { x } else { x + count };
+            match other.slots.is_empty() {
+                true => self.slots.extend((0..entries).map(moved)),
+                false => self.slots.extend(other.slots.iter().copied().map(moved)),
+            }
+        }
+        let chars = write(&self.compressor).append(&read(&other.compressor));
+
+        let root = other.root.moved(self, chars, ids);
+        self.root = self.root.clone().graft(context, self, root);
+        // Maps of `other` grafted onto ones of this tree leave their index
+        let mut accs = Vec::new();
+        self.root.clone().accs(&mut accs);
+        *lock(&self.naccs) = accs;
+        self.accelerator.reset();
+
+        if let Some(keys) = self.keys.as_mut() {
+            match other.keys {
+                Some(other) => keys.extend(other),
+                None => {
+                    let names = read(&self.compressor);
+                    keys.extend((count..count + entries).map(|i| names.original(i).unwrap_or_default()));
+                }
+            }
+        }
+        self.objects.extend(other.objects);
+        self.stale += other.stale;
+        if self.stale > self.objects.len() {
+            self.retain(context, &mut |_, _| true);
+        }
+    }
+
+    /// Where the name of the tree id `id` ends, `None` for renamed away
+    /// names.
+    fn name_end(&self, id: usize) -> Option<usize> {
+        let index = if self.slots.is_empty() { id } else { *self.slots.get(id)? };
+        let compressor = read(&self.compressor);
+        let start = *compressor.offsets.get(index)?;
+        Some(start + compressor.chars_at(start).len())
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.objects.get(index)
+    }
+
+    /// The name the `index`-th object was inserted with.
+    pub fn name(&self, index: usize) -> Option<String> {
+        read(&self.compressor).original(index)
+    }
+
+    /// Like `search`, with results ordered by name using [`natural_cmp`].
+    pub fn search_sorted(&self, context: &PinIn, s: &str) -> Vec<&T> {
+        let mut ret: Vec<_> = self
+            .search_ids(context, s)
+            .into_iter()
+            .map(|i| (self.name(i).unwrap_or_default(), i))
+            .collect();
+        ret.sort_by(|(a, i), (b, j)| natural_cmp(a, b).then(i.cmp(j)));
+        ret.into_iter().map(|(_, i)| &self.objects[i]).collect()
+    }
+
+    /// Insertion indices of the matching objects, ascending. Unlike
+    /// `search` the result does not borrow the searcher, which suits
+    /// external storage keyed by row.
+    pub fn search_indices(&self, context: &PinIn, s: &str) -> Vec<usize> {
+        let mut ret = SpillSet::default();
+        self.search_ids_into(context, s, &mut ret);
+        ret.into_sorted_vec()
+    }
+
+    /// Like `search`, in insertion order and with at most `cap` results.
+    /// The flag tells whether more objects matched, in which case the
+    /// traversal stopped early and the results are not the first `cap`.
+    pub fn search_capped(&self, context: &PinIn, s: &str, cap: usize) -> (Vec<&T>, bool) {
+        let mut ret = SpillSet::default().with_cap(cap);
+        self.search_ids_into(context, s, &mut ret);
+        let truncated = ret.is_truncated();
+        (ret.into_sorted_vec().into_iter().map(|i| &self.objects[i]).collect(), truncated)
+    }
+
+    /// The `k` most relevant matches, best first. Hits are ranked by
+    ///
+    /// 1. whether the name is the query itself,
+    /// 2. how much of the query matches literally, see [`literal_hits`],
+    /// 3. how few chars of the name the query abbreviates, so that full
+    ///    pinyin beats abbreviations: for `xi`, 西 comes before 先, which
+    ///    `xi` only starts,
+    /// 4. how few chars the query spans: for `xian`, 先 comes before 西安,
+    /// 5. the name's count when the context has
+    ///    [word frequencies](PinIn::set_frequencies),
+    ///
+    /// and then by name. Names and query are compared folded, the way
+    /// they are matched.
+    pub fn search_ranked(&self, context: &PinIn, s: &str, k: usize) -> Vec<&T> {
+        let frequencies = context.frequencies();
+        let ids = self.search_ids(context, s);
+        let query = context.query_str(s);
+        let a = self.accelerator.claim();
+        a.search(&query);
+        let ranks: Vec<(usize, usize)> = {
+            let compressor = read(&self.compressor);
+            ids.iter().map(|i| self.rank(&a, context, &compressor, *i)).collect()
+        };
+        drop(a);
+
+        let mut ret: Vec<_> = ids
+            .into_iter()
+            .zip(ranks)
+            .map(|(i, (abbreviated, span))| {
+                let name = self.name(i).unwrap_or_default();
+                let folded = context.query_str(&name);
+                let count = frequencies.map(|x| x.count(&name)).unwrap_or(0);
+                let score = (folded == query, literal_hits(&folded, &query), Reverse(abbreviated), Reverse(span), count);
+                (score, name, i)
+            })
+            .collect();
+        type Scored = ((bool, usize, Reverse<usize>, Reverse<usize>, u64), String, usize);
+        let order = |(x, a, i): &Scored, (y, b, j): &Scored| {
+            y.cmp(x).then_with(|| natural_cmp(a, b)).then(i.cmp(j))
+        };
+        if k < ret.len() {
+            ret.select_nth_unstable_by(k, order);
+            ret.truncate(k);
+        }
+        ret.sort_by(order);
+        ret.into_iter().map(|(_, _, i)| &self.objects[i]).collect()
+    }
+
+    /// Like `search_indices`, telling where in each name the query
+    /// matched.
+    pub fn search_hits(&self, context: &PinIn, s: &str) -> Vec<SearchHit<'_, T>> {
+        let indices = self.search_indices(context, s);
+        let a = self.accelerator.claim();
+        a.search(&context.query_str(s));
+        let compressor = read(&self.compressor);
+        indices
+            .into_iter()
+            .map(|i| {
+                let start = compressor.offsets[i];
+                let entry = compressor.chars_at(start);
+                let len = entry.len();
+                let (offset, matched_len) = self
+                    .starts(&entry)
+                    .into_iter()
+                    .find_map(|x| {
+                        a.path(context, 0, start + x)
+                            .filter(|p| !self.logic.is_full() || x + p.len() == len)
+                            .map(|p| (x, p.len()))
+                    })
+                    .unwrap_or((0, len));
+                let name = compressor.original(i).unwrap_or_default();
+                let span = if name.chars().count() == len {
+                    let mut bytes = name.char_indices().map(|(x, _)| x).chain([name.len()]);
+                    let begin = bytes.nth(offset).unwrap_or(0);
+                    let end = if matched_len == 0 { begin } else { bytes.nth(matched_len - 1).unwrap_or(name.len()) };
+                    begin..end
+                } else {
+                    0..name.len()
+                };
+                SearchHit { id: &self.objects[i], entry_index: i, matched_len, span }
+            })
+            .collect()
+    }
+
+    /// How many chars of the name at `index` the query abbreviates and
+    /// spans, along the match abbreviating the fewest, see
+    /// [`TreeSearcher::search_ranked`].
+    fn rank(&self, a: &Accelerator, context: &PinIn, compressor: &Compressor, index: usize) -> (usize, usize) {
+        let start = compressor.offsets[index];
+        let entry = compressor.chars_at(start);
+        let end = start + entry.len();
+        let abbreviated = |at: usize, path: &[usize]| {
+            let mut offset = 0;
+            let chars = (at..).map(|x| compressor[x]);
+            path.iter().zip(chars).filter(|(n, c)| {
+                let literal = **n == 1 && a.search_char(offset) == Some(*c);
+                offset += **n;
+                let ch = context.target_character(*c);
+                let full = ch.pinyin.iter().flat_map(|p| std::iter::once(p.as_ref()).chain(&p.alternates));
+                **n > 0 && !literal && !ch.pinyin.is_empty() && full.into_iter().all(|p| p.typed_len() > **n)
+            })
+            .count()
+        };
+        self.starts(&entry)
+            .into_iter()
+            .filter_map(|x| a.path(context, 0, start + x).map(|path| (abbreviated(start + x, &path), path.len())))
+            .min()
+            .unwrap_or((0, end - start))
+    }
+
+    fn span(&self, a: &Accelerator, context: &PinIn, compressor: &Compressor, index: usize) -> usize {
+        let start = compressor.offsets[index];
+        let entry = compressor.chars_at(start);
+        let end = start + entry.len();
+        self.starts(&entry)
+            .into_iter()
+            .filter_map(|x| a.span(context, 0, start + x))
+            .min()
+            .unwrap_or(end - start)
+    }
+
+    /// Feeds the indices of matching objects to `ret`, as they are found
+    /// and possibly more than once; wrap `ret` in [`Dedup`] for distinct
+    /// ones. Objects are reached through [`TreeSearcher::get`].
+    pub fn search_ids_into(&self, context: &PinIn, s: &str, ret: &mut dyn Collection<usize>) {
+        self.search_ids_into_with(context, s, &SearchOptions::default(), ret);
+    }
+
+    /// Like `search_ids_into`, leaving out names outside `options`.
+    pub fn search_ids_into_with(&self, context: &PinIn, s: &str, options: &SearchOptions, ret: &mut dyn Collection<usize>) {
+        self.sync(context);
+        let a = self.accelerator.claim();
+        a.search(&context.query_str(s));
+        ret.reserve(self.objects.len());
+        let mut limited;
+        let ret: &mut dyn Collection<usize> = if *options == SearchOptions::default() {
+            ret
+        } else {
+            limited = Limited { searcher: self, context, accelerator: &a, options: *options, inner: ret };
+            &mut limited
+        };
+        if self.slots.is_empty() {
+            self.root.get_offset(context, self, &a, ret, 0);
+        } else {
+            let mut ret = Slots { slots: &self.slots, inner: ret };
+            self.root.get_offset(context, self, &a, &mut ret, 0);
+        }
+    }
+
+    /// Like `search`, leaving out names outside `options`.
+    pub fn search_with(&self, context: &PinIn, s: &str, options: &SearchOptions) -> Vec<&T> {
+        let mut ret = SpillSet::default();
+        self.search_ids_into_with(context, s, options, &mut ret);
+        ret.into_sorted_vec().into_iter().map(|i| &self.objects[i]).collect()
+    }
+
+    /// Whether the `index`-th name is within `options` for the query in
+    /// `a`.
+    fn within(&self, context: &PinIn, a: &Accelerator, options: &SearchOptions, index: usize) -> bool {
+        let compressor = read(&self.compressor);
+        let start = compressor.offsets[index];
+        let len = compressor.chars_at(start).len();
+        options.max_len.is_none_or(|x| len <= x)
+            && (options.min_span == 0 || self.span(a, context, &compressor, index) >= options.min_span)
+    }
+
+    /// Calls `on_hit` with each matching object as the traversal finds
+    /// it, once per object and in no particular order, so the first hits
+    /// can be shown before a big index is fully scanned. Returns
+    /// [`ControlFlow::Break`] if `on_hit` stopped the search.
+    pub fn search_streaming<'t, F>(&'t self, context: &PinIn, s: &str, on_hit: F) -> ControlFlow<()>
+    where
+        F: FnMut(&'t T) -> ControlFlow<()>,
+    {
+        let mut ret = Dedup::new(Streaming { objects: &self.objects, on_hit, stopped: false });
+        self.search_ids_into(context, s, &mut ret);
+        if ret.into_inner().stopped {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    }
+
+    /// Like `search`, yielding matches as they are needed, so taking the
+    /// first few stops the traversal early. Comes in traversal order
+    /// rather than insertion order.
+    pub fn search_iter<'s>(&'s self, context: &'s PinIn, s: &str) -> impl Iterator<Item = &'s T> + 's {
+        SearchIter {
+            searcher: self,
+            context,
+            query: s.to_string(),
+            hits: Dedup::new(Batch { found: Vec::new(), cap: 0 }),
+            next: 0,
+            done: false,
+        }
+    }
+
+    /// Keeps the indices in `candidates` whose object matches `s`, testing
+    /// each one directly instead of walking the tree.
+    pub(crate) fn retain_matching(&self, context: &PinIn, s: &str, candidates: &mut Vec<usize>) {
+        self.retain_matching_as(context, s, self.logic, candidates);
+    }
+
+    fn retain_matching_as(&self, context: &PinIn, s: &str, logic: SearcherLogic, candidates: &mut Vec<usize>) {
+        self.sync(context);
+        let a = self.accelerator.claim();
+        a.search(&context.query_str(s));
+        let compressor = read(&self.compressor);
+        candidates.retain(|i| {
+            let start = compressor.offsets[*i];
+            match (self.logic, &self.tokenizer) {
+                (SearcherLogic::Begin, Some(_)) => self
+                    .starts(&compressor.chars_at(start))
+                    .into_iter()
+                    .any(|x| logic.test_accelerator(&a, context, 0, start + x)),
+                _ => logic.test_accelerator(&a, context, 0, start),
+            }
+        });
+    }
+
+    pub(crate) fn logic(&self) -> SearcherLogic {
+        self.logic
+    }
+
+    pub(crate) fn search_ids(&self, context: &PinIn, s: &str) -> FxHashSet<usize> {
+        let mut ret: FxHashSet<usize> = Default::default();
+        self.search_ids_into(context, s, &mut ret);
+        ret
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct TreeImageRef<'a, T> {
+    logic: SearcherLogic,
+    policy: QueryPolicy,
+    objects: &'a [T],
+    keys: &'a Option<Vec<String>>,
+    compressor: &'a Compressor,
+    slots: &'a [usize],
+    stale: usize,
+    nodes: Vec<NodeImage>,
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct TreeImage<T> {
+    logic: SearcherLogic,
+    policy: QueryPolicy,
+    objects: Vec<T>,
+    keys: Option<Vec<String>>,
+    compressor: Compressor,
+    slots: Vec<usize>,
+    stale: usize,
+    nodes: Vec<NodeImage>,
+}
+
+/// Writes the indexed names, the tree and the objects, so that a big
+/// index can be loaded without building it again. The tokenizer is not
+/// part of it; names inserted after loading are indexed as if there were
+/// none until it is set again.
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize> serde::Serialize for TreeSearcher<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut nodes = Vec::new();
+        self.root.image(&mut nodes);
+        let compressor = read(&self.compressor);
+        serde::Serialize::serialize(
+            &TreeImageRef {
+                logic: self.logic,
+                policy: self.policy,
+                objects: &self.objects,
+                keys: &self.keys,
+                compressor: &compressor,
+                slots: &self.slots,
+                stale: self.stale,
+                nodes,
+            },
+            serializer,
+        )
+    }
+}
+
+/// Loads a searcher written by its `Serialize` impl. It gets an
+/// accelerator of its own, and builds the indexes that depend on the
+/// context on its first use.
+#[cfg(feature = "serde")]
+impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for TreeSearcher<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let image = TreeImage::<T>::deserialize(deserializer)?;
+        if image.objects.len() != image.compressor.offsets.len()
+            || image.keys.as_ref().is_some_and(|x| x.len() != image.objects.len())
+        {
+            return Err(D::Error::custom("objects and names differ in number"));
         }
+        let mut ret = TreeSearcher::new(image.logic, Arc::new(Accelerator::new()));
+        *write(&ret.compressor) = image.compressor;
+        ret.policy = image.policy;
+        ret.objects = image.objects;
+        ret.keys = image.keys;
+        ret.slots = image.slots;
+        ret.stale = image.stale;
+        ret.root = ret.restore(image.nodes).map_err(D::Error::custom)?;
+        Ok(ret)
     }
+}
 
+#[cfg(feature = "serde")]
+impl<T> TreeSearcher<T> where T: 'static {
+    /// Builds the tree from its serialized nodes.
+    fn restore(&self, images: Vec<NodeImage>) -> Result<Arc<dyn Node<T>>, &'static str> {
+        let mut built: Vec<Arc<dyn Node<T>>> = Vec::with_capacity(images.len());
+        for image in images {
+            let child = |i: usize| built.get(i).cloned().ok_or("node refers to a later one");
+            let node: Arc<dyn Node<T>> = match image {
+                NodeImage::Dense(data) => Arc::new(NDense { data: RwLock::new(data.into()), phantom: PhantomData }),
+                NodeImage::Slice { start, end, exit } => {
+                    let slice = NSlice::new(start, end);
+                    *write(&slice.exit) = child(exit)?;
+                    Arc::new(slice)
+                }
+                NodeImage::Map { leaves, children, acc } => {
+                    let map = Arc::new(NMap::new());
+                    write(&map.leaves).extend(leaves);
+                    for (ch, i) in children {
+                        map.put_char(ch, child(i)?);
+                    }
+                    if acc {
+                        // Indexed when the searcher first syncs
+                        let acc = Arc::new(NAcc { map, index: Default::default() });
+                        lock(&self.naccs).push(acc.clone());
+                        acc
+                    } else {
+                        map
+                    }
+                }
+            };
+            built.push(node);
+        }
+        built.pop().ok_or("no root node")
+    }
 }
 
+#[doc(hidden)]
 pub struct NMap<T> where T: 'static {
 
     #[allow(clippy::type_complexity)]
-    children: RefCell<Option<FxHashMap<char, Rc<dyn Node<T>>>>>,
+    children: RwLock<Option<FxHashMap<char, Arc<dyn Node<T>>>>>,
 
-    leaves: RefCell<FxHashSet<usize>>,
+    leaves: RwLock<FxHashSet<usize>>,
 }
 
 impl<T> Default for NMap<T> {
@@ -174,101 +1721,190 @@ impl<T> Default for NMap<T> {
 impl<T> NMap<T> where T: 'static {
     pub fn new() -> Self {
         NMap {
-            children: RefCell::new(None),
-            leaves: RefCell::new(Default::default()),
+            children: RwLock::new(None),
+            leaves: RwLock::new(Default::default()),
         }
     }
 
     pub fn init(&self) {
-        self.children.borrow_mut().get_or_insert_with(Default::default);
+        write(&self.children).get_or_insert_with(Default::default);
     }
 
-    pub fn put_char(&self, ch: char, node: Rc<dyn Node<T>>) {
+    pub fn put_char(&self, ch: char, node: Arc<dyn Node<T>>) {
         self.init();
-        self.children.borrow_mut().as_mut().unwrap().insert(ch, node);
+        write(&self.children).as_mut().unwrap().insert(ch, node);
+    }
+
+    fn moved_map(&self, p: &TreeSearcher<T>, chars: usize, ids: usize) -> NMap<T> {
+        let children = read(&self.children).as_ref().map(|x| {
+            x.iter().map(|(ch, node)| (*ch, node.moved(p, chars, ids))).collect()
+        });
+        NMap {
+            children: RwLock::new(children),
+            leaves: RwLock::new(read(&self.leaves).iter().map(|x| x + ids).collect()),
+        }
+    }
+
+    /// Merges the leaves and children of `other` into this map.
+    fn graft_map(&self, context: &PinIn, p: &TreeSearcher<T>, other: &NMap<T>) {
+        write(&self.leaves).extend(read(&other.leaves).iter().copied());
+        let children: Vec<(char, Arc<dyn Node<T>>)> =
+            read(&other.children).iter().flatten().map(|(ch, node)| (*ch, node.clone())).collect();
+        for (ch, node) in children {
+            let mine = read(&self.children).as_ref().and_then(|x| x.get(&ch).cloned());
+            self.put_char(ch, match mine {
+                Some(mine) => mine.graft(context, p, node),
+                None => node,
+            });
+        }
+    }
+
+    fn map_entries(&self, p: &TreeSearcher<T>, depth: usize, ret: &mut Vec<(usize, usize)>) {
+        let leaves = read(&self.leaves);
+        ret.extend(leaves.iter().filter_map(|id| Some((p.name_end(*id)? - depth, *id))));
+        read(&self.children).iter().flatten().for_each(|(_, node)| node.entries(p, depth + 1, ret));
+    }
+
+    fn map_accs(&self, ret: &mut Vec<Arc<NAcc<T>>>) {
+        read(&self.children).iter().flatten().for_each(|(_, node)| node.clone().accs(ret));
+    }
+
+    #[cfg(feature = "serde")]
+    fn image_as(&self, nodes: &mut Vec<NodeImage>, acc: bool) -> usize {
+        let mut children: Vec<(char, usize)> = read(&self.children)
+            .iter()
+            .flatten()
+            .map(|(ch, node)| (*ch, node.image(nodes)))
+            .collect();
+        children.sort_unstable();
+        let mut leaves: Vec<usize> = read(&self.leaves).iter().copied().collect();
+        leaves.sort_unstable();
+        nodes.push(NodeImage::Map { leaves, children, acc });
+        nodes.len() - 1
     }
 }
 
 impl<T> Node<T> for NMap<T> {
-    fn get_offset(&self, context: &PinIn, p: &TreeSearcher<T>, ret: &mut dyn Collection<usize>, offset: usize) {
-        if p.accelerator.search_string.borrow().chars().count() == offset {
-            if p.logic == SearcherLogic::Equal {
-                self.leaves.borrow().iter().copied().for_each(|x| { ret.insert(x); });
+    fn get_offset(&self, context: &PinIn, p: &TreeSearcher<T>, a: &Accelerator, ret: &mut dyn Collection<usize>, offset: usize) {
+        if ret.is_full() {
+            return;
+        }
+        if offset >= a.search_len() {
+            if p.logic.is_full() {
+                read(&self.leaves).iter().copied().for_each(|x| { ret.insert(x); });
+            } else {
+                self.get(context, p, ret);
             }
-        } else if let Some(children) = &*self.children.borrow() {
+        } else if let Some(children) = &*read(&self.children) {
             children.iter().for_each(|(key, value)| {
-                p.accelerator.get(context, *key, offset)
-                    .for_each(|i| value.get_offset(context, p, ret, offset + i as usize));
+                a.get(context, *key, offset)
+                    .for_each(|i| value.get_offset(context, p, a, ret, offset + i as usize));
             });
         }
     }
 
     fn get(&self, context: &PinIn, p: &TreeSearcher<T>, ret: &mut dyn Collection<usize>) {
-        self.leaves.borrow().iter().copied().for_each(|leaf| { ret.insert(leaf); });
+        if ret.is_full() {
+            return;
+        }
+        read(&self.leaves).iter().copied().for_each(|leaf| { ret.insert(leaf); });
 
-        if let Some(children) = &*self.children.borrow() {
+        if let Some(children) = &*read(&self.children) {
             children.values().for_each(|node| node.get(context, p, ret));
         }
     }
 
-    fn put(self: Rc<Self>, context: &PinIn, p: &TreeSearcher<T>, name: usize, id: usize) -> Rc<dyn Node<T>> {
-        if p.compressor.borrow().chars[name] == '\0' {
-            // TODO Check and replace to BTree
-            if self.leaves.borrow().len() >= BTREE_THRESHOLD {
-
-            }
-
-            self.leaves.borrow_mut().insert(id);
+    fn put(self: Arc<Self>, context: &PinIn, p: &TreeSearcher<T>, name: usize, id: usize) -> Arc<dyn Node<T>> {
+        if read(&p.compressor)[name] == '\0' {
+            // TODO Check and replace to BTree once leaves exceed BTREE_THRESHOLD
+            write(&self.leaves).insert(id);
         } else {
             self.init();
 
-            let ch = p.compressor.borrow().chars[name];
-            if !self.children.borrow().as_ref().unwrap().contains_key(&ch) {
-                self.put_char(ch, Rc::new(NDense::new()));
+            let ch = read(&p.compressor)[name];
+            if !read(&self.children).as_ref().unwrap().contains_key(&ch) {
+                self.put_char(ch, Arc::new(NDense::new()));
             }
 
             {
-                let mut map = self.children.borrow_mut();
+                let mut map = write(&self.children);
 
                 let node = map.as_mut().unwrap().get_mut(&ch).unwrap();
                 *node = node.clone().put(context, p, name + 1, id);
             }
         }
 
-        if self.children.borrow().as_ref().map(|x| x.len() > 32).unwrap_or_default() {
+        if read(&self.children).as_ref().map(|x| x.len() > 32).unwrap_or_default() {
+            NAcc::new(context, p, self)
+        } else {
+            self
+        }
+    }
+
+    fn moved(&self, p: &TreeSearcher<T>, chars: usize, ids: usize) -> Arc<dyn Node<T>> {
+        Arc::new(self.moved_map(p, chars, ids))
+    }
+
+    fn graft(self: Arc<Self>, context: &PinIn, p: &TreeSearcher<T>, other: Arc<dyn Node<T>>) -> Arc<dyn Node<T>> {
+        let Some(map) = other.map() else {
+            return put_all(self, context, p, &*other);
+        };
+        self.graft_map(context, p, map);
+        if read(&self.children).as_ref().map(|x| x.len() > 32).unwrap_or_default() {
             NAcc::new(context, p, self)
         } else {
             self
         }
     }
+
+    fn entries(&self, p: &TreeSearcher<T>, depth: usize, ret: &mut Vec<(usize, usize)>) {
+        self.map_entries(p, depth, ret);
+    }
+
+    fn map(&self) -> Option<&NMap<T>> {
+        Some(self)
+    }
+
+    fn accs(self: Arc<Self>, ret: &mut Vec<Arc<NAcc<T>>>) {
+        self.map_accs(ret);
+    }
+
+    #[cfg(feature = "serde")]
+    fn image(&self, nodes: &mut Vec<NodeImage>) -> usize {
+        self.image_as(nodes, false)
+    }
 }
 
+#[doc(hidden)]
 pub struct NAcc<T> where T: 'static {
-    map: Rc<NMap<T>>,
-    index: RefCell<FxHashMap<Phoneme, FxHashSet<char>>>,
+    map: Arc<NMap<T>>,
+    index: RwLock<FxHashMap<Phoneme, FxHashSet<char>>>,
 }
 
 impl<T> NAcc<T> where T: 'static {
-    pub fn new(context: &PinIn, searcher: &TreeSearcher<T>, map: Rc<NMap<T>>) -> Rc<Self> {
-        let acc = Rc::new(NAcc {
+    pub fn new(context: &PinIn, searcher: &TreeSearcher<T>, map: Arc<NMap<T>>) -> Arc<Self> {
+        let acc = Arc::new(NAcc {
             map,
-            index: RefCell::new(Default::default())
+            index: RwLock::new(Default::default())
         });
 
         acc.reload(context);
 
-        searcher.naccs.borrow_mut().push(acc.clone());
+        lock(&searcher.naccs).push(acc.clone());
         acc
     }
 
     fn index(&self, context: &PinIn, c: char) {
-        let ch = context.get_character(c);
+        Self::index_into(&mut write(&self.index), context, c);
+    }
 
-        let mut index = self.index.borrow_mut();
+    fn index_into(index: &mut FxHashMap<Phoneme, FxHashSet<char>>, context: &PinIn, c: char) {
+        let ch = context.target_character(c);
 
-        ch.pinyin.iter().for_each(|py: &Pinyin| {
+        let keyboards = ch.pinyin.iter().flat_map(|py| std::iter::once(py.as_ref()).chain(&py.alternates));
+        keyboards.for_each(|py: &Pinyin| {
             let key = &py.phonemes[0];
-            if let Some(set) = index.get_mut(&key) {
+            if let Some(set) = index.get_mut(key) {
                 set.insert(c);
             } else {
                 let mut set = FxHashSet::default();
@@ -279,64 +1915,101 @@ impl<T> NAcc<T> where T: 'static {
     }
 
     pub fn reload(&self, context: &PinIn) {
-        self.index.borrow_mut().clear();
-        self.map.children.borrow_mut().get_or_insert_with(Default::default);
-        self.map.children.borrow().as_ref().unwrap().keys().copied().for_each(|i| {
-            self.index(context, i);
-        });
+        let chars: Vec<char> = read(&self.map.children).iter().flat_map(|x| x.keys()).copied().collect();
+        let mut index = write(&self.index);
+        index.clear();
+        chars.into_iter().for_each(|c| Self::index_into(&mut index, context, c));
     }
 }
 
 impl<T: 'static> Node<T> for NAcc<T> {
-    fn get_offset(&self, context: &PinIn, p: &TreeSearcher<T>, ret: &mut dyn Collection<usize>, offset: usize) {
-        if p.accelerator.search_string.borrow().chars().count() == offset {
-            if p.logic == SearcherLogic::Equal {
-                self.map.leaves.borrow().iter().copied().for_each(|x| { ret.insert(x); });
+    fn get_offset(&self, context: &PinIn, p: &TreeSearcher<T>, a: &Accelerator, ret: &mut dyn Collection<usize>, offset: usize) {
+        if ret.is_full() {
+            return;
+        }
+        if offset >= a.search_len() {
+            if p.logic.is_full() {
+                read(&self.map.leaves).iter().copied().for_each(|x| { ret.insert(x); });
             } else {
                 self.get(context, p, ret);
             }
-        } else {
-            if let Some(children) = self.map.children.borrow().as_ref() {
-                if let Some(node) = children.get(&p.accelerator.search_string.borrow().chars().nth(offset).unwrap()) {
-                    node.get_offset(context, p, ret, offset + 1);
-                }
+        } else if let Some(children) = read(&self.map.children).as_ref() {
+            if let Some(node) = a.search_char(offset).and_then(|c| children.get(&c)) {
+                node.get_offset(context, p, a, ret, offset + 1);
             }
 
-            self.index.borrow().iter()
-                .filter(|(key, _value)| key.match_string(p.accelerator.search_string.borrow().as_str(), offset, true) != IndexSet::none())
-                .flat_map(|(_, value)| value)
-                .copied()
-                .for_each(|c| {
-                    p.accelerator.get(context, c, offset)
-                        .for_each(|j| {
-                            if let Some(children) = self.map.children.borrow().as_ref() {
-                                children[&c].get_offset(context, p, ret, offset + j as usize);
-                            }
-                        })
-                });
+            let matched: Vec<char> = a.with_query(|search| {
+                read(&self.index).iter()
+                    .filter(|(key, _value)| key.match_segmented(search, offset, true) != IndexSet::none())
+                    .flat_map(|(_, value)| value)
+                    .copied()
+                    .collect()
+            });
+            matched.into_iter().for_each(|c| {
+                a.get(context, c, offset)
+                    .for_each(|j| children[&c].get_offset(context, p, a, ret, offset + j as usize))
+            });
         }
     }
 
     fn get(&self, context: &PinIn, p: &TreeSearcher<T>, ret: &mut dyn Collection<usize>) {
-        self.map.leaves.borrow().iter().copied().for_each(|leaf| { ret.insert(leaf); });
+        if ret.is_full() {
+            return;
+        }
+        read(&self.map.leaves).iter().copied().for_each(|leaf| { ret.insert(leaf); });
 
-        if let Some(children) = &*self.map.children.borrow() {
+        if let Some(children) = &*read(&self.map.children) {
             children.values().for_each(|node| node.get(context, p, ret));
         }
     }
 
-    fn put(self: Rc<Self>, context: &PinIn, p: &TreeSearcher<T>, name: usize, id: usize) -> Rc<dyn Node<T>> {
+    fn put(self: Arc<Self>, context: &PinIn, p: &TreeSearcher<T>, name: usize, id: usize) -> Arc<dyn Node<T>> {
         let _ = self.map.clone().put(context, p, name, id);
-        self.index(context, p.compressor.borrow().chars[name]);
+        self.index(context, read(&p.compressor)[name]);
+
+        self
+    }
+
+    fn moved(&self, p: &TreeSearcher<T>, chars: usize, ids: usize) -> Arc<dyn Node<T>> {
+        Arc::new(NAcc {
+            map: Arc::new(self.map.moved_map(p, chars, ids)),
+            index: RwLock::new(read(&self.index).clone()),
+        })
+    }
 
+    fn graft(self: Arc<Self>, context: &PinIn, p: &TreeSearcher<T>, other: Arc<dyn Node<T>>) -> Arc<dyn Node<T>> {
+        let Some(map) = other.map() else {
+            return put_all(self, context, p, &*other);
+        };
+        self.map.graft_map(context, p, map);
+        self.reload(context);
         self
     }
+
+    fn entries(&self, p: &TreeSearcher<T>, depth: usize, ret: &mut Vec<(usize, usize)>) {
+        self.map.map_entries(p, depth, ret);
+    }
+
+    fn map(&self) -> Option<&NMap<T>> {
+        Some(&self.map)
+    }
+
+    fn accs(self: Arc<Self>, ret: &mut Vec<Arc<NAcc<T>>>) {
+        self.map.map_accs(ret);
+        ret.push(self);
+    }
+
+    #[cfg(feature = "serde")]
+    fn image(&self, nodes: &mut Vec<NodeImage>) -> usize {
+        self.map.image_as(nodes, true)
+    }
 }
 
+#[doc(hidden)]
 #[derive(Debug)]
 pub struct NDense<T> {
-    data: RefCell<SmallVec<[usize; 32]>>,
-    phantom: PhantomData<T>,
+    data: RwLock<SmallVec<[usize; 32]>>,
+    phantom: PhantomData<fn() -> T>,
 }
 
 impl<T> Default for NDense<T> {
@@ -348,65 +2021,106 @@ impl<T> Default for NDense<T> {
 impl<T> NDense<T> {
     pub fn new() -> Self {
         NDense {
-            data: RefCell::new(Default::default()),
-            phantom: PhantomData::default(),
+            data: RwLock::new(Default::default()),
+            phantom: PhantomData,
         }
     }
 }
 
 
 impl<T> Node<T> for NDense<T> where T: 'static {
-    fn get_offset(&self, context: &PinIn, p: &TreeSearcher<T>, ret: &mut dyn Collection<usize>, offset: usize) {
-        let full = p.logic == SearcherLogic::Equal;
-        if full && p.accelerator.search_string.borrow().chars().count() == offset {
-            self.get(context, p, ret);
-        } else {
-            for i in 0..self.data.borrow().len() / 2 {
-                let ch = self.data.borrow()[i * 2];
-                if full {
-                    if p.accelerator.matches(context, offset, ch) {
-                        ret.insert(self.data.borrow()[i * 2 + 1]);
-                    }
-                } else if p.accelerator.begins(context, offset, ch) {
-                    ret.insert(self.data.borrow()[i * 2 + 1]);
+    fn get_offset(&self, context: &PinIn, p: &TreeSearcher<T>, a: &Accelerator, ret: &mut dyn Collection<usize>, offset: usize) {
+        if ret.is_full() {
+            return;
+        }
+        // With a full logic and the query used up, only names ending here
+        // match, which `matches` tells like any other entry
+        let full = p.logic.is_full();
+        let data = read(&self.data);
+        for i in 0..data.len() / 2 {
+            if ret.is_full() {
+                break;
+            }
+            let ch = data[i * 2];
+            if full {
+                if a.matches(context, offset, ch) {
+                    ret.insert(data[i * 2 + 1]);
                 }
+            } else if a.begins(context, offset, ch) {
+                ret.insert(data[i * 2 + 1]);
             }
         }
     }
 
     fn get(&self, _context: &PinIn, _p: &TreeSearcher<T>, ret: &mut dyn Collection<usize>) {
-        for i in 0..self.data.borrow().len() / 2 {
-            ret.insert(self.data.borrow()[i * 2 + 1]);
+        let data = read(&self.data);
+        for i in 0..data.len() / 2 {
+            if ret.is_full() {
+                break;
+            }
+            ret.insert(data[i * 2 + 1]);
         }
     }
 
-    fn put(self: Rc<Self>, context: &PinIn, p: &TreeSearcher<T>, name: usize, id: usize) -> Rc<dyn Node<T>> {
-        if self.data.borrow().len() >= BTREE_THRESHOLD {
+    fn put(self: Arc<Self>, context: &PinIn, p: &TreeSearcher<T>, name: usize, id: usize) -> Arc<dyn Node<T>> {
+        if read(&self.data).len() >= BTREE_THRESHOLD {
             //let time = std::time::Instant::now();
-            let pattern = self.data.borrow()[0];
-            let ret = Rc::new(NSlice::new(pattern, pattern + self.match_tree(p)));
-            for j in 0..self.data.borrow().len() / 2 {
-                ret.clone().put(context, p, self.data.borrow()[j * 2], self.data.borrow()[j * 2 + 1]);
+            let len = self.match_tree(p);
+            let data = read(&self.data);
+            let pattern = data[0];
+            let ret = Arc::new(NSlice::new(pattern, pattern + len));
+            for j in 0..data.len() / 2 {
+                ret.clone().put(context, p, data[j * 2], data[j * 2 + 1]);
             }
             ret.clone().put(context, p, name, id);
 
            //println!("convert to tree took {:?}", std::time::Instant::now() - time);
             ret
         } else {
-            self.data.borrow_mut().push(name);
-            self.data.borrow_mut().push(id);
+            {
+                let mut data = write(&self.data);
+                data.push(name);
+                data.push(id);
+            }
             self
         }
     }
+
+    fn moved(&self, _p: &TreeSearcher<T>, chars: usize, ids: usize) -> Arc<dyn Node<T>> {
+        let data = read(&self.data).chunks(2).flat_map(|x| [x[0] + chars, x[1] + ids]).collect();
+        Arc::new(NDense { data: RwLock::new(data), phantom: PhantomData })
+    }
+
+    fn graft(self: Arc<Self>, context: &PinIn, p: &TreeSearcher<T>, other: Arc<dyn Node<T>>) -> Arc<dyn Node<T>> {
+        if read(&self.data).is_empty() {
+            return other;
+        }
+        put_all(self, context, p, &*other)
+    }
+
+    fn entries(&self, _p: &TreeSearcher<T>, depth: usize, ret: &mut Vec<(usize, usize)>) {
+        ret.extend(read(&self.data).chunks(2).map(|x| (x[0] - depth, x[1])));
+    }
+
+    fn accs(self: Arc<Self>, _ret: &mut Vec<Arc<NAcc<T>>>) {}
+
+    #[cfg(feature = "serde")]
+    fn image(&self, nodes: &mut Vec<NodeImage>) -> usize {
+        nodes.push(NodeImage::Dense(read(&self.data).to_vec()));
+        nodes.len() - 1
+    }
 }
 
 impl<T> NDense<T> {
     pub fn match_tree(&self, searcher: &TreeSearcher<T>) -> usize {
+        let data = read(&self.data);
+        let names = read(&searcher.compressor);
+        let chars = &*names;
         let mut i = 0;
         loop {
-            let a = searcher.compressor.borrow().chars[self.data.borrow()[0] + i];
-            for j in 1..self.data.borrow().len() / 2 {
-                let b = searcher.compressor.borrow().chars[self.data.borrow()[j * 2] + i];
+            let a = chars[data[0] + i];
+            for j in 1..data.len() / 2 {
+                let b = chars[data[j * 2] + i];
                 if a != b || a == '\0' {
                     return i;
                 }
@@ -417,87 +2131,165 @@ impl<T> NDense<T> {
     }
 }
 
+#[doc(hidden)]
 pub struct NSlice<T> where T: 'static {
-    exit: RefCell<Rc<dyn Node<T>>>,
+    exit: RwLock<Arc<dyn Node<T>>>,
     start: usize,
-    end: Cell<usize>,
+    end: AtomicUsize,
 }
 
 impl<T> NSlice<T> where T: 'static {
     pub fn new(start: usize, end: usize) -> Self {
         NSlice {
             start,
-            end: Cell::new(end),
-            exit: RefCell::new(Rc::new(NMap::new()))
+            end: AtomicUsize::new(end),
+            exit: RwLock::new(Arc::new(NMap::new()))
         }
     }
 
-    pub fn get_slice(&self, context: &PinIn, p: &TreeSearcher<T>, ret: &mut dyn Collection<usize>, offset: usize, start: usize) {
-        if self.start + start == self.end.get() {
-            self.exit.borrow().get_offset(context, p, ret, offset);
-        } else if offset == p.accelerator.search_string.borrow().chars().count() {
-            if p.logic != SearcherLogic::Equal {
-                self.exit.borrow().get(context, p, ret);
+    #[allow(clippy::too_many_arguments)]
+    pub fn get_slice(&self, context: &PinIn, p: &TreeSearcher<T>, a: &Accelerator, ret: &mut dyn Collection<usize>, offset: usize, start: usize) {
+        if ret.is_full() {
+            return;
+        }
+        if self.start + start == self.end.load(Ordering::Relaxed) {
+            read(&self.exit).get_offset(context, p, a, ret, offset);
+        } else if offset >= a.search_len() {
+            if !p.logic.is_full() {
+                read(&self.exit).get(context, p, ret);
             }
         } else {
-            let ch = p.compressor.borrow().chars[self.start + start];
-            p.accelerator.get(context, ch, offset).for_each(|i| {
-                self.get_slice(context, p, ret, offset + i as usize, start + 1);
+            let ch = read(&p.compressor)[self.start + start];
+            a.get(context, ch, offset).for_each(|i| {
+                self.get_slice(context, p, a, ret, offset + i as usize, start + 1);
             });
         }
     }
 
     pub fn cut(&self, p: &TreeSearcher<T>, offset: usize) {
-        let insert = Rc::new(NMap::new());
-        if offset + 1 == self.end.get() {
-            insert.put_char(p.compressor.borrow().chars[offset], self.exit.borrow().clone());
+        let insert = Arc::new(NMap::new());
+        let end = self.end.load(Ordering::Relaxed);
+        let ch = read(&p.compressor)[offset];
+        if offset + 1 == end {
+            insert.put_char(ch, read(&self.exit).clone());
         } else {
-            let half = Rc::new(NSlice::new(offset + 1, self.end.get()));
-            *half.exit.borrow_mut() = self.exit.borrow().clone();
+            let half = Arc::new(NSlice::new(offset + 1, end));
+            *write(&half.exit) = read(&self.exit).clone();
 
-            insert.put_char(p.compressor.borrow().chars[offset], half);
+            insert.put_char(ch, half);
         }
 
-        *self.exit.borrow_mut() = insert;
-        self.end.set(offset);
+        *write(&self.exit) = insert;
+        self.end.store(offset, Ordering::Relaxed);
     }
 }
 
 impl<T> Node<T> for NSlice<T> where T: 'static {
-    fn get_offset(&self, context: &PinIn, p: &TreeSearcher<T>, ret: &mut dyn Collection<usize>, offset: usize) {
-        self.get_slice(context, p, ret, offset, 0);
+    fn get_offset(&self, context: &PinIn, p: &TreeSearcher<T>, a: &Accelerator, ret: &mut dyn Collection<usize>, offset: usize) {
+        if ret.is_full() {
+            return;
+        }
+        self.get_slice(context, p, a, ret, offset, 0);
     }
 
     fn get(&self, context: &PinIn, p: &TreeSearcher<T>, ret: &mut dyn Collection<usize>) {
-        self.exit.borrow().get(context, p, ret);
+        if ret.is_full() {
+            return;
+        }
+        read(&self.exit).get(context, p, ret);
     }
 
-    fn put(self: Rc<Self>, context: &PinIn, p: &TreeSearcher<T>, name: usize, id: usize) -> Rc<dyn Node<T>> {
-        let len = self.end.get() - self.start;
+    fn put(self: Arc<Self>, context: &PinIn, p: &TreeSearcher<T>, name: usize, id: usize) -> Arc<dyn Node<T>> {
+        let len = self.end.load(Ordering::Relaxed) - self.start;
         let matched = p.accelerator.common(self.start, name, len);
         if matched >= len {
-            let node = self.exit.borrow().clone().put(context, p, name + len, id);
-            *self.exit.borrow_mut() = node;
+            let node = read(&self.exit).clone().put(context, p, name + len, id);
+            *write(&self.exit) = node;
         } else {
             self.cut(p, self.start + matched);
 
-            let node = self.exit.borrow().clone().put(context, p, name + matched, id);
-            *self.exit.borrow_mut() = node;
+            let node = read(&self.exit).clone().put(context, p, name + matched, id);
+            *write(&self.exit) = node;
         }
 
-        if self.start == self.end.get() {
-            self.exit.borrow().clone()
-        } else {
-            self
+        if self.start == self.end.load(Ordering::Relaxed) {
+            return read(&self.exit).clone();
         }
+        self
+    }
+
+    fn moved(&self, p: &TreeSearcher<T>, chars: usize, ids: usize) -> Arc<dyn Node<T>> {
+        let ret = NSlice::new(self.start + chars, self.end.load(Ordering::Relaxed) + chars);
+        *write(&ret.exit) = read(&self.exit).moved(p, chars, ids);
+        Arc::new(ret)
+    }
+
+    fn graft(self: Arc<Self>, context: &PinIn, p: &TreeSearcher<T>, other: Arc<dyn Node<T>>) -> Arc<dyn Node<T>> {
+        put_all(self, context, p, &*other)
+    }
+
+    fn entries(&self, p: &TreeSearcher<T>, depth: usize, ret: &mut Vec<(usize, usize)>) {
+        let len = self.end.load(Ordering::Relaxed) - self.start;
+        read(&self.exit).entries(p, depth + len, ret);
+    }
+
+    fn accs(self: Arc<Self>, ret: &mut Vec<Arc<NAcc<T>>>) {
+        read(&self.exit).clone().accs(ret);
+    }
+
+    #[cfg(feature = "serde")]
+    fn image(&self, nodes: &mut Vec<NodeImage>) -> usize {
+        let exit = read(&self.exit).image(nodes);
+        nodes.push(NodeImage::Slice { start: self.start, end: self.end.load(Ordering::Relaxed), exit });
+        nodes.len() - 1
     }
 }
 
-#[derive(Hash, Eq, PartialEq, Copy, Clone)]
+#[derive(Hash, Eq, PartialEq, Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum SearcherLogic {
     Begin,
     Contain,
     Equal,
+    /// The query matches the end of the name, e.g. a file extension.
+    EndsWith,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseSearcherLogicError(String);
+
+impl Display for ParseSearcherLogicError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown searcher logic `{}`, expected begin, contain, equal or endswith", self.0)
+    }
+}
+
+impl Error for ParseSearcherLogicError {}
+
+impl Display for SearcherLogic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SearcherLogic::Begin => "begin",
+            SearcherLogic::Contain => "contain",
+            SearcherLogic::Equal => "equal",
+            SearcherLogic::EndsWith => "endswith",
+        })
+    }
+}
+
+impl FromStr for SearcherLogic {
+    type Err = ParseSearcherLogicError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "begin" | "begins" => Ok(SearcherLogic::Begin),
+            "contain" | "contains" => Ok(SearcherLogic::Contain),
+            "equal" | "equals" => Ok(SearcherLogic::Equal),
+            "endswith" | "ends_with" | "ends" => Ok(SearcherLogic::EndsWith),
+            _ => Err(ParseSearcherLogicError(s.to_string())),
+        }
+    }
 }
 
 impl SearcherLogic {
@@ -506,14 +2298,21 @@ impl SearcherLogic {
             SearcherLogic::Begin => a.begins(context, offset, start),
             SearcherLogic::Contain => a.contains(context, offset, start),
             SearcherLogic::Equal => a.matches(context, offset, start),
+            SearcherLogic::EndsWith => a.ends(context, offset, start),
         }
     }
 
+    /// Whether the query has to match up to the end of the name.
+    pub fn is_full(&self) -> bool {
+        matches!(self, SearcherLogic::Equal | SearcherLogic::EndsWith)
+    }
+
     pub fn test_pinyin(&self, p: &PinIn, s1: &str, s2: &str) -> bool {
         match *self {
             SearcherLogic::Begin => p.begins(s1, s2),
             SearcherLogic::Contain => p.contains(s1, s2),
             SearcherLogic::Equal => p.matches(s1, s2),
+            SearcherLogic::EndsWith => p.ends(s1, s2),
         }
     }
 }
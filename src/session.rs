@@ -0,0 +1,61 @@
+use crate::accelerator::{Accelerator, StringProvider};
+use crate::pinin::PinIn;
+use crate::sync::write;
+use std::sync::{Arc, RwLock};
+
+/// Per-caller matching state borrowed from a [`PinIn`].
+///
+/// A session owns the accelerator cache and a reusable target buffer, so
+/// matching through it only needs `&PinIn`. Repeated calls with the same
+/// query reuse the cached pinyin matches across targets.
+pub struct MatchSession<'p> {
+    context: &'p PinIn,
+    accelerator: Accelerator,
+    target: Arc<RwLock<StringProvider>>,
+}
+
+impl<'p> MatchSession<'p> {
+    pub fn new(context: &'p PinIn) -> Self {
+        let target = Arc::new(RwLock::new(StringProvider::default()));
+        let accelerator = Accelerator::with_provider(target.clone());
+        MatchSession {
+            context,
+            accelerator,
+            target,
+        }
+    }
+
+    pub fn context(&self) -> &'p PinIn {
+        self.context
+    }
+
+    /// Runs `f` with the target set to `s1` and the query to `s2`. Calls
+    /// from several threads take turns.
+    fn run<F: FnOnce(&Accelerator) -> bool>(&self, s1: &str, s2: &str, f: F) -> bool {
+        let _guard = self.accelerator.lock();
+        write(&self.target).set(&self.context.target_str(s1));
+        self.accelerator.search(&self.context.folded(s2));
+        f(&self.accelerator)
+    }
+
+    pub fn contains(&self, s1: &str, s2: &str) -> bool {
+        self.run(s1, s2, |a| a.contains(self.context, 0, 0))
+    }
+
+    pub fn begins(&self, s1: &str, s2: &str) -> bool {
+        self.run(s1, s2, |a| a.begins(self.context, 0, 0))
+    }
+
+    pub fn matches(&self, s1: &str, s2: &str) -> bool {
+        self.run(s1, s2, |a| a.matches(self.context, 0, 0))
+    }
+
+    pub fn ends(&self, s1: &str, s2: &str) -> bool {
+        self.run(s1, s2, |a| a.ends(self.context, 0, 0))
+    }
+
+    /// Drops cached matches, freeing what they hold.
+    pub fn reset(&self) {
+        self.accelerator.reset();
+    }
+}
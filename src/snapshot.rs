@@ -0,0 +1,82 @@
+use crate::accelerator::Accelerator;
+use crate::pinin::PinIn;
+use crate::searcher::{Searcher, SearcherLogic, TreeSearcher};
+use std::mem;
+use std::sync::Arc;
+
+/// A double-buffered [`TreeSearcher`].
+///
+/// Inserts go to a back buffer while queries run against the published
+/// front buffer, so an index can be updated in the background without
+/// readers observing a half-built tree. [`SnapshotSearcher::publish`]
+/// swaps the buffers and replays the pending inserts onto the old front.
+pub struct SnapshotSearcher<T: Clone + 'static> {
+    logic: SearcherLogic,
+    front: Arc<TreeSearcher<T>>,
+    back: TreeSearcher<T>,
+    pending: Vec<(String, T)>,
+}
+
+impl<T: Clone + 'static> SnapshotSearcher<T> {
+    pub fn new(logic: SearcherLogic) -> Self {
+        SnapshotSearcher {
+            logic,
+            front: Arc::new(Self::empty(logic)),
+            back: Self::empty(logic),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Publishes `searcher` as it is, inserting into a copy of it from
+    /// then on, so that its keys, tokenizer and policy carry over.
+    pub fn with_searcher(searcher: TreeSearcher<T>) -> Self {
+        SnapshotSearcher {
+            logic: searcher.logic(),
+            front: Arc::new(searcher.clone()),
+            back: searcher,
+            pending: Vec::new(),
+        }
+    }
+
+    fn empty(logic: SearcherLogic) -> TreeSearcher<T> {
+        TreeSearcher::new(logic, Arc::new(Accelerator::new()))
+    }
+
+    /// Adds an entry to the back buffer; it becomes visible on `publish`.
+    pub fn insert(&mut self, context: &PinIn, name: &str, id: T) {
+        self.back.insert(context, name, id.clone());
+        self.pending.push((name.to_string(), id));
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// The currently published index. Holding on to it keeps that version
+    /// alive and unchanged across later publishes.
+    pub fn snapshot(&self) -> Arc<TreeSearcher<T>> {
+        self.front.clone()
+    }
+
+    pub fn search(&self, context: &PinIn, s: &str) -> Vec<&T> {
+        self.front.search(context, s)
+    }
+
+    pub fn publish(&mut self, context: &PinIn) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let back = mem::replace(&mut self.back, Self::empty(self.logic));
+        let old = mem::replace(&mut self.front, Arc::new(back));
+        self.back = match Arc::try_unwrap(old) {
+            Ok(old) => old,
+            // A reader still holds the old version, so take a private copy of it
+            Err(shared) => (*shared).clone(),
+        };
+
+        mem::take(&mut self.pending)
+            .into_iter()
+            .for_each(|(name, id)| self.back.insert(context, &name, id));
+    }
+}
@@ -0,0 +1,75 @@
+use std::cmp::Ordering;
+use std::iter::Peekable;
+use std::str::Chars;
+
+fn take_number(chars: &mut Peekable<Chars>) -> (String, usize) {
+    let mut digits = String::new();
+    while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+        digits.push(c);
+    }
+    let len = digits.len();
+    let trimmed = digits.trim_start_matches('0');
+    (trimmed.to_string(), len)
+}
+
+/// Compares two strings so that embedded runs of ASCII digits are
+/// ordered by value: `轰6K < 轰20 < 轰100`.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x, x_len) = take_number(&mut a);
+                let (y, y_len) = take_number(&mut b);
+                let ord = x
+                    .len()
+                    .cmp(&y.len())
+                    .then_with(|| x.cmp(&y))
+                    // "007" after "7" so that the order stays total
+                    .then_with(|| x_len.cmp(&y_len));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(&y);
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+pub fn sort_natural_by_key<T, K, F>(slice: &mut [T], mut f: F)
+where
+    F: FnMut(&T) -> K,
+    K: AsRef<str>,
+{
+    slice.sort_by(|a, b| natural_cmp(f(a).as_ref(), f(b).as_ref()));
+}
+
+/// How many chars of `query` appear literally in `name`, in order. Typed
+/// digits, Latin letters and hanzi that are really in the name count,
+/// while matches through pinyin do not.
+pub fn literal_hits(name: &str, query: &str) -> usize {
+    let mut name = name.chars();
+    query
+        .chars()
+        .filter(|q| {
+            // Chars not found are skipped without consuming the name
+            let mut rest = name.clone();
+            let found = rest.any(|c| c == *q || c.to_lowercase().eq(q.to_lowercase()));
+            if found {
+                name = rest;
+            }
+            found
+        })
+        .count()
+}
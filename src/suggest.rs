@@ -0,0 +1,145 @@
+//! "Did you mean" suggestions for queries that match nothing.
+//!
+//! Candidates are the query with one key dropped, two neighbouring keys
+//! swapped or one key replaced by a key next to it on the keyboard. Only
+//! candidates that still split into syllables of the context's layouts
+//! are searched, and those matching something are offered, most hits
+//! first and then the longest, which says the most about what was meant.
+
+use crate::pinin::PinIn;
+use crate::searcher::Searcher;
+use rustc_hash::FxHashSet;
+
+/// Rows of the physical keyboard every layout is typed on.
+const ROWS: [&str; 4] = ["1234567890-", "qwertyuiop", "asdfghjkl;", "zxcvbnm,./"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Suggestion {
+    pub query: String,
+    /// How many objects the query matches.
+    pub count: usize,
+}
+
+/// Keys around `c`: beside it, and touching it in the rows above and
+/// below, which are shifted by half a key.
+fn neighbours(c: char) -> Vec<char> {
+    let Some((row, col)) = ROWS
+        .iter()
+        .enumerate()
+        .find_map(|(row, keys)| keys.chars().position(|x| x == c).map(|col| (row, col)))
+    else {
+        return Vec::new();
+    };
+    let at = |row: usize, col: usize| ROWS.get(row).and_then(|x| x.chars().nth(col));
+    [
+        col.checked_sub(1).and_then(|x| at(row, x)),
+        at(row, col + 1),
+        row.checked_sub(1).and_then(|r| at(r, col)),
+        row.checked_sub(1).and_then(|r| at(r, col + 1)),
+        col.checked_sub(1).and_then(|x| at(row + 1, x)),
+        at(row + 1, col),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// The syllables of the loaded readings as typed on the context's
+/// layouts, and the keys of the tones.
+fn typed_syllables(context: &PinIn) -> (FxHashSet<String>, FxHashSet<String>) {
+    let syllables: FxHashSet<&str> = context.pinyins.values().map(|x| x.syllable()).collect();
+    let mut typed = FxHashSet::default();
+    let mut tones = FxHashSet::default();
+    for keyboard in context.keyboards() {
+        typed.extend(
+            syllables
+                .iter()
+                .map(|syllable| {
+                    let raw = format!("{}1", syllable);
+                    let mut phonemes = keyboard.split(&raw);
+                    phonemes.pop();
+                    phonemes.iter().map(|x| keyboard.keys(x)).collect::<String>()
+                })
+                .filter(|x| !x.is_empty()),
+        );
+        tones.extend(["0", "1", "2", "3", "4"].into_iter().map(|x| keyboard.keys(x).to_string()));
+    }
+    (typed, tones)
+}
+
+/// Whether `query` splits into typed syllables, each optionally followed
+/// by a tone key, with the last one possibly cut short. Chars that are
+/// not keys of the layout, such as hanzi, stand for themselves.
+fn segments(context: &PinIn, syllables: &FxHashSet<String>, tones: &FxHashSet<String>, query: &[char]) -> bool {
+    let starts: FxHashSet<char> = syllables.iter().filter_map(|x| x.chars().next()).collect();
+    let mut reached = vec![false; query.len() + 1];
+    reached[0] = true;
+    for i in 0..query.len() {
+        if !reached[i] {
+            continue;
+        }
+        let rest: String = query[i..].iter().collect();
+        if !query[i].is_ascii() || query[i].is_whitespace() {
+            reached[i + 1] = true;
+            continue;
+        }
+        if context.keyboards().any(|x| x.sequence) && starts.contains(&query[i]) {
+            reached[i + 1] = true;
+        }
+        for syllable in syllables {
+            if syllable.starts_with(&rest) {
+                reached[query.len()] = true;
+            } else if let Some(after) = rest.strip_prefix(syllable.as_str()) {
+                let end = i + syllable.chars().count();
+                reached[end] = true;
+                for tone in tones.iter().filter(|x| !x.is_empty() && after.starts_with(x.as_str())) {
+                    reached[end + tone.chars().count()] = true;
+                }
+            }
+        }
+    }
+    reached[query.len()]
+}
+
+/// Up to `limit` corrections of `query` that match something in
+/// `searcher`, most hits first.
+pub fn suggest<T: 'static, S: Searcher<T> + ?Sized>(searcher: &S, context: &PinIn, query: &str, limit: usize) -> Vec<Suggestion> {
+    let chars: Vec<char> = query.chars().collect();
+    let mut candidates: Vec<Vec<char>> = Vec::new();
+    for i in 0..chars.len() {
+        let mut x = chars.clone();
+        x.remove(i);
+        candidates.push(x);
+        if i + 1 < chars.len() && chars[i] != chars[i + 1] {
+            let mut x = chars.clone();
+            x.swap(i, i + 1);
+            candidates.push(x);
+        }
+        for key in neighbours(chars[i]) {
+            let mut x = chars.clone();
+            x[i] = key;
+            candidates.push(x);
+        }
+    }
+
+    let (syllables, tones) = typed_syllables(context);
+    let mut seen = FxHashSet::default();
+    let mut ret: Vec<Suggestion> = candidates
+        .into_iter()
+        .filter(|x| !x.is_empty() && *x != chars && seen.insert(x.clone()))
+        .filter(|x| segments(context, &syllables, &tones, x))
+        .map(|x| x.into_iter().collect::<String>())
+        .filter_map(|query| {
+            let count = searcher.count(context, &query);
+            (count > 0).then_some(Suggestion { query, count })
+        })
+        .collect();
+    ret.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then_with(|| b.query.len().cmp(&a.query.len()))
+            .then_with(|| a.query.cmp(&b.query))
+    });
+    ret.truncate(limit);
+    ret
+}
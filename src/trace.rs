@@ -0,0 +1,169 @@
+//! How a query is read, for answering "why doesn't this match" from a
+//! bug report instead of a debugger.
+//!
+//! ```
+//! use pinin_rs::pinin::PinIn;
+//!
+//! let mut pinin = PinIn::new();
+//! pinin.load_default_dict();
+//! let trace = pinin.trace_query("zhong guo");
+//! assert_eq!(trace.steps.len(), 3);
+//! println!("{}", trace);
+//! ```
+//!
+//! prints the query as matched, then the keys each syllable consumes with
+//! the readings that consume them and the keys each phoneme of the first
+//! reading accepts, fuzzy variants included:
+//!
+//! ```text
+//! "zhong guo"
+//! zhong: zhong1, zhong3, zhong4 (zh ong 1)
+//!  : literal
+//! guo: guo1, guo2, guo3, guo4 (g uo 1)
+//! ```
+
+use crate::elements::{Phoneme, Pinyin};
+use crate::pinin::PinIn;
+use crate::unicode_utils::SegmentedStr;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+/// A query as read by a context, see [`PinIn::trace_query`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryTrace {
+    pub query: String,
+    /// The query after folding and canonicalization, as it is matched.
+    pub normalized: String,
+    /// The graphemes of `normalized`, each consumed as one key.
+    pub keys: Vec<String>,
+    /// One way to read the keys, with as few syllables as possible.
+    pub steps: Vec<TraceStep>,
+}
+
+/// Keys of a query read as one syllable, or as a literal char.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceStep {
+    /// The first key and the one past the last, as indices of
+    /// [`QueryTrace::keys`].
+    pub start: usize,
+    pub end: usize,
+    /// Every loaded reading that consumes exactly these keys, empty for a
+    /// key that only matches itself.
+    pub readings: Vec<String>,
+    /// The keys each phoneme of the first reading accepts, after the
+    /// keyboard and the fuzzy settings.
+    pub phonemes: Vec<Vec<String>>,
+    /// Whether the query ends within the syllable.
+    pub partial: bool,
+}
+
+impl Display for QueryTrace {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.normalized == self.query {
+            writeln!(f, "{:?}", self.query)?;
+        } else {
+            writeln!(f, "{:?} -> {:?}", self.query, self.normalized)?;
+        }
+        for step in &self.steps {
+            write!(f, "{}: ", self.keys[step.start..step.end].concat())?;
+            if step.readings.is_empty() {
+                writeln!(f, "literal")?;
+                continue;
+            }
+            let phonemes: Vec<String> = step
+                .phonemes
+                .iter()
+                .map(|x| match x.as_slice() {
+                    [key] => key.clone(),
+                    keys => format!("[{}]", keys.join(" ")),
+                })
+                .collect();
+            write!(f, "{} ({})", step.readings.join(", "), phonemes.join(" "))?;
+            if step.partial {
+                write!(f, ", partial")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+fn phoneme_keys(phoneme: &Phoneme) -> Vec<String> {
+    match phoneme {
+        Phoneme::Single(x) => vec![x.to_string()],
+        Phoneme::Multiple(xs) => xs.iter().map(|x| x.to_string()).collect(),
+    }
+}
+
+impl PinIn {
+    /// How `query` is read: normalized as searchers do, then split into
+    /// the fewest syllables of the loaded readings, with keys no reading
+    /// consumes taken literally. Other splits may match too; this is the
+    /// one a user most likely meant.
+    pub fn trace_query(&self, query: &str) -> QueryTrace {
+        let normalized = self.query_str(query).into_owned();
+        let s = SegmentedStr::from(normalized.as_str());
+        let len = s.graphemes.len();
+        let mut pinyins: Vec<&Pinyin> = self.pinyins.values().map(Arc::as_ref).collect();
+        pinyins.sort_by_key(|x| x.id);
+
+        // Readings by the keys they consume, from each key on
+        let mut spans: Vec<BTreeMap<usize, Vec<&Pinyin>>> = vec![BTreeMap::new(); len];
+        for (start, spans) in spans.iter_mut().enumerate() {
+            for p in &pinyins {
+                let whole = p.match_segmented(&s, start, false);
+                p.match_segmented(&s, start, true).for_each(|i| {
+                    let end = start + i as usize;
+                    if i > 0 && (whole.get(i as usize) || end == len) {
+                        spans.entry(end).or_default().push(p);
+                    }
+                });
+            }
+        }
+
+        // Fewest steps to each key, a literal costing as much as two syllables
+        let mut best: Vec<Option<(usize, usize, bool)>> = vec![None; len + 1];
+        best[0] = Some((0, 0, false));
+        for start in 0..len {
+            let Some((cost, _, _)) = best[start] else {
+                continue;
+            };
+            let literal = (start + 1, cost + 2, true);
+            let steps = spans[start].keys().map(|end| (*end, cost + 1, false)).chain([literal]);
+            for (end, cost, literal) in steps {
+                if best[end].is_none_or(|(x, _, _)| cost < x) {
+                    best[end] = Some((cost, start, literal));
+                }
+            }
+        }
+
+        let mut steps = Vec::new();
+        let mut end = len;
+        while let Some((_, start, literal)) = best[end].filter(|_| end > 0) {
+            let readings = match spans[start].get(&end) {
+                Some(x) if !literal => x.as_slice(),
+                _ => &[],
+            };
+            let whole = readings.iter().any(|p| p.match_segmented(&s, start, false).get(end - start));
+            steps.push(TraceStep {
+                start,
+                end,
+                readings: readings.iter().map(|p| p.raw.to_string()).collect(),
+                phonemes: readings.first().map(|p| p.phonemes.iter().map(phoneme_keys).collect()).unwrap_or_default(),
+                partial: !readings.is_empty() && !whole,
+            });
+            end = start;
+        }
+        steps.reverse();
+
+        let keys = s.graphemes.iter().map(|(_, x)| x.to_string()).collect();
+        drop(s);
+        QueryTrace {
+            query: query.to_string(),
+            normalized,
+            keys,
+            steps,
+        }
+    }
+}
@@ -1,4 +1,22 @@
+//! Grapheme-aware string helpers shared by matching and formatting.
+//!
+//! [`UnicodeUtils`] slices by grapheme cluster, so that a combining
+//! accent or an emoji sequence is never split. On a plain `str` every
+//! call segments the string again; [`SegmentedStr`] segments once and
+//! slices, including into smaller [`SegmentedStr`]s, by index:
+//!
+//! ```
+//! use pinin_rs::unicode_utils::{SegmentedStr, UnicodeUtils};
+//!
+//! let s = SegmentedStr::from("ü测试");
+//! assert_eq!(s.len(), 3);
+//! assert_eq!(s.substring(1, 2), "测试");
+//! assert_eq!(s.slice(1, 1).as_str(), "测");
+//! ```
+
 use smallvec::SmallVec;
+use std::borrow::Cow;
+use std::ops::Range;
 use unicode_segmentation::UnicodeSegmentation;
 
 pub trait UnicodeUtils<'a> {
@@ -8,14 +26,84 @@ pub trait UnicodeUtils<'a> {
     fn last_grapheme(&'a self) -> &'a str;
     fn remove_last_grapheme(&'a self) -> &'a str;
 
+    /// `len` graphemes from the `start`-th one, clamped to the string.
     fn substring(&'a self, start: usize, len: usize) -> &'a str;
+
+    /// `len` graphemes from the `start`-th one, or `None` when the string
+    /// has fewer than `start + len` graphemes.
+    fn substring_checked(&'a self, start: usize, len: usize) -> Option<&'a str>;
+
+    /// Like `substring_checked`, clamping the range to the string instead.
+    fn substring_saturating(&'a self, start: usize, len: usize) -> &'a str;
 }
 
+/// A string with its grapheme clusters found up front.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SegmentedStr<'a> {
     pub raw: &'a str,
+    /// Byte offset into `raw` and text of each grapheme.
     pub graphemes: SmallVec<[(usize, &'a str); 7]>,
 }
 
+impl<'a> SegmentedStr<'a> {
+    pub fn as_str(&self) -> &'a str {
+        self.raw
+    }
+
+    /// Number of graphemes.
+    pub fn len(&self) -> usize {
+        self.graphemes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.graphemes.is_empty()
+    }
+
+    pub fn graphemes(&self) -> impl Iterator<Item = &'a str> + '_ {
+        self.graphemes.iter().map(|x| x.1)
+    }
+
+    /// Bytes of `raw` covered by `len` graphemes from the `start`-th one,
+    /// clamped to the string.
+    pub fn byte_range(&self, start: usize, len: usize) -> Range<usize> {
+        let count = self.graphemes.len();
+        let start = start.min(count);
+        let end = start.saturating_add(len).min(count);
+        let byte = |i: usize| self.graphemes.get(i).map(|x| x.0).unwrap_or(self.raw.len());
+        byte(start)..byte(end)
+    }
+
+    /// `raw` split at the byte offsets of its graphemes as found before,
+    /// for strings segmented once and looked at many times.
+    pub fn from_offsets(raw: &'a str, offsets: &[usize]) -> Self {
+        let ends = offsets.iter().skip(1).copied().chain([raw.len()]);
+        SegmentedStr {
+            raw,
+            graphemes: offsets.iter().zip(ends).map(|(start, end)| (*start, &raw[*start..end])).collect(),
+        }
+    }
+
+    /// Byte offsets of the graphemes, to build it again with
+    /// [`SegmentedStr::from_offsets`].
+    pub fn offsets(&self) -> Vec<usize> {
+        self.graphemes.iter().map(|x| x.0).collect()
+    }
+
+    /// Like `substring_saturating`, keeping the segmentation.
+    pub fn slice(&self, start: usize, len: usize) -> SegmentedStr<'a> {
+        let range = self.byte_range(start, len);
+        let first = start.min(self.graphemes.len());
+        let count = len.min(self.graphemes.len() - first);
+        SegmentedStr {
+            raw: &self.raw[range.clone()],
+            graphemes: self.graphemes[first..first + count]
+                .iter()
+                .map(|(i, g)| (i - range.start, *g))
+                .collect(),
+        }
+    }
+}
+
 impl<'a> From<&'a str> for SegmentedStr<'a> {
     fn from(value: &'a str) -> Self {
         SegmentedStr {
@@ -25,72 +113,272 @@ impl<'a> From<&'a str> for SegmentedStr<'a> {
     }
 }
 
-impl<'a> UnicodeUtils<'a> for SegmentedStr<'a> {
+impl<'a, 'b: 'a> UnicodeUtils<'a> for SegmentedStr<'b> {
     fn first_grapheme(&'a self) -> &'a str {
-        self.graphemes.first().unwrap().1
+        self.graphemes.first().map(|x| x.1).unwrap_or_default()
     }
 
     fn remove_first_grapheme(&'a self) -> &'a str {
-        &self.raw[self.graphemes.first().unwrap().1.len()..]
+        &self.raw[self.first_grapheme().len()..]
     }
 
     fn last_grapheme(&'a self) -> &'a str {
-        self.graphemes.last().unwrap().1
+        self.graphemes.last().map(|x| x.1).unwrap_or_default()
     }
 
     fn remove_last_grapheme(&'a self) -> &'a str {
-        &self.raw[..self.graphemes.last().unwrap().0]
+        &self.raw[..self.graphemes.last().map(|x| x.0).unwrap_or(0)]
     }
 
     fn substring(&'a self, start: usize, len: usize) -> &'a str {
-        let end = self.graphemes[start + len];
-        &self.raw[self.graphemes[start].0..end.0 + end.1.len()]
+        self.substring_saturating(start, len)
+    }
+
+    fn substring_checked(&'a self, start: usize, len: usize) -> Option<&'a str> {
+        let end = start.checked_add(len)?;
+        if end > self.graphemes.len() {
+            return None;
+        }
+        Some(self.substring_saturating(start, len))
+    }
+
+    fn substring_saturating(&'a self, start: usize, len: usize) -> &'a str {
+        &self.raw[self.byte_range(start, len)]
     }
 }
 
 impl<'a> UnicodeUtils<'a> for str {
     fn first_grapheme(&'a self) -> &'a str {
-        self.graphemes(true).next().unwrap()
+        self.graphemes(true).next().unwrap_or_default()
     }
 
     fn remove_first_grapheme(&'a self) -> &'a str {
-        &self[self.graphemes(true).next().unwrap().len()..]
+        &self[self.first_grapheme().len()..]
     }
 
     fn last_grapheme(&'a self) -> &'a str {
-        self.graphemes(true).last().unwrap()
+        self.graphemes(true).next_back().unwrap_or_default()
     }
 
     fn remove_last_grapheme(&'a self) -> &'a str {
-        &self[..self.grapheme_indices(true).last().unwrap().0]
+        &self[..self.len() - self.last_grapheme().len()]
     }
 
     fn substring(&'a self, start: usize, len: usize) -> &'a str {
-        assert!(len > 0, "Length must > 0");
+        self.substring_saturating(start, len)
+    }
 
-        let mut begin = 0;
+    fn substring_checked(&'a self, start: usize, len: usize) -> Option<&'a str> {
+        let end = start.checked_add(len)?;
+        let mut indices = self.grapheme_indices(true).map(|(i, _)| i).chain(std::iter::once(self.len()));
+        let begin = indices.nth(start)?;
+        let end = if len == 0 { begin } else { indices.nth(end - start - 1)? };
+        Some(&self[begin..end])
+    }
 
-        let mut end = 0;
-        let mut temp = "";
+    fn substring_saturating(&'a self, start: usize, len: usize) -> &'a str {
+        let mut indices = self.grapheme_indices(true).map(|(i, _)| i).chain(std::iter::once(self.len()));
+        let begin = indices.nth(start).unwrap_or(self.len());
+        let end = if len == 0 {
+            begin
+        } else {
+            indices.nth(len - 1).unwrap_or(self.len())
+        };
+        &self[begin..end]
+    }
+}
 
-        for (i, (index, s)) in self
-            .grapheme_indices(true)
-            .skip(start)
-            .take(len)
-            .enumerate()
-        {
-            if i == 0 {
-                begin = index;
-            }
+/// The form entries are indexed under: whitespace runs collapse to a
+/// single space, leading and trailing whitespace is trimmed and other
+/// control characters are dropped. Borrows when `s` is already canonical.
+pub fn canonicalize(s: &str) -> Cow<'_, str> {
+    let canonical = !s.starts_with(char::is_whitespace)
+        && !s.ends_with(char::is_whitespace)
+        && !s.contains(|c: char| (c.is_whitespace() && c != ' ') || c.is_control())
+        && !s.contains("  ");
+    if canonical {
+        return Cow::Borrowed(s);
+    }
 
-            end = index;
-            temp = s;
+    let mut ret = String::with_capacity(s.len());
+    for word in s.split(char::is_whitespace).filter(|x| !x.is_empty()) {
+        if !ret.is_empty() {
+            ret.push(' ');
         }
+        ret.extend(word.chars().filter(|c| !c.is_control()));
+    }
+    Cow::Owned(ret)
+}
 
-        if begin == end {
-            return temp;
+/// Roman numerals Ⅰ to Ⅻ, spelled out.
+const ROMAN: [&str; 12] = ["I", "II", "III", "IV", "V", "VI", "VII", "VIII", "IX", "X", "XI", "XII"];
+
+/// What `c` folds to, see [`fold`]. `None` keeps it as it is.
+pub(crate) fn fold_char(c: char) -> Option<&'static str> {
+    const ASCII: &str = "!\"#$%&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\\]^_`abcdefghijklmnopqrstuvwxyz{|}~";
+    match c as u32 {
+        // Punctuation, which would otherwise stand between chars to match
+        0x3001..=0x3003
+        | 0x3008..=0x3011
+        | 0x3014..=0x301F
+        | 0xFF01..=0xFF0F
+        | 0xFF1A..=0xFF20
+        | 0xFF3B..=0xFF40
+        | 0xFF5B..=0xFF65
+        | 0x2010..=0x2027
+        | 0x00B7
+        | 0x30FB => Some(""),
+        // The remaining fullwidth forms: digits and letters
+        x @ 0xFF10..=0xFF5A => Some(&ASCII[(x - 0xFF01) as usize..(x - 0xFF00) as usize]),
+        0x3000 => Some(" "),
+        x @ 0x2160..=0x216B => Some(ROMAN[(x - 0x2160) as usize]),
+        x @ 0x2170..=0x217B => Some(ROMAN[(x - 0x2170) as usize]),
+        _ => None,
+    }
+}
+
+/// `s` with the forms common in Chinese text folded, as done by
+/// [`PinIn::fold`](crate::pinin::PinIn::fold): fullwidth digits and
+/// letters become ASCII, e.g. `第２集` becomes `第2集`, roman numerals are
+/// spelled out with letters, the ideographic space becomes a space, and
+/// CJK and fullwidth punctuation such as `、，。《》` is dropped. Borrows
+/// when there is nothing to fold.
+pub fn fold(s: &str) -> Cow<'_, str> {
+    if !s.chars().any(|c| fold_char(c).is_some()) {
+        return Cow::Borrowed(s);
+    }
+    let mut ret = String::with_capacity(s.len());
+    for c in s.chars() {
+        match fold_char(c) {
+            Some(x) => ret.push_str(x),
+            None => ret.push(c),
         }
+    }
+    Cow::Owned(ret)
+}
+
+/// The lowercase of `c` when it is an uppercase letter of the Latin-1,
+/// Greek or Cyrillic blocks, see [`fold_case`]. Final sigma folds to `σ`.
+pub(crate) fn lower_char(c: char) -> Option<char> {
+    let x = c as u32;
+    let lower = match x {
+        0x41..=0x5A | 0xC0..=0xD6 | 0xD8..=0xDE => x + 0x20,
+        0x391..=0x3A1 | 0x3A3..=0x3AB => x + 0x20,
+        0x386 => 0x3AC,
+        0x388..=0x38A => x + 0x25,
+        0x38C => 0x3CC,
+        0x38E..=0x38F => x + 0x3F,
+        0x3C2 => 0x3C3,
+        0x400..=0x40F => x + 0x50,
+        0x410..=0x42F => x + 0x20,
+        _ => return None,
+    };
+    char::from_u32(lower)
+}
 
-        &self[begin..end + temp.len()]
+/// `s` with Latin, Greek and Cyrillic letters in lowercase, as done by
+/// [`PinIn::fold_case`](crate::pinin::PinIn::fold_case), e.g. `ΩМЕГА`
+/// becomes `ωмега`. Only letters of those blocks are folded, through a
+/// small table rather than the full Unicode case mappings. Borrows when
+/// there is nothing to fold.
+pub fn fold_case(s: &str) -> Cow<'_, str> {
+    if !s.chars().any(|c| lower_char(c).is_some()) {
+        return Cow::Borrowed(s);
+    }
+    Cow::Owned(s.chars().map(|c| lower_char(c).unwrap_or(c)).collect())
+}
+
+/// Whether `c` is a CJK ideograph, including the extension blocks,
+/// compatibility ideographs, radicals and the ideographic zero `〇`.
+pub fn is_han(c: char) -> bool {
+    matches!(c as u32,
+        0x2E80..=0x2FDF
+        | 0x3005
+        | 0x3007
+        | 0x31C0..=0x31EF
+        | 0x3400..=0x4DBF
+        | 0x4E00..=0x9FFF
+        | 0xF900..=0xFAFF
+        | 0x20000..=0x2A6DF
+        | 0x2A700..=0x2EE5F
+        | 0x2F800..=0x2FA1F
+        | 0x30000..=0x323AF)
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Script {
+    Han,
+    Latin,
+    Digit,
+    Whitespace,
+    Other,
+}
+
+impl Script {
+    pub fn of(c: char) -> Self {
+        if is_han(c) {
+            Script::Han
+        } else if c.is_ascii_digit() {
+            Script::Digit
+        } else if c.is_whitespace() {
+            Script::Whitespace
+        } else if c.is_ascii_alphabetic()
+            || matches!(c as u32, 0xC0..=0x24F | 0x1E00..=0x1EFF)
+        {
+            Script::Latin
+        } else {
+            Script::Other
+        }
+    }
+}
+
+/// Splits a string into maximal runs of characters sharing the same [`Script`].
+pub struct ScriptRuns<'a> {
+    s: &'a str,
+}
+
+pub fn script_runs(s: &str) -> ScriptRuns<'_> {
+    ScriptRuns { s }
+}
+
+impl<'a> Iterator for ScriptRuns<'a> {
+    type Item = (Script, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut chars = self.s.char_indices();
+        let script = Script::of(chars.next()?.1);
+        let end = chars
+            .find(|(_, c)| Script::of(*c) != script)
+            .map(|(i, _)| i)
+            .unwrap_or(self.s.len());
+        let (run, rest) = self.s.split_at(end);
+        self.s = rest;
+        Some((script, run))
+    }
+}
+
+/// Char offsets where a word starts: at changes of [`Script`], after
+/// whitespace, and at camel case humps such as the `S` of `ItemStack` or
+/// the `P` of `HTMLParser`.
+pub fn word_starts(chars: &[char]) -> Vec<usize> {
+    let mut ret = Vec::new();
+    for (i, c) in chars.iter().copied().enumerate() {
+        let script = Script::of(c);
+        if script == Script::Whitespace {
+            continue;
+        }
+        let start = match i.checked_sub(1).map(|j| chars[j]) {
+            None => true,
+            Some(prev) if Script::of(prev) != script => true,
+            Some(prev) => {
+                c.is_uppercase()
+                    && (prev.is_lowercase()
+                        || (prev.is_uppercase() && chars.get(i + 1).is_some_and(|x| x.is_lowercase())))
+            }
+        };
+        if start {
+            ret.push(i);
+        }
     }
+    ret
 }
@@ -0,0 +1,178 @@
+//! Readings supplied by end users, layered over the base dictionary.
+//!
+//! The file format is the one of the embedded dictionary, one character
+//! per line followed by its readings:
+//!
+//! ```text
+//! # comments and blank lines are ignored
+//! 行: xing2, hang2
+//! 㐀: qiu1
+//! ```
+
+use crate::dict_loader::{DictError, DictLoader};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+use std::{fs, io};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(transparent))]
+pub struct UserDict {
+    entries: BTreeMap<char, Vec<String>>,
+}
+
+#[derive(Debug)]
+pub enum UserDictError {
+    Io(io::Error),
+    /// A line without a `char: readings` shape, numbered from 1.
+    Syntax(usize),
+    /// A reading that is not lowercase pinyin with a tone digit.
+    InvalidReading(usize, String),
+}
+
+impl Display for UserDictError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UserDictError::Io(e) => write!(f, "{}", e),
+            UserDictError::Syntax(line) => write!(f, "line {}: expected `char: readings`", line),
+            UserDictError::InvalidReading(line, reading) => write!(f, "line {}: invalid reading `{}`", line, reading),
+        }
+    }
+}
+
+impl Error for UserDictError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            UserDictError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for UserDictError {
+    fn from(value: io::Error) -> Self {
+        UserDictError::Io(value)
+    }
+}
+
+/// Lowercase letters followed by a tone from 0 to 4, `v` standing for ü.
+pub fn is_valid_reading(s: &str) -> bool {
+    match s.as_bytes().split_last() {
+        Some((tone, syllable)) => {
+            (b'0'..=b'4').contains(tone) && !syllable.is_empty() && syllable.iter().all(u8::is_ascii_lowercase)
+        }
+        None => false,
+    }
+}
+
+impl UserDict {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, UserDictError> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    /// Adds a reading for `c`, after the ones it already has. Returns false
+    /// if the reading is invalid or already present.
+    pub fn add(&mut self, c: char, reading: &str) -> bool {
+        if !is_valid_reading(reading) {
+            return false;
+        }
+        let readings = self.entries.entry(c).or_default();
+        if readings.iter().any(|x| x == reading) {
+            return false;
+        }
+        readings.push(reading.to_string());
+        true
+    }
+
+    /// Removes one reading of `c`, dropping the character once it has none.
+    pub fn remove(&mut self, c: char, reading: &str) -> bool {
+        let Some(readings) = self.entries.get_mut(&c) else {
+            return false;
+        };
+        let len = readings.len();
+        readings.retain(|x| x != reading);
+        let removed = readings.len() != len;
+        if readings.is_empty() {
+            self.entries.remove(&c);
+        }
+        removed
+    }
+
+    /// Forgets every reading of `c`.
+    pub fn remove_char(&mut self, c: char) -> bool {
+        self.entries.remove(&c).is_some()
+    }
+
+    pub fn get(&self, c: char) -> Option<&[String]> {
+        self.entries.get(&c).map(Vec::as_slice)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (char, &[String])> {
+        self.entries.iter().map(|(c, v)| (*c, v.as_slice()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl FromStr for UserDict {
+    type Err = UserDictError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ret = UserDict::new();
+        for (i, line) in s.lines().enumerate().map(|(i, x)| (i + 1, x.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (c, readings) = line.split_once(':').ok_or(UserDictError::Syntax(i))?;
+            let mut chars = c.trim().chars();
+            let (Some(c), None) = (chars.next(), chars.next()) else {
+                return Err(UserDictError::Syntax(i));
+            };
+            for reading in readings.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+                if !is_valid_reading(reading) {
+                    return Err(UserDictError::InvalidReading(i, reading.to_string()));
+                }
+                ret.add(c, reading);
+            }
+        }
+        Ok(ret)
+    }
+}
+
+impl Display for UserDict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (c, readings) in &self.entries {
+            writeln!(f, "{}: {}", c, readings.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl UserDict {
+    pub(crate) fn readings(&self) -> HashMap<char, Vec<String>> {
+        self.entries.iter().map(|(c, v)| (*c, v.clone())).collect()
+    }
+}
+
+/// Never fails, entries are checked as they are added.
+impl DictLoader for &UserDict {
+    fn load_dict(&self) -> Result<HashMap<char, Vec<String>>, DictError> {
+        Ok(self.readings())
+    }
+}
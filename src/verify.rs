@@ -0,0 +1,168 @@
+//! Consistency checks for keyboard layouts.
+
+use crate::keyboard::{Keyboard, KEYBOARD_DAQIAN, KEYBOARD_QUANPIN, KEYBOARD_XIAOHE, KEYBOARD_ZIRANMA};
+use crate::pinin::PinIn;
+use crate::searcher::SearcherLogic;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Problems found by [`verify`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KeyboardReport {
+    /// Syllables with a phoneme that has no key, paired with that phoneme.
+    pub unreachable: Vec<(String, String)>,
+    /// Key sequences typed for more than one syllable.
+    pub collisions: Vec<(String, Vec<String>)>,
+}
+
+impl KeyboardReport {
+    pub fn is_ok(&self) -> bool {
+        self.unreachable.is_empty() && self.collisions.is_empty()
+    }
+}
+
+pub use crate::format::syllables;
+
+/// Cross-checks `keyboard` against the syllable inventory.
+///
+/// A phoneme is unreachable when the layout has a key table without an
+/// entry for it and it is longer than a single key, or, on double-pinyin
+/// layouts, when its key is not a single character.
+pub fn verify(keyboard: &Keyboard, inventory: &BTreeSet<String>) -> KeyboardReport {
+    let mut ret = KeyboardReport::default();
+    let mut typed: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+    for syllable in inventory {
+        // Any tone works here, only the syllable part is typed
+        let raw = format!("{}1", syllable);
+        let mut phonemes = keyboard.split(&raw);
+        // Layouts cutting zero-initial syllables drop the tone themselves
+        if phonemes.last().map(|x| x == "1").unwrap_or(false) {
+            phonemes.pop();
+        }
+
+        let mut keys = String::new();
+        for phoneme in &phonemes {
+            let key = keyboard.keys(phoneme);
+            let missing = keyboard.has_keys() && key == phoneme.as_ref() && phoneme.chars().count() > 1;
+            let too_long = keyboard.duo && key.chars().count() != 1;
+            if missing || too_long {
+                ret.unreachable.push((syllable.clone(), phoneme.to_string()));
+            }
+            keys.push_str(key);
+        }
+        typed.entry(keys).or_default().push(syllable.clone());
+    }
+
+    ret.collisions = typed.into_iter().filter(|(_, v)| v.len() > 1).collect();
+    ret
+}
+
+/// A reference case: typing `query` on a layout should match `target`
+/// under `logic` exactly when `expected` is true.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TestVector<'a> {
+    pub logic: SearcherLogic,
+    pub target: &'a str,
+    pub query: &'a str,
+    pub expected: bool,
+}
+
+impl<'a> TestVector<'a> {
+    /// A vector expecting `query` to be contained in `target`.
+    pub const fn hit(target: &'a str, query: &'a str) -> Self {
+        TestVector { logic: SearcherLogic::Contain, target, query, expected: true }
+    }
+
+    /// A vector expecting `query` not to be contained in `target`.
+    pub const fn miss(target: &'a str, query: &'a str) -> Self {
+        TestVector { logic: SearcherLogic::Contain, target, query, expected: false }
+    }
+}
+
+pub const QUANPIN_VECTORS: &[TestVector<'static>] = &[
+    TestVector::hit("测试文本", "ceshiwenben"),
+    TestVector::hit("测试文本", "ceshiwenbe"),
+    TestVector::hit("测试文本", "ceshiwben"),
+    TestVector::hit("测试文本", "ce4shi4wb"),
+    TestVector::miss("测试文本", "ce2shi4wb"),
+    TestVector::hit("合金炉", "hejinlu"),
+    TestVector::hit("洗矿场", "xikuangchang"),
+    TestVector::hit("石头", "stou"),
+    TestVector::hit("安全", "aquan"),
+    TestVector::hit("昂扬", "ayang"),
+    TestVector::miss("昂扬", "anyang"),
+    TestVector::hit("昂扬", "angyang"),
+];
+
+pub const DAQIAN_VECTORS: &[TestVector<'static>] = &[
+    TestVector::hit("测试文本", "hk4g4jp61p3"),
+    TestVector::hit("测试文本", "hkgjp1"),
+    TestVector::hit("錫", "vu6"),
+    TestVector::hit("鑽石", "yj0"),
+    TestVector::hit("物質", "j456"),
+    TestVector::hit("腳手架", "rul3g.3ru84"),
+    TestVector::hit("鵝", "k6"),
+    TestVector::hit("葉", "u,4"),
+    TestVector::hit("共同", "ej/wj/"),
+];
+
+pub const XIAOHE_VECTORS: &[TestVector<'static>] = &[
+    TestVector::hit("测试文本", "ceuiwfbf"),
+    TestVector::hit("测试文本", "ceuiwf2"),
+    TestVector::miss("测试文本", "ceuiw2"),
+    TestVector::hit("合金炉", "hej"),
+    TestVector::hit("洗矿场", "xikl4"),
+    TestVector::hit("月球", "ytqq"),
+    TestVector::hit("中国", "vsgo"),
+];
+
+pub const ZIRANMA_VECTORS: &[TestVector<'static>] = &[
+    TestVector::hit("测试文本", "ceuiwfbf"),
+    TestVector::hit("测试文本", "ceuiwf2"),
+    TestVector::miss("测试文本", "ceuiw2"),
+    TestVector::hit("合金炉", "hej"),
+    TestVector::hit("洗矿场", "xikd4"),
+    TestVector::hit("月球", "ytqq"),
+    TestVector::hit("安全", "anqr"),
+];
+
+/// The bundled vectors of a built-in layout, empty for other layouts.
+pub fn vectors(keyboard: &Keyboard) -> &'static [TestVector<'static>] {
+    [
+        (&*KEYBOARD_QUANPIN, QUANPIN_VECTORS),
+        (&*KEYBOARD_DAQIAN, DAQIAN_VECTORS),
+        (&*KEYBOARD_XIAOHE, XIAOHE_VECTORS),
+        (&*KEYBOARD_ZIRANMA, ZIRANMA_VECTORS),
+    ]
+    .into_iter()
+    .find(|(x, _)| std::ptr::eq(*x, keyboard))
+    .map(|(_, v)| v)
+    .unwrap_or_default()
+}
+
+/// Vectors that failed in [`conformance`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConformanceReport<'a> {
+    pub failures: Vec<TestVector<'a>>,
+}
+
+impl ConformanceReport<'_> {
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Runs `vectors` against `keyboard` with the embedded dictionary and
+/// default fuzzy settings, the way the built-in layouts are tested.
+pub fn conformance<'a>(keyboard: &'static Keyboard, vectors: &[TestVector<'a>]) -> ConformanceReport<'a> {
+    let mut context = PinIn::new();
+    context.keyboard = keyboard;
+    context.load_default_dict();
+    ConformanceReport {
+        failures: vectors
+            .iter()
+            .filter(|x| x.logic.test_pinyin(&context, x.target, x.query) != x.expected)
+            .copied()
+            .collect(),
+    }
+}